@@ -0,0 +1,129 @@
+//! A little drawing that the brachiograph can do by itself, for showing off without a computer.
+//!
+//! The firmware runs it when asked with [`Op::RunDemo`](crate::Op::RunDemo), feeding the ops
+//! from [`Demo`] into its queue as if they came from a host.
+
+use crate::{Fixed, Op, Point};
+
+/// The strokes of the demo drawing, in hundredths of a unit. These fit in the default drawing
+/// area (see [`geom::Config`](crate::geom::Config)).
+#[rustfmt::skip]
+const STROKES: &[&[(i16, i16)]] = &[
+    // A five-pointed star.
+    &[
+        (-450, 1200), (-509, 1031), (-688, 1027), (-545, 919), (-597, 748), (-450, 850),
+        (-303, 748), (-355, 919), (-212, 1027), (-391, 1031), (-450, 1200),
+    ],
+    // A circle.
+    &[
+        (200, 950), (193, 1002), (173, 1050), (141, 1091), (100, 1123), (52, 1143), (0, 1150),
+        (-52, 1143), (-100, 1123), (-141, 1091), (-173, 1050), (-193, 1002), (-200, 950),
+        (-193, 898), (-173, 850), (-141, 809), (-100, 777), (-52, 757), (0, 750), (52, 757),
+        (100, 777), (141, 809), (173, 850), (193, 898), (200, 950),
+    ],
+    // A spiral.
+    &[
+        (470, 950), (474, 958), (475, 968), (471, 979), (463, 989), (450, 996), (434, 999),
+        (417, 996), (400, 986), (386, 971), (378, 950), (376, 926), (383, 901), (398, 879),
+        (421, 861), (450, 851), (482, 851), (514, 862), (543, 883), (564, 913), (575, 950),
+        (574, 990), (560, 1030), (533, 1064), (495, 1089), (450, 1101), (402, 1099), (355, 1081),
+        (315, 1048), (286, 1003), (272, 950), (276, 894), (298, 839), (336, 794), (389, 761),
+        (450, 746), (515, 751), (576, 777), (628, 821), (664, 881), (680, 950),
+    ],
+    // A wavy line underneath.
+    &[
+        (-600, 650), (-550, 675), (-500, 693), (-450, 700), (-400, 693), (-350, 675), (-300, 650),
+        (-250, 625), (-200, 607), (-150, 600), (-100, 607), (-50, 625), (0, 650), (50, 675),
+        (100, 693), (150, 700), (200, 693), (250, 675), (300, 650), (350, 625), (400, 607),
+        (450, 600), (500, 607), (550, 625), (600, 650),
+    ],
+];
+
+/// The ops that draw the demo: each stroke starts with a move to its first point and a
+/// [`Op::PenDown`], and ends with a [`Op::PenUp`]. At the end, the pen gets parked.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Demo {
+    stroke: usize,
+    // The index of the next point in the stroke, except that the pen goes down between points 0
+    // and 1, and up after the last one.
+    point: usize,
+    pen_down: bool,
+}
+
+impl Demo {
+    pub fn new() -> Demo {
+        Demo::default()
+    }
+}
+
+fn point((x, y): (i16, i16)) -> Op {
+    Op::MoveTo(Point {
+        x: Fixed::from_num(x) / 100,
+        y: Fixed::from_num(y) / 100,
+    })
+}
+
+impl Iterator for Demo {
+    type Item = Op;
+
+    fn next(&mut self) -> Option<Op> {
+        let Some(stroke) = STROKES.get(self.stroke) else {
+            // One past the last stroke is parking.
+            if self.stroke == STROKES.len() {
+                self.stroke += 1;
+                return Some(Op::Park);
+            }
+            return None;
+        };
+        if self.point == 1 && !self.pen_down {
+            self.pen_down = true;
+            return Some(Op::PenDown);
+        }
+        match stroke.get(self.point) {
+            Some(&p) => {
+                self.point += 1;
+                Some(point(p))
+            }
+            None => {
+                self.stroke += 1;
+                self.point = 0;
+                self.pen_down = false;
+                Some(Op::PenUp)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geom::Config;
+
+    #[test]
+    fn fits_on_the_page() {
+        let config = Config::default();
+        let mut pen_down = false;
+        let mut demo = Demo::new();
+        let ops: Vec<Op> = demo.by_ref().collect();
+        for op in &ops {
+            match op {
+                Op::MoveTo(p) => assert!(config.coord_is_valid(p.x, p.y), "{p:?}"),
+                Op::PenDown => {
+                    assert!(!pen_down);
+                    pen_down = true;
+                }
+                Op::PenUp => {
+                    assert!(pen_down);
+                    pen_down = false;
+                }
+                Op::Park => assert!(!pen_down),
+                op => panic!("unexpected {op:?}"),
+            }
+        }
+        let points: usize = STROKES.iter().map(|s| s.len()).sum();
+        assert_eq!(ops.len(), points + 2 * STROKES.len() + 1);
+        assert_eq!(ops.last(), Some(&Op::Park));
+        assert_eq!(demo.next(), None);
+    }
+}
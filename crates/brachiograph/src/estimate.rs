@@ -0,0 +1,299 @@
+//! Working out how long a brachiograph will take to get through the ops in its queue.
+//!
+//! The firmware uses this to answer [`Op::EstimateTime`], so that the host can show how long a
+//! plot has left. It's only an estimate: moves that get clamped by the
+//! [`LimitPolicy`](crate::LimitPolicy) are shorter than the ones that were asked for.
+
+use crate::{
+    polar_target, turns_within, Angle, Brachiograph, Duration, Fixed, Instant, Op, PenState, Point,
+    Segment, State,
+};
+
+/// Adds up how long some ops take, keeping track of where the pen ends up and of the speed
+/// changes along the way.
+#[derive(Clone)]
+pub struct Estimate {
+    // This is only here for its settings: the speed, the pen lifting time, and everything that
+    // affects how long a movement takes (see `Brachiograph::movement_duration`).
+    settings: Brachiograph,
+    pos: Point,
+    pen: PenState,
+    // The angle that the pen goes down at, if it isn't its usual down position (see
+    // `Op::SetPenAngle`).
+    pen_angle: Option<Angle>,
+    // If the last op was a move, the direction it was going at the end and how long it took to
+    // slow down: if the next op is a move that carries straight on, the previous one doesn't
+    // slow down after all (see `Brachiograph::continue_into`).
+    prev_move: Option<((Fixed, Fixed), Duration)>,
+    total: Duration,
+}
+
+impl Estimate {
+    /// Starts an estimate from what `brachio` is doing at `now`, counting the time that's left
+    /// of its current movement or pen lift.
+    pub fn new(brachio: &Brachiograph, now: Instant) -> Estimate {
+        let zero = Duration::from_ticks(0);
+        let mut prev_move = None;
+        let (pos, pen, total) = match &brachio.state {
+            State::Resting(pos, pen) => (*pos, *pen, zero),
+            State::Moving(movement, pen) => {
+                // It has already been told whether to carry on into the next one.
+                if movement.continues {
+                    let heading = movement.segment.end_heading(movement.init);
+                    prev_move = Some((heading, zero));
+                }
+                (movement.target, *pen, movement.remaining(now))
+            }
+            State::Lifting(pos, pen, until) | State::Dwelling(pos, pen, until) => (
+                *pos,
+                *pen,
+                until.checked_duration_since(now).unwrap_or(zero),
+            ),
+        };
+        Estimate {
+            settings: brachio.clone(),
+            pos,
+            pen,
+            pen_angle: None,
+            prev_move,
+            total,
+        }
+    }
+
+    /// Says that the pen was put down at an angle (see [`Op::SetPenAngle`]) before the ops that
+    /// are going to be added, or `None` if it wasn't.
+    pub fn with_pen_angle(mut self, angle: Option<Angle>) -> Estimate {
+        self.pen_angle = angle;
+        self
+    }
+
+    /// Adds the time that `op` takes, if it comes after everything that has been added so far.
+    ///
+    /// Ops that don't take any time (like fast ops) are ignored. An [`Op::Pause`] only counts
+    /// the time to lift the pen and put it back down, and not the wait in between.
+    pub fn add(&mut self, op: &Op) {
+        let prev_move = self.prev_move.take();
+        match *op {
+            Op::SetSpeed(speed) => self.settings.set_speed(speed),
+            Op::SetPenLift(ms) => self.settings.set_pen_lift(Duration::millis(ms.into())),
+            Op::SetAngleInterpolation(max_len) => self.settings.set_angle_interpolation(max_len),
+            Op::PenUp => self.pen_to(PenState::Up),
+            Op::PenDown => self.pen_angle_to(None),
+            Op::SetPenAngle(angle) => self.pen_angle_to(Some(angle)),
+            Op::MoveBy(r, theta) => {
+                self.follow(Segment::Line(polar_target(self.pos, r, theta)), prev_move);
+            }
+            Op::Home | Op::Park | Op::Finish => {
+                let config = self.settings.config();
+                let (x, y) = if matches!(op, Op::Home) {
+                    config.home
+                } else {
+                    config.park
+                };
+                self.pen_to(PenState::Up);
+                self.follow(Segment::Line(Point { x, y }), None);
+            }
+            Op::Dwell(ms) => self.total += Duration::millis(ms.into()),
+            Op::Pause(_) => {
+                if self.pen == PenState::Down {
+                    self.total += self.settings.pen_lift() + self.settings.pen_lift();
+                }
+            }
+            ref op => {
+                if let Some(segment) = op.segment() {
+                    self.follow(segment, prev_move);
+                }
+            }
+        }
+    }
+
+    /// How long everything that has been added so far takes.
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    fn pen_to(&mut self, pen: PenState) {
+        if self.pen != pen {
+            self.pen = pen;
+            self.total += self.settings.pen_lift();
+        }
+    }
+
+    // Puts the pen down, at `angle` if there is one.
+    fn pen_angle_to(&mut self, angle: Option<Angle>) {
+        if self.pen == PenState::Down && self.pen_angle != angle {
+            // The pen servo moves without the pen going up (see `settle_pen`).
+            self.total += self.settings.pen_lift() / 2;
+        }
+        self.pen_to(PenState::Down);
+        self.pen_angle = angle;
+    }
+
+    // Follows `segment`, after a move that ended like `prev_move`.
+    fn follow(&mut self, segment: Segment, prev_move: Option<((Fixed, Fixed), Duration)>) {
+        let settings = &self.settings;
+        let heading = segment.start_heading(self.pos);
+        let blends = match (settings.blend_angle, prev_move) {
+            (Some(max), Some((prev, _))) => turns_within(prev, heading, max),
+            _ => false,
+        };
+        if let (true, Some((_, ramp_out))) = (blends, prev_move) {
+            // The previous move doesn't slow down after all.
+            self.total = self.total.checked_sub(ramp_out / 2).unwrap_or(self.total);
+        }
+        let pace = settings.pace(self.pos, &segment, !blends);
+        self.total += pace.total();
+        self.prev_move = Some((segment.end_heading(self.pos), pace.ramp_out));
+        self.pos = segment.end(self.pos);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Angle, Fixed, DEFAULT_BLEND_ANGLE, DEFAULT_PEN_LIFT};
+
+    fn point(x: i32, y: i32) -> Point {
+        Point {
+            x: Fixed::from_num(x),
+            y: Fixed::from_num(y),
+        }
+    }
+
+    // How long `brachio` takes to move in a line from `from` to `to`, starting and ending at
+    // rest. That's the time at full speed, plus a little bit to speed up and slow down.
+    fn line_time(brachio: &Brachiograph, from: Point, to: Point) -> Duration {
+        brachio.pace(from, &Segment::Line(to), true).total()
+    }
+
+    #[test]
+    fn adds_up_ops() {
+        let now = Instant::from_ticks(0);
+        let mut brachio = Brachiograph::new(0, 10).with_speed(2);
+        let mut estimate = Estimate::new(&brachio, now);
+        assert_eq!(estimate.total(), Duration::millis(0));
+
+        // Four units at two units per second.
+        estimate.add(&Op::MoveTo(point(4, 10)));
+        let there = line_time(&brachio, point(0, 10), point(4, 10));
+        assert!(there >= Duration::secs(2) && there < Duration::millis(2010));
+        assert_eq!(estimate.total(), there);
+        estimate.add(&Op::PenDown);
+        assert_eq!(estimate.total(), there + DEFAULT_PEN_LIFT);
+        // The pen is already down.
+        estimate.add(&Op::PenDown);
+        estimate.add(&Op::GetQueue);
+        assert_eq!(estimate.total(), there + DEFAULT_PEN_LIFT);
+
+        estimate.add(&Op::SetSpeed(Fixed::from_num(4)));
+        estimate.add(&Op::SetPenLift(100));
+        estimate.add(&Op::MoveBy(Fixed::from_num(4), Angle::from_degrees(180)));
+        estimate.add(&Op::PenUp);
+        brachio.set_speed(Fixed::from_num(4));
+        let back = line_time(&brachio, point(4, 10), point(0, 10));
+        assert_eq!(
+            estimate.total(),
+            there + back + DEFAULT_PEN_LIFT + Duration::millis(100)
+        );
+        estimate.add(&Op::Dwell(150));
+        assert_eq!(
+            estimate.total(),
+            there + back + DEFAULT_PEN_LIFT + Duration::millis(250)
+        );
+    }
+
+    #[test]
+    fn pen_angles() {
+        let now = Instant::from_ticks(0);
+        let brachio = Brachiograph::new(0, 10);
+        let mut estimate = Estimate::new(&brachio, now);
+        // Going down at an angle is like going down.
+        estimate.add(&Op::SetPenAngle(Angle::from_degrees(60)));
+        assert_eq!(estimate.total(), DEFAULT_PEN_LIFT);
+        estimate.add(&Op::SetPenAngle(Angle::from_degrees(60)));
+        assert_eq!(estimate.total(), DEFAULT_PEN_LIFT);
+        // Changing the angle (or going back to the usual down position) just waits for the
+        // servo to settle.
+        estimate.add(&Op::SetPenAngle(Angle::from_degrees(50)));
+        estimate.add(&Op::PenDown);
+        assert_eq!(estimate.total(), DEFAULT_PEN_LIFT + DEFAULT_PEN_LIFT);
+
+        // Starting out down at an angle.
+        let mut down = brachio.clone();
+        down.resting().unwrap().pen_down(now);
+        let later = now + DEFAULT_PEN_LIFT;
+        let mut angled = Estimate::new(&down, later).with_pen_angle(Some(Angle::from_degrees(50)));
+        angled.add(&Op::PenDown);
+        angled.add(&Op::PenDown);
+        assert_eq!(angled.total(), DEFAULT_PEN_LIFT / 2);
+    }
+
+    #[test]
+    fn counts_the_current_movement() {
+        let start = Instant::from_ticks(0);
+        let mut brachio = Brachiograph::new(0, 10).with_speed(2);
+        brachio.resting().unwrap().move_to(start, 4, 10).unwrap();
+
+        let now = start + Duration::millis(500);
+        let mut estimate = Estimate::new(&brachio, now);
+        let there = line_time(&brachio, point(0, 10), point(4, 10));
+        assert_eq!(estimate.total(), there - Duration::millis(500));
+        // The next move starts where this one ends.
+        estimate.add(&Op::MoveTo(point(4, 12)));
+        let up = line_time(&brachio, point(4, 10), point(4, 12));
+        assert_eq!(estimate.total(), there + up - Duration::millis(500));
+
+        let later = Estimate::new(&brachio, start + Duration::secs(5));
+        assert_eq!(later.total(), Duration::millis(0));
+    }
+
+    #[test]
+    fn parks_with_the_pen_up() {
+        let now = Instant::from_ticks(0);
+        let brachio = Brachiograph::new(0, 10);
+        let mut estimate = Estimate::new(&brachio, now);
+        estimate.add(&Op::PenDown);
+        estimate.add(&Op::Park);
+        assert_eq!(
+            brachio.config().park,
+            (Fixed::from_num(-8), Fixed::from_num(8))
+        );
+        assert_eq!(
+            estimate.total(),
+            DEFAULT_PEN_LIFT + DEFAULT_PEN_LIFT + line_time(&brachio, point(0, 10), point(-8, 8))
+        );
+        // It's already there.
+        let parked = estimate.total();
+        estimate.add(&Op::Finish);
+        assert_eq!(estimate.total(), parked);
+    }
+
+    #[test]
+    fn carries_straight_on() {
+        let now = Instant::from_ticks(0);
+        let brachio = Brachiograph::new(0, 10)
+            .with_speed(2)
+            .with_blend_angle(Some(DEFAULT_BLEND_ANGLE));
+        let first = brachio.pace(point(0, 10), &Segment::Line(point(0, 11)), true);
+        let second = brachio.pace(point(0, 11), &Segment::Line(point(0, 12)), false);
+        let mut estimate = Estimate::new(&brachio, now);
+        estimate.add(&Op::MoveTo(point(0, 11)));
+        estimate.add(&Op::MoveTo(point(0, 12)));
+        // The first doesn't slow down, and the second doesn't speed up.
+        assert_eq!(
+            estimate.total(),
+            first.total() - first.ramp_out / 2 + second.total()
+        );
+        assert!(estimate.total() < first.total() + line_time(&brachio, point(0, 11), point(0, 12)));
+
+        // Anything in between makes it stop.
+        let mut estimate = Estimate::new(&brachio, now);
+        estimate.add(&Op::MoveTo(point(0, 11)));
+        estimate.add(&Op::SetSpeed(Fixed::from_num(2)));
+        estimate.add(&Op::MoveTo(point(0, 12)));
+        assert_eq!(
+            estimate.total(),
+            first.total() + line_time(&brachio, point(0, 11), point(0, 12))
+        );
+    }
+}
@@ -4,8 +4,13 @@ use crate::{Angle, Angles, Fixed};
 
 use cordic::{asin, atan, cos, sin, sqrt};
 use fixed::traits::{FromFixed, ToFixed};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+/// The shape of a brachiograph, and the part of the page that it draws on.
+///
+/// Brachiographs with different arm lengths can share firmware by sending their own config
+/// (see [`crate::Op::SetConfig`]).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     // Length of the arms. We assume they're the same length: it cuts down
     // on the required trig operations.
@@ -15,6 +20,37 @@ pub struct Config {
     pub elbow_range: (Angle, Angle),
     pub x_range: (Fixed, Fixed),
     pub y_range: (Fixed, Fixed),
+
+    /// Where the pen goes for [`crate::Op::Home`]. This should be somewhere that's easy to
+    /// check by eye, like the middle of the drawing area.
+    pub home: (Fixed, Fixed),
+    /// Where the pen goes for [`crate::Op::Park`] (and after [`crate::Op::Cancel`]), out of the
+    /// way of the drawing.
+    pub park: (Fixed, Fixed),
+}
+
+// The derive can't format the pairs of numbers.
+#[cfg(feature = "defmt")]
+impl defmt::Format for Config {
+    fn format(&self, f: defmt::Formatter) {
+        use defmt::Display2Format as D;
+        defmt::write!(
+            f,
+            "Config {{ arm_len: {}, shoulder_range: {}, elbow_range: {}, x_range: ({}, {}), \
+             y_range: ({}, {}), home: ({}, {}), park: ({}, {}) }}",
+            D(&self.arm_len),
+            self.shoulder_range,
+            self.elbow_range,
+            D(&self.x_range.0),
+            D(&self.x_range.1),
+            D(&self.y_range.0),
+            D(&self.y_range.1),
+            D(&self.home.0),
+            D(&self.home.1),
+            D(&self.park.0),
+            D(&self.park.1),
+        );
+    }
 }
 
 impl Default for Config {
@@ -25,12 +61,15 @@ impl Default for Config {
             elbow_range: (Angle::from_degrees(-60), Angle::from_degrees(75)),
             x_range: ((-8).to_fixed(), 8.to_fixed()),
             y_range: (5.to_fixed(), 13.to_fixed()),
+            home: (0.to_fixed(), 9.to_fixed()),
+            park: ((-8).to_fixed(), 8.to_fixed()),
         }
     }
 }
 
 impl Config {
-    // The configuration is valid if every point in the configured x/y-range can be reached by the arms.
+    // The configuration is valid if every point in the configured x/y-range can be reached by the arms,
+    // and the home and park positions are in that range.
     pub fn is_valid(&self) -> bool {
         let (y0, y1) = self.y_range;
         let (x0, x1) = self.x_range;
@@ -39,6 +78,11 @@ impl Config {
         if y0 <= 0 || y1 <= y0 || x1 <= x0 {
             return false;
         }
+        for (x, y) in [self.home, self.park] {
+            if !self.coord_is_valid(x, y) {
+                return false;
+            }
+        }
 
         let x_max = x0.abs().max(x1.abs());
         if x_max * x_max + y1 * y1 >= 4 * ell * ell {
@@ -226,6 +270,9 @@ mod tests {
             let mut conf = Config::default();
             conf.x_range = (Fixed::from_num(x0), Fixed::from_num(x1));
             conf.y_range = (Fixed::from_num(y0), Fixed::from_num(y1));
+            // Keep the home and park positions out of the way of what we're checking.
+            conf.home = (Fixed::from_num(x1), Fixed::from_num(y1));
+            conf.park = conf.home;
             assert_eq!(good, conf.is_valid());
         }
 
@@ -238,5 +285,12 @@ mod tests {
         check(false, -8, 8, 5, 14);
         // The right-hand edge is too far for the shoulder.
         check(false, 4, 14, 3, 4);
+
+        // Parking has to be in the drawing area.
+        let conf = Config {
+            park: (Fixed::from_num(-9), Fixed::from_num(8)),
+            ..Config::default()
+        };
+        assert!(!conf.is_valid());
     }
 }
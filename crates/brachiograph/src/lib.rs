@@ -1,13 +1,19 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
+use cordic::{cos, sin, sqrt};
 use fixed::traits::ToFixed;
 
+pub mod demo;
+pub mod estimate;
 pub mod geom;
 pub mod pwm;
+pub mod segment;
 pub use fixed;
 pub use fugit;
 use serde::{Deserialize, Serialize};
 
+pub use segment::Segment;
+
 /// The type that we use for most of our numerical computations.
 ///
 /// (Because this library is intended to be usable on embedded processors
@@ -20,37 +26,317 @@ pub type Duration = fugit::Duration<u64, 1, 1_000_000>;
 /// The instant type that we use for most of our time calculations.
 pub type Instant = fugit::Instant<u64, 1, 1_000_000>;
 
+/// The speed of a brachiograph that hasn't been told otherwise, in units per second.
+pub const DEFAULT_SPEED: Fixed = Fixed::const_from_int(4);
+
+/// How long a brachiograph takes to put its pen up or down, if it hasn't been told otherwise.
+pub const DEFAULT_PEN_LIFT: Duration = Duration::millis(800);
+
+/// A good angle for [`Brachiograph::set_blend_angle`]: flattened curves turn by less than this
+/// at each corner, while the corners of most shapes are sharper.
+pub const DEFAULT_BLEND_ANGLE: Angle = Angle(Fixed::const_from_int(30));
+
+/// A good length for [`Brachiograph::set_angle_interpolation`]. With the default arms, a
+/// movement this long strays from its segment by less than 0.02 units when its joint angles are
+/// interpolated.
+pub const DEFAULT_ANGLE_INTERPOLATION: Fixed = Fixed::from_bits(1 << (Fixed::FRAC_NBITS - 1));
+
+/// How fast the joints of a brachiograph that hasn't been told otherwise may turn (see
+/// [`Brachiograph::set_joint_limits`]). Hobby servos manage about 600 degrees per second with
+/// nothing attached, so this leaves room for the weight of the arms. Ordinary drawing stays well
+/// inside it; it's for moves near the base, where a small move of the pen swings the arms a long
+/// way.
+pub const DEFAULT_JOINT_LIMITS: JointLimits = JointLimits {
+    max_speed: Fixed::const_from_int(300),
+    max_accel: Fixed::const_from_int(3000),
+};
+
+/// How many pieces a movement gets split into when checking it against the joint limits.
+const JOINT_LIMIT_SAMPLES: i32 = 8;
+
+/// If the next movement arrives more than this long after the previous one finished, it
+/// doesn't get blended: the brachiograph has been sitting still, and starting the movement in
+/// the past would make it jump. This is longer than the firmware's tick.
+const MAX_BLEND_GAP: Duration = Duration::millis(50);
+
+// A duration in milliseconds, keeping the fraction of a millisecond.
+fn millis(dur: Duration) -> Fixed {
+    let ticks = dur.ticks();
+    Fixed::saturating_from_num(ticks / 1000) + Fixed::from_num(ticks % 1000) / 1000
+}
+
+// How long a movement takes. It speeds up from rest for `ramp_in`, goes at its top speed, and
+// slows down to rest for `ramp_out`; at its top speed the whole way, it would take `steady`.
+// Either ramp can be zero, if the movement starts or ends at full speed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Pace {
+    ramp_in: Duration,
+    steady: Duration,
+    ramp_out: Duration,
+}
+
+impl Pace {
+    // If the movement is too short to get up to its top speed and back down again, the top
+    // speed gets lowered until it isn't. Then the ramps get shorter, because there's less speed
+    // to gain and lose.
+    fn new(ramp_in: Duration, steady: Duration, ramp_out: Duration) -> Pace {
+        let (mut a, mut b, mut c) = (ramp_in.ticks(), ramp_out.ticks(), steady.ticks());
+        if c == 0 {
+            (a, b) = (0, 0);
+        } else if a / 2 + b / 2 > c {
+            // Lowering the top speed by some factor stretches `steady` by that factor, and
+            // shrinks the ramps by it. With no time left at the top speed, the ramps are all
+            // there is, so `steady` is half of them.
+            let top = c.saturating_mul(a / 2 + b / 2).isqrt();
+            a = a.saturating_mul(c) / top;
+            b = b.saturating_mul(c) / top;
+            c = top;
+        }
+        Pace {
+            ramp_in: Duration::from_ticks(a),
+            steady: Duration::from_ticks(c),
+            ramp_out: Duration::from_ticks(b),
+        }
+    }
+
+    pub(crate) fn total(&self) -> Duration {
+        self.steady + self.ramp_in / 2 + self.ramp_out / 2
+    }
+}
+
 /// Represents a brachiograph in transition from one point to another.
 #[derive(Clone)]
-#[cfg_attr(target_os = "none", derive(defmt::Format))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Movement {
     init: Point,
+    segment: Segment,
     target: Point,
     start: Instant,
     dur: Duration,
+    // How long it spends speeding up from rest at the start, and slowing down to rest at the
+    // end (see `Pace`).
+    ramp_in: Duration,
+    ramp_out: Duration,
+    // Whether it goes straight on into the next movement, instead of slowing down at the end
+    // (see `Brachiograph::continue_into`).
+    continues: bool,
+    // The joint angles at the start and the end, if they're getting interpolated instead of
+    // following the segment (see `Brachiograph::set_angle_interpolation`).
+    angles: Option<(Angles, Angles)>,
 }
 
 impl Movement {
-    /// At time `now`, where is this movement?
-    pub fn interpolate(&self, now: Instant) -> Point {
-        let dur = now.checked_duration_since(self.start).unwrap();
-        let total_dur: Fixed = self.dur.to_millis().to_fixed();
-        let dur: Fixed = dur.to_millis().to_fixed();
-        let ratio = if total_dur > 0 {
-            (dur / total_dur).clamp(0.to_fixed(), 1.to_fixed())
-        } else {
-            1.to_fixed()
+    // How far through the movement we are at time `now`, from 0 to 1.
+    //
+    // Movements never start after the time they were made, so if `now` is before the start then
+    // the clock has gone wrong somehow. Finishing the movement is better than panicking.
+    fn ratio(&self, now: Instant) -> Fixed {
+        let Some(so_far) = now.checked_duration_since(self.start) else {
+            return Fixed::ONE;
         };
-        let ret = Point {
-            x: self.init.x + ratio * (self.target.x - self.init.x),
-            y: self.init.y + ratio * (self.target.y - self.init.y),
+        if so_far >= self.dur {
+            return Fixed::ONE;
+        }
+        let t = millis(so_far);
+        let total = millis(self.dur);
+        let (ramp_in, ramp_out) = (millis(self.ramp_in), millis(self.ramp_out));
+        // How long the movement would take if it went at its top speed the whole way.
+        let steady = millis(self.dur - self.ramp_in / 2 - self.ramp_out / 2);
+        if steady <= 0 {
+            return (t / total).clamp(Fixed::ZERO, Fixed::ONE);
+        }
+        // How far we've got, in milliseconds at the top speed. The speed goes up steadily
+        // during the ramps, so the distance goes up with the square of the time.
+        let dist = if t < ramp_in {
+            t / ramp_in * t / 2
+        } else if t <= total - ramp_out {
+            t - ramp_in / 2
+        } else {
+            let left = total - t;
+            steady - left / ramp_out * left / 2
         };
-        ret
+        (dist / steady).clamp(Fixed::ZERO, Fixed::ONE)
+    }
+
+    /// At time `now`, where is this movement?
+    ///
+    /// If the joint angles are getting interpolated, this is where the pen would be if it
+    /// followed the segment exactly, which isn't quite where it is.
+    pub fn interpolate(&self, now: Instant) -> Point {
+        self.segment.eval(self.init, self.ratio(now))
+    }
+
+    /// At time `now`, what are the joint angles? This is only known without working it out
+    /// from the position if the joint angles are getting interpolated.
+    pub fn interpolate_angles(&self, now: Instant) -> Option<Angles> {
+        let (init, target) = self.angles?;
+        let ratio = self.ratio(now);
+        Some(Angles {
+            shoulder: init.shoulder.interpolate(target.shoulder, ratio),
+            elbow: init.elbow.interpolate(target.elbow, ratio),
+        })
+    }
+
+    /// How long a movement along `segment` from `init` takes, at `speed` units per second.
+    ///
+    /// This doesn't count the joint limits, which can make a movement slower (see
+    /// [`Brachiograph::movement_duration`]), or blending (see [`Brachiograph::set_blend_angle`]),
+    /// which can make it finish sooner.
+    pub fn duration(init: Point, segment: &Segment, speed: Fixed) -> Duration {
+        // Very slow speeds saturate instead of overflowing.
+        let seconds = segment.length(init).saturating_div(speed);
+        Duration::millis(seconds.saturating_mul_int(1000).to_num())
+    }
+
+    /// How long the movement has left to go at time `now`.
+    pub fn remaining(&self, now: Instant) -> Duration {
+        match now.checked_duration_since(self.start) {
+            Some(so_far) => self
+                .dur
+                .checked_sub(so_far)
+                .unwrap_or(Duration::from_ticks(0)),
+            // See `Movement::ratio`.
+            None => Duration::from_ticks(0),
+        }
     }
 
     /// Has the movement finished moving?
+    ///
+    /// A movement that starts after `now` counts as finished (see `Movement::ratio`).
     pub fn is_finished(&self, now: Instant) -> bool {
-        now >= self.start + self.dur
+        now.checked_duration_since(self.start)
+            .is_none_or(|dur| dur >= self.dur)
+    }
+}
+
+/// Limits on how fast a brachiograph's joints turn, whatever it's asked to do (see
+/// [`Brachiograph::set_joint_limits`]).
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct JointLimits {
+    /// The fastest that either joint may turn, in degrees per second.
+    #[cfg_attr(feature = "defmt", defmt(Display2Format))]
+    pub max_speed: Fixed,
+    /// The fastest that either joint's speed may change, in degrees per second per second. This
+    /// is also how quickly a movement speeds up from rest and slows down to a stop.
+    #[cfg_attr(feature = "defmt", defmt(Display2Format))]
+    pub max_accel: Fixed,
+}
+
+impl Default for JointLimits {
+    fn default() -> Self {
+        DEFAULT_JOINT_LIMITS
+    }
+}
+
+impl JointLimits {
+    /// Both limits have to be positive.
+    pub fn is_valid(&self) -> bool {
+        self.max_speed > 0 && self.max_accel > 0
+    }
+
+    /// The shortest time that a movement along `segment` from `init` can take without breaking
+    /// these limits.
+    ///
+    /// The movement is checked in `pieces` pieces, by looking at the joint angles at the ends of
+    /// each one. If the joint angles are getting interpolated then one piece is exact, because
+    /// the joints turn at a steady speed. Points that the arms can't reach don't count.
+    pub fn min_duration(
+        &self,
+        config: &geom::Config,
+        init: Point,
+        segment: &Segment,
+        pieces: i32,
+    ) -> Duration {
+        let pieces = pieces.max(1);
+        let dt = Fixed::ONE / pieces;
+        let Ok(mut prev) = config.at_coord(init.x, init.y) else {
+            return Duration::from_ticks(0);
+        };
+        // The joint speeds, and how fast they change, in degrees per whole movement: the
+        // movement's duration (in seconds) divides these to get degrees per second.
+        let mut prev_speeds: Option<(Fixed, Fixed)> = None;
+        let (mut speed, mut accel) = (Fixed::ZERO, Fixed::ZERO);
+        for i in 1..=pieces {
+            let p = if i == pieces {
+                segment.end(init)
+            } else {
+                segment.eval(init, dt * i)
+            };
+            let Ok(angles) = config.at_coord(p.x, p.y) else {
+                return Duration::from_ticks(0);
+            };
+            let turn = |a: Angle, b: Angle| (b.degrees() - a.degrees()).saturating_mul_int(pieces);
+            let speeds = (
+                turn(prev.shoulder, angles.shoulder),
+                turn(prev.elbow, angles.elbow),
+            );
+            speed = speed.max(speeds.0.abs()).max(speeds.1.abs());
+            if let Some((sh, el)) = prev_speeds {
+                let change = |a: Fixed, b: Fixed| (b - a).saturating_mul_int(pieces).abs();
+                accel = accel.max(change(sh, speeds.0)).max(change(el, speeds.1));
+            }
+            prev_speeds = Some(speeds);
+            prev = angles;
+        }
+        // Stretching a movement by a factor slows the joints down by that factor, and their
+        // changes in speed by its square.
+        let for_speed = speed.saturating_div(self.max_speed);
+        let for_accel = sqrt(accel.saturating_div(self.max_accel));
+        let seconds = for_speed.max(for_accel);
+        Duration::millis(seconds.saturating_mul_int(1000).to_num())
+    }
+
+    /// How long the joints take to get up to speed at the start of a movement along `segment`
+    /// from `init`, and to slow down to a stop at its end, without turning faster or slower
+    /// more quickly than these limits allow.
+    ///
+    /// The movement takes `steady` at its top speed, and the joint speeds at its ends come from
+    /// the first and last of `pieces` pieces (like in [`JointLimits::min_duration`]).
+    pub fn ramps(
+        &self,
+        config: &geom::Config,
+        init: Point,
+        segment: &Segment,
+        pieces: i32,
+        steady: Duration,
+    ) -> (Duration, Duration) {
+        let pieces = pieces.max(1);
+        let dt = Fixed::ONE / pieces;
+        let angles = |i: i32| {
+            let p = if i == 0 {
+                init
+            } else if i == pieces {
+                segment.end(init)
+            } else {
+                segment.eval(init, dt * i)
+            };
+            config.at_coord(p.x, p.y).ok()
+        };
+        // How many times the movement fits into a second.
+        let per_second = Fixed::from_num(1000).saturating_div(millis(steady).max(Fixed::DELTA));
+        // The fastest that either joint turns during piece `i`, in degrees per second.
+        let speed = |i: i32| {
+            let (Some(a), Some(b)) = (angles(i), angles(i + 1)) else {
+                return Fixed::ZERO;
+            };
+            let turn = |a: Angle, b: Angle| (b.degrees() - a.degrees()).abs();
+            turn(a.shoulder, b.shoulder)
+                .max(turn(a.elbow, b.elbow))
+                .saturating_mul_int(pieces)
+                .saturating_mul(per_second)
+        };
+        let ramp = |speed: Fixed| {
+            let seconds = speed.saturating_div(self.max_accel);
+            // Rounded up, so that the ramp is never too short.
+            let micros = (seconds.to_bits().max(0) as u64) * 1_000_000;
+            Duration::micros(micros.div_ceil(1 << Fixed::FRAC_NBITS))
+        };
+        if steady.ticks() == 0 {
+            let zero = Duration::from_ticks(0);
+            return (zero, zero);
+        }
+        (ramp(speed(0)), ramp(speed(pieces - 1)))
     }
 }
 
@@ -63,6 +349,9 @@ pub enum State {
     Moving(Movement, PenState),
     /// Putting the pen either up or down (at a given point, and finishing at a given time).
     Lifting(Point, PenState, Instant),
+    /// Staying still (either pen up or pen down) at a point until a given time (see
+    /// [`Op::Dwell`]).
+    Dwelling(Point, PenState, Instant),
 }
 
 impl State {
@@ -81,7 +370,7 @@ impl State {
                     movement.interpolate(now)
                 }
             }
-            State::Lifting(pos, pen, until) => {
+            State::Lifting(pos, pen, until) | State::Dwelling(pos, pen, until) => {
                 let ret = *pos;
                 if now >= *until {
                     *self = State::Resting(ret, *pen);
@@ -105,9 +394,49 @@ pub struct Brachiograph {
     config: geom::Config,
     // Target speed, in units per second.
     speed: Fixed,
+    // How long it takes to put the pen up or down.
+    pen_lift: Duration,
+    // How sharp a corner can be blended (see `set_blend_angle`).
+    blend_angle: Option<Angle>,
+    // Movements up to this long get their joint angles interpolated (see
+    // `set_angle_interpolation`).
+    angle_interpolation: Option<Fixed>,
+    // How fast the joints may turn (see `set_joint_limits`).
+    joint_limits: JointLimits,
+    // When the most recent movement finished, and the direction it was going in, if nothing
+    // else has happened since.
+    finished: Option<(Instant, (Fixed, Fixed))>,
     state: State,
 }
 
+// Whether going in the direction `b` after going in the direction `a` turns by at most `max`.
+fn turns_within((ax, ay): (Fixed, Fixed), (bx, by): (Fixed, Fixed), max: Angle) -> bool {
+    let len = sqrt(ax * ax + ay * ay) * sqrt(bx * bx + by * by);
+    if len == 0 {
+        return false;
+    }
+    // The cosine of the angle between the headings.
+    let cos_turn = (ax * bx + ay * by) / len;
+    cos_turn >= cos(max.radians())
+}
+
+// Where a move of `r` in the direction `theta` (see `Op::MoveBy`) from `from` ends up.
+fn polar_target(from: Point, r: Fixed, theta: Angle) -> Point {
+    // Keep the angle small, so that the trigonometry stays accurate.
+    let full = Fixed::from_num(360);
+    let mut deg = theta.degrees() % full;
+    if deg > 180 {
+        deg -= full;
+    } else if deg < -180 {
+        deg += full;
+    }
+    let theta = Angle::from_degrees(deg).radians();
+    Point {
+        x: from.x + r * cos(theta),
+        y: from.y + r * sin(theta),
+    }
+}
+
 /// A brachiograph that is resting, ready to undertake another action.
 pub struct RestingBrachiograph<'a> {
     inner: &'a mut Brachiograph,
@@ -125,24 +454,60 @@ pub struct CalibratingBrachiograph<'a> {
 */
 
 impl<'a> RestingBrachiograph<'a> {
+    /// Where the pen is.
+    pub fn pos(&self) -> Point {
+        self.pos
+    }
+
     // TODO: error type
-    pub fn move_to(mut self, now: Instant, x: impl ToFixed, y: impl ToFixed) -> Result<(), ()> {
+    #[allow(clippy::result_unit_err)]
+    pub fn move_to(self, now: Instant, x: impl ToFixed, y: impl ToFixed) -> Result<(), ()> {
+        let target = Point {
+            x: x.to_fixed(),
+            y: y.to_fixed(),
+        };
+        self.follow(now, Segment::Line(target))
+    }
+
+    /// Moves in a straight line for a distance `r` in the direction `theta` (counter-clockwise
+    /// from the positive x axis).
+    #[allow(clippy::result_unit_err)]
+    pub fn move_by(self, now: Instant, r: Fixed, theta: Angle) -> Result<(), ()> {
+        let target = polar_target(self.pos, r, theta);
+        self.follow(now, Segment::Line(target))
+    }
+
+    /// Starts moving along a segment, failing if any of it is outside the workspace.
+    #[allow(clippy::result_unit_err)]
+    pub fn follow(self, now: Instant, segment: Segment) -> Result<(), ()> {
         let init = self.pos;
-        let x: Fixed = x.to_fixed();
-        let y: Fixed = y.to_fixed();
-        if !self.inner.config.coord_is_valid(x, y) {
+        if !segment.is_valid(init, &self.inner.config) {
             return Err(());
         };
 
-        let dx = x - init.x;
-        let dy = y - init.y;
-        let dist = cordic::sqrt(dx * dx + dy * dy);
-        let seconds = dist / self.inner.speed;
+        let blended = self.inner.blended_start(now, init, &segment);
+        // A movement that carries straight on from the previous one is already up to speed.
+        let pace = self.inner.pace(init, &segment, blended.is_none());
+        let len = segment.length(init);
+        let target = segment.end(init);
+        let config = &self.inner.config;
+        let angles = match self.inner.angle_interpolation {
+            Some(max_len) if len <= max_len => {
+                let init = config.at_coord(init.x, init.y).ok();
+                init.zip(config.at_coord(target.x, target.y).ok())
+            }
+            _ => None,
+        };
         let mov = Movement {
             init,
-            target: Point { x, y },
-            start: now,
-            dur: Duration::millis((seconds * 1000).to_num()),
+            segment,
+            target,
+            start: blended.unwrap_or(now),
+            dur: pace.total(),
+            ramp_in: pace.ramp_in,
+            ramp_out: pace.ramp_out,
+            continues: false,
+            angles,
         };
         self.inner.state = State::Moving(mov, self.pen);
         Ok(())
@@ -154,7 +519,8 @@ impl<'a> RestingBrachiograph<'a> {
     pub fn pen_up(mut self, now: Instant) {
         if self.pen == PenState::Down {
             self.pen = PenState::Up;
-            self.inner.state = State::Lifting(self.pos, PenState::Up, now + Duration::millis(800));
+            self.inner.finished = None;
+            self.inner.state = State::Lifting(self.pos, PenState::Up, now + self.inner.pen_lift);
         }
     }
 
@@ -164,10 +530,26 @@ impl<'a> RestingBrachiograph<'a> {
     pub fn pen_down(mut self, now: Instant) {
         if self.pen == PenState::Up {
             self.pen = PenState::Down;
-            self.inner.state =
-                State::Lifting(self.pos, PenState::Down, now + Duration::millis(800));
+            self.inner.finished = None;
+            self.inner.state = State::Lifting(self.pos, PenState::Down, now + self.inner.pen_lift);
         }
     }
+
+    /// Stays still for `dur`, without moving the pen up or down (see [`Op::Dwell`]).
+    pub fn dwell(self, now: Instant, dur: Duration) {
+        self.inner.finished = None;
+        self.inner.state = State::Dwelling(self.pos, self.pen, now + dur);
+    }
+
+    /// Waits for the pen servo to settle after being moved without going up or down, like
+    /// when the pen gets pressed harder.
+    ///
+    /// This takes half the [pen lifting time](Brachiograph::set_pen_lift), which is how long
+    /// the pen servo gets to settle when the pen goes up or down.
+    pub fn settle_pen(self, now: Instant) {
+        self.inner.finished = None;
+        self.inner.state = State::Lifting(self.pos, self.pen, now + self.inner.pen_lift / 2);
+    }
 }
 
 impl Brachiograph {
@@ -177,31 +559,250 @@ impl Brachiograph {
             y: y.to_fixed(),
         };
         Brachiograph {
-            // Note that we only ever use the default config, whose validity is checked in the tests.
-            // If we ever use a non-default config, make sure to check validity at runtime.
+            // Any config other than the default one gets checked in `set_config`.
             config: Default::default(),
             state: State::Resting(pos, PenState::Up),
-            speed: Fixed::from_num(4),
+            speed: DEFAULT_SPEED,
+            pen_lift: DEFAULT_PEN_LIFT,
+            blend_angle: None,
+            angle_interpolation: None,
+            joint_limits: DEFAULT_JOINT_LIMITS,
+            finished: None,
         }
     }
 
+    /// Sets the sharpest corner to blend (see [`Brachiograph::set_blend_angle`]).
+    pub fn with_blend_angle(mut self, angle: Option<Angle>) -> Brachiograph {
+        self.set_blend_angle(angle);
+        self
+    }
+
+    /// Sets the longest movement to interpolate in angle space (see
+    /// [`Brachiograph::set_angle_interpolation`]).
+    pub fn with_angle_interpolation(mut self, max_len: Option<Fixed>) -> Brachiograph {
+        self.set_angle_interpolation(max_len);
+        self
+    }
+
+    /// Sets the drawing speed (see [`Brachiograph::set_speed`]).
+    pub fn with_speed(mut self, speed: impl ToFixed) -> Brachiograph {
+        self.set_speed(speed);
+        self
+    }
+
+    /// Sets the pen lifting time (see [`Brachiograph::set_pen_lift`]).
+    pub fn with_pen_lift(mut self, pen_lift: Duration) -> Brachiograph {
+        self.set_pen_lift(pen_lift);
+        self
+    }
+
+    /// Sets the limits on how fast the joints turn (see [`Brachiograph::set_joint_limits`]).
+    pub fn with_joint_limits(mut self, limits: JointLimits) -> Brachiograph {
+        self.set_joint_limits(limits);
+        self
+    }
+
+    /// The speed that the brachiograph moves at, in units per second.
+    pub fn speed(&self) -> Fixed {
+        self.speed
+    }
+
+    /// Changes the speed that the brachiograph moves at, in units per second.
+    ///
+    /// This doesn't affect a movement that has already started. The speed must be positive.
+    pub fn set_speed(&mut self, speed: impl ToFixed) {
+        let speed: Fixed = speed.to_fixed();
+        debug_assert!(speed > 0);
+        self.speed = speed.max(Fixed::DELTA);
+    }
+
+    /// How long the brachiograph takes to put its pen up or down.
+    pub fn pen_lift(&self) -> Duration {
+        self.pen_lift
+    }
+
+    /// Changes how long the brachiograph waits for its pen to go up or down.
+    ///
+    /// This doesn't affect a lift that has already started.
+    pub fn set_pen_lift(&mut self, pen_lift: Duration) {
+        self.pen_lift = pen_lift;
+    }
+
+    /// Changes the sharpest corner that gets blended, or turns off blending if `None`.
+    ///
+    /// Normally, a movement starts when it's asked for, which is usually the first tick after
+    /// the previous one finished. So the pen waits at every corner, and a run of short movements
+    /// (like a flattened curve) is much slower than it should be. With blending, a movement that
+    /// continues in roughly the same direction as the previous one starts at the moment that
+    /// the previous one finished, so the pen doesn't pause at all. The previous one has to know
+    /// that it's coming (see [`Brachiograph::continue_into`]), or it slows down to a stop.
+    pub fn set_blend_angle(&mut self, angle: Option<Angle>) {
+        self.blend_angle = angle;
+    }
+
+    /// Makes movements that are at most `max_len` long interpolate between the joint angles at
+    /// their ends, instead of following their segments exactly; `None` turns this off.
+    ///
+    /// Following a segment means working out the joint angles for every point along it, which
+    /// is most of the work that goes into each [`Brachiograph::update`]. Interpolating the
+    /// angles is much cheaper, and over a short movement (like a piece of a flattened curve)
+    /// the pen hardly strays from the segment; see [`DEFAULT_ANGLE_INTERPOLATION`]. This
+    /// doesn't affect a movement that has already started.
+    ///
+    /// It's off unless it gets turned on with this (or with [`Op::SetAngleInterpolation`]).
+    pub fn set_angle_interpolation(&mut self, max_len: Option<Fixed>) {
+        self.angle_interpolation = max_len;
+    }
+
+    /// The limits on how fast the joints turn.
+    pub fn joint_limits(&self) -> JointLimits {
+        self.joint_limits
+    }
+
+    /// Changes the limits on how fast the joints turn. Movements that would turn them faster
+    /// get slowed down, whatever the speed (see [`Brachiograph::set_speed`]).
+    ///
+    /// This is the last line of defence against moves that would fling the arms around, like
+    /// one that passes close to the shoulder, or a curve with a wild control point. It doesn't
+    /// affect a movement that has already started. The limits must be positive (see
+    /// [`JointLimits::is_valid`]).
+    pub fn set_joint_limits(&mut self, limits: JointLimits) {
+        debug_assert!(limits.is_valid());
+        self.joint_limits = JointLimits {
+            max_speed: limits.max_speed.max(Fixed::DELTA),
+            max_accel: limits.max_accel.max(Fixed::DELTA),
+        };
+    }
+
+    /// How long a movement along `segment` from `init` would take at its top speed: the speed,
+    /// or slower if that's what it takes to stay within the joint limits.
+    ///
+    /// It takes a little longer than this if it has to speed up at the start or slow down at
+    /// the end (see [`Brachiograph::continue_into`]).
+    pub fn movement_duration(&self, init: Point, segment: &Segment) -> Duration {
+        let dur = Movement::duration(init, segment, self.speed);
+        dur.max(self.joint_limits.min_duration(
+            &self.config,
+            init,
+            segment,
+            self.limit_pieces(init, segment),
+        ))
+    }
+
+    // How many pieces to check a movement in against the joint limits.
+    fn limit_pieces(&self, init: Point, segment: &Segment) -> i32 {
+        match self.angle_interpolation {
+            Some(max_len) if segment.length(init) <= max_len => 1,
+            _ => JOINT_LIMIT_SAMPLES,
+        }
+    }
+
+    // How a movement along `segment` from `init` goes, if it starts from rest or if it carries
+    // straight on from the one before.
+    pub(crate) fn pace(&self, init: Point, segment: &Segment, from_rest: bool) -> Pace {
+        let steady = self.movement_duration(init, segment);
+        let pieces = self.limit_pieces(init, segment);
+        let (ramp_in, ramp_out) =
+            self.joint_limits
+                .ramps(&self.config, init, segment, pieces, steady);
+        let ramp_in = if from_rest {
+            ramp_in
+        } else {
+            Duration::from_ticks(0)
+        };
+        Pace::new(ramp_in, steady, ramp_out)
+    }
+
+    /// Says that the movement in progress will be followed straight away by one along `next`,
+    /// so that if `next` goes in nearly the same direction (see
+    /// [`Brachiograph::set_blend_angle`]), this one carries straight on into it instead of
+    /// slowing down to a stop.
+    ///
+    /// Every movement stops at its end unless it's told about the next one like this, because
+    /// stopping suddenly would break the [joint limits](JointLimits). It's too late once the
+    /// movement has started to slow down, and this does nothing if the brachiograph isn't
+    /// moving.
+    pub fn continue_into(&mut self, now: Instant, next: &Segment) {
+        let (Some(max), State::Moving(mov, _)) = (self.blend_angle, &mut self.state) else {
+            return;
+        };
+        if mov.continues || now >= mov.start + (mov.dur - mov.ramp_out) {
+            return;
+        }
+        let heading = mov.segment.end_heading(mov.init);
+        if turns_within(heading, next.start_heading(mov.target), max) {
+            // Without the ramp at the end, the rest of the movement goes the same way.
+            mov.dur -= mov.ramp_out / 2;
+            mov.ramp_out = Duration::from_ticks(0);
+            mov.continues = true;
+        }
+    }
+
+    /// Where the pen will be once the brachiograph has finished what it's doing.
+    pub fn destination(&self) -> Point {
+        match &self.state {
+            State::Resting(pos, _) | State::Lifting(pos, ..) | State::Dwelling(pos, ..) => *pos,
+            State::Moving(mov, _) => mov.target,
+        }
+    }
+
+    // If a movement starting now from `init` along `segment` carries straight on from the
+    // previous one, when it should really start.
+    fn blended_start(&mut self, now: Instant, init: Point, segment: &Segment) -> Option<Instant> {
+        let (Some(max), Some((at, heading))) = (self.blend_angle, self.finished.take()) else {
+            return None;
+        };
+        if !matches!(now.checked_duration_since(at), Some(gap) if gap <= MAX_BLEND_GAP) {
+            return None;
+        }
+        turns_within(heading, segment.start_heading(init), max).then_some(at)
+    }
+
     pub fn config(&self) -> &geom::Config {
         &self.config
     }
 
+    /// Changes the shape of the brachiograph and the area that it draws in.
+    ///
+    /// This fails (and changes nothing) if the arms can't reach all of the new area (see
+    /// [`geom::Config::is_valid`]), if the pen is in the middle of moving, or if the pen is
+    /// resting somewhere outside the new area.
+    pub fn set_config(&mut self, config: geom::Config) -> Result<(), ()> {
+        let State::Resting(pos, _) = self.state else {
+            return Err(());
+        };
+        if !config.is_valid() || !config.coord_is_valid(pos.x, pos.y) {
+            return Err(());
+        }
+        self.config = config;
+        self.finished = None;
+        Ok(())
+    }
+
     pub fn warp_to(&mut self, x: impl ToFixed, y: impl ToFixed) {
         let pos = Point {
             x: x.to_fixed(),
             y: y.to_fixed(),
         };
         self.state = State::Resting(pos, PenState::Up);
+        self.finished = None;
+    }
+
+    /// Abandons whatever the brachiograph was doing, leaving it resting with the pen up
+    /// wherever it is now.
+    pub fn stop(&mut self, now: Instant) {
+        let pos = self.state.update(now);
+        self.state = State::Resting(pos, PenState::Up);
+        self.finished = None;
     }
 
     pub fn pen(&self, now: Instant) -> PenState {
         match self.state {
-            State::Resting(_, pen) | State::Moving(_, pen) => pen,
+            State::Resting(_, pen) | State::Moving(_, pen) | State::Dwelling(_, pen, _) => pen,
             State::Lifting(_, pen, finished) => {
-                if now >= (finished - Duration::millis(400)) {
+                // The pen servo gets told to move halfway through, so that it has time to
+                // settle before we start moving.
+                if now >= (finished - self.pen_lift / 2) {
                     pen
                 } else {
                     !pen
@@ -210,6 +811,34 @@ impl Brachiograph {
         }
     }
 
+    /// If the pen is going up or down, where it's going and how far (from 0 to 1) it is
+    /// through the first half of the pen lifting time, which is when the pen servo gets to move
+    /// (see [`pwm::PenRamp`]).
+    ///
+    /// The pen servo is done moving once this gets to 1, which is when [`Brachiograph::pen`]
+    /// changes.
+    pub fn lift_progress(&self, now: Instant) -> Option<(PenState, Fixed)> {
+        let State::Lifting(_, pen, finished) = self.state else {
+            return None;
+        };
+        let half = self.pen_lift / 2;
+        let move_at = finished - half;
+        let progress = if now >= move_at || half.ticks() == 0 {
+            Fixed::ONE
+        } else {
+            // In thousandths, because the times could be too long for a `Fixed`.
+            let left = (move_at - now).min(half);
+            let done = (half - left).ticks() * 1000 / half.ticks();
+            Fixed::from_num(done) / 1000
+        };
+        Some((pen, progress))
+    }
+
+    /// Is the brachiograph resting (see [`Brachiograph::resting`])?
+    pub fn is_resting(&self) -> bool {
+        self.state.is_resting()
+    }
+
     pub fn resting(&mut self) -> Option<RestingBrachiograph<'_>> {
         if let State::Resting(pos, pen) = &self.state {
             Some(RestingBrachiograph {
@@ -223,6 +852,15 @@ impl Brachiograph {
     }
 
     pub fn update(&mut self, now: Instant) -> Angles {
+        if let State::Moving(mov, _) = &self.state {
+            if mov.is_finished(now) {
+                // The next movement can only pick up where this one left off if it didn't stop.
+                let heading = mov.segment.end_heading(mov.init);
+                self.finished = mov.continues.then_some((mov.start + mov.dur, heading));
+            } else if let Some(angles) = mov.interpolate_angles(now) {
+                return angles;
+            }
+        }
         let pos = self.state.update(now);
         // FIXME: unwrap. Should we store both position and angles?
         self.config.at_coord(pos.x, pos.y).unwrap()
@@ -234,7 +872,7 @@ impl Brachiograph {
 #[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Angle(Fixed);
 
-#[cfg(target_os = "none")]
+#[cfg(feature = "defmt")]
 impl defmt::Format for Angle {
     fn format(&self, f: defmt::Formatter) {
         let degs: i32 = self.0.to_num();
@@ -303,9 +941,15 @@ impl core::ops::Sub<Angle> for Angle {
     }
 }
 
+impl core::ops::SubAssign<Angle> for Angle {
+    fn sub_assign(&mut self, rhs: Angle) {
+        self.0 -= rhs.degrees()
+    }
+}
+
 /// Represented as milliseconds, between 0 and 1000.
 #[derive(Debug)]
-#[cfg_attr(target_os = "none", derive(defmt::Format))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Delay(u16);
 
 impl Delay {
@@ -324,33 +968,65 @@ impl From<core::time::Duration> for Delay {
     }
 }
 
-#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
-#[cfg_attr(target_os = "none", derive(defmt::Format))]
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Angles {
     pub shoulder: Angle,
     pub elbow: Angle,
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
-#[cfg_attr(target_os = "none", derive(defmt::Format))]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct Point {
-    #[cfg_attr(target_os = "none", defmt(Display2Format))]
+    #[cfg_attr(feature = "defmt", defmt(Display2Format))]
     pub x: Fixed,
-    #[cfg_attr(target_os = "none", defmt(Display2Format))]
+    #[cfg_attr(feature = "defmt", defmt(Display2Format))]
     pub y: Fixed,
 }
 
+#[cfg(feature = "kurbo")]
+impl From<Point> for kurbo::Point {
+    fn from(p: Point) -> kurbo::Point {
+        kurbo::Point::new(p.x.to_num(), p.y.to_num())
+    }
+}
+
+/// Rounds to the nearest point that we can represent.
+///
+/// # Panics
+///
+/// Panics if the point is too far away to represent at all (see [`Fixed`]).
+#[cfg(feature = "kurbo")]
+impl From<kurbo::Point> for Point {
+    fn from(p: kurbo::Point) -> Point {
+        Point {
+            x: Fixed::from_num(p.x),
+            y: Fixed::from_num(p.y),
+        }
+    }
+}
+
 /// The "raw" position of the shoulder and elbow servos.
 ///
 /// This differs from [`Angles`] in that `Angles` have been calibrated.
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
-#[cfg_attr(target_os = "none", derive(defmt::Format))]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct ServoPosition {
     pub shoulder: u16,
     pub elbow: u16,
     pub pen: u16,
 }
 
+impl ServoPosition {
+    /// Moves the pen servo by `delta`, saturating instead of overflowing.
+    pub fn with_pen_delta(self, delta: i16) -> ServoPosition {
+        ServoPosition {
+            pen: (self.pen as i32 + delta as i32).clamp(0, u16::MAX as i32) as u16,
+            ..self
+        }
+    }
+}
+
 impl core::ops::Add<ServoPositionDelta> for ServoPosition {
     type Output = ServoPosition;
 
@@ -366,26 +1042,51 @@ impl core::ops::Add<ServoPositionDelta> for ServoPosition {
     }
 }
 
+/// How many slow ops are waiting in the brachiograph's queue.
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct QueueStatus {
+    pub len: u16,
+    pub capacity: u16,
+}
+
+impl QueueStatus {
+    /// The number of slow ops that can be sent without getting [`Resp::QueueFull`].
+    pub fn free(&self) -> u16 {
+        self.capacity.saturating_sub(self.len)
+    }
+}
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
-#[cfg_attr(target_os = "none", derive(defmt::Format))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Position {
     Raw(ServoPosition),
     Cooked(Point),
 }
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
-#[cfg_attr(target_os = "none", derive(defmt::Format))]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct ServoPositionDelta {
     pub shoulder: i16,
     pub elbow: i16,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ServoCalibration {
     pub data: arrayvec::ArrayVec<(i16, u16), 16>,
 }
 
-#[cfg(target_os = "none")]
+impl ServoCalibration {
+    /// Can the servo actually follow this calibration? The angles need to be increasing, and
+    /// the duties need to be ones that a servo understands.
+    pub fn is_valid(&self) -> bool {
+        self.data.len() >= 2
+            && self.data.windows(2).all(|w| w[0].0 < w[1].0)
+            && self.data.iter().all(|&(_, duty)| pwm::duty_is_valid(duty))
+    }
+}
+
+#[cfg(feature = "defmt")]
 impl defmt::Format for ServoCalibration {
     fn format(&self, _fmt: defmt::Formatter) {
         todo!()
@@ -393,14 +1094,14 @@ impl defmt::Format for ServoCalibration {
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-#[cfg_attr(target_os = "none", derive(defmt::Format))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Joint {
     Shoulder,
     Elbow,
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-#[cfg_attr(target_os = "none", derive(defmt::Format))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum PenState {
     Up,
     Down,
@@ -418,14 +1119,28 @@ impl core::ops::Not for PenState {
 }
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
-#[cfg_attr(target_os = "none", derive(defmt::Format))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Direction {
     Increasing,
     Decreasing,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-#[cfg_attr(target_os = "none", derive(defmt::Format))]
+/// Identifies one of several programs that are sharing a brachiograph.
+pub type SessionId = u32;
+
+/// Why the host asked the brachiograph to wait (see [`Op::Pause`]).
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PauseReason {
+    /// The next part of the drawing needs a different pen, with this number (counting from
+    /// zero, in the order that the pens are first used).
+    ChangePen(u8),
+    /// Something else that needs a person's attention.
+    Other,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Op {
     // Slow ops
     ChangePosition(ServoPositionDelta),
@@ -434,17 +1149,910 @@ pub enum Op {
     PenDown,
 
     // Fast ops
+    /// Empties the queue of slow ops, and then parks (see [`Op::Park`]).
     Cancel,
     Calibrate(Joint, Direction, ServoCalibration),
     GetPosition,
+    /// Asks for the exclusive right to send slow ops. This is granted if no one else
+    /// has it, or if the previous holder's ops have all finished and it hasn't been heard
+    /// from in a long time.
+    ///
+    /// Ops don't say who sent them, so the slow ops after a `Claim` are taken to come from
+    /// the session that it names. While someone else holds the claim, they get
+    /// [`Resp::Denied`].
+    Claim(SessionId),
+    /// Gives up a claim obtained by [`Op::Claim`].
+    Release(SessionId),
+    /// Like [`Op::ChangePosition`], but for the pen servo.
+    ChangePenPosition(i16),
+    /// Sets the pen servo's up and down duties.
+    CalibratePen(pwm::TogglePwm),
+    /// Stays still for this many milliseconds, with the pen wherever it is. This is for
+    /// stopping at sharp corners, so that the arms stop wobbling before the next move sets off
+    /// in a new direction.
+    ///
+    /// Like [`Op::PenUp`], this goes through the queue.
+    Dwell(u16),
+    /// Continues plotting after the brachiograph paused (see [`Op::SetPlotLimit`] and
+    /// [`Op::Pause`]), or after it ran out of ops in the middle of a stroke (see
+    /// [`Resp::Starved`]).
+    Resume,
+    /// Sets the maximum time (in seconds) that the brachiograph will plot continuously before
+    /// pausing with the pen up, or `None` to plot for as long as it's asked to.
+    SetPlotLimit(Option<u32>),
+    /// Asks how full the queue of slow ops is. The answer is a [`Resp::Queue`].
+    GetQueue,
+    /// Sets the drawing speed, in units per second (see [`Brachiograph::set_speed`]).
+    ///
+    /// This goes through the queue, so it only affects the moves that are sent after it.
+    SetSpeed(#[cfg_attr(feature = "defmt", defmt(Display2Format))] Fixed),
+    /// Sets how long (in milliseconds) to wait for the pen to go up or down.
+    ///
+    /// Like [`Op::SetSpeed`], this goes through the queue.
+    SetPenLift(u16),
+    /// Draws a quadratic Bézier curve with a control point and an end point.
+    QuadTo(Point, Point),
+    /// Draws a cubic Bézier curve with two control points and an end point.
+    CurveTo(Point, Point, Point),
+    /// Draws a circular arc around a center point, turning through an angle (counter-clockwise
+    /// if positive).
+    ArcTo(Point, Angle),
+    /// Gets the brachiograph out of the stopped state that it enters when its emergency stop
+    /// button is pressed (see [`Resp::EStop`]). This fails if the button is still held down.
+    ClearEStop,
+    /// Asks for the angles that the arms are at right now. The answer is a [`Resp::Angles`].
+    ///
+    /// The brachiograph only ever responds to ops, so to check that a move ended up in the right
+    /// place, wait for the queue to empty (see [`Op::GetQueue`]) and then ask.
+    GetAngles,
+    /// Asks the brachiograph to describe itself. The answer is a [`Resp::Info`].
+    GetInfo,
+    /// Moves in a straight line for a distance in a direction (counter-clockwise from the
+    /// positive x axis), starting from wherever the previous move ended.
+    MoveBy(
+        #[cfg_attr(feature = "defmt", defmt(Display2Format))] Fixed,
+        Angle,
+    ),
+    /// Says whether a joint's servo is mounted the other way around, in which case its duties
+    /// get mirrored (see [`pwm::Pwm::inverted`]).
+    SetInverted(Joint, bool),
+    /// Changes how often the servos get a pulse and how often their positions are updated. This
+    /// is part of the calibration, so it counts towards [`DeviceInfo::calibration_crc`].
+    SetTiming(pwm::Timing),
+    /// Changes the length of the arms and the area that the brachiograph draws in. This is
+    /// refused with [`ErrorCode::InvalidConfig`] if the arms can't reach all of the area, and
+    /// with [`ErrorCode::InvalidState`] if the brachiograph is busy.
+    SetConfig(geom::Config),
+    /// Asks for the length of the arms and the area that the brachiograph draws in. The answer
+    /// is a [`Resp::Config`].
+    GetConfig,
+    /// Finishes everything that was queued before this, and then waits with the pen up until
+    /// [`Op::Resume`]. Slow ops that arrive while it's waiting get [`Resp::Paused`].
+    ///
+    /// Slow ops that are sent after this but before the brachiograph gets to it are queued as
+    /// usual, so to be sure that they're refused, wait for the queue to empty first.
+    Pause(PauseReason),
+    /// Lifts the pen and moves it to the home position in the [config](geom::Config::home).
+    Home,
+    /// Lifts the pen and moves it to the park position in the [config](geom::Config::park), out
+    /// of the way of the drawing. [`Op::Cancel`] does this too.
+    Park,
+    /// Draws the built-in [demo](demo::Demo). While it's running, slow ops are refused with
+    /// [`ErrorCode::Busy`]; [`Op::Cancel`] stops it.
+    RunDemo,
+    /// Asks how far the brachiograph has got. The answer is a [`Resp::Progress`].
+    GetProgress,
+    /// Says what to do with moves that would take the pen outside the drawing area (see
+    /// [`LimitPolicy`]). This applies to moves that are sent after it: moves that are already
+    /// queued keep the policy that they were sent under.
+    SetLimitPolicy(LimitPolicy),
+    /// Makes the moves after this one interpolate between the joint angles at their ends if
+    /// they're at most this long, or stops that if `None` (see
+    /// [`Brachiograph::set_angle_interpolation`]). It's off until this is sent.
+    ///
+    /// Like [`Op::SetSpeed`], this goes through the queue. It's refused with
+    /// [`ErrorCode::MalformedOp`] if the length is negative.
+    SetAngleInterpolation(#[cfg_attr(feature = "defmt", defmt(Debug2Format))] Option<Fixed>),
+    /// Lifts the pen, parks it (like [`Op::Park`]) and then relaxes the servos, so that they
+    /// don't spend the time until the next drawing holding the arms still. This is how a
+    /// drawing should end. The servos wake up again for the next slow op.
+    Finish,
+    /// Sets the pen servo's duties for when the pen is up and when it's down, for tuning how
+    /// hard the pen presses on the paper and how far it lifts off it.
+    ///
+    /// This does the same thing as [`Op::CalibratePen`], except that it goes through the queue
+    /// (like [`Op::SetSpeed`]), so it can change along with the pen in the middle of a job.
+    SetPenDuty {
+        up: u16,
+        down: u16,
+    },
+    /// Asks how long the brachiograph will take to finish the ops in its queue, from its current
+    /// speed and pen lifting time and the lengths of the queued moves (see
+    /// [`estimate::Estimate`]). The answer is a [`Resp::TimeLeft`].
+    EstimateTime,
+    /// Changes how fast the joints may turn (see [`Brachiograph::set_joint_limits`]). This takes
+    /// effect right away for moves that haven't started yet, even if they're already queued, and
+    /// it lasts until the brachiograph restarts. It's refused with [`ErrorCode::MalformedOp`]
+    /// unless the limits are [valid](JointLimits::is_valid).
+    SetJointLimits(JointLimits),
+    /// Puts the pen down with its servo at an angle (from the pen's [angle
+    /// table](pwm::Calibration::pen_angles)) instead of at its usual down duty, for pressing
+    /// harder or more lightly, or for tools that have more than two positions.
+    ///
+    /// Like [`Op::PenDown`], this goes through the queue and waits for the pen servo to get
+    /// there. The pen stays at this angle whenever it's down (including after a pause) until the
+    /// next [`Op::PenDown`], which puts it back at its usual down duty.
+    SetPenAngle(Angle),
+    /// Sets the pen servo's angle table (see [`Op::SetPenAngle`]).
+    CalibratePenAngles(ServoCalibration),
+    /// Changes how the pen servo eases between up and down (see [`pwm::PenRamp`]). There's no
+    /// ramp until this is sent. This is part of the calibration, so any ramp other than the
+    /// default counts towards [`DeviceInfo::calibration_crc`]. It's refused
+    /// with [`ErrorCode::CalibrationRange`] unless the ramp is [valid](pwm::PenRamp::is_valid).
+    SetPenRamp(pwm::PenRamp),
+}
+
+/// What the brachiograph does with a move that would take the pen outside the drawing area
+/// (see [`Op::SetLimitPolicy`]).
+///
+/// Arcs are always refused if they leave the drawing area, whatever the policy.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LimitPolicy {
+    /// Refuse the move with [`ErrorCode::OutOfRange`], so that it gets skipped.
+    #[default]
+    Reject,
+    /// Go to the nearest point in the drawing area instead. Curves have their control points
+    /// moved in the same way, so they stay inside too.
+    ClampToRect,
+    /// Head in the same direction, but stop where the pen would leave the drawing area. Curves
+    /// are treated like [`LimitPolicy::ClampToRect`].
+    ClampToReachable,
+}
+
+impl LimitPolicy {
+    /// Applies this policy to a move that starts at `from` (which should be in the drawing
+    /// area), returning the move to make instead. Ops that don't move, and moves that stay in
+    /// the drawing area, come back unchanged. Returns `None` if the move should be refused.
+    pub fn apply(self, config: &geom::Config, from: Point, op: Op) -> Option<Op> {
+        let valid = |p: &Point| config.coord_is_valid(p.x, p.y);
+        let clamp = |p: Point| Point {
+            x: p.x.clamp(config.x_range.0, config.x_range.1),
+            y: p.y.clamp(config.y_range.0, config.y_range.1),
+        };
+        let op = match op {
+            Op::MoveBy(r, theta) => {
+                let target = polar_target(from, r, theta);
+                if valid(&target) {
+                    return Some(op);
+                }
+                Op::MoveTo(target)
+            }
+            op => op,
+        };
+        match (self, &op) {
+            (_, Op::MoveTo(p)) if valid(p) => Some(op),
+            (_, Op::QuadTo(c, p)) if valid(c) && valid(p) => Some(op),
+            (_, Op::CurveTo(c1, c2, p)) if valid(c1) && valid(c2) && valid(p) => Some(op),
+            (LimitPolicy::Reject, Op::MoveTo(_) | Op::QuadTo(..) | Op::CurveTo(..)) => None,
+            (LimitPolicy::ClampToRect, &Op::MoveTo(p)) => Some(Op::MoveTo(clamp(p))),
+            (LimitPolicy::ClampToReachable, &Op::MoveTo(p)) => {
+                if !valid(&from) {
+                    return Some(Op::MoveTo(clamp(p)));
+                }
+                // Go as far along the line as we can in each direction, and take the shortest.
+                let mut t = Fixed::ONE;
+                let mut limit = |from: Fixed, to: Fixed, (lo, hi): (Fixed, Fixed)| {
+                    let edge = if to > hi {
+                        hi
+                    } else if to < lo {
+                        lo
+                    } else {
+                        return;
+                    };
+                    t = t.min((edge - from) / (to - from));
+                };
+                limit(from.x, p.x, config.x_range);
+                limit(from.y, p.y, config.y_range);
+                let t = t.max(Fixed::ZERO);
+                // Rounding could leave us just outside.
+                Some(Op::MoveTo(clamp(Point {
+                    x: from.x + t * (p.x - from.x),
+                    y: from.y + t * (p.y - from.y),
+                })))
+            }
+            (_, &Op::QuadTo(c, p)) => Some(Op::QuadTo(clamp(c), clamp(p))),
+            (_, &Op::CurveTo(c1, c2, p)) => Some(Op::CurveTo(clamp(c1), clamp(c2), clamp(p))),
+            _ => Some(op),
+        }
+    }
+}
+
+impl Op {
+    /// Slow ops are the ones that go into the brachiograph's queue, and that take
+    /// time to carry out.
+    pub fn is_slow(&self) -> bool {
+        matches!(
+            self,
+            Op::ChangePosition(_)
+                | Op::ChangePenPosition(_)
+                | Op::MoveTo(_)
+                | Op::PenUp
+                | Op::PenDown
+                | Op::Dwell(_)
+                | Op::SetSpeed(_)
+                | Op::SetPenLift(_)
+                | Op::QuadTo(..)
+                | Op::CurveTo(..)
+                | Op::ArcTo(..)
+                | Op::MoveBy(..)
+                | Op::Pause(_)
+                | Op::Home
+                | Op::Park
+                | Op::SetAngleInterpolation(_)
+                | Op::Finish
+                | Op::SetPenDuty { .. }
+                | Op::SetPenAngle(_)
+        )
+    }
+
+    /// Can this op be written as a text command (see [`Op::from_str`](core::str::FromStr))?
+    pub fn has_text(&self) -> bool {
+        matches!(
+            self,
+            Op::PenUp | Op::PenDown | Op::MoveTo(_) | Op::MoveBy(..) | Op::GetAngles
+        )
+    }
+
+    /// If this op moves the pen, the segment that it moves along when it starts at `from`. This
+    /// is like [`Op::segment`], but it knows where an [`Op::MoveBy`] goes too.
+    pub fn segment_from(&self, from: Point) -> Option<Segment> {
+        match *self {
+            Op::MoveBy(r, theta) => Some(Segment::Line(polar_target(from, r, theta))),
+            _ => self.segment(),
+        }
+    }
+
+    /// If this op moves the pen, the segment that it moves along.
+    pub fn segment(&self) -> Option<Segment> {
+        match *self {
+            Op::MoveTo(p) => Some(Segment::Line(p)),
+            Op::QuadTo(c, p) => Some(Segment::Quad(c, p)),
+            Op::CurveTo(c1, c2, p) => Some(Segment::Cubic(c1, c2, p)),
+            Op::ArcTo(center, sweep) => Some(Segment::Arc { center, sweep }),
+            _ => None,
+        }
+    }
+}
+
+/// The error from parsing an [`Op`] that was written as a text command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseOpError;
+
+impl core::fmt::Display for ParseOpError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("unrecognized command")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseOpError {}
+
+/// Writes the op as the text command that parses back into it, like `moveto 1.5 -2`. Ops that
+/// don't have a text command (see [`Op::has_text`]) get written the same way as with `Debug`.
+impl core::fmt::Display for Op {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Op::PenUp => f.write_str("penup"),
+            Op::PenDown => f.write_str("pendown"),
+            Op::MoveTo(p) => write!(f, "moveto {} {}", p.x, p.y),
+            Op::MoveBy(r, theta) => write!(f, "moveby {} {}", r, theta.degrees()),
+            Op::GetAngles => f.write_str("angles"),
+            op => write!(f, "{op:?}"),
+        }
+    }
+}
+
+/// Parses the text commands that people type into terminals: `penup`, `pendown`, `moveto x y`,
+/// `moveby r theta` (with `theta` in degrees, counter-clockwise from the positive x axis) and
+/// `angles`.
+impl core::str::FromStr for Op {
+    type Err = ParseOpError;
+
+    fn from_str(s: &str) -> Result<Op, ParseOpError> {
+        let mut words = s.split_whitespace();
+        let cmd = words.next().ok_or(ParseOpError)?;
+        let mut num = || -> Result<Fixed, ParseOpError> {
+            words
+                .next()
+                .ok_or(ParseOpError)?
+                .parse()
+                .map_err(|_| ParseOpError)
+        };
+        let op = match cmd {
+            "penup" => Op::PenUp,
+            "pendown" => Op::PenDown,
+            "moveto" => Op::MoveTo(Point {
+                x: num()?,
+                y: num()?,
+            }),
+            "moveby" => Op::MoveBy(num()?, Angle::from_degrees(num()?)),
+            "angles" => Op::GetAngles,
+            _ => return Err(ParseOpError),
+        };
+        if words.next().is_some() {
+            return Err(ParseOpError);
+        }
+        Ok(op)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-#[cfg_attr(target_os = "none", derive(defmt::Format))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Resp {
     Ack,
     Nack,
     QueueFull,
+    /// The answer to [`Op::GetAngles`].
     Angles(Angles),
+    /// The answer to [`Op::GetPosition`].
     CurPosition(ServoPosition),
+    /// Someone else has claimed the brachiograph.
+    Denied,
+    /// The brachiograph has been plotting for too long, or it got to an [`Op::Pause`], and it's
+    /// waiting for [`Op::Resume`].
+    Paused,
+    Queue(QueueStatus),
+    /// The emergency stop button was pressed: the pen is up, the queue has been emptied, and
+    /// nothing will move until [`Op::ClearEStop`].
+    EStop,
+    /// The answer to [`Op::GetInfo`].
+    Info(DeviceInfo),
+    /// The op was refused, for this reason.
+    Error(ErrorCode),
+    /// The answer to [`Op::GetConfig`].
+    Config(geom::Config),
+    /// The brachiograph ran out of ops while the pen was down and someone had claimed it (see
+    /// [`Op::Claim`]). Rather than leave a blob of ink while it waited, it lifted the pen at
+    /// `at`. Slow ops get this answer instead of being queued until [`Op::Resume`]; after that,
+    /// put the pen back down to carry on with the stroke.
+    Starved {
+        at: Point,
+    },
+    /// The answer to [`Op::GetProgress`].
+    Progress {
+        /// How many slow ops the brachiograph has finished since it started taking positions
+        /// (after calibrating, or after an emergency stop). This wraps around, so to see how far
+        /// a plot has got, compare it to what it was at the start.
+        ops_done: u32,
+        /// How many slow ops are still waiting in the queue.
+        queue_len: u16,
+    },
+    /// The answer to [`Op::EstimateTime`]: about how many milliseconds it will be until the
+    /// queue is empty and the brachiograph has stopped moving. This doesn't count the time
+    /// spent waiting in a pause, or the rest of the [demo](Op::RunDemo) beyond what's in the
+    /// queue.
+    TimeLeft {
+        millis: u32,
+    },
+}
+
+/// Why the brachiograph refused an op (see [`Resp::Error`]).
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ErrorCode {
+    /// The op would take the pen somewhere that it can't reach.
+    OutOfRange,
+    /// The brachiograph can't do that right now. For example, it was being moved with raw servo
+    /// duties (see [`Op::ChangePosition`]), so it doesn't know where the pen is.
+    InvalidState,
+    /// The op doesn't make sense, like a speed that isn't positive.
+    MalformedOp,
+    /// A calibration has duties that the servos can't produce, or its angles are out of order,
+    /// or its timing is out of the supported range (see [`pwm::Timing::is_valid`]).
+    CalibrationRange,
+    /// The arms can't reach all of the drawing area in a config (see
+    /// [`geom::Config::is_valid`]).
+    InvalidConfig,
+    /// The brachiograph is busy with something else, like its built-in demo (see
+    /// [`Op::RunDemo`]).
+    Busy,
+}
+
+impl core::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            ErrorCode::OutOfRange => "the brachiograph can't reach that far",
+            ErrorCode::InvalidState => {
+                "the brachiograph doesn't know where it is (has it been calibrating?)"
+            }
+            ErrorCode::MalformedOp => "the brachiograph didn't understand that op",
+            ErrorCode::CalibrationRange => "the calibration is out of range",
+            ErrorCode::InvalidConfig => "the arms can't reach all of that drawing area",
+            ErrorCode::Busy => "the brachiograph is busy with something else",
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ErrorCode {}
+
+/// What a brachiograph says about itself, in answer to [`Op::GetInfo`].
+///
+/// This is enough to pin down exactly what produced a drawing: which firmware, and which
+/// calibration.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceInfo {
+    /// The (abbreviated) git commit that the firmware was built from, or empty if that isn't
+    /// known.
+    #[cfg_attr(feature = "defmt", defmt(Display2Format))]
+    pub git_hash: arrayvec::ArrayString<16>,
+    /// The day that the firmware was built, like `2023-01-31`, or empty if that isn't known.
+    #[cfg_attr(feature = "defmt", defmt(Display2Format))]
+    pub build_date: arrayvec::ArrayString<16>,
+    /// The checksum of the calibration that the brachiograph is using (see
+    /// [`pwm::Calibration::checksum`]).
+    pub calibration_crc: u32,
+}
+
+/// What the brachiograph sends back for each [`Op`].
+///
+/// Along with the actual response, every reply says how full the queue is. That way a host that
+/// is streaming slow ops knows how many more it can send without waiting (or asking).
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Reply {
+    pub resp: Resp,
+    pub queue: QueueStatus,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speed_and_pen_lift() {
+        let start = Instant::from_ticks(0);
+        let mut brachio = Brachiograph::new(0, 10)
+            .with_speed(2)
+            .with_pen_lift(Duration::millis(300));
+        assert_eq!(brachio.speed(), 2);
+
+        brachio.resting().unwrap().move_to(start, 0, 12).unwrap();
+        let State::Moving(mov, _) = &brachio.state else {
+            panic!("should be moving");
+        };
+        // It takes a second at full speed, and a few more milliseconds to speed up and slow down.
+        assert_eq!(
+            mov.dur - mov.ramp_in / 2 - mov.ramp_out / 2,
+            Duration::millis(1000)
+        );
+        assert!(mov.dur < Duration::millis(1010), "{}", mov.dur);
+
+        let end = start + mov.dur;
+        brachio.update(end);
+        brachio.resting().unwrap().pen_down(end);
+        assert_eq!(brachio.pen(end + Duration::millis(100)), PenState::Up);
+        assert_eq!(brachio.pen(end + Duration::millis(200)), PenState::Down);
+        brachio.update(end + Duration::millis(300));
+        assert!(brachio.state.is_resting());
+    }
+
+    #[test]
+    fn lift_progress() {
+        let start = Instant::from_ticks(0);
+        let mut brachio = Brachiograph::new(0, 10).with_pen_lift(Duration::millis(400));
+        assert_eq!(brachio.lift_progress(start), None);
+
+        brachio.resting().unwrap().pen_down(start);
+        let at = |ms| brachio.lift_progress(start + Duration::millis(ms));
+        assert_eq!(at(0), Some((PenState::Down, Fixed::ZERO)));
+        assert_eq!(at(100), Some((PenState::Down, Fixed::from_num(0.5))));
+        // The second half is for settling.
+        assert_eq!(at(200), Some((PenState::Down, Fixed::ONE)));
+        assert_eq!(at(300), Some((PenState::Down, Fixed::ONE)));
+
+        // Settling the pen doesn't move it up or down.
+        brachio.update(start + Duration::millis(400));
+        brachio
+            .resting()
+            .unwrap()
+            .settle_pen(start + Duration::millis(400));
+        assert_eq!(
+            brachio.lift_progress(start + Duration::millis(400)),
+            Some((PenState::Down, Fixed::ONE))
+        );
+    }
+
+    #[test]
+    fn dwell() {
+        let start = Instant::from_ticks(0);
+        let mut brachio = Brachiograph::new(0, 10);
+        brachio.resting().unwrap().pen_down(start);
+        let start = start + DEFAULT_PEN_LIFT;
+        let angles = brachio.update(start);
+        brachio
+            .resting()
+            .unwrap()
+            .dwell(start, Duration::millis(150));
+        // Nothing moves, and it's busy until the time is up.
+        let at = start + Duration::millis(100);
+        assert_eq!(brachio.update(at), angles);
+        assert_eq!(brachio.pen(at), PenState::Down);
+        assert_eq!(brachio.lift_progress(at), None);
+        assert!(brachio.resting().is_none());
+        brachio.update(start + Duration::millis(150));
+        assert!(brachio.resting().is_some());
+    }
+
+    #[test]
+    fn stop_mid_move() {
+        let start = Instant::from_ticks(0);
+        let mut brachio = Brachiograph::new(0, 10).with_speed(2);
+        brachio.resting().unwrap().pen_down(start);
+        let start = start + DEFAULT_PEN_LIFT;
+        brachio.update(start);
+        brachio.resting().unwrap().move_to(start, 0, 12).unwrap();
+
+        let now = start + Duration::millis(500);
+        brachio.stop(now);
+        assert_eq!(brachio.pen(now), PenState::Up);
+        let resting = brachio.resting().unwrap();
+        assert_eq!(resting.pos.x, 0);
+        assert!(resting.pos.y > 10 && resting.pos.y < 12);
+    }
+
+    #[test]
+    fn clock_going_backwards() {
+        let start = Instant::from_ticks(0) + Duration::millis(1000);
+        let mut brachio = Brachiograph::new(0, 10).with_speed(2);
+        brachio.resting().unwrap().move_to(start, 0, 12).unwrap();
+
+        // Rather than panicking, the movement just finishes.
+        brachio.update(start - Duration::millis(10));
+        let resting = brachio.resting().unwrap();
+        assert_eq!(resting.pos.x, 0);
+        assert_eq!(resting.pos.y, 12);
+    }
+
+    #[test]
+    fn blending() {
+        let start = Instant::from_ticks(0);
+        let line = |x: f64, y: f64| {
+            Segment::Line(Point {
+                x: Fixed::from_num(x),
+                y: Fixed::from_num(y),
+            })
+        };
+        let moving = |brachio: &Brachiograph| {
+            let State::Moving(mov, _) = &brachio.state else {
+                panic!("should be moving");
+            };
+            mov.clone()
+        };
+
+        let mut brachio = Brachiograph::new(0, 10)
+            .with_speed(2)
+            .with_blend_angle(Some(DEFAULT_BLEND_ANGLE));
+        brachio.resting().unwrap().move_to(start, 0, 11).unwrap();
+        let alone = moving(&brachio);
+        // Almost straight on, so it doesn't slow down at the end, and the next one starts when
+        // this one finished.
+        brachio.continue_into(start + Duration::millis(100), &line(0.1, 12.0));
+        let mov = moving(&brachio);
+        assert_eq!(mov.ramp_out, Duration::from_ticks(0));
+        assert_eq!(mov.dur, alone.dur - alone.ramp_out / 2);
+        let finished = start + mov.dur;
+        let now = finished + Duration::millis(20);
+        brachio.update(now);
+        brachio.resting().unwrap().move_to(now, 0.1, 12).unwrap();
+        let mov = moving(&brachio);
+        assert_eq!(mov.start, finished);
+        // It's already going at full speed.
+        assert_eq!(mov.ramp_in, Duration::from_ticks(0));
+
+        // A right angle, so it slows down to a stop and the next one waits.
+        brachio.continue_into(finished + Duration::millis(100), &line(1.1, 12.0));
+        assert!(!moving(&brachio).continues);
+        let now = finished + mov.dur + Duration::millis(20);
+        brachio.update(now);
+        brachio.resting().unwrap().move_to(now, 1.1, 12).unwrap();
+        assert_eq!(moving(&brachio).start, now);
+
+        // Straight on, but it's been sitting there for too long.
+        brachio.continue_into(now, &line(2.1, 12.0));
+        let now = now + Duration::millis(1000);
+        brachio.update(now);
+        brachio.resting().unwrap().move_to(now, 2.1, 12).unwrap();
+        assert_eq!(moving(&brachio).start, now);
+
+        // Too late, because it has started slowing down.
+        let mov = moving(&brachio);
+        brachio.continue_into(now + mov.dur - mov.ramp_out, &line(3.1, 12.0));
+        assert!(!moving(&brachio).continues);
+
+        // Straight on, but blending is off.
+        let mut brachio = Brachiograph::new(0, 10).with_speed(2);
+        brachio.resting().unwrap().move_to(start, 0, 11).unwrap();
+        brachio.continue_into(start, &line(0.0, 12.0));
+        let now = start + moving(&brachio).dur + Duration::millis(20);
+        brachio.update(now);
+        brachio.resting().unwrap().move_to(now, 0, 12).unwrap();
+        assert_eq!(moving(&brachio).start, now);
+    }
+
+    #[test]
+    fn speeds_up_and_slows_down() {
+        let start = Instant::from_ticks(0);
+        // Slow acceleration, so that the ramps are easy to see.
+        let limits = JointLimits {
+            max_speed: Fixed::from_num(300),
+            max_accel: Fixed::from_num(20),
+        };
+        let mut brachio = Brachiograph::new(0, 10)
+            .with_speed(2)
+            .with_joint_limits(limits);
+        brachio.resting().unwrap().move_to(start, 0, 12).unwrap();
+        let State::Moving(mov, _) = &brachio.state else {
+            panic!("should be moving");
+        };
+        let mov = mov.clone();
+        assert!(mov.ramp_in > Duration::millis(100), "{}", mov.ramp_in);
+        assert!(mov.ramp_out > Duration::millis(100), "{}", mov.ramp_out);
+        // At a steady speed it would be a quarter of the way there after a quarter of a second,
+        // but it's still getting going.
+        let ratio = |ms: u64| mov.ratio(start + Duration::millis(ms));
+        assert!(ratio(250) < Fixed::from_num(0.25) - Fixed::from_num(0.05));
+        // It gets going smoothly, and it gets there.
+        let mut prev = Fixed::ZERO;
+        for ms in (0..mov.dur.to_millis()).step_by(10) {
+            let r = ratio(ms);
+            assert!(r >= prev && r - prev < Fixed::from_num(0.02), "{ms}: {r}");
+            prev = r;
+        }
+        assert_eq!(mov.ratio(start + mov.dur), Fixed::ONE);
+
+        // A short movement never gets up to full speed.
+        let end = start + mov.dur;
+        brachio.update(end);
+        brachio.resting().unwrap().move_to(end, 0, 12.01).unwrap();
+        let State::Moving(short, _) = &brachio.state else {
+            panic!("should be moving");
+        };
+        let steady = short.dur - short.ramp_in / 2 - short.ramp_out / 2;
+        assert!(short.ramp_in / 2 + short.ramp_out / 2 <= steady + Duration::micros(2));
+    }
+
+    #[test]
+    fn angle_interpolation() {
+        let start = Instant::from_ticks(0);
+        let mut brachio =
+            Brachiograph::new(0, 10).with_angle_interpolation(Some(DEFAULT_ANGLE_INTERPOLATION));
+        let config = brachio.config().clone();
+        let angles = |x: f64, y: f64| config.at_coord(Fixed::from_num(x), Fixed::from_num(y));
+        let close = |a: Angle, b: Angle| (a.degrees() - b.degrees()).abs() < 0.05;
+
+        brachio
+            .resting()
+            .unwrap()
+            .move_to(start, 0.3, 10.3)
+            .unwrap();
+        let State::Moving(mov, _) = &brachio.state else {
+            panic!("should be moving");
+        };
+        let end = start + mov.dur;
+        let (a, b) = (angles(0.0, 10.0).unwrap(), angles(0.3, 10.3).unwrap());
+        let mid = brachio.update(start + mov.dur / 2);
+        let half = Fixed::from_num(0.5);
+        assert!(close(
+            mid.shoulder,
+            a.shoulder.interpolate(b.shoulder, half)
+        ));
+        assert!(close(mid.elbow, a.elbow.interpolate(b.elbow, half)));
+        // That's very nearly halfway along the line.
+        let (x, y) = config.coord_at_angle::<f64>(mid);
+        assert!(
+            (x - 0.15).abs() < 0.02 && (y - 10.15).abs() < 0.02,
+            "{x}, {y}"
+        );
+        assert_eq!(brachio.update(end), b);
+
+        // Longer movements follow their segments.
+        brachio.resting().unwrap().move_to(end, 0.3, 11.3).unwrap();
+        let State::Moving(mov, _) = &brachio.state else {
+            panic!("should be moving");
+        };
+        assert!(mov.angles.is_none());
+    }
+
+    #[test]
+    fn joint_limits() {
+        let start = Instant::from_ticks(0);
+        // Leaving out the time to speed up and slow down.
+        let duration = |brachio: &Brachiograph| {
+            let State::Moving(mov, _) = &brachio.state else {
+                panic!("should be moving");
+            };
+            mov.dur - mov.ramp_in / 2 - mov.ramp_out / 2
+        };
+
+        // Close to the shoulder, a quick move swings the arms a long way, so it gets slowed down.
+        let mut brachio = Brachiograph::new(-4, 5).with_speed(100);
+        brachio.resting().unwrap().move_to(start, 4, 5).unwrap();
+        let dur = duration(&brachio);
+        assert!(dur > Duration::millis(100), "{dur}");
+        let config = brachio.config().clone();
+        let (a, b) = (
+            config
+                .at_coord(Fixed::from_num(-4), Fixed::from_num(5))
+                .unwrap(),
+            config
+                .at_coord(Fixed::from_num(4), Fixed::from_num(5))
+                .unwrap(),
+        );
+        let secs = dur.to_millis() as f64 / 1000.0;
+        for turn in [b.shoulder - a.shoulder, b.elbow - a.elbow] {
+            let speed = turn.degrees().to_num::<f64>().abs() / secs;
+            assert!(speed <= DEFAULT_JOINT_LIMITS.max_speed, "{speed}");
+        }
+
+        // Without the limits, it just goes at the speed.
+        let mut brachio = Brachiograph::new(-4, 5)
+            .with_speed(100)
+            .with_joint_limits(JointLimits {
+                max_speed: Fixed::from_num(100_000),
+                max_accel: Fixed::from_num(100_000),
+            });
+        brachio.resting().unwrap().move_to(start, 4, 5).unwrap();
+        let line = Segment::Line(Point {
+            x: Fixed::from_num(4),
+            y: Fixed::from_num(5),
+        });
+        let init = Point {
+            x: Fixed::from_num(-4),
+            y: Fixed::from_num(5),
+        };
+        assert_eq!(
+            duration(&brachio),
+            Movement::duration(init, &line, 100.to_fixed())
+        );
+
+        // Ordinary drawing doesn't come close to the limits.
+        let mut brachio = Brachiograph::new(0, 10).with_speed(2);
+        brachio.resting().unwrap().move_to(start, 0.5, 11).unwrap();
+        let line = Segment::Line(Point {
+            x: Fixed::from_num(0.5),
+            y: Fixed::from_num(11),
+        });
+        let init = Point {
+            x: Fixed::ZERO,
+            y: Fixed::from_num(10),
+        };
+        assert_eq!(
+            duration(&brachio),
+            Movement::duration(init, &line, 2.to_fixed())
+        );
+    }
+
+    #[test]
+    fn move_by() {
+        let start = Instant::from_ticks(0);
+        let mut brachio = Brachiograph::new(0, 10);
+        let r = Fixed::from_num(2);
+        brachio
+            .resting()
+            .unwrap()
+            .move_by(start, r, Angle::from_degrees(450))
+            .unwrap();
+        let State::Moving(mov, _) = &brachio.state else {
+            panic!("should be moving");
+        };
+        assert!(mov.target.x.abs() < 0.01);
+        assert!((mov.target.y - Fixed::from_num(12)).abs() < 0.01);
+
+        // Off the edge of the page.
+        let mut brachio = Brachiograph::new(0, 10);
+        let far = Fixed::from_num(100);
+        let resting = brachio.resting().unwrap();
+        assert!(resting.move_by(start, far, Angle::from_degrees(0)).is_err());
+    }
+
+    #[test]
+    fn parse_text_commands() {
+        let p = |x: i32, y: f64| Point {
+            x: Fixed::from_num(x),
+            y: Fixed::from_num(y),
+        };
+        assert_eq!("penup".parse(), Ok(Op::PenUp));
+        assert_eq!(" pendown \n".parse(), Ok(Op::PenDown));
+        assert_eq!("moveto -3 10.5".parse(), Ok(Op::MoveTo(p(-3, 10.5))));
+        assert_eq!(
+            "moveby 2 -90".parse(),
+            Ok(Op::MoveBy(Fixed::from_num(2), Angle::from_degrees(-90)))
+        );
+        assert_eq!("angles".parse(), Ok(Op::GetAngles));
+
+        for bad in [
+            "",
+            "jump",
+            "moveto 1",
+            "moveto 1 2 3",
+            "moveby x 2",
+            "penup now",
+        ] {
+            assert_eq!(bad.parse::<Op>(), Err(ParseOpError), "{bad:?}");
+        }
+    }
+
+    #[cfg(feature = "kurbo")]
+    #[test]
+    fn kurbo_points() {
+        let p = Point {
+            x: Fixed::from_num(-1.5),
+            y: Fixed::from_num(9),
+        };
+        assert_eq!(kurbo::Point::from(p), kurbo::Point::new(-1.5, 9.0));
+        assert_eq!(Point::from(kurbo::Point::new(-1.5, 9.0)), p);
+    }
+
+    #[test]
+    fn limit_policies() {
+        let config = geom::Config::default();
+        let p = |x: f64, y: f64| Point {
+            x: Fixed::from_num(x),
+            y: Fixed::from_num(y),
+        };
+        // The clipped lines get rounded a little.
+        let close = |q: Point, x: f64, y: f64| {
+            (q.x.to_num::<f64>() - x).abs() < 0.01 && (q.y.to_num::<f64>() - y).abs() < 0.01
+        };
+        let from = p(0.0, 9.0);
+        let inside = Op::MoveTo(p(1.0, 10.0));
+        let outside = Op::MoveTo(p(12.0, 11.0));
+        for policy in [
+            LimitPolicy::Reject,
+            LimitPolicy::ClampToRect,
+            LimitPolicy::ClampToReachable,
+        ] {
+            assert_eq!(
+                policy.apply(&config, from, inside.clone()),
+                Some(inside.clone())
+            );
+            assert_eq!(policy.apply(&config, from, Op::PenUp), Some(Op::PenUp));
+        }
+
+        assert_eq!(
+            LimitPolicy::Reject.apply(&config, from, outside.clone()),
+            None
+        );
+        assert_eq!(
+            LimitPolicy::ClampToRect.apply(&config, from, outside.clone()),
+            Some(Op::MoveTo(p(8.0, 11.0)))
+        );
+        // The line from (0, 9) to (12, 11) leaves through the right-hand edge, a third of the
+        // way up.
+        let Some(Op::MoveTo(q)) = LimitPolicy::ClampToReachable.apply(&config, from, outside)
+        else {
+            panic!("expected a move");
+        };
+        assert!(close(q, 8.0, 9.0 + 2.0 * 8.0 / 12.0));
+
+        // Relative moves get turned into absolute ones if they need clamping.
+        let far_right = Op::MoveBy(Fixed::from_num(20), Angle::from_degrees(0));
+        let Some(Op::MoveTo(q)) =
+            LimitPolicy::ClampToReachable.apply(&config, from, far_right.clone())
+        else {
+            panic!("expected a move");
+        };
+        assert!(close(q, 8.0, 9.0));
+        assert_eq!(LimitPolicy::Reject.apply(&config, from, far_right), None);
+
+        // Curves get their control points clamped.
+        let curve = Op::QuadTo(p(0.0, 20.0), p(2.0, 9.0));
+        assert_eq!(
+            LimitPolicy::ClampToReachable.apply(&config, from, curve),
+            Some(Op::QuadTo(p(0.0, 13.0), p(2.0, 9.0)))
+        );
+    }
 }
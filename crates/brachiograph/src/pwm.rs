@@ -1,12 +1,86 @@
 use arrayvec::ArrayVec;
+use serde::{Deserialize, Serialize};
 
-use crate::{Angle, Angles, Direction, Fixed, Joint, PenState, ServoCalibration, ServoPosition};
+use crate::{
+    Angle, Angles, Direction, Duration, Fixed, Joint, PenState, ServoCalibration, ServoPosition,
+};
 
 #[derive(Debug, Clone)]
 pub struct Calibration {
     pub shoulder: Pwm,
     pub elbow: Pwm,
     pub pen: TogglePwm,
+    /// The pen servo's duties for pointing it at an angle (see [`Op::SetPenAngle`]).
+    ///
+    /// [`Op::SetPenAngle`]: crate::Op::SetPenAngle
+    pub pen_angles: AnglePwm,
+    pub timing: Timing,
+    pub pen_ramp: PenRamp,
+}
+
+impl Calibration {
+    /// A CRC-32 of all the calibration tables.
+    ///
+    /// Two brachiographs with the same checksum will (almost certainly) put their servos in
+    /// the same places.
+    pub fn checksum(&self) -> u32 {
+        let mut crc = Crc32::default();
+        for table in [
+            &self.shoulder.inc,
+            &self.shoulder.dec,
+            &self.elbow.inc,
+            &self.elbow.dec,
+            &self.pen_angles.table,
+        ] {
+            // Include the lengths, so that entries can't move from one table to the next
+            // without changing the checksum.
+            crc.update(&[table.len() as u8]);
+            for (deg, duty) in table {
+                crc.update(&deg.to_le_bytes());
+                crc.update(&duty.to_le_bytes());
+            }
+        }
+        crc.update(&self.pen.on.to_le_bytes());
+        crc.update(&self.pen.off.to_le_bytes());
+        crc.update(&[
+            u8::from(self.shoulder.inverted),
+            u8::from(self.elbow.inverted),
+        ]);
+        crc.update(&self.timing.frame_us.to_le_bytes());
+        crc.update(&self.timing.tick_ms.to_le_bytes());
+        // Without a ramp the pen moves just as it did before ramps existed, so leave it out of the
+        // checksum to keep older calibrations matching.
+        if self.pen_ramp != PenRamp::default() {
+            crc.update(&[self.pen_ramp.percent]);
+        }
+        crc.finish()
+    }
+}
+
+// The usual CRC-32 (the one used by zip, ethernet, etc.), computed a bit at a time because we
+// can't spare the space for a table.
+struct Crc32(u32);
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32(0xFFFF_FFFF)
+    }
+}
+
+impl Crc32 {
+    fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= u32::from(b);
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    fn finish(self) -> u32 {
+        !self.0
+    }
 }
 
 impl Default for Calibration {
@@ -15,6 +89,9 @@ impl Default for Calibration {
             shoulder: Pwm::shoulder(),
             elbow: Pwm::elbow(),
             pen: TogglePwm::pen(),
+            pen_angles: AnglePwm::pen(),
+            timing: Timing::default(),
+            pen_ramp: PenRamp::default(),
         }
     }
 }
@@ -22,19 +99,27 @@ impl Default for Calibration {
 #[derive(Debug, Clone, Default)]
 pub struct CalibratedPosition {
     pub calib: Calibration,
-    // For the hysteresis correction, we need to store the previous angles.
-    pub last_angles: Angles,
+    // For the hysteresis correction, we need to know which way each joint has been turning.
+    pub shoulder_hysteresis: Hysteresis,
+    pub elbow_hysteresis: Hysteresis,
+    /// Where the pen servo goes instead of its usual down position, if it has been put down at
+    /// an angle by [`Op::SetPenAngle`](crate::Op::SetPenAngle).
+    pub pen_angle: Option<Angle>,
 }
 
 impl CalibratedPosition {
     pub fn update(&mut self, angles: Angles, pen: PenState) -> ServoPosition {
+        self.shoulder_hysteresis = self.shoulder_hysteresis.step(angles.shoulder);
+        self.elbow_hysteresis = self.elbow_hysteresis.step(angles.elbow);
         let shoulder = self
             .calib
             .shoulder
-            .duty(self.last_angles.shoulder, angles.shoulder);
-        let elbow = self.calib.elbow.duty(self.last_angles.elbow, angles.elbow);
-        let pen = self.calib.pen.duty(pen);
-        self.last_angles = angles;
+            .duty(self.shoulder_hysteresis.dir, angles.shoulder);
+        let elbow = self
+            .calib
+            .elbow
+            .duty(self.elbow_hysteresis.dir, angles.elbow);
+        let pen = self.pen_duty(pen);
         ServoPosition {
             shoulder,
             elbow,
@@ -42,6 +127,49 @@ impl CalibratedPosition {
         }
     }
 
+    /// Like [`CalibratedPosition::update`], but for while the pen is going up or down: `to` is
+    /// where it's going, and `progress` is how far it has got (see
+    /// [`Brachiograph::lift_progress`](crate::Brachiograph::lift_progress)).
+    pub fn update_lifting(
+        &mut self,
+        angles: Angles,
+        to: PenState,
+        progress: Fixed,
+    ) -> ServoPosition {
+        let mut pos = self.update(angles, to);
+        pos.pen = self.ramp_duty(to, progress);
+        pos
+    }
+
+    /// The duty for the pen servo when it's `progress` of the way to `to`, following the
+    /// [ramp](Calibration::pen_ramp).
+    pub fn ramp_duty(&self, to: PenState, progress: Fixed) -> u16 {
+        let from = self.pen_duty(!to);
+        let to = self.pen_duty(to);
+        let t = self.calib.pen_ramp.position(progress);
+        let from_f = Fixed::from_num(from);
+        let duty = from_f + (Fixed::from_num(to) - from_f) * t;
+        duty.round().to_num()
+    }
+
+    /// The duty for holding the pen servo up or down.
+    pub fn pen_duty(&self, pen: PenState) -> u16 {
+        match (pen, self.pen_angle) {
+            (PenState::Down, Some(angle)) => self.calib.pen_angles.duty(angle),
+            _ => self.calib.pen.duty(pen),
+        }
+    }
+
+    /// Changes where the pen servo goes when the pen is down: to `angle` (in the
+    /// [table](Calibration::pen_angles)) if there is one, or otherwise to its usual down duty.
+    ///
+    /// Returns whether that's somewhere different.
+    pub fn set_pen_angle(&mut self, angle: Option<Angle>) -> bool {
+        let before = self.pen_duty(PenState::Down);
+        self.pen_angle = angle;
+        self.pen_duty(PenState::Down) != before
+    }
+
     pub fn change_calibration(&mut self, joint: Joint, dir: Direction, calib: ServoCalibration) {
         let list = match (joint, dir) {
             (Joint::Shoulder, Direction::Increasing) => &mut self.calib.shoulder.inc,
@@ -51,30 +179,224 @@ impl CalibratedPosition {
         };
         *list = calib.data;
     }
+
+    pub fn change_pen_calibration(&mut self, pen: TogglePwm) {
+        self.calib.pen = pen;
+    }
+
+    pub fn change_pen_angles(&mut self, calib: ServoCalibration) {
+        self.calib.pen_angles.table = calib.data;
+    }
+
+    pub fn change_timing(&mut self, timing: Timing) {
+        self.calib.timing = timing;
+    }
+
+    pub fn change_pen_ramp(&mut self, ramp: PenRamp) {
+        self.calib.pen_ramp = ramp;
+    }
+
+    /// Says whether a joint's servo is mounted the other way around (see [`Pwm::inverted`]).
+    pub fn change_inversion(&mut self, joint: Joint, inverted: bool) {
+        match joint {
+            Joint::Shoulder => self.calib.shoulder.inverted = inverted,
+            Joint::Elbow => self.calib.elbow.inverted = inverted,
+        }
+    }
 }
 
 // A pair of (degrees, pulse-width-modulation-in-microseconds)
 pub type CalibrationEntry = (i16, u16);
 
+/// The shortest servo pulse, in microseconds.
+pub const MIN_DUTY: u16 = 500;
+/// The longest servo pulse, in microseconds.
+pub const MAX_DUTY: u16 = 2500;
+
+// This turns a duty into the one that points the opposite way.
+const MIRROR: u16 = MIN_DUTY + MAX_DUTY;
+
+/// Is this a duty that a servo understands?
+pub fn duty_is_valid(duty: u16) -> bool {
+    (MIN_DUTY..=MAX_DUTY).contains(&duty)
+}
+
+/// How far (in degrees) a joint has to turn back before its servo counts as turning the other
+/// way.
+///
+/// There's a little play in a servo's gears, so when it reverses by a tiny amount it doesn't
+/// really move back at all, and it should keep using the calibration table for the way it was
+/// going. Without this, the jitter in something like a hatching pattern would keep hopping
+/// between the tables.
+pub const HYSTERESIS_DEADBAND: i16 = 1;
+
+/// Which of a servo's calibration tables is in use.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hysteresis {
+    /// The way that the joint is turning, as far as the calibration is concerned.
+    pub dir: Direction,
+    /// The furthest that the joint has got while turning that way.
+    pub extreme: Angle,
+}
+
+impl Default for Hysteresis {
+    fn default() -> Self {
+        Hysteresis::new(Direction::Decreasing, Angle::default())
+    }
+}
+
+impl Hysteresis {
+    /// A joint at `angle`, which got there by turning in the direction `dir`.
+    pub fn new(dir: Direction, angle: Angle) -> Hysteresis {
+        Hysteresis {
+            dir,
+            extreme: angle,
+        }
+    }
+
+    /// The state after the joint turns to `angle`.
+    ///
+    /// The direction only changes once the joint has turned back from its extreme by more than
+    /// [`HYSTERESIS_DEADBAND`], so small reversals and standing still keep using the same table.
+    pub fn step(self, angle: Angle) -> Hysteresis {
+        let deg = angle.degrees();
+        let extreme = self.extreme.degrees();
+        let deadband = Fixed::from_num(HYSTERESIS_DEADBAND);
+        match self.dir {
+            Direction::Increasing if deg >= extreme => Hysteresis::new(self.dir, angle),
+            Direction::Decreasing if deg <= extreme => Hysteresis::new(self.dir, angle),
+            Direction::Increasing if extreme - deg > deadband => {
+                Hysteresis::new(Direction::Decreasing, angle)
+            }
+            Direction::Decreasing if deg - extreme > deadband => {
+                Hysteresis::new(Direction::Increasing, angle)
+            }
+            _ => self,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Pwm {
     // Calibrations to use when the angle is increasing.
     pub inc: ArrayVec<CalibrationEntry, 16>,
     // Calibrations to use when the angle is decreasing.
     pub dec: ArrayVec<CalibrationEntry, 16>,
+    /// Is the servo mounted the other way around? If so, the duties from the tables get
+    /// mirrored, so that the joint still turns the right way.
+    pub inverted: bool,
 }
 
-#[derive(Debug, Clone)]
+/// The duties for a servo that only has two positions, like the one that lifts the pen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct TogglePwm {
+    /// The duty for when the pen is down.
     pub on: u16,
+    /// The duty for when the pen is up.
     pub off: u16,
 }
 
+/// The duties for pointing a servo at an angle, like [`Pwm`] but without the hysteresis
+/// correction.
+///
+/// This is for the pen servo, when just up and down aren't enough: pressing a brush down by
+/// different amounts, say, or lifting some other tool to different heights.
+#[derive(Debug, Clone)]
+pub struct AnglePwm {
+    pub table: ArrayVec<CalibrationEntry, 16>,
+}
+
+/// How often the servos get a pulse, and how often the firmware works out where they should be.
+///
+/// Analog servos want a pulse every 20ms, but many digital servos are happy with one every
+/// 3ms or so, and they respond more quickly if they get them. There's no point in sending
+/// pulses faster than the positions change, so the tick should usually be made shorter too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Timing {
+    /// The time from the start of one servo pulse to the start of the next, in microseconds.
+    pub frame_us: u16,
+    /// The time between updates of the servo positions, in milliseconds.
+    pub tick_ms: u16,
+}
+
+/// The shortest frame period. This leaves a little room after the longest pulse.
+pub const MIN_FRAME_US: u16 = 3000;
+/// The longest frame period, which is the usual one for analog servos.
+pub const MAX_FRAME_US: u16 = 20000;
+/// The shortest tick. Any shorter and the firmware can't keep up with the arithmetic.
+pub const MIN_TICK_MS: u16 = 4;
+/// The longest tick. Any longer and the movements get jerky.
+pub const MAX_TICK_MS: u16 = 50;
+
+impl Default for Timing {
+    fn default() -> Self {
+        Timing {
+            frame_us: MAX_FRAME_US,
+            tick_ms: 20,
+        }
+    }
+}
+
+impl Timing {
+    /// Are the frame period and tick in the ranges that the firmware supports?
+    pub fn is_valid(&self) -> bool {
+        (MIN_FRAME_US..=MAX_FRAME_US).contains(&self.frame_us)
+            && (MIN_TICK_MS..=MAX_TICK_MS).contains(&self.tick_ms)
+    }
+
+    /// The time between updates of the servo positions.
+    pub fn tick(&self) -> Duration {
+        Duration::millis(self.tick_ms.into())
+    }
+}
+
+/// How the pen servo gets from up to down and back.
+///
+/// Sending the pen servo straight to its new position flicks the pen onto the paper (leaving a
+/// blob) or drags it off (leaving a tail). Instead, it can be eased across during the first half
+/// of the [pen lifting time](crate::Brachiograph::set_pen_lift), finishing at the halfway point
+/// where it would otherwise have jumped. The second half is left for it to settle.
+///
+/// The default is no ramp at all, which is how the pen moved before this existed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PenRamp {
+    /// How much of the first half of the pen lifting time the servo spends moving, in percent.
+    /// At 0 it jumps at the halfway point; at 100 it starts moving as soon as the pen starts
+    /// going up or down.
+    pub percent: u8,
+}
+
+impl PenRamp {
+    /// Is the percentage at most 100?
+    pub fn is_valid(&self) -> bool {
+        self.percent <= 100
+    }
+
+    /// How far (from 0 to 1) the pen servo should be between where it was and where it's going,
+    /// when the pen is `progress` (from 0 to 1) of the way through the first half of its
+    /// lifting time.
+    pub fn position(&self, progress: Fixed) -> Fixed {
+        let ramp = Fixed::from_num(self.percent.min(100)) / 100;
+        let start = Fixed::ONE - ramp;
+        if progress >= Fixed::ONE {
+            Fixed::ONE
+        } else if progress <= start {
+            Fixed::ZERO
+        } else {
+            (progress - start) / ramp
+        }
+    }
+}
+
 impl Pwm {
     pub fn shoulder() -> Pwm {
         Pwm {
             inc: [(-45, 2333), (120, 500)].into_iter().collect(),
             dec: [(-45, 2333), (120, 500)].into_iter().collect(),
+            inverted: false,
         }
     }
 
@@ -82,32 +404,73 @@ impl Pwm {
         Pwm {
             inc: [(-60, 2167), (75, 833)].into_iter().collect(),
             dec: [(-60, 2167), (75, 833)].into_iter().collect(),
+            inverted: false,
         }
     }
 
-    pub fn duty(&self, last_angle: Angle, angle: Angle) -> u16 {
-        let deg = angle.degrees();
-        let slices = if angle.degrees() > last_angle.degrees() {
-            self.inc.windows(2)
-        } else {
-            self.dec.windows(2)
-        };
-        for slice in slices {
-            let before = Fixed::from_num(slice[0].0);
-            let after = Fixed::from_num(slice[1].0);
-            if deg < before {
-                // We cannot represent an angle so small, so return the smallest angle we have.
-                return slice[0].1;
-            } else if deg <= after {
-                let lambda = (deg - before) / (after - before);
-                let mu = Fixed::from_num(1i32) - lambda;
-                let before: Fixed = Fixed::from(slice[0].1);
-                let after: Fixed = Fixed::from(slice[1].1);
-                return (before * mu + after * lambda).round().to_num();
+    /// Roughly how much the duty changes for each degree that the servo turns, going by the
+    /// ends of its table.
+    pub fn duty_per_degree(&self) -> Fixed {
+        match (self.inc.first(), self.inc.last()) {
+            (Some(&(a0, d0)), Some(&(a1, d1))) if a0 != a1 => {
+                let duties = Fixed::from_num(d1) - Fixed::from_num(d0);
+                (duties / Fixed::from_num(i32::from(a1) - i32::from(a0)))
+                    .abs()
+                    .max(Fixed::ONE)
             }
+            // About right for the servos in `Pwm::shoulder` and `Pwm::elbow`.
+            _ => Fixed::from_num(11),
+        }
+    }
+
+    /// The duty that puts the servo at `angle`, when it's turning in the direction `dir` (see
+    /// [`Hysteresis`]).
+    pub fn duty(&self, dir: Direction, angle: Angle) -> u16 {
+        let table = match dir {
+            Direction::Increasing => &self.inc,
+            Direction::Decreasing => &self.dec,
+        };
+        let duty = table_duty(table, angle);
+        if self.inverted {
+            MIRROR.saturating_sub(duty)
+        } else {
+            duty
+        }
+    }
+}
+
+fn table_duty(table: &[CalibrationEntry], angle: Angle) -> u16 {
+    let deg = angle.degrees();
+    for slice in table.windows(2) {
+        let before = Fixed::from_num(slice[0].0);
+        let after = Fixed::from_num(slice[1].0);
+        if deg < before {
+            // We cannot represent an angle so small, so return the smallest angle we have.
+            return slice[0].1;
+        } else if deg <= after {
+            let lambda = (deg - before) / (after - before);
+            let mu = Fixed::from_num(1i32) - lambda;
+            let before: Fixed = Fixed::from(slice[0].1);
+            let after: Fixed = Fixed::from(slice[1].1);
+            return (before * mu + after * lambda).round().to_num();
+        }
+    }
+    // We cannot represent an angle so large, so return the largest angle we have.
+    table.last().unwrap().1
+}
+
+impl AnglePwm {
+    /// A table for the pen servo that agrees with [`TogglePwm::pen`]: the pen is up at 0 degrees
+    /// and down at 45.
+    pub fn pen() -> AnglePwm {
+        AnglePwm {
+            table: [(0, 750), (90, 1750)].into_iter().collect(),
         }
-        // We cannot represent an angle so large, so return the largest angle we have.
-        return self.inc.last().unwrap().1;
+    }
+
+    /// The duty that puts the servo at `angle`.
+    pub fn duty(&self, angle: Angle) -> u16 {
+        table_duty(&self.table, angle)
     }
 }
 
@@ -116,6 +479,11 @@ impl TogglePwm {
         TogglePwm { off: 750, on: 1250 }
     }
 
+    /// Are both duties ones that a servo understands?
+    pub fn is_valid(&self) -> bool {
+        duty_is_valid(self.on) && duty_is_valid(self.off)
+    }
+
     pub fn duty(&self, state: PenState) -> u16 {
         match state {
             PenState::Up => self.off,
@@ -131,12 +499,229 @@ mod tests {
         assert!((a as i32 - b as i32).abs() < 10);
     }
 
+    #[test]
+    fn crc32() {
+        let mut crc = Crc32::default();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn duty_per_degree() {
+        // 1833 duties over 165 degrees.
+        assert_eq!(Pwm::shoulder().duty_per_degree().round(), 11);
+        let mut short = Pwm::elbow();
+        short.inc.truncate(1);
+        assert_eq!(short.duty_per_degree(), 11);
+    }
+
+    #[test]
+    fn checksum_follows_calibration() {
+        let mut pos = CalibratedPosition::default();
+        let before = pos.calib.checksum();
+        assert_eq!(before, Calibration::default().checksum());
+
+        pos.change_pen_calibration(TogglePwm { on: 1300, off: 750 });
+        let after_pen = pos.calib.checksum();
+        assert_ne!(after_pen, before);
+
+        pos.change_inversion(Joint::Elbow, true);
+        let after_inversion = pos.calib.checksum();
+        assert_ne!(after_inversion, after_pen);
+
+        pos.change_timing(Timing {
+            frame_us: 4000,
+            tick_ms: 5,
+        });
+        let after_timing = pos.calib.checksum();
+        assert_ne!(after_timing, after_inversion);
+
+        pos.change_pen_angles(ServoCalibration {
+            data: [(0, 800), (90, 1800)].into_iter().collect(),
+        });
+        let after_angles = pos.calib.checksum();
+        assert_ne!(after_angles, after_timing);
+
+        pos.change_pen_ramp(PenRamp { percent: 50 });
+        assert_ne!(pos.calib.checksum(), after_angles);
+    }
+
+    #[test]
+    fn pen_ramp() {
+        let mut pos = CalibratedPosition::default();
+        let without_ramp = pos.calib.checksum();
+        pos.change_pen_ramp(PenRamp { percent: 100 });
+        assert_ne!(pos.calib.checksum(), without_ramp);
+        let up = pos.pen_duty(PenState::Up);
+        let down = pos.pen_duty(PenState::Down);
+        let at = |pos: &CalibratedPosition, progress: f32| {
+            pos.ramp_duty(PenState::Down, Fixed::from_num(progress))
+        };
+        assert_eq!(at(&pos, 0.0), up);
+        assert_eq!(at(&pos, 0.5), (up + down) / 2);
+        assert_eq!(at(&pos, 1.0), down);
+        // Going up is the same thing backwards.
+        assert_eq!(
+            pos.ramp_duty(PenState::Up, Fixed::from_num(0.25)),
+            at(&pos, 0.75)
+        );
+
+        // A shorter ramp waits before starting.
+        pos.change_pen_ramp(PenRamp { percent: 50 });
+        assert_eq!(at(&pos, 0.5), up);
+        assert_eq!(at(&pos, 0.75), (up + down) / 2);
+
+        // Without a ramp, the pen jumps at the end.
+        pos.change_pen_ramp(PenRamp { percent: 0 });
+        assert_eq!(at(&pos, 0.99), up);
+        assert_eq!(at(&pos, 1.0), down);
+        assert_eq!(pos.calib.checksum(), without_ramp);
+        assert!(!PenRamp { percent: 101 }.is_valid());
+    }
+
+    #[test]
+    fn pen_angles() {
+        let mut pos = CalibratedPosition::default();
+        let angles = Angles {
+            shoulder: Angle::from_degrees(0),
+            elbow: Angle::from_degrees(0),
+        };
+        // The default table agrees with the default up and down duties.
+        let up = pos.calib.pen.duty(PenState::Up);
+        let down = pos.calib.pen.duty(PenState::Down);
+        assert_eq!(pos.calib.pen_angles.duty(Angle::from_degrees(0)), up);
+        assert_eq!(pos.calib.pen_angles.duty(Angle::from_degrees(45)), down);
+
+        assert!(pos.set_pen_angle(Some(Angle::from_degrees(60))));
+        assert!(!pos.set_pen_angle(Some(Angle::from_degrees(60))));
+        assert_eq!(pos.update(angles, PenState::Down).pen, 1417);
+        // The angle only says where the pen goes when it's down.
+        assert_eq!(pos.update(angles, PenState::Up).pen, up);
+
+        assert!(pos.set_pen_angle(None));
+        assert_eq!(pos.update(angles, PenState::Down).pen, down);
+        assert!(!pos.set_pen_angle(Some(Angle::from_degrees(45))));
+    }
+
+    #[test]
+    fn timing_ranges() {
+        assert!(Timing::default().is_valid());
+        let fast = Timing {
+            frame_us: MIN_FRAME_US,
+            tick_ms: MIN_TICK_MS,
+        };
+        assert!(fast.is_valid());
+        assert!(!Timing {
+            frame_us: MIN_FRAME_US - 1,
+            ..fast
+        }
+        .is_valid());
+        assert!(!Timing {
+            frame_us: MAX_FRAME_US + 1,
+            ..fast
+        }
+        .is_valid());
+        assert!(!Timing { tick_ms: 0, ..fast }.is_valid());
+        assert!(!Timing {
+            tick_ms: MAX_TICK_MS + 1,
+            ..fast
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn inverted_duties() {
+        let mut pos = CalibratedPosition::default();
+        let angles = Angles {
+            shoulder: Angle::from_degrees(0),
+            elbow: Angle::from_degrees(30),
+        };
+        let normal = pos.update(angles, PenState::Up);
+        pos.change_inversion(Joint::Shoulder, true);
+        let inverted = pos.update(angles, PenState::Up);
+        assert_eq!(inverted.shoulder, 3000 - normal.shoulder);
+        assert_eq!(inverted.elbow, normal.elbow);
+    }
+
+    #[test]
+    fn calibration_validity() {
+        let calib = |data: &[(i16, u16)]| ServoCalibration {
+            data: data.iter().copied().collect(),
+        };
+        assert!(calib(&[(-45, 2333), (120, 500)]).is_valid());
+        assert!(!calib(&[(-45, 2333)]).is_valid());
+        assert!(!calib(&[(120, 500), (-45, 2333)]).is_valid());
+        assert!(!calib(&[(-45, 2600), (120, 500)]).is_valid());
+        assert!(TogglePwm::pen().is_valid());
+        assert!(!TogglePwm { on: 0, off: 750 }.is_valid());
+    }
+
     #[test]
     fn precomputed_duties() {
         let sh = Pwm::shoulder();
-        assert_approx(
-            1833,
-            sh.duty(Angle::from_degrees(0), Angle::from_degrees(0)),
+        assert_approx(1833, sh.duty(Direction::Decreasing, Angle::from_degrees(0)));
+    }
+
+    // Steps through some angles (in degrees), returning the direction after each one.
+    fn directions(start: Hysteresis, degs: &[f64]) -> Vec<Direction> {
+        let mut state = start;
+        degs.iter()
+            .map(|&d| {
+                state = state.step(Angle::from_degrees(d));
+                state.dir
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hysteresis_deadband() {
+        use Direction::*;
+        let start = Hysteresis::new(Increasing, Angle::from_degrees(10));
+        // Standing still, and wobbling back by less than the deadband, keep the table.
+        assert_eq!(
+            directions(start, &[10.0, 10.0, 9.5, 10.0, 9.2]),
+            [Increasing; 5]
         );
+        // The deadband is measured from the furthest point, not from the last one.
+        assert_eq!(
+            directions(start, &[12.0, 11.5, 11.2, 10.9, 11.5]),
+            [Increasing, Increasing, Increasing, Decreasing, Decreasing]
+        );
+        // Going back more than the deadband switches tables straight away.
+        assert_eq!(directions(start, &[8.0]), [Decreasing]);
+    }
+
+    #[test]
+    fn hysteresis_hatching() {
+        use Direction::*;
+        // Hatching goes back and forth over the same few degrees, with a little jitter at the
+        // ends of each line.
+        let start = Hysteresis::new(Decreasing, Angle::from_degrees(20));
+        let degs = [
+            25.0, 30.0, 29.8, 30.1, 25.0, 20.0, 20.3, 19.9, 25.0, 30.0, 30.2,
+        ];
+        assert_eq!(
+            directions(start, &degs),
+            [
+                Increasing, Increasing, Increasing, Increasing, Decreasing, Decreasing, Decreasing,
+                Decreasing, Increasing, Increasing, Increasing
+            ]
+        );
+
+        // Jittering at the end of a line keeps getting the duty from the same table.
+        let mut pos = CalibratedPosition::default();
+        pos.calib.shoulder.inc = [(0, 1000), (100, 2000)].into_iter().collect();
+        pos.calib.shoulder.dec = [(0, 1020), (100, 2020)].into_iter().collect();
+        let duties: Vec<u16> = [0.0, 30.0, 29.8, 30.0, 29.9, 30.0]
+            .iter()
+            .map(|&d| {
+                let angles = Angles {
+                    shoulder: Angle::from_degrees(d),
+                    elbow: Angle::from_degrees(0),
+                };
+                pos.update(angles, PenState::Up).shoulder
+            })
+            .collect();
+        assert_eq!(duties, [1020, 1300, 1298, 1300, 1299, 1300]);
     }
 }
@@ -0,0 +1,245 @@
+//! The shapes of path that a brachiograph can follow in a single movement.
+
+use cordic::{atan2, cos, sin, sqrt};
+
+use crate::{geom, Angle, Fixed, Point};
+
+/// A piece of path, starting from wherever the brachiograph currently is.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Segment {
+    /// A straight line to a point.
+    Line(Point),
+    /// A quadratic Bézier curve with a control point and an end point.
+    Quad(Point, Point),
+    /// A cubic Bézier curve with two control points and an end point.
+    Cubic(Point, Point, Point),
+    /// A circular arc around `center`, turning through `sweep` (counter-clockwise if positive).
+    Arc { center: Point, sweep: Angle },
+}
+
+fn lerp(a: Point, b: Point, t: Fixed) -> Point {
+    Point {
+        x: a.x + t * (b.x - a.x),
+        y: a.y + t * (b.y - a.y),
+    }
+}
+
+fn dist(a: Point, b: Point) -> Fixed {
+    let dx = b.x - a.x;
+    let dy = b.y - a.y;
+    sqrt(dx * dx + dy * dy)
+}
+
+// The vector from `a` to `b`.
+fn delta(a: Point, b: Point) -> (Fixed, Fixed) {
+    (b.x - a.x, b.y - a.y)
+}
+
+// The direction of travel around an arc at `p`.
+fn arc_heading(center: Point, sweep: Angle, p: Point) -> (Fixed, Fixed) {
+    let (vx, vy) = delta(center, p);
+    if sweep.degrees() >= 0 {
+        (-vy, vx)
+    } else {
+        (vy, -vx)
+    }
+}
+
+impl Segment {
+    /// The direction that the segment sets off in, if it starts at `init`.
+    ///
+    /// This isn't normalized, and it's zero if the segment doesn't go anywhere.
+    pub fn start_heading(&self, init: Point) -> (Fixed, Fixed) {
+        // For Béziers, it's the direction to the first control point that isn't on top of the
+        // start.
+        let first_away = |points: &[Point]| {
+            let p = points.iter().find(|&&p| p != init).unwrap_or(&init);
+            delta(init, *p)
+        };
+        match *self {
+            Segment::Line(p) => delta(init, p),
+            Segment::Quad(c, p) => first_away(&[c, p]),
+            Segment::Cubic(c1, c2, p) => first_away(&[c1, c2, p]),
+            Segment::Arc { center, sweep } => arc_heading(center, sweep, init),
+        }
+    }
+
+    /// The direction that the segment is going in when it ends, if it starts at `init`.
+    ///
+    /// This isn't normalized, and it's zero if the segment doesn't go anywhere.
+    pub fn end_heading(&self, init: Point) -> (Fixed, Fixed) {
+        let last_away = |end: Point, points: &[Point]| {
+            let p = points.iter().rev().find(|&&p| p != end).unwrap_or(&end);
+            delta(*p, end)
+        };
+        match *self {
+            Segment::Line(p) => delta(init, p),
+            Segment::Quad(c, p) => last_away(p, &[init, c]),
+            Segment::Cubic(c1, c2, p) => last_away(p, &[init, c1, c2]),
+            Segment::Arc { center, sweep } => arc_heading(center, sweep, self.end(init)),
+        }
+    }
+
+    /// Where the segment ends, if it starts at `init`.
+    pub fn end(&self, init: Point) -> Point {
+        match *self {
+            Segment::Line(p) | Segment::Quad(_, p) | Segment::Cubic(_, _, p) => p,
+            Segment::Arc { .. } => self.eval(init, Fixed::ONE),
+        }
+    }
+
+    /// The point at parameter `t` (between zero and one) along the segment, if it starts at `init`.
+    pub fn eval(&self, init: Point, t: Fixed) -> Point {
+        match *self {
+            Segment::Line(p) => lerp(init, p, t),
+            Segment::Quad(c, p) => lerp(lerp(init, c, t), lerp(c, p, t), t),
+            Segment::Cubic(c1, c2, p) => {
+                let a = lerp(init, c1, t);
+                let b = lerp(c1, c2, t);
+                let c = lerp(c2, p, t);
+                lerp(lerp(a, b, t), lerp(b, c, t), t)
+            }
+            Segment::Arc { center, sweep } => {
+                let theta = sweep.radians() * t;
+                let (c, s) = (cos(theta), sin(theta));
+                let vx = init.x - center.x;
+                let vy = init.y - center.y;
+                Point {
+                    x: center.x + vx * c - vy * s,
+                    y: center.y + vx * s + vy * c,
+                }
+            }
+        }
+    }
+
+    /// The length of the segment, if it starts at `init`.
+    ///
+    /// For Bézier curves, this is only an estimate: the average of the distance between
+    /// the endpoints and the length of the control polygon.
+    pub fn length(&self, init: Point) -> Fixed {
+        match *self {
+            Segment::Line(p) => dist(init, p),
+            Segment::Quad(c, p) => (dist(init, p) + dist(init, c) + dist(c, p)) / 2,
+            Segment::Cubic(c1, c2, p) => {
+                (dist(init, p) + dist(init, c1) + dist(c1, c2) + dist(c2, p)) / 2
+            }
+            Segment::Arc { center, sweep } => dist(center, init) * sweep.radians().abs(),
+        }
+    }
+
+    /// Does the whole segment stay inside the brachiograph's workspace, if it starts at `init`?
+    pub fn is_valid(&self, init: Point, config: &geom::Config) -> bool {
+        let valid = |p: Point| config.coord_is_valid(p.x, p.y);
+        match *self {
+            // The workspace is a rectangle, so it's enough to check the control points.
+            Segment::Line(p) => valid(init) && valid(p),
+            Segment::Quad(c, p) => valid(init) && valid(c) && valid(p),
+            Segment::Cubic(c1, c2, p) => valid(init) && valid(c1) && valid(c2) && valid(p),
+            Segment::Arc { center, sweep } => {
+                if !valid(init) || !valid(self.end(init)) {
+                    return false;
+                }
+                // The arc sticks out furthest at the points where it's heading straight up,
+                // down, left, or right, so check all of those that it passes.
+                let r = dist(center, init);
+                let start = atan2(init.y - center.y, init.x - center.x);
+                let end = start + sweep.radians();
+                let (lo, hi) = if start <= end {
+                    (start, end)
+                } else {
+                    (end, start)
+                };
+                let quarter = Fixed::FRAC_PI_2;
+                let first = (lo / quarter).ceil().to_num::<i32>();
+                let last = (hi / quarter).floor().to_num::<i32>().min(first + 3);
+                (first..=last).all(|k| {
+                    let (dx, dy) = match k.rem_euclid(4) {
+                        0 => (r, Fixed::ZERO),
+                        1 => (Fixed::ZERO, r),
+                        2 => (-r, Fixed::ZERO),
+                        _ => (Fixed::ZERO, -r),
+                    };
+                    valid(Point {
+                        x: center.x + dx,
+                        y: center.y + dy,
+                    })
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pt(x: f64, y: f64) -> Point {
+        Point {
+            x: Fixed::from_num(x),
+            y: Fixed::from_num(y),
+        }
+    }
+
+    fn assert_close(a: Point, b: Point) {
+        assert!(dist(a, b) < 0.01, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn headings() {
+        let init = pt(0.0, 10.0);
+        let heading = |(x, y): (Fixed, Fixed)| (x.to_num::<f64>(), y.to_num::<f64>());
+
+        let quad = Segment::Quad(init, pt(2.0, 12.0));
+        assert_eq!(heading(quad.start_heading(init)), (2.0, 2.0));
+        assert_eq!(heading(quad.end_heading(init)), (2.0, 2.0));
+
+        let cubic = Segment::Cubic(pt(1.0, 10.0), pt(2.0, 11.0), pt(2.0, 12.0));
+        assert_eq!(heading(cubic.start_heading(init)), (1.0, 0.0));
+        assert_eq!(heading(cubic.end_heading(init)), (0.0, 1.0));
+
+        // Counter-clockwise around a center below the start, so heading left.
+        let arc = Segment::Arc {
+            center: pt(0.0, 9.0),
+            sweep: Angle::from_degrees(90),
+        };
+        assert_eq!(heading(arc.start_heading(init)), (-1.0, 0.0));
+        let (x, y) = heading(arc.end_heading(init));
+        assert!(x.abs() < 0.01 && (y + 1.0).abs() < 0.01, "{x} {y}");
+    }
+
+    #[test]
+    fn arc() {
+        let arc = Segment::Arc {
+            center: pt(0.0, 10.0),
+            sweep: Angle::from_degrees(90),
+        };
+        assert_close(arc.end(pt(2.0, 10.0)), pt(0.0, 12.0));
+        assert_close(
+            arc.eval(pt(2.0, 10.0), Fixed::from_num(0.5)),
+            pt(1.414, 11.414),
+        );
+        assert!((arc.length(pt(2.0, 10.0)) - Fixed::PI).abs() < 0.01);
+
+        let config = geom::Config::default();
+        assert!(arc.is_valid(pt(2.0, 10.0), &config));
+        // Both ends of this one are inside, but the middle pokes out the top.
+        let y_max: f64 = config.y_range.1.to_num();
+        let arc = Segment::Arc {
+            center: pt(0.0, y_max - 1.0),
+            sweep: Angle::from_degrees(-180),
+        };
+        assert!(!arc.is_valid(pt(-2.0, y_max - 1.0), &config));
+    }
+
+    #[test]
+    fn cubic() {
+        let cubic = Segment::Cubic(pt(0.0, 11.0), pt(2.0, 11.0), pt(2.0, 10.0));
+        assert_close(cubic.eval(pt(0.0, 10.0), Fixed::ZERO), pt(0.0, 10.0));
+        assert_close(
+            cubic.eval(pt(0.0, 10.0), Fixed::from_num(0.5)),
+            pt(1.0, 10.75),
+        );
+        assert_close(cubic.end(pt(0.0, 10.0)), pt(2.0, 10.0));
+    }
+}
@@ -0,0 +1,65 @@
+//! Property tests for the text commands (see `Op`'s `FromStr` and `Display` impls).
+
+use brachiograph::{Angle, Fixed, Op, Point};
+use proptest::prelude::*;
+
+fn fixed() -> impl Strategy<Value = Fixed> {
+    prop_oneof![
+        any::<i32>().prop_map(Fixed::from_bits),
+        // Small whole numbers, like the ones that people actually type.
+        (-100..100i32).prop_map(Fixed::from_num),
+    ]
+}
+
+// The ops that have text commands.
+fn text_op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        Just(Op::PenUp),
+        Just(Op::PenDown),
+        Just(Op::GetAngles),
+        (fixed(), fixed()).prop_map(|(x, y)| Op::MoveTo(Point { x, y })),
+        (fixed(), fixed()).prop_map(|(r, theta)| Op::MoveBy(r, Angle::from_degrees(theta))),
+    ]
+}
+
+proptest! {
+    #[test]
+    fn display_then_parse(op in text_op()) {
+        prop_assert!(op.has_text());
+        let text = op.to_string();
+        prop_assert_eq!(text.parse::<Op>(), Ok(op), "{:?}", text);
+    }
+
+    #[test]
+    fn parse_never_panics(bytes in any::<Vec<u8>>()) {
+        let _ = String::from_utf8_lossy(&bytes).parse::<Op>();
+    }
+
+    // Random bytes hardly ever look like commands, so also try things that are close.
+    #[test]
+    fn parse_near_misses_never_panics(
+        text in "(penup|pendown|moveto|moveby|angles)?( +[-+]?[0-9.eE]{0,12}){0,3} *"
+    ) {
+        if let Ok(op) = text.parse::<Op>() {
+            // Anything that parses has a canonical form that parses back the same way.
+            prop_assert_eq!(op.to_string().parse::<Op>(), Ok(op));
+        }
+    }
+}
+
+#[test]
+fn canonical_text() {
+    let p = Point {
+        x: Fixed::from_num(1.5),
+        y: Fixed::from_num(-2),
+    };
+    assert_eq!(Op::MoveTo(p).to_string(), "moveto 1.5 -2");
+    assert_eq!(
+        Op::MoveBy(Fixed::from_num(3), Angle::from_degrees(90)).to_string(),
+        "moveby 3 90"
+    );
+    assert_eq!(" penup ".parse::<Op>(), Ok(Op::PenUp));
+    assert!("penup now".parse::<Op>().is_err());
+    assert!("moveto 1".parse::<Op>().is_err());
+    assert!(!Op::Home.has_text());
+}
@@ -0,0 +1,243 @@
+//! Draws a test page, using most of the host library along the way.
+//!
+//! ```text
+//! cargo run --example plot_demo -- --port auto
+//! ```
+//!
+//! `--port` is either `auto` (to look for a connected brachiograph), the name of a serial port
+//! like `/dev/ttyACM0`, or `sim` (to draw on a [`Simulator`] instead of a real brachiograph).
+//! The demo checks that the brachiograph goes where it's told, plots some text, a square, a
+//! circle and a spiral, finishes off (see [`Finish`]), and then says how it went.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use brachiograph::{Op, Resp};
+use brachiograph_host::{
+    finish::Finish,
+    font, path,
+    profile::{Profile, Settings},
+    sim::Simulator,
+    Job, JournalEntry, Layer, Plotter, Serial, Transport,
+};
+use kurbo::{Affine, BezPath, Circle, PathEl, Point, Rect, Shape};
+
+enum Device {
+    Real(Box<Serial>),
+    Sim(Box<Simulator>),
+}
+
+impl Device {
+    fn open(port: &str) -> anyhow::Result<Device> {
+        Ok(match port {
+            "sim" => Device::Sim(Box::new(Simulator::new())),
+            "auto" => Device::Real(Box::new(
+                Serial::detect().context("couldn't find a brachiograph")?,
+            )),
+            name => Device::Real(Box::new(Serial::open(name, Transport::Postcard)?)),
+        })
+    }
+
+    fn plotter(&mut self) -> &mut dyn Plotter {
+        match self {
+            Device::Real(serial) => serial.as_mut(),
+            Device::Sim(sim) => sim.as_mut(),
+        }
+    }
+
+    fn send(&mut self, op: Op) -> anyhow::Result<Resp> {
+        self.plotter().send(op)
+    }
+
+    fn plot(&mut self, job: &Job) -> anyhow::Result<()> {
+        match self {
+            Device::Real(serial) => serial
+                .send_batch(job.ops())
+                .map_err(|e| e.error.context(format!("stopped at op {}", e.sent))),
+            Device::Sim(sim) => {
+                for op in job {
+                    expect_ack(sim.send(op.clone()))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(&mut self, finish: &Finish) -> anyhow::Result<()> {
+        match self {
+            Device::Real(serial) => serial.finish(finish),
+            Device::Sim(sim) => {
+                let Resp::Config(config) = sim.send(Op::GetConfig) else {
+                    bail!("the simulator didn't say what its drawing area is");
+                };
+                for op in finish.ops(&config) {
+                    expect_ack(sim.send(op))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn expect_ack(resp: Resp) -> anyhow::Result<()> {
+    match resp {
+        Resp::Ack => Ok(()),
+        resp => bail!("unexpected response: {resp:?}"),
+    }
+}
+
+// Checks that the brachiograph can say what it is, and that it goes where it's told.
+fn self_test(device: &mut Device) -> anyhow::Result<()> {
+    if let Resp::Info(info) = device.send(Op::GetInfo)? {
+        println!(
+            "Firmware {} (built {}), calibration checksum {:08x}",
+            info.git_hash, info.build_date, info.calibration_crc
+        );
+    }
+    let Resp::Config(config) = device.send(Op::GetConfig)? else {
+        bail!("the brachiograph didn't say how long its arms are");
+    };
+    for (op, (x, y)) in [(Op::Home, config.home), (Op::Park, config.park)] {
+        let target = Point::new(x.to_num(), y.to_num());
+        expect_ack(device.send(op)?)?;
+        wait_until_still(device)?;
+        let Resp::Angles(angles) = device.send(Op::GetAngles)? else {
+            bail!("the brachiograph didn't say where its arms are");
+        };
+        let (x, y) = config.coord_at_angle::<f64>(angles);
+        let error = (Point::new(x, y) - target).hypot();
+        println!("Moved to {target:?}, and ended up {error:.3} away");
+        if error > 0.05 {
+            bail!("the brachiograph didn't go where it was told; is it calibrated?");
+        }
+    }
+    Ok(())
+}
+
+// Waits for the brachiograph to finish everything that it's been sent. An empty queue isn't
+// enough, because the last move leaves the queue as soon as it starts.
+fn wait_until_still(device: &mut Device) -> anyhow::Result<()> {
+    let start = Instant::now();
+    loop {
+        let Resp::Progress { queue_len, .. } = device.send(Op::GetProgress)? else {
+            bail!("the brachiograph didn't say how far it has got");
+        };
+        let Resp::TimeLeft { millis } = device.send(Op::EstimateTime)? else {
+            bail!("the brachiograph didn't say how long it has left");
+        };
+        if queue_len == 0 && millis == 0 {
+            return Ok(());
+        }
+        if start.elapsed() > Duration::from_secs(30) {
+            bail!("the brachiograph didn't stop moving");
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+// The test page: one layer each of text, a square, a circle and a spiral.
+fn demo_page(settings: &Settings) -> Job {
+    let text = Affine::translate((-6.0, 11.5)) * font::text("BRACHIOGRAPH", 0.8);
+    let square = Rect::new(-6.0, 6.0, -3.0, 9.0).to_path(0.0);
+    let circle = Circle::new((0.0, 7.5), 1.5).to_path(settings.flatten_tolerance);
+    let mut spiral = BezPath::new();
+    let center = Point::new(4.5, 7.5);
+    for i in 0..=360 {
+        let theta = (i as f64 * 4.0).to_radians();
+        let p = center + kurbo::Vec2::from_angle(theta) * (1.5 * i as f64 / 360.0);
+        if i == 0 {
+            spiral.move_to(p);
+        } else {
+            spiral.line_to(p);
+        }
+    }
+
+    let mut ops = Vec::new();
+    let mut layers = Vec::new();
+    for (name, shape) in [
+        ("text", text),
+        ("square", square),
+        ("circle", circle),
+        ("spiral", spiral),
+    ] {
+        // Flattening leaves the square and the circle closed, but simplifying wants lines.
+        let mut flat = BezPath::new();
+        let mut start = Point::ORIGIN;
+        shape.flatten(settings.flatten_tolerance, |el| match el {
+            PathEl::MoveTo(p) => {
+                start = p;
+                flat.move_to(p);
+            }
+            PathEl::ClosePath => flat.line_to(start),
+            el => flat.push(el),
+        });
+        layers.push(Layer {
+            name: name.to_owned(),
+            start: ops.len(),
+            pen: None,
+        });
+        ops.extend(path::to_ops(&path::simplify(
+            &flat,
+            settings.simplify_epsilon,
+        )));
+    }
+    Job::new(ops).with_name("demo page").with_layers(layers)
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let port = match (args.next().as_deref(), args.next()) {
+        (None, _) => "auto".to_owned(),
+        (Some("--port"), Some(port)) => port,
+        _ => bail!("usage: plot_demo [--port auto|sim|<serial port>]"),
+    };
+    let mut device = Device::open(&port)?;
+    self_test(&mut device)?;
+
+    let settings = Profile::Normal.settings();
+    let job = demo_page(&settings);
+    let stats = job.stats();
+    println!(
+        "Plotting {} ops, drawing {:.1} and moving {:.1}; this should take about {:?}",
+        job.len(),
+        stats.draw_distance,
+        stats.travel_distance,
+        job.estimate_duration(&settings)
+    );
+    for layer in job.layer_stats(&settings) {
+        println!(
+            "  {:8} {:>6.1} drawn in about {:.0}s",
+            layer.name,
+            layer.stats.draw_distance,
+            layer.duration.as_secs_f64()
+        );
+    }
+    for op in settings.ops() {
+        expect_ack(device.send(op)?)?;
+    }
+    let start = std::time::Instant::now();
+    device.plot(&job)?;
+    let finish = Finish::default();
+    device.finish(&finish)?;
+
+    match &device {
+        Device::Real(serial) => {
+            if let Some(report) = finish.report(&stats, start.elapsed()) {
+                println!("{report}");
+            }
+            for entry in serial.journal() {
+                if let JournalEntry::Nudged { from, to } = entry {
+                    println!("Moved {from:?} to {to:?} because the brachiograph refused it");
+                }
+            }
+        }
+        Device::Sim(sim) => {
+            let bbox = sim.drawing().bounding_box();
+            println!(
+                "Done; a real brachiograph would have taken {:?}, and the drawing covers {bbox:?}",
+                sim.elapsed()
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,559 @@
+//! Measuring how accurately a brachiograph draws, from a photo of what it drew.
+//!
+//! [`Grid::ops`] draws little crosses at known positions. Once they've been photographed from
+//! straight above (with the brachiograph's base at the bottom of the picture), [`Accuracy::measure`]
+//! finds the crosses in the photo and compares them with where they should have been.
+//!
+//! Nobody knows exactly where the camera was, so the photo gets lined up with the grid as well as
+//! it can be by moving, turning and scaling it. That hides any error that is the same all over
+//! the page (which is what [registration](crate::registration) is for), and leaves the errors that
+//! change from place to place, which are the ones that come from the calibration.
+
+use std::collections::BTreeMap;
+
+use brachiograph::{geom, Joint, Op};
+use kurbo::{Affine, BezPath, Point, Rect, Vec2};
+
+use crate::path;
+
+/// Where the crosses go.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Grid {
+    /// The area that the crosses cover, in brachiograph units. There's a cross on each corner.
+    pub rect: Rect,
+    /// How far apart the crosses are.
+    pub spacing: f64,
+    /// How long each arm of a cross is.
+    pub mark_size: f64,
+}
+
+impl Default for Grid {
+    fn default() -> Self {
+        Grid {
+            rect: Rect::new(-6.0, 6.0, 6.0, 12.0),
+            spacing: 2.0,
+            mark_size: 0.3,
+        }
+    }
+}
+
+impl Grid {
+    /// The centers of the crosses, a row at a time from the bottom.
+    pub fn points(&self) -> Vec<Point> {
+        let count = |len: f64| (len / self.spacing + 1e-9).floor() as usize + 1;
+        let (cols, rows) = (count(self.rect.width()), count(self.rect.height()));
+        (0..rows)
+            .flat_map(|j| {
+                (0..cols)
+                    .map(move |i| self.rect.origin() + Vec2::new(i as f64, j as f64) * self.spacing)
+            })
+            .collect()
+    }
+
+    /// The ops that draw the crosses.
+    pub fn ops(&self) -> Vec<Op> {
+        let mut crosses = BezPath::new();
+        let (dx, dy) = (
+            Vec2::new(self.mark_size, 0.0),
+            Vec2::new(0.0, self.mark_size),
+        );
+        for p in self.points() {
+            crosses.move_to(p - dx);
+            crosses.line_to(p + dx);
+            crosses.move_to(p - dy);
+            crosses.line_to(p + dy);
+        }
+        path::to_ops(&crosses)
+    }
+}
+
+/// A grayscale photo, a row at a time from the top, with 0 for black and 255 for white.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Photo {
+    pub width: usize,
+    pub height: usize,
+    pub luma: Vec<u8>,
+}
+
+/// Finds the marks in a photo of some crosses, in pixels from the top left of the photo.
+///
+/// A mark is a blob of dark pixels, and its position is the blob's centroid, which for a cross is
+/// its center. Blobs that touch the edge of the photo, or that are much bigger or smaller than
+/// the typical one (like specks of dust, or shadows), are ignored.
+pub fn find_marks(photo: &Photo) -> Vec<Point> {
+    let (w, h) = (photo.width, photo.height);
+    let threshold = otsu(&photo.luma);
+    let mut seen: Vec<bool> = photo.luma.iter().map(|&l| l > threshold).collect();
+    let mut blobs = Vec::new();
+    let mut stack = Vec::new();
+    for start in 0..w * h {
+        if seen[start] {
+            continue;
+        }
+        seen[start] = true;
+        stack.push(start);
+        let (mut n, mut sum, mut on_edge) = (0usize, Vec2::ZERO, false);
+        while let Some(i) = stack.pop() {
+            let (x, y) = (i % w, i / w);
+            n += 1;
+            sum += Vec2::new(x as f64, y as f64);
+            on_edge |= x == 0 || y == 0 || x == w - 1 || y == h - 1;
+            for ny in y.saturating_sub(1)..(y + 2).min(h) {
+                for nx in x.saturating_sub(1)..(x + 2).min(w) {
+                    let j = ny * w + nx;
+                    if !seen[j] {
+                        seen[j] = true;
+                        stack.push(j);
+                    }
+                }
+            }
+        }
+        if !on_edge {
+            // The middle of the pixels, not their top left corners.
+            blobs.push((n, (sum / n as f64).to_point() + Vec2::new(0.5, 0.5)));
+        }
+    }
+
+    let mut sizes: Vec<usize> = blobs.iter().map(|(n, _)| *n).collect();
+    sizes.sort_unstable();
+    let Some(&typical) = sizes.get(sizes.len() / 2) else {
+        return Vec::new();
+    };
+    blobs
+        .into_iter()
+        .filter(|(n, _)| *n * 4 >= typical && *n <= typical * 4)
+        .map(|(_, p)| p)
+        .collect()
+}
+
+// The brightness that best separates the ink from the paper (Otsu's method: the one that makes
+// the two groups of pixels as different from each other as possible).
+fn otsu(luma: &[u8]) -> u8 {
+    let mut hist = [0u64; 256];
+    for &l in luma {
+        hist[l as usize] += 1;
+    }
+    let total = luma.len() as f64;
+    let sum: f64 = hist
+        .iter()
+        .enumerate()
+        .map(|(l, &n)| l as f64 * n as f64)
+        .sum();
+    let (mut dark, mut dark_sum) = (0.0, 0.0);
+    let (mut best, mut best_spread) = (0, -1.0);
+    for (l, &n) in hist.iter().enumerate() {
+        dark += n as f64;
+        dark_sum += l as f64 * n as f64;
+        let light = total - dark;
+        if dark == 0.0 || light == 0.0 {
+            continue;
+        }
+        let diff = dark_sum / dark - (sum - dark_sum) / light;
+        let spread = dark * light * diff * diff;
+        if spread > best_spread {
+            best = l;
+            best_spread = spread;
+        }
+    }
+    best as u8
+}
+
+/// One of the crosses in the grid.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mark {
+    /// Where it should be.
+    pub target: Point,
+    /// Where it was found, or `None` if it wasn't.
+    pub found: Option<Point>,
+}
+
+impl Mark {
+    /// How far from its target the mark was found.
+    pub fn error(&self) -> Option<Vec2> {
+        self.found.map(|p| p - self.target)
+    }
+}
+
+/// How far off each joint is, on average, around one angle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct JointError {
+    pub joint: Joint,
+    /// The angle (in degrees) that the joint was asked for, to the nearest [`JOINT_BIN`].
+    pub angle: i16,
+    /// How much further the joint turned than it was asked to, in degrees.
+    pub error: f64,
+    /// How many marks this was worked out from.
+    pub marks: usize,
+}
+
+/// How finely [`Accuracy::joint_errors`] divides up the joint angles, in degrees.
+pub const JOINT_BIN: i16 = 10;
+
+/// How accurately a grid was drawn.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Accuracy {
+    pub marks: Vec<Mark>,
+    /// How to get from pixels in the photo to brachiograph units.
+    pub photo_to_page: Affine,
+}
+
+impl Accuracy {
+    /// Finds the marks of `grid` in a photo of it.
+    ///
+    /// This fails if the photo doesn't look enough like the grid to be lined up with it.
+    pub fn measure(grid: &Grid, photo: &Photo) -> anyhow::Result<Accuracy> {
+        let found = find_marks(photo);
+        let targets = grid.points();
+        if found.len() < 3 {
+            anyhow::bail!(
+                "only found {} marks in the photo, but there should be {}",
+                found.len(),
+                targets.len()
+            );
+        }
+
+        // Start by lining up the bounding boxes, and then line up the marks themselves. Each
+        // time, the marks get matched up better. The photo is y-down, so it gets flipped first
+        // to make the rest of the lining up a rotation.
+        let flip = Affine::FLIP_Y;
+        let flipped: Vec<Point> = found.iter().map(|p| flip * *p).collect();
+        let (found_box, target_box) = (bounding_box(&flipped), bounding_box(&targets));
+        let scale = (target_box.width() + target_box.height())
+            / (found_box.width() + found_box.height()).max(1.0);
+        let mut photo_to_page = Affine::translate(target_box.center().to_vec2())
+            * Affine::scale(scale)
+            * Affine::translate(-found_box.center().to_vec2())
+            * flip;
+        for _ in 0..3 {
+            let pairs: Vec<(Point, Point)> =
+                match_marks(&targets, &found, photo_to_page, grid.spacing / 2.0)
+                    .into_iter()
+                    .filter_map(|(target, p)| Some((flip * p?, target)))
+                    .collect();
+            if pairs.len() < 3 {
+                anyhow::bail!("couldn't line the photo up with the grid");
+            }
+            photo_to_page = fit_similarity(&pairs) * flip;
+        }
+        let marks = match_marks(&targets, &found, photo_to_page, grid.spacing / 2.0)
+            .into_iter()
+            .map(|(target, p)| Mark {
+                target,
+                found: p.map(|p| photo_to_page * p),
+            })
+            .collect();
+        Ok(Accuracy {
+            marks,
+            photo_to_page,
+        })
+    }
+
+    /// The root-mean-square error of the marks that were found.
+    pub fn rms_error(&self) -> f64 {
+        let errors: Vec<f64> = self
+            .marks
+            .iter()
+            .filter_map(|m| Some(m.error()?.hypot2()))
+            .collect();
+        if errors.is_empty() {
+            0.0
+        } else {
+            (errors.iter().sum::<f64>() / errors.len() as f64).sqrt()
+        }
+    }
+
+    /// The mark that's furthest from its target.
+    pub fn worst(&self) -> Option<&Mark> {
+        self.marks
+            .iter()
+            .filter(|m| m.found.is_some())
+            .max_by(|a, b| {
+                let len = |m: &Mark| m.error().map_or(0.0, |e| e.hypot());
+                len(a).total_cmp(&len(b))
+            })
+    }
+
+    /// How far each joint is off at the angles that it was at while drawing the grid.
+    ///
+    /// A joint that's consistently off around some angle needs its calibration measured again
+    /// there. This is sorted by joint and then by angle.
+    pub fn joint_errors(&self, config: &geom::Config) -> Vec<JointError> {
+        let joints = [Joint::Shoulder, Joint::Elbow];
+        // For each joint, the total error and the number of marks at each angle.
+        let mut bins: [BTreeMap<i16, (f64, usize)>; 2] = Default::default();
+        for mark in &self.marks {
+            let Some(found) = mark.found else {
+                continue;
+            };
+            let (Ok(want), Ok(got)) = (
+                config.at_coord(mark.target.x, mark.target.y),
+                config.at_coord(found.x, found.y),
+            ) else {
+                continue;
+            };
+            let deg = |a: brachiograph::Angle| a.degrees().to_num::<f64>();
+            let angles = [(want.shoulder, got.shoulder), (want.elbow, got.elbow)];
+            for ((want, got), bins) in angles.into_iter().zip(&mut bins) {
+                let angle = (deg(want) / JOINT_BIN as f64).round() as i16 * JOINT_BIN;
+                let bin = bins.entry(angle).or_default();
+                bin.0 += deg(got) - deg(want);
+                bin.1 += 1;
+            }
+        }
+        joints
+            .into_iter()
+            .zip(bins)
+            .flat_map(|(joint, bins)| {
+                bins.into_iter()
+                    .map(move |(angle, (sum, marks))| JointError {
+                        joint,
+                        angle,
+                        error: sum / marks as f64,
+                        marks,
+                    })
+            })
+            .collect()
+    }
+
+    /// A picture of the errors, as an SVG document: each mark gets a square that goes from
+    /// green to red as its error gets bigger (or gray, if it wasn't found), with a line showing
+    /// which way it was off.
+    ///
+    /// The lines are stretched so that the worst one is half as long as the marks are apart.
+    pub fn heatmap_svg(&self, grid: &Grid) -> String {
+        let worst = self
+            .worst()
+            .and_then(Mark::error)
+            .map_or(0.0, |e| e.hypot())
+            .max(1e-9);
+        let stretch = grid.spacing / 2.0 / worst;
+        let half = grid.spacing / 2.0;
+        let view = grid.rect.inflate(half, half);
+        let mut cells = String::new();
+        let mut lines = String::new();
+        for mark in &self.marks {
+            let Point { x, y } = mark.target;
+            let fill = match mark.error() {
+                Some(e) => format!("hsl({:.0}, 80%, 50%)", 120.0 * (1.0 - e.hypot() / worst)),
+                None => "gray".to_owned(),
+            };
+            cells += &format!(
+                "    <rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{fill}\"/>\n",
+                x - half,
+                y - half,
+                grid.spacing,
+                grid.spacing
+            );
+            if let Some(e) = mark.error() {
+                let end = mark.target + e * stretch;
+                lines += &format!(
+                    "    <line x1=\"{x}\" y1=\"{y}\" x2=\"{}\" y2=\"{}\"/>\n",
+                    end.x, end.y
+                );
+            }
+        }
+        format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{} {} {} {}">
+  <g transform="scale(1 -1)">
+{cells}  </g>
+  <g transform="scale(1 -1)" stroke="black" stroke-width="{}" stroke-linecap="round">
+{lines}  </g>
+</svg>
+"#,
+            view.x0,
+            -view.y1,
+            view.width(),
+            view.height(),
+            grid.spacing / 40.0,
+        )
+    }
+}
+
+// Matches up the targets with the marks in the photo, once they've been moved onto the page.
+// Each mark goes with at most one target and each target with at most one mark, as close as
+// possible: the closest pair gets matched first, and then the closest of what's left, and so on,
+// leaving out pairs that are more than `max_dist` apart.
+fn match_marks(
+    targets: &[Point],
+    found: &[Point],
+    photo_to_page: Affine,
+    max_dist: f64,
+) -> Vec<(Point, Option<Point>)> {
+    let mut pairs: Vec<(f64, usize, usize)> = targets
+        .iter()
+        .enumerate()
+        .flat_map(|(i, &target)| {
+            found.iter().enumerate().filter_map(move |(j, p)| {
+                let dist = (photo_to_page * *p).distance(target);
+                (dist <= max_dist).then_some((dist, i, j))
+            })
+        })
+        .collect();
+    pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut matches = vec![None; targets.len()];
+    let mut used = vec![false; found.len()];
+    for (_, i, j) in pairs {
+        if matches[i].is_none() && !used[j] {
+            matches[i] = Some(found[j]);
+            used[j] = true;
+        }
+    }
+    targets.iter().copied().zip(matches).collect()
+}
+
+fn bounding_box(points: &[Point]) -> Rect {
+    points
+        .iter()
+        .fold(Rect::from_points(points[0], points[0]), |r, p| {
+            r.union_pt(*p)
+        })
+}
+
+// The combination of moving, turning and scaling that takes the first point of each pair as
+// close as possible to the second one.
+fn fit_similarity(pairs: &[(Point, Point)]) -> Affine {
+    let n = pairs.len() as f64;
+    let mean = |f: fn(&(Point, Point)) -> Point| {
+        pairs
+            .iter()
+            .fold(Vec2::ZERO, |sum, pair| sum + f(pair).to_vec2())
+            / n
+    };
+    let (from_mean, to_mean) = (mean(|(p, _)| *p), mean(|(_, q)| *q));
+    let (mut cos, mut sin, mut norm) = (0.0, 0.0, 0.0);
+    for (p, q) in pairs {
+        let (p, q) = (p.to_vec2() - from_mean, q.to_vec2() - to_mean);
+        cos += p.dot(q);
+        sin += p.cross(q);
+        norm += p.hypot2();
+    }
+    let (c, s) = (cos / norm.max(1e-12), sin / norm.max(1e-12));
+    let turn = Affine::new([c, s, -s, c, 0.0, 0.0]);
+    Affine::translate(to_mean) * turn * Affine::translate(-from_mean)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Draws the crosses of `grid` into a photo, as a camera at `page_to_photo` would see them.
+    // Each cross gets moved by `error` (which is in brachiograph units) first.
+    fn photograph(grid: &Grid, page_to_photo: Affine, error: impl Fn(Point) -> Vec2) -> Photo {
+        let (width, height) = (400, 300);
+        let mut luma = vec![230; width * height];
+        let photo_to_page = page_to_photo.inverse();
+        for (i, px) in luma.iter_mut().enumerate() {
+            let p = photo_to_page * Point::new((i % width) as f64 + 0.5, (i / width) as f64 + 0.5);
+            let on_cross = grid.points().iter().any(|&c| {
+                let d = p - (c + error(c));
+                (d.x.abs() <= grid.mark_size && d.y.abs() <= 0.04)
+                    || (d.y.abs() <= grid.mark_size && d.x.abs() <= 0.04)
+            });
+            if on_cross {
+                *px = 20;
+            }
+        }
+        Photo {
+            width,
+            height,
+            luma,
+        }
+    }
+
+    // A camera looking down at the grid, a bit skewed and turned.
+    fn camera() -> Affine {
+        Affine::translate((200.0, 150.0))
+            * Affine::rotate(0.05)
+            * Affine::scale(25.0)
+            * Affine::FLIP_Y
+            * Affine::translate((0.0, -9.0))
+    }
+
+    #[test]
+    fn grid() {
+        let grid = Grid::default();
+        let points = grid.points();
+        assert_eq!(points.len(), 7 * 4);
+        assert_eq!(points[0], Point::new(-6.0, 6.0));
+        assert_eq!(points[27], Point::new(6.0, 12.0));
+        assert_eq!(
+            grid.ops().iter().filter(|op| **op == Op::PenDown).count(),
+            2 * 28
+        );
+    }
+
+    #[test]
+    fn finds_marks() {
+        let grid = Grid::default();
+        let photo = photograph(&grid, camera(), |_| Vec2::ZERO);
+        let marks = find_marks(&photo);
+        assert_eq!(marks.len(), 28);
+        for target in grid.points() {
+            let expected = camera() * target;
+            assert!(
+                marks.iter().any(|m| m.distance(expected) < 0.5),
+                "nothing near {expected:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn accurate_drawing() {
+        let grid = Grid::default();
+        let photo = photograph(&grid, camera(), |_| Vec2::ZERO);
+        let accuracy = Accuracy::measure(&grid, &photo).unwrap();
+        assert!(accuracy.marks.iter().all(|m| m.found.is_some()));
+        assert!(accuracy.rms_error() < 0.02, "{}", accuracy.rms_error());
+    }
+
+    #[test]
+    fn inaccurate_drawing() {
+        let grid = Grid::default();
+        // Everything on the right is drawn too high.
+        let error = |p: Point| Vec2::new(0.0, if p.x > 3.0 { 0.2 } else { 0.0 });
+        let photo = photograph(&grid, camera(), error);
+        let accuracy = Accuracy::measure(&grid, &photo).unwrap();
+        let worst = accuracy.worst().unwrap();
+        assert!(worst.target.x > 3.0, "{worst:?}");
+        // Lining up the photo hides some of the error, but not most of it.
+        assert!(worst.error().unwrap().y > 0.05, "{worst:?}");
+        assert!(accuracy.rms_error() > 0.05);
+
+        let errors = accuracy.joint_errors(&geom::Config::default());
+        assert!(!errors.is_empty());
+        assert!(errors.iter().any(|e| e.error.abs() > 0.5), "{errors:?}");
+
+        let svg = accuracy.heatmap_svg(&grid);
+        assert_eq!(svg.matches("<rect").count(), 28);
+        assert_eq!(svg.matches("<line").count(), 28);
+    }
+
+    #[test]
+    fn marks_match_once() {
+        let targets = [Point::new(0.0, 0.0), Point::new(1.0, 0.0)];
+        // Both marks are nearest to the first target, but the second one is still close enough
+        // to the second target.
+        let found = [Point::new(0.1, 0.0), Point::new(0.4, 0.0)];
+        let matches = match_marks(&targets, &found, Affine::IDENTITY, 0.7);
+        assert_eq!(
+            matches,
+            vec![(targets[0], Some(found[0])), (targets[1], Some(found[1]))]
+        );
+        let matches = match_marks(&targets, &found[..1], Affine::IDENTITY, 1.0);
+        assert_eq!(
+            matches,
+            vec![(targets[0], Some(found[0])), (targets[1], None)]
+        );
+    }
+
+    #[test]
+    fn blank_photo() {
+        let photo = Photo {
+            width: 10,
+            height: 10,
+            luma: vec![255; 100],
+        };
+        assert!(find_marks(&photo).is_empty());
+        assert!(Accuracy::measure(&Grid::default(), &photo).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//! Talking to a brachiograph from async code.
+//!
+//! [`Serial`](crate::Serial) blocks while it waits for the brachiograph (and sleeps while the
+//! brachiograph's queue is full), which is no good on a UI thread. [`AsyncSerial`] hands the
+//! port to a tokio task instead, and every op that you send gets back a [`Response`] to await.
+
+use anyhow::{anyhow, bail};
+use brachiograph::{Op, Reply, Resp};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::{mpsc, oneshot},
+};
+
+use crate::serial::{brachiograph_ports, QUEUE_POLL_INTERVAL};
+
+/// The error for ops that were thrown away by [`AsyncSerial::cancel`] before they were sent.
+///
+/// You can tell these apart from other errors with [`anyhow::Error::downcast_ref`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the op was cancelled before it was sent")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+struct Command {
+    op: Op,
+    // How many times `cancel` had been called when this was sent. If it's been called since,
+    // this gets thrown away.
+    epoch: u64,
+    reply: oneshot::Sender<anyhow::Result<Resp>>,
+}
+
+/// The brachiograph's answer to an op sent with [`AsyncSerial::send`].
+///
+/// The op gets sent whether or not anyone is waiting for this, except that if it's dropped
+/// before the op's turn comes then the op is skipped.
+pub struct Response(oneshot::Receiver<anyhow::Result<Resp>>);
+
+impl Future for Response {
+    type Output = anyhow::Result<Resp>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx).map(|result| {
+            result.unwrap_or_else(|_| Err(anyhow!("the connection to the brachiograph was closed")))
+        })
+    }
+}
+
+/// A connection to a brachiograph, for async code.
+///
+/// The connection is looked after by a tokio task, so this has to be created inside a tokio
+/// runtime. Ops are sent in the order that [`AsyncSerial::send`] was called, one at a time; if
+/// the brachiograph's queue is full then the task waits for room, without holding up anything
+/// else. It only speaks the postcard protocol.
+///
+/// Clones all talk to the same brachiograph, and the connection closes once they're all gone.
+#[derive(Clone)]
+pub struct AsyncSerial {
+    commands: mpsc::UnboundedSender<Command>,
+    epoch: Arc<AtomicU64>,
+}
+
+impl AsyncSerial {
+    /// Talks to a brachiograph over anything that reads and writes, like a serial port (or one
+    /// end of a [`tokio::io::duplex`], in tests).
+    pub fn from_stream<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (commands, rx) = mpsc::unbounded_channel();
+        let epoch = Arc::new(AtomicU64::new(0));
+        tokio::spawn(run(stream, rx, Arc::clone(&epoch)));
+        AsyncSerial { commands, epoch }
+    }
+
+    /// Opens a brachiograph on a specific serial port, like `/dev/ttyACM0`.
+    pub fn open(name: &str) -> anyhow::Result<Self> {
+        let port = tokio_serial::SerialStream::open(&tokio_serial::new(name, 9600))?;
+        Ok(AsyncSerial::from_stream(port))
+    }
+
+    /// Looks for a connected brachiograph.
+    pub fn detect() -> Option<Self> {
+        for name in brachiograph_ports() {
+            match AsyncSerial::open(&name) {
+                Ok(serial) => return Some(serial),
+                Err(e) => log::warn!("failed to open port '{}': {}", name, e),
+            }
+        }
+        None
+    }
+
+    /// Sends an op to the brachiograph, once everything sent before it has been answered.
+    ///
+    /// Like [`Serial::send`](crate::Serial::send), this waits (and retries) while the
+    /// brachiograph's queue is full, and if the brachiograph ran out of ops in the middle of a
+    /// stroke then it puts the pen back down before sending this one again.
+    pub fn send(&self, op: Op) -> Response {
+        self.send_at(op, self.epoch.load(Ordering::SeqCst))
+    }
+
+    /// Throws away every op that hasn't been sent yet (including one that's waiting for room in
+    /// the brachiograph's queue), and then sends [`Op::Cancel`] so that the brachiograph throws
+    /// away its queue too.
+    ///
+    /// The ops that were thrown away fail with [`Cancelled`].
+    pub fn cancel(&self) -> Response {
+        let epoch = self.epoch.fetch_add(1, Ordering::SeqCst) + 1;
+        self.send_at(Op::Cancel, epoch)
+    }
+
+    fn send_at(&self, op: Op, epoch: u64) -> Response {
+        let (reply, rx) = oneshot::channel();
+        // If the task has finished then `reply` gets dropped, and the response says so.
+        let _ = self.commands.send(Command { op, epoch, reply });
+        Response(rx)
+    }
+}
+
+// Looks after the connection until every `AsyncSerial` has been dropped.
+async fn run<S>(stream: S, mut commands: mpsc::UnboundedReceiver<Command>, epoch: Arc<AtomicU64>)
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (read, write) = tokio::io::split(stream);
+    let mut conn = Connection {
+        read: BufReader::new(read),
+        write,
+        epoch,
+    };
+    while let Some(cmd) = commands.recv().await {
+        if cmd.reply.is_closed() {
+            continue;
+        }
+        let result = conn.send(&cmd.op, cmd.epoch).await;
+        let _ = cmd.reply.send(result);
+    }
+}
+
+struct Connection<R, W> {
+    read: BufReader<R>,
+    write: W,
+    epoch: Arc<AtomicU64>,
+}
+
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> Connection<R, W> {
+    fn check_cancelled(&self, epoch: u64) -> anyhow::Result<()> {
+        if epoch < self.epoch.load(Ordering::SeqCst) {
+            Err(Cancelled.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Sends an op, waiting if the queue is full.
+    async fn send(&mut self, op: &Op, epoch: u64) -> anyhow::Result<Resp> {
+        log::debug!("sending {op:?}");
+        loop {
+            self.check_cancelled(epoch)?;
+            match self.send_postcard(op).await? {
+                Resp::QueueFull => tokio::time::sleep(QUEUE_POLL_INTERVAL).await,
+                Resp::Starved { at } => {
+                    log::warn!("the brachiograph ran out of ops, and lifted the pen at {at:?}");
+                    self.expect_ack(&Op::Resume).await?;
+                    if *op != Op::PenUp {
+                        self.expect_ack(&Op::PenDown).await?;
+                    }
+                }
+                other => return Ok(other),
+            }
+        }
+    }
+
+    async fn expect_ack(&mut self, op: &Op) -> anyhow::Result<()> {
+        match self.send_postcard(op).await? {
+            Resp::Ack => Ok(()),
+            resp => bail!("Unexpected response to {op:?}: {resp:?}"),
+        }
+    }
+
+    async fn send_postcard(&mut self, op: &Op) -> anyhow::Result<Resp> {
+        let msg = postcard::to_stdvec_cobs(op)?;
+        self.write.write_all(&msg).await?;
+        self.write.flush().await?;
+
+        let mut buf = Vec::new();
+        if self.read.read_until(0, &mut buf).await? == 0 {
+            bail!("the brachiograph hung up");
+        }
+        let reply: Reply = postcard::from_bytes_cobs(&mut buf)?;
+        Ok(reply.resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use brachiograph::QueueStatus;
+    use std::sync::Mutex;
+    use tokio::io::DuplexStream;
+
+    // Pretends to be a brachiograph on the other end of `stream`, answering each op with
+    // `answer`. Returns all the ops it got.
+    async fn device(
+        stream: DuplexStream,
+        mut answer: impl FnMut(&Op) -> Resp,
+        log: Arc<Mutex<Vec<Op>>>,
+    ) {
+        let (read, mut write) = tokio::io::split(stream);
+        let mut read = BufReader::new(read);
+        loop {
+            let mut buf = Vec::new();
+            if read.read_until(0, &mut buf).await.unwrap() == 0 {
+                return;
+            }
+            let op: Op = postcard::from_bytes_cobs(&mut buf).unwrap();
+            let resp = answer(&op);
+            log.lock().unwrap().push(op);
+            let reply = Reply {
+                resp,
+                queue: QueueStatus::default(),
+            };
+            let msg = postcard::to_stdvec_cobs(&reply).unwrap();
+            write.write_all(&msg).await.unwrap();
+        }
+    }
+
+    fn connect(
+        answer: impl FnMut(&Op) -> Resp + Send + 'static,
+    ) -> (AsyncSerial, Arc<Mutex<Vec<Op>>>) {
+        let (ours, theirs) = tokio::io::duplex(256);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        tokio::spawn(device(theirs, answer, Arc::clone(&log)));
+        (AsyncSerial::from_stream(ours), log)
+    }
+
+    #[tokio::test]
+    async fn sends_in_order() {
+        let (serial, log) = connect(|op| match op {
+            Op::GetQueue => Resp::Queue(QueueStatus::default()),
+            _ => Resp::Ack,
+        });
+        let pen = serial.send(Op::PenDown);
+        let queue = serial.send(Op::GetQueue);
+        // Nobody wants this answer, so it doesn't get sent.
+        drop(serial.send(Op::Home));
+        assert!(matches!(queue.await.unwrap(), Resp::Queue(_)));
+        assert!(matches!(pen.await.unwrap(), Resp::Ack));
+        assert_eq!(*log.lock().unwrap(), [Op::PenDown, Op::GetQueue]);
+    }
+
+    #[tokio::test]
+    async fn waits_for_room() {
+        let mut full = 3;
+        let (serial, log) = connect(move |_| {
+            if full > 0 {
+                full -= 1;
+                Resp::QueueFull
+            } else {
+                Resp::Ack
+            }
+        });
+        assert!(matches!(serial.send(Op::PenUp).await.unwrap(), Resp::Ack));
+        assert_eq!(log.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn recovers_from_starvation() {
+        let mut starved = true;
+        let (serial, log) = connect(move |op| match op {
+            Op::Home if starved => {
+                starved = false;
+                Resp::Starved {
+                    at: brachiograph::Point {
+                        x: brachiograph::Fixed::from_num(0),
+                        y: brachiograph::Fixed::from_num(8),
+                    },
+                }
+            }
+            _ => Resp::Ack,
+        });
+        assert!(matches!(serial.send(Op::Home).await.unwrap(), Resp::Ack));
+        assert_eq!(
+            *log.lock().unwrap(),
+            [Op::Home, Op::Resume, Op::PenDown, Op::Home]
+        );
+    }
+
+    #[tokio::test]
+    async fn cancel() {
+        // The queue stays full until it's cancelled.
+        let (serial, log) = connect(|op| match op {
+            Op::Cancel => Resp::Ack,
+            _ => Resp::QueueFull,
+        });
+        let stuck = serial.send(Op::PenDown);
+        let queued = serial.send(Op::PenUp);
+        // Give the first op time to get stuck.
+        tokio::time::sleep(QUEUE_POLL_INTERVAL * 3).await;
+        let cancel = serial.cancel();
+
+        let err = stuck.await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Cancelled>(), Some(&Cancelled));
+        let err = queued.await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Cancelled>(), Some(&Cancelled));
+        assert!(matches!(cancel.await.unwrap(), Resp::Ack));
+
+        let log = log.lock().unwrap();
+        assert!(!log.contains(&Op::PenUp));
+        assert_eq!(log.last(), Some(&Op::Cancel));
+    }
+
+    #[tokio::test]
+    async fn hang_up() {
+        let (ours, theirs) = tokio::io::duplex(256);
+        drop(theirs);
+        let serial = AsyncSerial::from_stream(ours);
+        assert!(serial.send(Op::PenUp).await.is_err());
+    }
+}
@@ -0,0 +1,938 @@
+//! Turning measurements of the arm servos into calibration tables.
+//!
+//! Calibrating means turning each joint to the ticks on the calibration template (see the
+//! `template` crate) and recording the duty that got it there, once while the angle is
+//! increasing and once while it's decreasing. Ticks that the arm can't reach can be skipped;
+//! their duties get filled in from the neighboring ones.
+//!
+//! The firmware draws straight lines between the entries of a table, so the tables need more
+//! entries where the servo's response curves (see [`ArmCalibration::analyze`]).
+//!
+//! Calibrations from the original (Python) BrachioGraph can be read and written too, so that
+//! they don't need to be measured all over again (see [`ArmCalibration::from_python_json`]).
+//!
+//! The pen servo only has two positions, and they depend on the pen, so there's a set of them
+//! for each pen (see [`PenDuties`]).
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Write,
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, ensure, Context};
+use brachiograph::{pwm, Direction, Joint, Op, ServoCalibration};
+use serde::{Deserialize, Serialize};
+
+/// The calibration tables for the arm servos, and which way around the servos are mounted.
+///
+/// Each table is a list of (degrees, duty) pairs, sorted by angle. An empty table leaves the
+/// one in the firmware alone. This is also the format of the calibration files that get saved
+/// with postcard.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArmCalibration {
+    pub shoulder_inc: Vec<(i16, u16)>,
+    pub shoulder_dec: Vec<(i16, u16)>,
+    pub elbow_inc: Vec<(i16, u16)>,
+    pub elbow_dec: Vec<(i16, u16)>,
+    /// Whether the shoulder servo is mounted the other way around (see
+    /// [`pwm::Pwm::inverted`]).
+    pub shoulder_inverted: bool,
+    /// Whether the elbow servo is mounted the other way around.
+    pub elbow_inverted: bool,
+    /// How often the servos get a pulse and get moved, if it isn't the firmware's usual (see
+    /// [`pwm::Timing`]).
+    pub timing: Option<pwm::Timing>,
+    /// How the pen servo eases between up and down, if it should (see [`pwm::PenRamp`]).
+    pub pen_ramp: Option<pwm::PenRamp>,
+    /// The pen servo's duties for pointing it at an angle (see [`Op::SetPenAngle`]), as
+    /// (degrees, duty) pairs sorted by angle. Like the arm tables, this is left alone if it's
+    /// empty.
+    pub pen_angles: Vec<(i16, u16)>,
+}
+
+// Calibration files from before they said which way around the servos are, which was only ever
+// the usual way.
+#[derive(Deserialize)]
+struct TablesOnly {
+    shoulder_inc: Vec<(i16, u16)>,
+    shoulder_dec: Vec<(i16, u16)>,
+    elbow_inc: Vec<(i16, u16)>,
+    elbow_dec: Vec<(i16, u16)>,
+}
+
+impl From<TablesOnly> for ArmCalibration {
+    fn from(old: TablesOnly) -> Self {
+        ArmCalibration {
+            shoulder_inc: old.shoulder_inc,
+            shoulder_dec: old.shoulder_dec,
+            elbow_inc: old.elbow_inc,
+            elbow_dec: old.elbow_dec,
+            ..ArmCalibration::default()
+        }
+    }
+}
+
+// The tables in the order that they get checked and written out, with their names.
+const TABLES: [(Joint, Direction, &str); 4] = [
+    (Joint::Shoulder, Direction::Increasing, "SHOULDER_INC"),
+    (Joint::Shoulder, Direction::Decreasing, "SHOULDER_DEC"),
+    (Joint::Elbow, Direction::Increasing, "ELBOW_INC"),
+    (Joint::Elbow, Direction::Decreasing, "ELBOW_DEC"),
+];
+
+impl ArmCalibration {
+    /// Reads a calibration that was saved by `feeder calibrate`, or (if the file name ends in
+    /// `.json`) one from the Python BrachioGraph.
+    pub fn load(path: &Path) -> anyhow::Result<ArmCalibration> {
+        if path.extension().is_some_and(|ext| ext == "json") {
+            let json = std::fs::read_to_string(path)?;
+            return ArmCalibration::from_python_json(&json)
+                .with_context(|| format!("couldn't read {}", path.display()));
+        }
+        let data = std::fs::read(path)?;
+        ArmCalibration::from_bytes(&data)
+            .with_context(|| format!("couldn't read {}", path.display()))
+    }
+
+    /// Like [`ArmCalibration::load`], but an empty calibration (which leaves the firmware's
+    /// alone) if there's no file there yet.
+    pub fn load_or_default(path: &Path) -> anyhow::Result<ArmCalibration> {
+        if path.exists() {
+            ArmCalibration::load(path)
+        } else {
+            Ok(ArmCalibration::default())
+        }
+    }
+
+    /// Reads a calibration in the postcard format of the files that `feeder calibrate` saves,
+    /// including ones from older versions.
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<ArmCalibration> {
+        match postcard::from_bytes(data) {
+            Ok(calib) => Ok(calib),
+            Err(e) => match postcard::from_bytes::<TablesOnly>(data) {
+                Ok(old) => Ok(old.into()),
+                Err(_) => Err(e.into()),
+            },
+        }
+    }
+
+    /// Reads the pulse widths from the JSON settings of the Python BrachioGraph: the
+    /// `servo_1_angle_pws_bidi` table for the shoulder and `servo_2_angle_pws_bidi` for the
+    /// elbow, which give the pulse widths for each angle when the servo is turning clockwise
+    /// (`"cw"`) and anticlockwise (`"acw"`). The one-way `servo_1_angle_pws` and
+    /// `servo_2_angle_pws` tables (lists of `[angle, pulse width]`) are accepted too, and get
+    /// used for both directions. Everything else in the settings is ignored.
+    ///
+    /// The Python BrachioGraph measures the shoulder clockwise from straight ahead, and the
+    /// elbow from a straight arm, so the angles get converted: its shoulder at -90 degrees and
+    /// elbow at 90 degrees (the usual parking spot, with the pen at `(-8, 8)`) are 0 degrees
+    /// here.
+    pub fn from_python_json(json: &str) -> anyhow::Result<ArmCalibration> {
+        let py: PythonCalibration = serde_json::from_str(json)?;
+        let mut calib = ArmCalibration::default();
+        calib.read_python_tables(
+            Joint::Shoulder,
+            py.servo_1_angle_pws_bidi,
+            py.servo_1_angle_pws,
+        )?;
+        calib.read_python_tables(
+            Joint::Elbow,
+            py.servo_2_angle_pws_bidi,
+            py.servo_2_angle_pws,
+        )?;
+        Ok(calib)
+    }
+
+    /// Writes the tables in the JSON format of the Python BrachioGraph (see
+    /// [`ArmCalibration::from_python_json`]).
+    ///
+    /// That format has both pulse widths at every angle, so any angles that are only in one
+    /// direction's table get filled in (see [`fill_gaps`]) for the other direction.
+    pub fn to_python_json(&self) -> anyhow::Result<String> {
+        let mut ret = String::from("{\n");
+        for (joint, name) in [(Joint::Shoulder, "servo_1"), (Joint::Elbow, "servo_2")] {
+            let inc = self.table(joint, Direction::Increasing);
+            let dec = self.table(joint, Direction::Decreasing);
+            let angles: BTreeSet<i16> = inc.iter().chain(dec).map(|&(deg, _)| deg).collect();
+            let fill = |table: &[(i16, u16)]| {
+                let measured: Vec<_> = angles
+                    .iter()
+                    .map(|&deg| (deg, table.iter().find(|e| e.0 == deg).map(|e| e.1)))
+                    .collect();
+                fill_gaps(&measured)
+            };
+            let (inc, dec) = (fill(inc)?, fill(dec)?);
+            let (cw, acw) = if duty_sign(&inc) == std::cmp::Ordering::Greater {
+                (dec, inc)
+            } else {
+                (inc, dec)
+            };
+            let mut entries: Vec<(i16, u16, u16)> = cw
+                .iter()
+                .zip(&acw)
+                .map(|(&(deg, cw), &(_, acw))| (to_python_angle(joint, deg), cw, acw))
+                .collect();
+            entries.sort_by_key(|&(deg, _, _)| deg);
+
+            if joint == Joint::Elbow {
+                ret.push_str(",\n");
+            }
+            writeln!(ret, "  \"{name}_angle_pws_bidi\": {{").unwrap();
+            for (i, (deg, cw, acw)) in entries.iter().enumerate() {
+                let comma = if i + 1 < entries.len() { "," } else { "" };
+                writeln!(
+                    ret,
+                    "    \"{deg}\": {{\"cw\": {cw}, \"acw\": {acw}}}{comma}"
+                )
+                .unwrap();
+            }
+            ret.push_str("  }");
+        }
+        ret.push_str("\n}\n");
+        Ok(ret)
+    }
+
+    /// The ops that tell a brachiograph to use this calibration instead of the one that was
+    /// built into its firmware (see
+    /// [`Serial::upload_calibration`](crate::Serial::upload_calibration)).
+    pub fn ops(&self) -> anyhow::Result<Vec<Op>> {
+        let mut ops = TABLES
+            .iter()
+            .filter(|&&(joint, dir, _)| !self.table(joint, dir).is_empty())
+            .map(|&(joint, dir, name)| {
+                let table = self.table(joint, dir);
+                let data = table.try_into().map_err(|_| {
+                    anyhow!(
+                        "{}: there are {} entries, but the brachiograph only has room for 16",
+                        table_name(name),
+                        table.len()
+                    )
+                })?;
+                Ok(Op::Calibrate(joint, dir, ServoCalibration { data }))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        ops.push(Op::SetInverted(Joint::Shoulder, self.shoulder_inverted));
+        ops.push(Op::SetInverted(Joint::Elbow, self.elbow_inverted));
+        if let Some(timing) = self.timing {
+            if !timing.is_valid() {
+                bail!("the servo timing {timing:?} isn't one that the brachiograph supports");
+            }
+            ops.push(Op::SetTiming(timing));
+        }
+        if let Some(ramp) = self.pen_ramp {
+            if !ramp.is_valid() {
+                bail!("the pen ramp must be between 0 and 100 percent");
+            }
+            ops.push(Op::SetPenRamp(ramp));
+        }
+        if !self.pen_angles.is_empty() {
+            let table = ServoCalibration {
+                data: self.pen_angles.as_slice().try_into().map_err(|_| {
+                    anyhow!(
+                        "pen angles: there are {} entries, but the brachiograph only has room \
+                         for 16",
+                        self.pen_angles.len()
+                    )
+                })?,
+            };
+            if !table.is_valid() {
+                bail!("pen angles: the angles need to be increasing, with duties in range");
+            }
+            ops.push(Op::CalibratePenAngles(table));
+        }
+        Ok(ops)
+    }
+
+    /// The table for `joint` turning in the direction `dir`.
+    pub fn table(&self, joint: Joint, dir: Direction) -> &[(i16, u16)] {
+        match (joint, dir) {
+            (Joint::Shoulder, Direction::Increasing) => &self.shoulder_inc,
+            (Joint::Shoulder, Direction::Decreasing) => &self.shoulder_dec,
+            (Joint::Elbow, Direction::Increasing) => &self.elbow_inc,
+            (Joint::Elbow, Direction::Decreasing) => &self.elbow_dec,
+        }
+    }
+
+    pub fn table_mut(&mut self, joint: Joint, dir: Direction) -> &mut Vec<(i16, u16)> {
+        match (joint, dir) {
+            (Joint::Shoulder, Direction::Increasing) => &mut self.shoulder_inc,
+            (Joint::Shoulder, Direction::Decreasing) => &mut self.shoulder_dec,
+            (Joint::Elbow, Direction::Increasing) => &mut self.elbow_inc,
+            (Joint::Elbow, Direction::Decreasing) => &mut self.elbow_dec,
+        }
+    }
+
+    /// Checks that the tables make sense: there are at least two entries in each, the angles
+    /// are increasing, the duties are ones that a servo understands, and turning a joint
+    /// always moves its duty in the same direction.
+    ///
+    /// A table that goes back and forth usually means that a tick got recorded at the wrong
+    /// place.
+    pub fn check(&self) -> anyhow::Result<()> {
+        for (joint, dir, name) in TABLES {
+            check_table(self.table(joint, dir))
+                .map_err(|e| anyhow!("{}: {e}", table_name(name)))?;
+        }
+        for (joint, name) in [(Joint::Shoulder, "shoulder"), (Joint::Elbow, "elbow")] {
+            let inc = duty_sign(self.table(joint, Direction::Increasing));
+            let dec = duty_sign(self.table(joint, Direction::Decreasing));
+            if inc != dec {
+                bail!("the {name} tables disagree about which way the servo turns");
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the tables as Rust source, for building them into the firmware.
+    pub fn to_rust(&self) -> String {
+        let mut ret = String::from(
+            "// Written by `feeder calibrate`. Each entry is (degrees, duty in microseconds).\n",
+        );
+        for (joint, dir, name) in TABLES {
+            ret.push('\n');
+            writeln!(ret, "pub const {name}: &[(i16, u16)] = &[").unwrap();
+            for (deg, duty) in self.table(joint, dir) {
+                writeln!(ret, "    ({deg}, {duty}),").unwrap();
+            }
+            ret.push_str("];\n");
+        }
+        ret.push('\n');
+        writeln!(
+            ret,
+            "pub const SHOULDER_INVERTED: bool = {};",
+            self.shoulder_inverted
+        )
+        .unwrap();
+        writeln!(
+            ret,
+            "pub const ELBOW_INVERTED: bool = {};",
+            self.elbow_inverted
+        )
+        .unwrap();
+        ret
+    }
+
+    // Fills in `joint`'s tables from one of the Python BrachioGraph's tables.
+    fn read_python_tables(
+        &mut self,
+        joint: Joint,
+        bidi: Option<HashMap<String, PythonPulseWidths>>,
+        one_way: Option<Vec<(f64, f64)>>,
+    ) -> anyhow::Result<()> {
+        let name = match joint {
+            Joint::Shoulder => "servo_1",
+            Joint::Elbow => "servo_2",
+        };
+        // (angle, clockwise pulse width, anticlockwise pulse width)
+        let mut entries: Vec<(i16, f64, f64)> = match (bidi, one_way) {
+            (Some(bidi), _) => {
+                bidi.into_iter()
+                    .map(|(deg, pws)| {
+                        let deg: f64 = deg.trim().parse().map_err(|_| {
+                            anyhow!("{name}_angle_pws_bidi: \"{deg}\" isn't an angle")
+                        })?;
+                        Ok((from_python_angle(joint, deg), pws.cw, pws.acw))
+                    })
+                    .collect::<anyhow::Result<_>>()?
+            }
+            (None, Some(pws)) => pws
+                .into_iter()
+                .map(|(deg, pw)| (from_python_angle(joint, deg), pw, pw))
+                .collect(),
+            (None, None) => bail!("there's no {name}_angle_pws_bidi table"),
+        };
+        entries.sort_by_key(|&(deg, _, _)| deg);
+
+        let duty = |pw: f64| pw.round().clamp(0.0, f64::from(u16::MAX)) as u16;
+        let cw: Vec<_> = entries
+            .iter()
+            .map(|&(deg, cw, _)| (deg, duty(cw)))
+            .collect();
+        let acw: Vec<_> = entries
+            .iter()
+            .map(|&(deg, _, acw)| (deg, duty(acw)))
+            .collect();
+        // The Python BrachioGraph uses the anticlockwise pulse widths while the pulse width is going
+        // up, and the clockwise ones while it's going down.
+        let (inc, dec) = if duty_sign(&cw) == std::cmp::Ordering::Greater {
+            (acw, cw)
+        } else {
+            (cw, acw)
+        };
+        *self.table_mut(joint, Direction::Increasing) = inc;
+        *self.table_mut(joint, Direction::Decreasing) = dec;
+        Ok(())
+    }
+
+    /// Says how well each table describes its servo.
+    ///
+    /// If `dense` is given, it should be a re-measurement of the same servos at more angles;
+    /// every entry in it gets compared with what the tables say. Stretches of the tables where
+    /// the duty curves so much that interpolating across them could be off by more than
+    /// `max_error` degrees get pointed out, because that's where more entries would help.
+    pub fn analyze(&self, dense: Option<&ArmCalibration>, max_error: f64) -> Vec<TableReport> {
+        TABLES
+            .iter()
+            .map(|&(joint, dir, name)| {
+                let table = self.table(joint, dir);
+                TableReport {
+                    name: table_name(name),
+                    worst: dense.and_then(|d| worst_error(table, d.table(joint, dir))),
+                    coarse: coarse_spans(table)
+                        .into_iter()
+                        .filter(|span| span.error > max_error)
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// How well a calibration table describes its servo (see [`ArmCalibration::analyze`]).
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableReport {
+    /// Which table this is, like "shoulder inc".
+    pub name: String,
+    /// The worst difference between the table and the dense re-measurement: the angle where it
+    /// happened, and how many degrees off the table was.
+    pub worst: Option<(i16, f64)>,
+    /// The stretches of the table that are probably too long.
+    pub coarse: Vec<CoarseSpan>,
+}
+
+/// A stretch between two neighboring entries of a calibration table.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CoarseSpan {
+    pub from: i16,
+    pub to: i16,
+    /// Roughly how far off (in degrees) interpolating between the entries could be.
+    pub error: f64,
+}
+
+// The angle that the table turns `duty` into, if the duty is in the table's range.
+fn table_angle(table: &[(i16, u16)], duty: u16) -> Option<f64> {
+    table.windows(2).find_map(|w| {
+        let ((deg0, duty0), (deg1, duty1)) = (w[0], w[1]);
+        if duty0 == duty1 || duty < duty0.min(duty1) || duty > duty0.max(duty1) {
+            return None;
+        }
+        let t = (f64::from(duty) - f64::from(duty0)) / (f64::from(duty1) - f64::from(duty0));
+        Some(f64::from(deg0) + t * f64::from(deg1 - deg0))
+    })
+}
+
+// Compares the table with a denser measurement, returning the angle where they disagree the
+// most and by how many degrees.
+fn worst_error(table: &[(i16, u16)], dense: &[(i16, u16)]) -> Option<(i16, f64)> {
+    dense
+        .iter()
+        .filter_map(|&(deg, duty)| Some((deg, (table_angle(table, duty)? - f64::from(deg)).abs())))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+// Estimates how far off interpolating across each stretch of the table could be.
+//
+// Where the slope changes from one stretch to the next, the duty is curving, and a straight
+// line between two entries a distance `h` apart misses the curve by about `curvature * h^2 / 8`.
+// That gets turned into degrees using the stretch's slope.
+fn coarse_spans(table: &[(i16, u16)]) -> Vec<CoarseSpan> {
+    let slope = |w: &[(i16, u16)]| {
+        (f64::from(w[1].1) - f64::from(w[0].1)) / f64::from(w[1].0 - w[0].0).max(1.0)
+    };
+    let slopes: Vec<f64> = table.windows(2).map(slope).collect();
+    // The curvature at each entry in the middle of the table.
+    let curvature: Vec<f64> = table
+        .windows(3)
+        .zip(slopes.windows(2))
+        .map(|(t, s)| 2.0 * (s[1] - s[0]).abs() / f64::from(t[2].0 - t[0].0).max(1.0))
+        .collect();
+    (0..slopes.len())
+        .map(|i| {
+            let before = i.checked_sub(1).and_then(|j| curvature.get(j));
+            let after = curvature.get(i);
+            let curvature = before
+                .into_iter()
+                .chain(after)
+                .fold(0.0f64, |a, &b| a.max(b));
+            let h = f64::from(table[i + 1].0 - table[i].0);
+            CoarseSpan {
+                from: table[i].0,
+                to: table[i + 1].0,
+                error: curvature * h * h / 8.0 / slopes[i].abs().max(f64::EPSILON),
+            }
+        })
+        .collect()
+}
+
+// The parts of the Python BrachioGraph's settings that hold the arm calibration.
+#[derive(Deserialize)]
+struct PythonCalibration {
+    servo_1_angle_pws_bidi: Option<HashMap<String, PythonPulseWidths>>,
+    servo_2_angle_pws_bidi: Option<HashMap<String, PythonPulseWidths>>,
+    servo_1_angle_pws: Option<Vec<(f64, f64)>>,
+    servo_2_angle_pws: Option<Vec<(f64, f64)>>,
+}
+
+#[derive(Deserialize)]
+struct PythonPulseWidths {
+    cw: f64,
+    acw: f64,
+}
+
+// Converts an angle from the Python BrachioGraph's convention to ours.
+fn from_python_angle(joint: Joint, deg: f64) -> i16 {
+    let deg = match joint {
+        Joint::Shoulder => deg + 90.0,
+        Joint::Elbow => 90.0 - deg,
+    };
+    deg.round() as i16
+}
+
+fn to_python_angle(joint: Joint, deg: i16) -> i16 {
+    match joint {
+        Joint::Shoulder => deg - 90,
+        Joint::Elbow => 90 - deg,
+    }
+}
+
+// "SHOULDER_INC" -> "shoulder inc"
+fn table_name(name: &str) -> String {
+    name.to_lowercase().replace('_', " ")
+}
+
+// Which way the duty goes as the angle increases.
+fn duty_sign(table: &[(i16, u16)]) -> std::cmp::Ordering {
+    match table {
+        [first, .., last] => last.1.cmp(&first.1),
+        _ => std::cmp::Ordering::Equal,
+    }
+}
+
+fn check_table(table: &[(i16, u16)]) -> anyhow::Result<()> {
+    if table.len() < 2 {
+        bail!("there need to be at least two entries");
+    }
+    if let Some(&(deg, duty)) = table.iter().find(|(_, duty)| !pwm::duty_is_valid(*duty)) {
+        bail!("the duty {duty} at {deg} degrees is out of range");
+    }
+    let sign = duty_sign(table);
+    if sign == std::cmp::Ordering::Equal {
+        bail!("the duty is the same at both ends");
+    }
+    for w in table.windows(2) {
+        let ((deg0, duty0), (deg1, duty1)) = (w[0], w[1]);
+        if deg0 >= deg1 {
+            bail!("the angles aren't increasing at {deg1} degrees");
+        }
+        if duty1.cmp(&duty0) != sign {
+            bail!("the duty turns back between {deg0} and {deg1} degrees ({duty0} then {duty1})");
+        }
+    }
+    Ok(())
+}
+
+/// Fills in the entries that weren't measured, by drawing a line through the nearest ones that
+/// were.
+///
+/// The entries need to be sorted by angle. Gaps in the middle get interpolated, and gaps at
+/// the ends get extrapolated from the two closest measurements.
+pub fn fill_gaps(measured: &[(i16, Option<u16>)]) -> anyhow::Result<Vec<(i16, u16)>> {
+    let known: Vec<(f64, f64)> = measured
+        .iter()
+        .filter_map(|&(deg, duty)| Some((f64::from(deg), f64::from(duty?))))
+        .collect();
+    if known.len() < 2 {
+        bail!("at least two angles need to be measured");
+    }
+    Ok(measured
+        .iter()
+        .map(|&(deg, duty)| {
+            let duty = duty.unwrap_or_else(|| {
+                let deg = f64::from(deg);
+                // The first measurement after this angle, but not the very first one, so that
+                // there's always one before it to draw a line from.
+                let after = known
+                    .iter()
+                    .position(|&(d, _)| d > deg)
+                    .unwrap_or(known.len() - 1)
+                    .max(1);
+                let (d0, duty0) = known[after - 1];
+                let (d1, duty1) = known[after];
+                let duty = duty0 + (deg - d0) * (duty1 - duty0) / (d1 - d0);
+                duty.round().clamp(0.0, f64::from(u16::MAX)) as u16
+            });
+            (deg, duty)
+        })
+        .collect())
+}
+
+/// The pen servo's duties for each pen that's been calibrated, by the name of the pen.
+///
+/// A felt tip needs pressing harder than a ballpoint does, and a fat marker needs lifting
+/// further to clear the paper, so every pen gets its own up and down duties. These are saved
+/// with postcard, by `feeder calibrate --pen`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PenDuties {
+    pub pens: BTreeMap<String, pwm::TogglePwm>,
+}
+
+impl PenDuties {
+    /// Reads the pen duties that were saved by [`PenDuties::save`].
+    ///
+    /// Older versions of `feeder calibrate --pen` saved the duties of just one pen, with no
+    /// name. Those files still load, as a pen called "default".
+    pub fn load(path: &Path) -> anyhow::Result<PenDuties> {
+        let data = std::fs::read(path)?;
+        PenDuties::from_bytes(&data).with_context(|| format!("couldn't read {}", path.display()))
+    }
+
+    fn from_bytes(data: &[u8]) -> anyhow::Result<PenDuties> {
+        if let Ok((duties, [])) = postcard::take_from_bytes::<PenDuties>(data) {
+            return Ok(duties);
+        }
+        let (pen, rest) = postcard::take_from_bytes::<pwm::TogglePwm>(data)?;
+        ensure!(rest.is_empty(), "{} bytes left over", rest.len());
+        Ok(PenDuties {
+            pens: BTreeMap::from([("default".to_owned(), pen)]),
+        })
+    }
+
+    /// Like [`PenDuties::load`], but a file that doesn't exist yet has no pens in it.
+    pub fn load_or_default(path: &Path) -> anyhow::Result<PenDuties> {
+        if path.exists() {
+            PenDuties::load(path)
+        } else {
+            Ok(PenDuties::default())
+        }
+    }
+
+    /// Writes the pen duties to a file, for reading back with [`PenDuties::load`].
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, postcard::to_allocvec(self)?)
+            .with_context(|| format!("couldn't write {}", path.display()))
+    }
+
+    /// The op that tells the brachiograph to use the duties for the pen called `name`.
+    ///
+    /// Like the arm calibration, the pen duties don't survive a restart, so send this before
+    /// every plot. It goes through the queue, so it can also be sent in the middle of a job,
+    /// when the pen gets swapped.
+    pub fn op(&self, name: &str) -> anyhow::Result<Op> {
+        let Some(pen) = self.pens.get(name) else {
+            let names = self.pens.keys().map(String::as_str).collect::<Vec<_>>();
+            bail!(
+                "there are no duties for a pen called {name:?} (only for {})",
+                names.join(", ")
+            );
+        };
+        Ok(Op::SetPenDuty {
+            up: pen.off,
+            down: pen.on,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use brachiograph::Resp;
+
+    use super::*;
+    use crate::sim::Simulator;
+
+    fn calibration() -> ArmCalibration {
+        ArmCalibration {
+            shoulder_inc: vec![(-45, 2333), (0, 1800), (120, 500)],
+            shoulder_dec: vec![(-45, 2320), (0, 1790), (120, 510)],
+            elbow_inc: vec![(-60, 2167), (75, 833)],
+            elbow_dec: vec![(-60, 2160), (75, 840)],
+            ..ArmCalibration::default()
+        }
+    }
+
+    #[test]
+    fn fills_gaps() {
+        let measured = [
+            (-30, None),
+            (-15, Some(2000)),
+            (0, None),
+            (15, Some(1800)),
+            (30, Some(1750)),
+            (45, None),
+        ];
+        assert_eq!(
+            fill_gaps(&measured).unwrap(),
+            vec![
+                (-30, 2100),
+                (-15, 2000),
+                (0, 1900),
+                (15, 1800),
+                (30, 1750),
+                (45, 1700)
+            ]
+        );
+        assert!(fill_gaps(&[(0, Some(1000)), (15, None)]).is_err());
+    }
+
+    #[test]
+    fn checks_tables() {
+        assert!(calibration().check().is_ok());
+
+        let mut calib = calibration();
+        calib.shoulder_dec[1].1 = 2400;
+        let err = calib.check().unwrap_err().to_string();
+        assert!(
+            err.starts_with("shoulder dec: the duty turns back"),
+            "{err}"
+        );
+
+        let mut calib = calibration();
+        calib.elbow_inc.reverse();
+        assert!(calib.check().is_err());
+
+        let mut calib = calibration();
+        calib.elbow_dec = vec![(-60, 840), (75, 2160)];
+        let err = calib.check().unwrap_err().to_string();
+        assert!(err.contains("disagree"), "{err}");
+
+        let mut calib = calibration();
+        calib.elbow_dec[0].1 = 3000;
+        assert!(calib.check().is_err());
+    }
+
+    #[test]
+    fn interpolation_error() {
+        let calib = ArmCalibration {
+            shoulder_inc: vec![(0, 1000), (90, 1900)],
+            ..calibration()
+        };
+        let dense = ArmCalibration {
+            shoulder_inc: vec![(0, 1000), (45, 1500), (90, 1900)],
+            ..ArmCalibration::default()
+        };
+        let report = calib.analyze(Some(&dense), 100.0);
+        assert_eq!(report[0].name, "shoulder inc");
+        let (deg, error) = report[0].worst.unwrap();
+        assert_eq!(deg, 45);
+        assert!((error - 5.0).abs() < 1e-6, "{error}");
+        // There's nothing to compare the other tables with.
+        assert_eq!(report[1].worst, None);
+        assert!(calib.analyze(None, 100.0)[0].worst.is_none());
+    }
+
+    #[test]
+    fn coarse_tables() {
+        // The slope goes from 10 to 13.3 to 16.7, so the duty is curving.
+        let calib = ArmCalibration {
+            elbow_inc: vec![(0, 1000), (30, 1300), (60, 1700), (90, 2200)],
+            ..calibration()
+        };
+        let coarse = &calib.analyze(None, 0.5)[2].coarse;
+        assert_eq!(coarse.len(), 3);
+        assert_eq!((coarse[0].from, coarse[0].to), (0, 30));
+        assert!((coarse[0].error - 1.25).abs() < 1e-2, "{coarse:?}");
+        assert!(calib.analyze(None, 2.0)[2].coarse.is_empty());
+
+        // Straight lines are fine however far apart the entries are.
+        assert!(calib.analyze(None, 0.01)[3].coarse.is_empty());
+    }
+
+    #[test]
+    fn upload() {
+        let mut sim = Simulator::new();
+        let checksum = |sim: &mut Simulator| match sim.send(Op::GetInfo) {
+            Resp::Info(info) => info.calibration_crc,
+            resp => panic!("unexpected response {resp:?}"),
+        };
+        let default = checksum(&mut sim);
+        for op in calibration().ops().unwrap() {
+            assert!(matches!(sim.send(op), Resp::Ack));
+        }
+        assert_ne!(checksum(&mut sim), default);
+
+        // Which way around the servos are gets uploaded too, and it counts in the checksum.
+        let uploaded = checksum(&mut sim);
+        let calib = ArmCalibration {
+            elbow_inverted: true,
+            ..calibration()
+        };
+        for op in calib.ops().unwrap() {
+            assert!(matches!(sim.send(op), Resp::Ack));
+        }
+        assert_ne!(checksum(&mut sim), uploaded);
+
+        let calib = ArmCalibration {
+            timing: Some(pwm::Timing {
+                frame_us: 4000,
+                tick_ms: 5,
+            }),
+            ..calibration()
+        };
+        assert!(calib
+            .ops()
+            .unwrap()
+            .contains(&Op::SetTiming(calib.timing.unwrap())));
+        let calib = ArmCalibration {
+            timing: Some(pwm::Timing {
+                frame_us: 1,
+                tick_ms: 5,
+            }),
+            ..calibration()
+        };
+        assert!(calib.ops().is_err());
+
+        let calib = ArmCalibration {
+            pen_ramp: Some(pwm::PenRamp { percent: 60 }),
+            ..calibration()
+        };
+        let uploaded = checksum(&mut sim);
+        for op in calib.ops().unwrap() {
+            assert!(matches!(sim.send(op), Resp::Ack));
+        }
+        assert_ne!(checksum(&mut sim), uploaded);
+        let calib = ArmCalibration {
+            pen_ramp: Some(pwm::PenRamp { percent: 101 }),
+            ..calibration()
+        };
+        assert!(calib.ops().is_err());
+
+        let calib = ArmCalibration {
+            pen_angles: vec![(0, 800), (90, 1800)],
+            ..calibration()
+        };
+        let uploaded = checksum(&mut sim);
+        for op in calib.ops().unwrap() {
+            assert!(matches!(sim.send(op), Resp::Ack));
+        }
+        assert_ne!(checksum(&mut sim), uploaded);
+        let calib = ArmCalibration {
+            pen_angles: vec![(90, 800), (0, 1800)],
+            ..calibration()
+        };
+        assert!(calib.ops().is_err());
+
+        // Empty tables are left alone.
+        let ops = ArmCalibration::default().ops().unwrap();
+        assert!(ops.iter().all(|op| matches!(op, Op::SetInverted(..))));
+
+        let mut calib = calibration();
+        calib.elbow_dec = (0..20).map(|i| (i * 5, 1000 + i as u16)).collect();
+        let err = calib.ops().unwrap_err().to_string();
+        assert!(err.starts_with("elbow dec: there are 20 entries"), "{err}");
+    }
+
+    #[test]
+    fn pen_duties() {
+        let mut duties = PenDuties::default();
+        duties
+            .pens
+            .insert("felt".to_owned(), pwm::TogglePwm { on: 1350, off: 700 });
+        duties
+            .pens
+            .insert("ballpoint".to_owned(), pwm::TogglePwm::pen());
+        let data = postcard::to_allocvec(&duties).unwrap();
+        assert_eq!(PenDuties::from_bytes(&data).unwrap(), duties);
+
+        let old = postcard::to_allocvec(&pwm::TogglePwm { on: 1350, off: 700 }).unwrap();
+        let loaded = PenDuties::from_bytes(&old).unwrap();
+        assert_eq!(loaded.pens.len(), 1);
+        assert_eq!(loaded.pens["default"], duties.pens["felt"]);
+        assert!(PenDuties::from_bytes(&[1, 2, 3, 4, 5]).is_err());
+
+        let op = duties.op("felt").unwrap();
+        assert_eq!(
+            op,
+            Op::SetPenDuty {
+                up: 700,
+                down: 1350
+            }
+        );
+        assert!(matches!(Simulator::new().send(op), Resp::Ack));
+        let err = duties.op("marker").unwrap_err().to_string();
+        assert!(err.ends_with("(only for ballpoint, felt)"), "{err}");
+    }
+
+    #[test]
+    fn python_json() {
+        let json = r#"{
+            "servo_1_parked_pw": 1570,
+            "servo_1_angle_pws_bidi": {
+                "-135": {"cw": 2374, "acw": 2386},
+                "-90": {"cw": 1898, "acw": 1900},
+                "0": {"cw": 1048, "acw": 1060.4}
+            },
+            "servo_2_angle_pws": [[0, 1000], [90.2, 1900], [150, 2500]],
+            "pw_up": 1500
+        }"#;
+        let calib = ArmCalibration::from_python_json(json).unwrap();
+        assert_eq!(calib.shoulder_inc, vec![(-45, 2374), (0, 1898), (90, 1048)]);
+        assert_eq!(calib.shoulder_dec, vec![(-45, 2386), (0, 1900), (90, 1060)]);
+        assert_eq!(calib.elbow_inc, vec![(-60, 2500), (0, 1900), (90, 1000)]);
+        assert_eq!(calib.elbow_inc, calib.elbow_dec);
+
+        assert!(ArmCalibration::from_python_json(r#"{"servo_1_angle_pws": []}"#).is_err());
+        let err = ArmCalibration::from_python_json(
+            r#"{"servo_1_angle_pws_bidi": {"up": {"cw": 1, "acw": 2}}, "servo_2_angle_pws": []}"#,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("\"up\" isn't an angle"), "{err}");
+    }
+
+    #[test]
+    fn python_round_trip() {
+        let json = calibration().to_python_json().unwrap();
+        assert!(
+            json.contains("\"-135\": {\"cw\": 2333, \"acw\": 2320},"),
+            "{json}"
+        );
+        assert_eq!(
+            ArmCalibration::from_python_json(&json).unwrap(),
+            calibration()
+        );
+
+        // Angles that are only in one direction get filled in for the other.
+        let calib = ArmCalibration {
+            shoulder_dec: vec![(-45, 2320), (60, 1150), (120, 510)],
+            ..calibration()
+        };
+        let back = ArmCalibration::from_python_json(&calib.to_python_json().unwrap()).unwrap();
+        assert_eq!(
+            back.shoulder_dec,
+            vec![(-45, 2320), (0, 1819), (60, 1150), (120, 510)]
+        );
+        assert_eq!(
+            back.shoulder_inc,
+            vec![(-45, 2333), (0, 1800), (60, 1150), (120, 500)]
+        );
+    }
+
+    #[test]
+    fn rust_source() {
+        let rust = calibration().to_rust();
+        assert!(rust.contains("pub const ELBOW_INC: &[(i16, u16)] = &[\n    (-60, 2167),\n"));
+        assert!(rust.contains("pub const SHOULDER_INVERTED: bool = false;\n"));
+        assert_eq!(rust.matches("pub const").count(), 6);
+    }
+
+    #[test]
+    fn old_files() {
+        let calib = calibration();
+        let tables = (
+            &calib.shoulder_inc,
+            &calib.shoulder_dec,
+            &calib.elbow_inc,
+            &calib.elbow_dec,
+        );
+        let old = postcard::to_allocvec(&tables).unwrap();
+        assert_eq!(ArmCalibration::from_bytes(&old).unwrap(), calib);
+
+        let calib = ArmCalibration {
+            shoulder_inverted: true,
+            ..calibration()
+        };
+        let new = postcard::to_allocvec(&calib).unwrap();
+        assert_eq!(ArmCalibration::from_bytes(&new).unwrap(), calib);
+        assert!(ArmCalibration::from_bytes(&[1, 2, 3]).is_err());
+    }
+}
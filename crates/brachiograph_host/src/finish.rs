@@ -0,0 +1,182 @@
+//! What happens once the last line of a drawing is drawn.
+//!
+//! Every drawing should end the same way, whichever program sent it, so frontends end their
+//! drawings with [`Finish::ops`] instead of with their own final moves.
+
+use std::time::Duration;
+
+use brachiograph::{geom, Op};
+use kurbo::{Affine, Shape};
+
+use crate::{font, path, JobStats};
+
+/// How far a signature is from the edges of the drawing area, in brachiograph units.
+pub const SIGNATURE_MARGIN: f64 = 0.3;
+
+/// How to finish off a drawing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Finish {
+    /// Whether to move the pen to the [park position](geom::Config::park), out of the way of
+    /// the drawing. Otherwise the pen gets lifted and stays where the drawing ended.
+    pub park: bool,
+    /// Whether to let the servos go once the pen is parked (see [`Op::Finish`]), so that they
+    /// don't spend the time until the next drawing holding the arms still. This parks the pen
+    /// too, whatever `park` says.
+    pub relax: bool,
+    /// Some text (like initials) to sign the drawing with, written small in the bottom
+    /// right-hand corner of the drawing area.
+    pub signature: Option<String>,
+    /// How tall the capitals in the signature are.
+    pub signature_height: f64,
+    /// Whether to say what got drawn (see [`Finish::report`]).
+    pub stats: bool,
+}
+
+impl Default for Finish {
+    fn default() -> Self {
+        Finish {
+            park: true,
+            relax: true,
+            signature: None,
+            signature_height: 0.4,
+            stats: true,
+        }
+    }
+}
+
+impl Finish {
+    /// The ops that finish off a drawing on a brachiograph with the drawing area in `config`.
+    ///
+    /// They start with the pen in any state, and they leave it up.
+    pub fn ops(&self, config: &geom::Config) -> Vec<Op> {
+        let mut ops = self.signature_ops(config);
+        ops.push(if self.relax {
+            Op::Finish
+        } else if self.park {
+            Op::Park
+        } else {
+            Op::PenUp
+        });
+        ops
+    }
+
+    /// Like [`Finish::ops`], but only with ops that the text protocol has commands for, for
+    /// older firmware. That firmware can't relax its servos, so they get parked instead.
+    pub fn text_ops(&self, config: &geom::Config) -> Vec<Op> {
+        let mut ops = self.signature_ops(config);
+        ops.push(Op::PenUp);
+        if self.park || self.relax {
+            let (x, y) = config.park;
+            ops.push(Op::MoveTo(brachiograph::Point { x, y }));
+        }
+        ops
+    }
+
+    // The ops for writing the signature in the bottom right-hand corner of the drawing area.
+    fn signature_ops(&self, config: &geom::Config) -> Vec<Op> {
+        let Some(text) = &self.signature else {
+            return Vec::new();
+        };
+        let text = font::text(text, self.signature_height);
+        let bbox = text.bounding_box();
+        let right = config.x_range.1.to_num::<f64>() - SIGNATURE_MARGIN;
+        let bottom = config.y_range.0.to_num::<f64>() + SIGNATURE_MARGIN;
+        let shift = Affine::translate((right - bbox.max_x(), bottom - bbox.min_y()));
+        path::to_ops(&(shift * text))
+    }
+
+    /// A line saying what got drawn and how long it took, or `None` if `stats` is off.
+    pub fn report(&self, stats: &JobStats, elapsed: Duration) -> Option<String> {
+        self.stats.then(|| {
+            format!(
+                "Finished in {:.0?}, drawing {:.1} and moving {:.1} with the pen up ({} pen lifts)",
+                elapsed, stats.draw_distance, stats.travel_distance, stats.pen_ups
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use brachiograph::Resp;
+
+    use super::*;
+    use crate::sim::Simulator;
+
+    #[test]
+    fn finishing_ops() {
+        let config = geom::Config::default();
+        assert_eq!(Finish::default().ops(&config), [Op::Finish]);
+        let finish = Finish {
+            relax: false,
+            ..Finish::default()
+        };
+        assert_eq!(finish.ops(&config), [Op::Park]);
+        let finish = Finish {
+            park: false,
+            relax: false,
+            ..Finish::default()
+        };
+        assert_eq!(finish.ops(&config), [Op::PenUp]);
+
+        let park = brachiograph::Point {
+            x: config.park.0,
+            y: config.park.1,
+        };
+        assert_eq!(
+            Finish::default().text_ops(&config),
+            [Op::PenUp, Op::MoveTo(park)]
+        );
+        assert!(Finish::default().text_ops(&config).iter().all(Op::has_text));
+    }
+
+    #[test]
+    fn signature_in_the_corner() {
+        let config = geom::Config::default();
+        let finish = Finish {
+            signature: Some("JN".to_owned()),
+            ..Finish::default()
+        };
+        let ops = finish.ops(&config);
+        assert_eq!(ops.last(), Some(&Op::Finish));
+
+        let mut sim = Simulator::new();
+        for op in ops {
+            let resp = sim.send(op.clone());
+            assert!(matches!(resp, Resp::Ack), "{op:?} got {resp:?}");
+        }
+        let bbox = sim.drawing().bounding_box();
+        assert!(
+            (bbox.height() - finish.signature_height).abs() < 0.01,
+            "{bbox:?}"
+        );
+        assert!(
+            (bbox.max_x() - (8.0 - SIGNATURE_MARGIN)).abs() < 0.01,
+            "{bbox:?}"
+        );
+        assert!(
+            (bbox.min_y() - (5.0 + SIGNATURE_MARGIN)).abs() < 0.01,
+            "{bbox:?}"
+        );
+    }
+
+    #[test]
+    fn report() {
+        let stats = JobStats {
+            draw_distance: 12.34,
+            travel_distance: 5.0,
+            pen_ups: 3,
+            ..JobStats::default()
+        };
+        let report = Finish::default().report(&stats, Duration::from_secs(90));
+        assert_eq!(
+            report.as_deref(),
+            Some("Finished in 90s, drawing 12.3 and moving 5.0 with the pen up (3 pen lifts)")
+        );
+        let quiet = Finish {
+            stats: false,
+            ..Finish::default()
+        };
+        assert_eq!(quiet.report(&stats, Duration::from_secs(90)), None);
+    }
+}
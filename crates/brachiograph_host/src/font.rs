@@ -0,0 +1,277 @@
+//! A built-in font for writing text.
+//!
+//! The brachiograph draws with a pen, so outline fonts are no good to it: it would draw the
+//! outline of each letter instead of the letter. This is a small single-stroke font in the
+//! spirit of the Hershey fonts, where each letter is a few lines for the pen to follow. It
+//! only has capital letters (lowercase ones are drawn as capitals), digits and a little
+//! punctuation; anything else is drawn as a question mark.
+
+use kurbo::{Affine, BezPath};
+
+// The glyphs are drawn on a grid with the baseline at y = 0, the tops of the capitals at y = 6,
+// and the letters taking up 0 <= x <= 4.
+const GRID_HEIGHT: f64 = 6.0;
+// The distance from the start of one letter to the start of the next.
+const GRID_ADVANCE: f64 = 6.0;
+// The distance from the baseline of one line to the baseline of the next.
+const GRID_LINE: f64 = 10.0;
+
+type Glyph = &'static [&'static [(i8, i8)]];
+
+const O: &[(i8, i8)] = &[
+    (1, 0),
+    (0, 1),
+    (0, 5),
+    (1, 6),
+    (3, 6),
+    (4, 5),
+    (4, 1),
+    (3, 0),
+    (1, 0),
+];
+const P: &[(i8, i8)] = &[(0, 0), (0, 6), (3, 6), (4, 5), (4, 4), (3, 3), (0, 3)];
+const QUESTION: Glyph = &[
+    &[(0, 5), (1, 6), (3, 6), (4, 5), (4, 4), (2, 3), (2, 2)],
+    &[(2, 1), (2, 0)],
+];
+
+// The strokes for drawing a character, each one a list of points to join up.
+fn strokes(c: char) -> Glyph {
+    match c.to_ascii_uppercase() {
+        'A' => &[&[(0, 0), (2, 6), (4, 0)], &[(1, 3), (3, 3)]],
+        'B' => &[
+            &[(0, 0), (0, 6), (3, 6), (4, 5), (4, 4), (3, 3), (0, 3)],
+            &[(3, 3), (4, 2), (4, 1), (3, 0), (0, 0)],
+        ],
+        'C' => &[&[
+            (4, 5),
+            (3, 6),
+            (1, 6),
+            (0, 5),
+            (0, 1),
+            (1, 0),
+            (3, 0),
+            (4, 1),
+        ]],
+        'D' => &[&[(0, 0), (0, 6), (2, 6), (4, 4), (4, 2), (2, 0), (0, 0)]],
+        'E' => &[&[(4, 6), (0, 6), (0, 0), (4, 0)], &[(0, 3), (3, 3)]],
+        'F' => &[&[(4, 6), (0, 6), (0, 0)], &[(0, 3), (3, 3)]],
+        'G' => &[&[
+            (4, 5),
+            (3, 6),
+            (1, 6),
+            (0, 5),
+            (0, 1),
+            (1, 0),
+            (3, 0),
+            (4, 1),
+            (4, 3),
+            (2, 3),
+        ]],
+        'H' => &[&[(0, 0), (0, 6)], &[(4, 0), (4, 6)], &[(0, 3), (4, 3)]],
+        'I' => &[&[(1, 6), (3, 6)], &[(2, 6), (2, 0)], &[(1, 0), (3, 0)]],
+        'J' => &[&[(4, 6), (4, 1), (3, 0), (1, 0), (0, 1)]],
+        'K' => &[&[(0, 0), (0, 6)], &[(4, 6), (0, 2)], &[(1, 3), (4, 0)]],
+        'L' => &[&[(0, 6), (0, 0), (4, 0)]],
+        'M' => &[&[(0, 0), (0, 6), (2, 3), (4, 6), (4, 0)]],
+        'N' => &[&[(0, 0), (0, 6), (4, 0), (4, 6)]],
+        'O' => &[O],
+        'P' => &[P],
+        'Q' => &[O, &[(2, 2), (4, 0)]],
+        'R' => &[P, &[(2, 3), (4, 0)]],
+        'S' => &[&[
+            (4, 5),
+            (3, 6),
+            (1, 6),
+            (0, 5),
+            (0, 4),
+            (1, 3),
+            (3, 3),
+            (4, 2),
+            (4, 1),
+            (3, 0),
+            (1, 0),
+            (0, 1),
+        ]],
+        'T' => &[&[(0, 6), (4, 6)], &[(2, 6), (2, 0)]],
+        'U' => &[&[(0, 6), (0, 1), (1, 0), (3, 0), (4, 1), (4, 6)]],
+        'V' => &[&[(0, 6), (2, 0), (4, 6)]],
+        'W' => &[&[(0, 6), (1, 0), (2, 4), (3, 0), (4, 6)]],
+        'X' => &[&[(0, 0), (4, 6)], &[(0, 6), (4, 0)]],
+        'Y' => &[&[(0, 6), (2, 3), (4, 6)], &[(2, 3), (2, 0)]],
+        'Z' => &[&[(0, 6), (4, 6), (0, 0), (4, 0)]],
+        '0' => &[O, &[(0, 1), (4, 5)]],
+        '1' => &[&[(1, 5), (2, 6), (2, 0)], &[(1, 0), (3, 0)]],
+        '2' => &[&[(0, 5), (1, 6), (3, 6), (4, 5), (4, 4), (0, 0), (4, 0)]],
+        '3' => &[
+            &[(0, 5), (1, 6), (3, 6), (4, 5), (4, 4), (3, 3), (1, 3)],
+            &[(3, 3), (4, 2), (4, 1), (3, 0), (1, 0), (0, 1)],
+        ],
+        '4' => &[&[(3, 0), (3, 6), (0, 2), (4, 2)]],
+        '5' => &[&[
+            (4, 6),
+            (0, 6),
+            (0, 3),
+            (3, 3),
+            (4, 2),
+            (4, 1),
+            (3, 0),
+            (1, 0),
+            (0, 1),
+        ]],
+        '6' => &[&[
+            (4, 5),
+            (3, 6),
+            (1, 6),
+            (0, 5),
+            (0, 1),
+            (1, 0),
+            (3, 0),
+            (4, 1),
+            (4, 2),
+            (3, 3),
+            (0, 3),
+        ]],
+        '7' => &[&[(0, 6), (4, 6), (1, 0)]],
+        '8' => &[
+            &[
+                (1, 3),
+                (0, 4),
+                (0, 5),
+                (1, 6),
+                (3, 6),
+                (4, 5),
+                (4, 4),
+                (3, 3),
+            ],
+            &[
+                (1, 3),
+                (3, 3),
+                (4, 2),
+                (4, 1),
+                (3, 0),
+                (1, 0),
+                (0, 1),
+                (0, 2),
+                (1, 3),
+            ],
+        ],
+        '9' => &[&[
+            (4, 3),
+            (1, 3),
+            (0, 4),
+            (0, 5),
+            (1, 6),
+            (3, 6),
+            (4, 5),
+            (4, 1),
+            (3, 0),
+            (1, 0),
+            (0, 1),
+        ]],
+        ' ' => &[],
+        '.' => &[&[(2, 0), (2, 1)]],
+        ',' => &[&[(2, 1), (2, 0), (1, -1)]],
+        '!' => &[&[(2, 6), (2, 2)], &[(2, 1), (2, 0)]],
+        '?' => QUESTION,
+        '-' => &[&[(1, 3), (3, 3)]],
+        '+' => &[&[(0, 3), (4, 3)], &[(2, 1), (2, 5)]],
+        '=' => &[&[(0, 2), (4, 2)], &[(0, 4), (4, 4)]],
+        ':' => &[&[(2, 4), (2, 5)], &[(2, 1), (2, 2)]],
+        '\'' => &[&[(2, 6), (2, 4)]],
+        '/' => &[&[(0, 0), (4, 6)]],
+        '(' => &[&[(3, 6), (1, 4), (1, 2), (3, 0)]],
+        ')' => &[&[(1, 6), (3, 4), (3, 2), (1, 0)]],
+        _ => QUESTION,
+    }
+}
+
+/// How far apart the letters are, from the start of one to the start of the next, when the
+/// capitals are `height` tall.
+pub fn advance(height: f64) -> f64 {
+    GRID_ADVANCE * height / GRID_HEIGHT
+}
+
+/// Draws a single character, with the capitals `height` tall.
+///
+/// The character sits on the x axis, starting at the origin (and the y axis points up).
+pub fn glyph(c: char, height: f64) -> BezPath {
+    let mut ret = BezPath::new();
+    for stroke in strokes(c) {
+        for (i, &(x, y)) in stroke.iter().enumerate() {
+            let p = (f64::from(x), f64::from(y));
+            if i == 0 {
+                ret.move_to(p);
+            } else {
+                ret.line_to(p);
+            }
+        }
+    }
+    ret.apply_affine(Affine::scale(height / GRID_HEIGHT));
+    ret
+}
+
+/// Draws some text, with the capitals `height` tall.
+///
+/// The first line sits on the x axis, starting at the origin, and each new line (in the text)
+/// starts a new line below it.
+pub fn text(s: &str, height: f64) -> BezPath {
+    let scale = height / GRID_HEIGHT;
+    let mut ret = BezPath::new();
+    for (row, line) in s.lines().enumerate() {
+        for (col, c) in line.chars().enumerate() {
+            let offset = (
+                col as f64 * GRID_ADVANCE * scale,
+                -(row as f64) * GRID_LINE * scale,
+            );
+            for el in (Affine::translate(offset) * glyph(c, height)).elements() {
+                ret.push(*el);
+            }
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use kurbo::{PathEl, Rect, Shape};
+
+    use super::*;
+
+    #[test]
+    fn glyphs_stay_in_their_box() {
+        let chars = ('A'..='Z').chain('0'..='9').chain(".,!?-+=:'/()".chars());
+        // Only the comma goes below the baseline.
+        let cell = Rect::new(0.0, -1.0, 4.0, 6.0);
+        for c in chars {
+            let bbox = glyph(c, GRID_HEIGHT).bounding_box();
+            assert_eq!(cell.union(bbox), cell, "{c:?}: {bbox:?}");
+        }
+    }
+
+    #[test]
+    fn lowercase_and_unknown() {
+        assert_eq!(glyph('a', 1.0), glyph('A', 1.0));
+        assert_eq!(glyph('~', 1.0), glyph('?', 1.0));
+        assert!(glyph(' ', 1.0).elements().is_empty());
+    }
+
+    #[test]
+    fn text_layout() {
+        let hi = text("HI\nHI", 3.0);
+        let bbox = hi.bounding_box();
+        // The I is a bit narrower than the H, and the second line's baseline is 5 down.
+        assert!(
+            (bbox.width() - (advance(3.0) + 1.5)).abs() < 1e-9,
+            "{bbox:?}"
+        );
+        assert!((bbox.max_y() - 3.0).abs() < 1e-9, "{bbox:?}");
+        assert!((bbox.min_y() + 5.0).abs() < 1e-9, "{bbox:?}");
+        let strokes = hi
+            .elements()
+            .iter()
+            .filter(|el| matches!(el, PathEl::MoveTo(_)))
+            .count();
+        assert_eq!(strokes, 12);
+    }
+}
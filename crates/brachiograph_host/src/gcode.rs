@@ -0,0 +1,308 @@
+//! Turning G-code into jobs.
+//!
+//! This understands enough G-code to plot the toolpaths made by CAM and text-engraving
+//! programs: straight moves (`G0` and `G1`), arcs (`G2` and `G3`, with the center given by `I`
+//! and `J`), units (`G20` and `G21`), and absolute or relative positioning (`G90` and `G91`).
+//! The pen is down whenever `Z` is at most zero, or after an `M3` (and until an `M5`).
+//! Everything else (feed rates, tool changes, etc.) is ignored.
+
+use kurbo::{Arc, BezPath, PathEl, Point, Rect, Vec2};
+
+use crate::{path, profile::Settings, Job};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Motion {
+    Line,
+    Clockwise,
+    CounterClockwise,
+}
+
+// The words on one line, like `G1 X10 Y5`.
+fn words(line: &str) -> anyhow::Result<Vec<(char, f64)>> {
+    // A line with a percent sign marks the start or end of the program (and whatever comes
+    // after it is a comment).
+    if line.trim_start().starts_with('%') {
+        return Ok(Vec::new());
+    }
+    // Comments are either in parentheses or after a semicolon.
+    let mut code = String::new();
+    let mut in_paren = false;
+    for c in line.chars() {
+        match c {
+            ';' if !in_paren => break,
+            '(' => in_paren = true,
+            ')' => in_paren = false,
+            c if !in_paren => code.push(c),
+            _ => {}
+        }
+    }
+
+    let mut ret = Vec::new();
+    let mut rest = code.trim_start();
+    while let Some(letter) = rest.chars().next() {
+        if !letter.is_ascii_alphabetic() {
+            anyhow::bail!("expected a letter, found {letter:?}");
+        }
+        let num = rest[1..].trim_start();
+        let len = num
+            .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+            .unwrap_or(num.len());
+        let value = num[..len]
+            .parse()
+            .map_err(|_| anyhow::anyhow!("bad number after {letter:?}"))?;
+        ret.push((letter.to_ascii_uppercase(), value));
+        rest = num[len..].trim_start();
+    }
+    Ok(ret)
+}
+
+/// Reads the strokes out of a G-code program, in G-code coordinates (converted to millimeters).
+///
+/// The returned path has one subpath for each time the pen goes down.
+pub fn parse(text: &str) -> anyhow::Result<BezPath> {
+    let mut ret = BezPath::new();
+    let mut pos = Point::ORIGIN;
+    let mut motion = None;
+    let mut absolute = true;
+    let mut mm_per_unit = 1.0;
+    let mut z = 0.0;
+    let mut pen_down = false;
+    let mut in_stroke = false;
+
+    for (i, line) in text.lines().enumerate() {
+        let err = |msg: &str| anyhow::anyhow!("line {}: {msg}", i + 1);
+        let words = words(line).map_err(|e| err(&e.to_string()))?;
+
+        let mut end = false;
+        for &(letter, value) in &words {
+            match (letter, value as u32) {
+                ('G', 0 | 1) => motion = Some(Motion::Line),
+                ('G', 2) => motion = Some(Motion::Clockwise),
+                ('G', 3) => motion = Some(Motion::CounterClockwise),
+                ('G', 20) => mm_per_unit = 25.4,
+                ('G', 21) => mm_per_unit = 1.0,
+                ('G', 90) => absolute = true,
+                ('G', 91) => absolute = false,
+                ('M', 3 | 4) => pen_down = true,
+                ('M', 5) => pen_down = false,
+                ('M', 2 | 30) => end = true,
+                ('G' | 'M', _) => log::debug!("ignoring {letter}{value}"),
+                ('R', _) => return Err(err("arcs given by R aren't supported; use I and J")),
+                _ => {}
+            }
+        }
+        let get = |letter: char| {
+            words
+                .iter()
+                .find(|(l, _)| *l == letter)
+                .map(|(_, v)| *v * mm_per_unit)
+        };
+
+        if let Some(new_z) = get('Z') {
+            z = if absolute { new_z } else { z + new_z };
+            pen_down = z <= 0.0;
+        }
+        if !pen_down {
+            in_stroke = false;
+        }
+
+        let (x, y) = (get('X'), get('Y'));
+        if x.is_some() || y.is_some() {
+            let target = if absolute {
+                Point::new(x.unwrap_or(pos.x), y.unwrap_or(pos.y))
+            } else {
+                pos + Vec2::new(x.unwrap_or(0.0), y.unwrap_or(0.0))
+            };
+            let Some(motion) = motion else {
+                return Err(err("a move before any G0, G1, G2 or G3"));
+            };
+            if pen_down && !in_stroke {
+                ret.move_to(pos);
+                in_stroke = true;
+            }
+            match motion {
+                Motion::Line => {
+                    if pen_down {
+                        ret.line_to(target);
+                    }
+                }
+                Motion::Clockwise | Motion::CounterClockwise => {
+                    let (i, j) = (get('I'), get('J'));
+                    if i.is_none() && j.is_none() {
+                        return Err(err("an arc without I or J"));
+                    }
+                    let center = pos + Vec2::new(i.unwrap_or(0.0), j.unwrap_or(0.0));
+                    if pen_down {
+                        let arc = arc(pos, target, center, motion == Motion::CounterClockwise);
+                        ret.extend(arc.append_iter(1e-3 * arc.radii.x.max(1e-3)));
+                        // The arc should end at the target, but it might not quite: either
+                        // because of rounding, or because the center wasn't really
+                        // equidistant from the endpoints.
+                        if let Some(PathEl::CurveTo(_, _, p)) = ret.elements_mut().last_mut() {
+                            if p.distance(target) < 1e-6 {
+                                *p = target;
+                            } else {
+                                ret.line_to(target);
+                            }
+                        }
+                    }
+                }
+            }
+            pos = target;
+        }
+
+        if end {
+            break;
+        }
+    }
+    Ok(ret)
+}
+
+// The arc from `start` to `end` around `center`. If the endpoints are the same, it's a whole
+// circle.
+fn arc(start: Point, end: Point, center: Point, counter_clockwise: bool) -> Arc {
+    let radius = (start - center).hypot();
+    let start_angle = (start - center).atan2();
+    let mut sweep = (end - center).atan2() - start_angle;
+    let tau = std::f64::consts::TAU;
+    if (end - start).hypot() < 1e-9 {
+        sweep = if counter_clockwise { tau } else { -tau };
+    } else if counter_clockwise {
+        while sweep <= 0.0 {
+            sweep += tau;
+        }
+    } else {
+        while sweep >= 0.0 {
+            sweep -= tau;
+        }
+    }
+    Arc {
+        center,
+        radii: Vec2::new(radius, radius),
+        start_angle,
+        sweep_angle: sweep,
+        x_rotation: 0.0,
+    }
+}
+
+/// Turns a G-code program into a job that draws it, scaled to fit in `rect`.
+pub fn to_job(text: &str, rect: Rect, settings: &Settings) -> anyhow::Result<Job> {
+    let mut paths = [parse(text)?];
+    if paths[0].is_empty() {
+        anyhow::bail!("the G-code never puts the pen down");
+    }
+    path::fit(&mut paths, rect);
+    let mut flat = BezPath::new();
+    paths[0].flatten(settings.flatten_tolerance, |el| flat.push(el));
+    let simple = path::simplify(&flat, settings.simplify_epsilon);
+    let ops = path::to_ops(&path::split_lines(&simple, settings.max_line));
+    Ok(path::dwell_at_corners(ops, settings.corner_dwell).collect())
+}
+
+/// Like [`to_job`], but leaves arcs as curves for the brachiograph to follow by itself.
+pub fn to_curve_job(text: &str, rect: Rect) -> anyhow::Result<Job> {
+    let mut paths = [parse(text)?];
+    if paths[0].is_empty() {
+        anyhow::bail!("the G-code never puts the pen down");
+    }
+    path::fit(&mut paths, rect);
+    Ok(path::to_ops(&paths[0]).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use kurbo::Shape;
+
+    use super::*;
+
+    fn assert_close(a: Point, b: Point) {
+        assert!(a.distance(b) < 1e-6, "{a:?} != {b:?}");
+    }
+
+    fn strokes(path: &BezPath) -> usize {
+        path.elements()
+            .iter()
+            .filter(|el| matches!(el, PathEl::MoveTo(_)))
+            .count()
+    }
+
+    #[test]
+    fn square_with_z() {
+        let path = parse(
+            "%\n\
+             G21 G90 (millimeters, absolute)\n\
+             G0 Z5\n\
+             G0 X0 Y0\n\
+             G1 Z-1 F100 ; pen down\n\
+             G1 X10\n\
+             Y10\n\
+             X0\n\
+             Y0\n\
+             G0 Z5\n\
+             X20 Y20\n\
+             % the end\n",
+        )
+        .unwrap();
+        assert_eq!(strokes(&path), 1);
+        let bbox = path.bounding_box();
+        assert_eq!(bbox, Rect::new(0.0, 0.0, 10.0, 10.0));
+    }
+
+    #[test]
+    fn relative_inches_and_m_codes() {
+        let path = parse(
+            "G20 G91\n\
+             G1 X1\n\
+             M3\n\
+             G1 X1 Y1\n\
+             M5\n\
+             G1 X1\n\
+             M3\n\
+             G1 Y-1\n\
+             M2\n\
+             G1 X100\n",
+        )
+        .unwrap();
+        assert_eq!(strokes(&path), 2);
+        assert_eq!(path.elements()[0], PathEl::MoveTo(Point::new(25.4, 0.0)));
+        // Everything after the M2 is ignored.
+        let bbox = path.bounding_box();
+        assert!((bbox.max_x() - 76.2).abs() < 1e-6, "{bbox:?}");
+    }
+
+    #[test]
+    fn arcs() {
+        // A clockwise quarter circle from (0, 10) to (10, 0) around the origin, and then a
+        // whole circle.
+        let path = parse("G0 X0 Y10\nM3\nG2 X10 Y0 I0 J-10\nG3 X10 Y0 I-10 J0\n").unwrap();
+        assert_eq!(strokes(&path), 1);
+        let bbox = path.bounding_box();
+        assert!((bbox.min_x() + 10.0).abs() < 1e-2, "{bbox:?}");
+        assert!((bbox.max_y() - 10.0).abs() < 1e-2, "{bbox:?}");
+        assert!((bbox.min_y() + 10.0).abs() < 1e-2, "{bbox:?}");
+        let PathEl::CurveTo(_, _, end) = path.elements().last().unwrap() else {
+            panic!("expected a curve");
+        };
+        assert_close(*end, Point::new(10.0, 0.0));
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        let err = parse("G0 X0\nG1 X1 Y$\n").unwrap_err();
+        assert!(err.to_string().starts_with("line 2:"), "{err}");
+        let err = parse("X1 Y1\n").unwrap_err();
+        assert!(err.to_string().starts_with("line 1:"), "{err}");
+        assert!(parse("M3\nG2 X1 Y1 R1\n").is_err());
+        assert!(parse("M3\nG2 X1 Y1\n").is_err());
+    }
+
+    #[test]
+    fn job_fits_in_rect() {
+        let settings = crate::profile::Profile::Fine.settings();
+        let rect = Rect::new(-8.0, 5.0, 8.0, 13.0);
+        let job = to_job("M3\nG1 X100 Y0\nG1 X100 Y50\n", rect, &settings).unwrap();
+        assert_eq!(job.stats().pen_downs, 1);
+        assert!((job.stats().draw_distance - 24.0).abs() < 1e-2);
+        assert!(to_job("G0 X1 Y1\n", rect, &settings).is_err());
+    }
+}
@@ -0,0 +1,428 @@
+//! Noticing when a brachiograph gets plugged in or unplugged.
+//!
+//! [`Serial::detect`] only looks once, so a brachiograph that gets plugged in later (or that
+//! drops off the bus and comes back under a new name) would otherwise need a restart. A
+//! [`Watcher`] keeps looking at the serial ports in the background, and says when one that looks
+//! like a brachiograph appears or goes away. [`Reconnecting`] uses a watcher to hold on to a
+//! connection: it lets go when the brachiograph is unplugged or stops answering, and opens it
+//! again when it's back.
+//!
+//! A brachiograph that comes back has usually been reset, so it has forgotten its calibration
+//! and anything else that it was told. Whatever was set up after connecting (see
+//! [`Reconnecting::on_connect`]) gets done again every time it reconnects.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::bail;
+use brachiograph::{Op, Resp};
+
+use crate::{serial::brachiograph_ports, Serial, Transport};
+
+/// How often a [`Watcher`] looks at the serial ports, unless asked otherwise.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A brachiograph's serial port appearing or going away.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PortEvent {
+    /// A port appeared, or was already there when the watcher started.
+    Arrived(String),
+    /// A port went away.
+    Removed(String),
+}
+
+/// Watches for brachiographs being plugged in and unplugged, on a background thread.
+///
+/// The thread stops when the watcher is dropped.
+pub struct Watcher {
+    events: mpsc::Receiver<PortEvent>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Watcher {
+    /// Starts watching the serial ports that look like brachiographs.
+    pub fn spawn() -> Watcher {
+        Watcher::spawn_with(DEFAULT_POLL_INTERVAL, brachiograph_ports)
+    }
+
+    /// Starts watching, by calling `ports` every `interval` for the names of the ports that are
+    /// there now.
+    pub fn spawn_with(
+        interval: Duration,
+        mut ports: impl FnMut() -> Vec<String> + Send + 'static,
+    ) -> Watcher {
+        let (tx, events) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = thread::spawn({
+            let stop = Arc::clone(&stop);
+            move || {
+                let mut present: Vec<String> = Vec::new();
+                while !stop.load(Ordering::Relaxed) {
+                    let now = ports();
+                    let removed = present.iter().filter(|p| !now.contains(p));
+                    let removed = removed.map(|p| PortEvent::Removed(p.clone()));
+                    let arrived = now.iter().filter(|p| !present.contains(p));
+                    let arrived = arrived.map(|p| PortEvent::Arrived(p.clone()));
+                    for event in removed.chain(arrived) {
+                        if tx.send(event).is_err() {
+                            return;
+                        }
+                    }
+                    present = now;
+                    // Dropping the watcher wakes us up early.
+                    thread::park_timeout(interval);
+                }
+            }
+        });
+        Watcher {
+            events,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// The next port that appeared or went away, if there's been one that we haven't said yet.
+    pub fn try_next(&self) -> Option<PortEvent> {
+        self.events.try_recv().ok()
+    }
+
+    /// Waits for up to `timeout` for a port to appear or go away.
+    pub fn next_timeout(&self, timeout: Duration) -> Option<PortEvent> {
+        self.events.recv_timeout(timeout).ok()
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Whether a [`Reconnecting`] has a brachiograph to talk to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Connected to the brachiograph on this serial port.
+    Connected(String),
+    Disconnected,
+}
+
+/// A connection to a brachiograph that survives it being unplugged and plugged back in.
+///
+/// Nothing happens in the background: changes are noticed by [`Reconnecting::poll`], which
+/// everything else calls first. If an op fails because the brachiograph stopped answering, the
+/// connection gets opened again straight away, but the op isn't sent again, because there's no
+/// telling how much of it the brachiograph got.
+pub struct Reconnecting {
+    watcher: Watcher,
+    open: Opener,
+    // The port that we're connected to, and the connection.
+    serial: Option<(String, Serial)>,
+    // Ports that are there, that we aren't connected to and haven't failed to open, in the order
+    // that they turned up.
+    ports: Vec<String>,
+    setup: Option<Setup>,
+    on_change: Option<OnChange>,
+}
+
+// Opens a serial port.
+type Opener = Box<dyn FnMut(&str) -> anyhow::Result<Serial> + Send>;
+
+// Gets a newly opened brachiograph ready.
+type Setup = Box<dyn FnMut(&mut Serial) -> anyhow::Result<()> + Send>;
+
+// Gets told when the connection comes or goes.
+type OnChange = Box<dyn FnMut(&ConnectionState) + Send>;
+
+impl Reconnecting {
+    /// Connects to whichever brachiograph is plugged in, now or later.
+    pub fn new(transport: Transport) -> Reconnecting {
+        Reconnecting::with_watcher(Watcher::spawn(), move |name| Serial::open(name, transport))
+    }
+
+    /// Connects to the ports that `watcher` finds, opening them with `open`.
+    pub fn with_watcher(
+        watcher: Watcher,
+        open: impl FnMut(&str) -> anyhow::Result<Serial> + Send + 'static,
+    ) -> Reconnecting {
+        Reconnecting {
+            watcher,
+            open: Box::new(open),
+            serial: None,
+            ports: Vec::new(),
+            setup: None,
+            on_change: None,
+        }
+    }
+
+    /// Calls `setup` on the brachiograph every time that we connect to it, including when it
+    /// comes back after being unplugged or reset. This is where to upload the calibration
+    /// (see [`Serial::upload_calibration`]), the config and the joint limits, and to set the
+    /// page offset.
+    ///
+    /// If `setup` fails, the brachiograph is treated like one that couldn't be opened. The
+    /// current connection (if there is one) gets set up straight away.
+    pub fn on_connect(
+        &mut self,
+        setup: impl FnMut(&mut Serial) -> anyhow::Result<()> + Send + 'static,
+    ) -> anyhow::Result<()> {
+        let mut setup: Setup = Box::new(setup);
+        let ret = match &mut self.serial {
+            Some((_, serial)) => setup(serial),
+            None => Ok(()),
+        };
+        self.setup = Some(setup);
+        ret
+    }
+
+    /// Calls `callback` whenever the connection comes or goes.
+    pub fn on_change(&mut self, callback: impl FnMut(&ConnectionState) + Send + 'static) {
+        self.on_change = Some(Box::new(callback));
+    }
+
+    /// Whether we're connected, as of the last [`poll`](Reconnecting::poll).
+    pub fn state(&self) -> ConnectionState {
+        match &self.serial {
+            Some((name, _)) => ConnectionState::Connected(name.clone()),
+            None => ConnectionState::Disconnected,
+        }
+    }
+
+    /// Catches up with brachiographs being plugged in and unplugged, connecting to one if we
+    /// aren't connected.
+    ///
+    /// A port that can't be opened isn't tried again until it's been unplugged and plugged back
+    /// in.
+    pub fn poll(&mut self) -> ConnectionState {
+        while let Some(event) = self.watcher.try_next() {
+            match event {
+                PortEvent::Arrived(name) => {
+                    if !self.ports.contains(&name) {
+                        self.ports.push(name);
+                    }
+                }
+                PortEvent::Removed(name) => {
+                    self.ports.retain(|p| *p != name);
+                    if matches!(&self.serial, Some((current, _)) if *current == name) {
+                        log::info!("brachiograph on {name} went away");
+                        self.disconnect();
+                    }
+                }
+            }
+        }
+        while self.serial.is_none() && !self.ports.is_empty() {
+            let name = self.ports.remove(0);
+            let opened = (self.open)(&name).and_then(|mut serial| {
+                if let Some(setup) = &mut self.setup {
+                    setup(&mut serial)?;
+                }
+                Ok(serial)
+            });
+            match opened {
+                Ok(serial) => {
+                    log::info!("connected to brachiograph on {name}");
+                    self.serial = Some((name, serial));
+                    self.notify();
+                }
+                Err(e) => {
+                    log::warn!("failed to open port '{name}': {e:#}");
+                }
+            }
+        }
+        self.state()
+    }
+
+    /// The brachiograph, if there's one connected.
+    pub fn serial(&mut self) -> Option<&mut Serial> {
+        self.poll();
+        self.serial.as_mut().map(|(_, serial)| serial)
+    }
+
+    /// Does something with the brachiograph, if there's one connected.
+    ///
+    /// If `f` fails because of a problem with the connection, the connection gets opened again
+    /// before the error is returned.
+    pub fn with<T>(
+        &mut self,
+        f: impl FnOnce(&mut Serial) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let Some(serial) = self.serial() else {
+            bail!("no brachiograph is connected");
+        };
+        let ret = f(serial);
+        if let Err(e) = &ret {
+            if e.chain().any(|cause| cause.is::<std::io::Error>()) {
+                log::warn!("lost the connection to the brachiograph: {e}");
+                if let Some((name, _)) = self.disconnect() {
+                    // It's still plugged in (as far as we know), so try it again.
+                    self.ports.insert(0, name);
+                }
+                self.poll();
+            }
+        }
+        ret
+    }
+
+    fn disconnect(&mut self) -> Option<(String, Serial)> {
+        let ret = self.serial.take();
+        self.notify();
+        ret
+    }
+
+    fn notify(&mut self) {
+        let state = self.state();
+        if let Some(callback) = &mut self.on_change {
+            callback(&state);
+        }
+    }
+}
+
+impl crate::Plotter for Reconnecting {
+    fn send(&mut self, op: Op) -> anyhow::Result<Resp> {
+        self.with(|serial| serial.send(op))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{registration::PageOffset, transcript::Transcript, Plotter};
+
+    // A watcher of pretend ports, which are whatever's in the returned list.
+    fn fake_watcher() -> (Watcher, Arc<Mutex<Vec<String>>>) {
+        let ports = Arc::new(Mutex::new(Vec::new()));
+        let watcher = Watcher::spawn_with(Duration::from_millis(1), {
+            let ports = Arc::clone(&ports);
+            move || ports.lock().unwrap().clone()
+        });
+        (watcher, ports)
+    }
+
+    fn set_ports(ports: &Mutex<Vec<String>>, names: &[&str]) {
+        *ports.lock().unwrap() = names.iter().map(|s| s.to_string()).collect();
+    }
+
+    #[test]
+    fn watcher_sees_changes() {
+        let (watcher, ports) = fake_watcher();
+        let next = || watcher.next_timeout(Duration::from_secs(5));
+
+        set_ports(&ports, &["a"]);
+        assert_eq!(next(), Some(PortEvent::Arrived("a".into())));
+        set_ports(&ports, &["b"]);
+        assert_eq!(next(), Some(PortEvent::Removed("a".into())));
+        assert_eq!(next(), Some(PortEvent::Arrived("b".into())));
+        set_ports(&ports, &[]);
+        assert_eq!(next(), Some(PortEvent::Removed("b".into())));
+        assert_eq!(watcher.try_next(), None);
+    }
+
+    // Polls until the connection is in `state`.
+    fn wait_for(conn: &mut Reconnecting, state: ConnectionState) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while conn.poll() != state {
+            assert!(Instant::now() < deadline, "still {:?}", conn.state());
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn sets_up_every_connection() {
+        let (watcher, ports) = fake_watcher();
+        let mut conn = Reconnecting::with_watcher(watcher, |_| {
+            Ok(Serial::replay(&Transcript::default(), Transport::Postcard))
+        });
+        let setups = Arc::new(Mutex::new(0));
+        conn.on_connect({
+            let setups = Arc::clone(&setups);
+            move |serial| {
+                let mut setups = setups.lock().unwrap();
+                *setups += 1;
+                serial.set_page_offset(PageOffset(kurbo::Vec2::new(1.0, 2.0)));
+                // The third brachiograph can't be set up.
+                if *setups == 3 {
+                    bail!("no calibration for you");
+                }
+                Ok(())
+            }
+        })
+        .unwrap();
+        let connected = ConnectionState::Connected("a".into());
+
+        set_ports(&ports, &["a"]);
+        wait_for(&mut conn, connected.clone());
+        set_ports(&ports, &[]);
+        wait_for(&mut conn, ConnectionState::Disconnected);
+        set_ports(&ports, &["a"]);
+        wait_for(&mut conn, connected);
+        assert_eq!(*setups.lock().unwrap(), 2);
+        let offset = conn.serial().unwrap().page_offset();
+        assert_eq!(offset, PageOffset(kurbo::Vec2::new(1.0, 2.0)));
+
+        set_ports(&ports, &["b"]);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while *setups.lock().unwrap() < 3 {
+            assert!(Instant::now() < deadline);
+            conn.poll();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(conn.poll(), ConnectionState::Disconnected);
+    }
+
+    #[test]
+    fn reconnects() {
+        let (watcher, ports) = fake_watcher();
+        // The pretend brachiograph doesn't answer, so anything sent to it is a connection error.
+        let mut conn = Reconnecting::with_watcher(watcher, |_| {
+            Ok(Serial::replay(&Transcript::default(), Transport::Postcard))
+        });
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        conn.on_change({
+            let changes = Arc::clone(&changes);
+            move |state| changes.lock().unwrap().push(state.clone())
+        });
+        let connected = ConnectionState::Connected("a".into());
+
+        wait_for(&mut conn, ConnectionState::Disconnected);
+        set_ports(&ports, &["a"]);
+        wait_for(&mut conn, connected.clone());
+        set_ports(&ports, &[]);
+        wait_for(&mut conn, ConnectionState::Disconnected);
+        set_ports(&ports, &["a"]);
+        wait_for(&mut conn, connected.clone());
+
+        // A broken connection gets opened again.
+        assert!(conn.send(Op::PenUp).is_err());
+        assert_eq!(conn.state(), connected);
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![
+                connected.clone(),
+                ConnectionState::Disconnected,
+                connected.clone(),
+                ConnectionState::Disconnected,
+                connected,
+            ]
+        );
+
+        // Without a brachiograph, there's nothing to send to.
+        set_ports(&ports, &[]);
+        wait_for(&mut conn, ConnectionState::Disconnected);
+        assert!(conn.send(Op::PenUp).is_err());
+    }
+}
@@ -0,0 +1,632 @@
+//! A planned plot, ready to be sent to a brachiograph.
+
+use std::time::Duration;
+
+use brachiograph::{Fixed, Op, PauseReason};
+use kurbo::{BezPath, ParamCurve, Point, Rect, Shape};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    path::{from_device, segment_els},
+    profile::Settings,
+};
+
+/// An ordered list of ops, along with some information about where they came from.
+///
+/// Jobs assume that the pen starts out up, and that nothing is known about where it is until
+/// the first [`Op::MoveTo`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    /// A human-readable name, like the file that the job was made from.
+    pub name: Option<String>,
+    ops: Vec<Op>,
+    layers: Vec<Layer>,
+}
+
+/// A named run of ops in a [`Job`], like the ones that came from a single layer of an SVG file.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Layer {
+    pub name: String,
+    /// The index of the layer's first op. The layer goes up to the start of the next one.
+    pub start: usize,
+    /// The pen that the layer should be drawn with (like the color of the lines in an SVG
+    /// file), if it matters.
+    pub pen: Option<String>,
+}
+
+/// Some numbers describing a [`Job`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JobStats {
+    pub moves: usize,
+    pub pen_ups: usize,
+    pub pen_downs: usize,
+    /// The total length of the lines that get drawn.
+    pub draw_distance: f64,
+    /// The total distance the pen moves while it's up.
+    pub travel_distance: f64,
+    /// The bounding box of the points that the pen visits, if it visits any.
+    pub bounds: Option<Rect>,
+    /// How long the pen stops at corners (see [`Op::Dwell`]).
+    pub dwell: Duration,
+}
+
+impl JobStats {
+    // Adds a single op to the stats, using (and updating) the state in `cursor`.
+    fn add(&mut self, cursor: &mut Cursor, op: &Op) {
+        match op {
+            Op::PenUp => self.pen_ups += 1,
+            Op::PenDown => self.pen_downs += 1,
+            Op::Dwell(ms) => self.dwell += Duration::from_millis((*ms).into()),
+            _ => {}
+        }
+        let pen_down = cursor.pen_down;
+        if let Some(step) = cursor.step(op) {
+            self.moves += 1;
+            self.bounds = Some(match self.bounds {
+                Some(r) => r.union(step.bounds),
+                None => step.bounds,
+            });
+            if pen_down {
+                self.draw_distance += step.dist;
+            } else {
+                self.travel_distance += step.dist;
+            }
+        }
+    }
+
+    /// Estimates how long it takes to do everything counted in these stats.
+    pub fn duration(&self, settings: &Settings) -> Duration {
+        let moving = (self.draw_distance + self.travel_distance) / settings.speed;
+        Duration::from_secs_f64(moving)
+            + settings.pen_lift * (self.pen_ups + self.pen_downs) as u32
+            + self.dwell
+    }
+}
+
+/// The [`JobStats`] for one [`Layer`] of a job.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerStats {
+    pub name: String,
+    pub stats: JobStats,
+    /// How long the layer should take to plot.
+    pub duration: Duration,
+}
+
+// The state of the brachiograph while we walk through a job's ops.
+#[derive(Clone, Copy, Debug, Default)]
+struct Cursor {
+    pos: Option<Point>,
+    pen_down: bool,
+}
+
+// A move that the pen made.
+struct Step {
+    dist: f64,
+    bounds: Rect,
+}
+
+impl Cursor {
+    // Updates the state for a single op, returning some information about the move (if it was a
+    // move).
+    fn step(&mut self, op: &Op) -> Option<Step> {
+        match op {
+            Op::PenUp => self.pen_down = false,
+            Op::PenDown => self.pen_down = true,
+            // We don't know where the pen was before the first move, so we don't count how far
+            // it went.
+            Op::MoveTo(p) if self.pos.is_none() => {
+                let p = from_device(p);
+                self.pos = Some(p);
+                return Some(Step {
+                    dist: 0.0,
+                    bounds: Rect::from_points(p, p),
+                });
+            }
+            op => {
+                let segment = op.segment()?;
+                let from = self.pos.unwrap_or(Point::ORIGIN);
+                let mut path = BezPath::new();
+                path.move_to(from);
+                segment_els(from, &segment)
+                    .into_iter()
+                    .for_each(|el| path.push(el));
+                let end = path.segments().last().map_or(from, |seg| seg.end());
+                self.pos = Some(end);
+                return Some(Step {
+                    dist: path.perimeter(1e-3),
+                    bounds: path.bounding_box(),
+                });
+            }
+        }
+        None
+    }
+}
+
+impl Job {
+    pub fn new(ops: Vec<Op>) -> Job {
+        Job {
+            name: None,
+            ops,
+            layers: Vec::new(),
+        }
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Job {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Divides the job into layers. Any ops before the first layer's start count as part of
+    /// the first layer.
+    pub fn with_layers(mut self, layers: Vec<Layer>) -> Job {
+        self.layers = layers;
+        self
+    }
+
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// The layer that the op at `index` belongs to, if the job has layers.
+    pub fn layer_at(&self, index: usize) -> Option<&Layer> {
+        self.layers
+            .iter()
+            .rev()
+            .find(|layer| layer.start <= index)
+            .or(self.layers.first())
+    }
+
+    /// Returns a job that stops to have its pen changed whenever a layer needs a different pen
+    /// from the one before it.
+    ///
+    /// Each change is an [`Op::Pause`] at the start of the layer, numbering the pens in the
+    /// order that they're first used. Layers that don't say which pen they need are drawn with
+    /// whatever pen is in, and if the job only needs one pen then nothing changes.
+    pub fn with_pen_changes(self) -> Job {
+        let mut pens: Vec<&str> = Vec::new();
+        for pen in self.layers.iter().filter_map(|layer| layer.pen.as_deref()) {
+            if !pens.contains(&pen) {
+                pens.push(pen);
+            }
+        }
+        if pens.len() <= 1 {
+            return self;
+        }
+
+        let mut ops = Vec::with_capacity(self.ops.len() + self.layers.len());
+        let mut layers = Vec::with_capacity(self.layers.len());
+        let mut current = None;
+        for (i, layer) in self.layers.iter().enumerate() {
+            let start = if i == 0 { 0 } else { layer.start };
+            let end = self
+                .layers
+                .get(i + 1)
+                .map_or(self.ops.len(), |next| next.start);
+            layers.push(Layer {
+                start: ops.len(),
+                ..layer.clone()
+            });
+            if let Some(pen) = layer.pen.as_deref() {
+                if current.is_some_and(|cur| cur != pen) {
+                    // Unwrap: all the pens are in `pens`.
+                    let n = pens.iter().position(|&p| p == pen).unwrap();
+                    let n = u8::try_from(n).unwrap_or(u8::MAX);
+                    ops.push(Op::PenUp);
+                    ops.push(Op::Pause(PauseReason::ChangePen(n)));
+                }
+                current = Some(pen);
+            }
+            ops.extend_from_slice(&self.ops[start.min(end)..end]);
+        }
+        Job {
+            name: self.name,
+            ops,
+            layers,
+        }
+    }
+
+    /// Returns a job that goes home (with [`Op::Home`]) after every `every` strokes, before
+    /// moving on to the next one.
+    ///
+    /// Cheap servo horns slip a little over a long plot, and the slack in them depends on which
+    /// way they last turned. Going back to the same place every so often and coming at the next
+    /// stroke from there keeps the end of a plot lined up with the start. Zero turns this off.
+    ///
+    /// Home is wherever the brachiograph's own configuration puts it, so it doesn't get moved by
+    /// a [`PageOffset`](crate::registration::PageOffset), but the way back to the next stroke
+    /// does. The [stats](Job::stats) don't count the trips home, because the job doesn't know
+    /// where that is.
+    pub fn with_rezeroing(self, every: usize) -> Job {
+        let last_pen_down = self.ops.iter().rposition(|op| *op == Op::PenDown);
+        if every == 0 || last_pen_down.is_none() {
+            return self;
+        }
+
+        let mut ops = Vec::with_capacity(self.ops.len() + self.ops.len() / every);
+        let mut layers = self.layers.clone();
+        let mut next_layer = 0;
+        let mut strokes = 0;
+        for (i, op) in self.ops.iter().enumerate() {
+            while let Some(layer) = layers.get_mut(next_layer).filter(|l| l.start <= i) {
+                layer.start = ops.len();
+                next_layer += 1;
+            }
+            ops.push(op.clone());
+            match op {
+                Op::PenDown => strokes += 1,
+                // There's no point in going back after the last stroke.
+                Op::PenUp if strokes >= every && Some(i) < last_pen_down => {
+                    ops.push(Op::Home);
+                    strokes = 0;
+                }
+                _ => {}
+            }
+        }
+        for layer in &mut layers[next_layer..] {
+            layer.start = ops.len();
+        }
+        Job {
+            name: self.name,
+            ops,
+            layers,
+        }
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Op> {
+        self.ops.iter()
+    }
+
+    /// If the op at `index` is a [`Op::PenDown`], returns the number of ops in the stroke that
+    /// it starts (including the `PenDown` itself, and up to the next [`Op::PenUp`]).
+    pub fn stroke_len(&self, index: usize) -> Option<usize> {
+        if self.ops.get(index) != Some(&Op::PenDown) {
+            return None;
+        }
+        let rest = &self.ops[index..];
+        Some(
+            rest.iter()
+                .position(|op| *op == Op::PenUp)
+                .unwrap_or(rest.len()),
+        )
+    }
+
+    /// Returns a job that does everything that this one does, starting from op number `index`.
+    ///
+    /// This is for resuming an interrupted plot: the new job starts by putting the pen back
+    /// where it would have been just before `index`.
+    pub fn resume_from(&self, index: usize) -> Job {
+        let index = index.min(self.ops.len());
+        let mut cursor = Cursor::default();
+        for op in &self.ops[..index] {
+            cursor.step(op);
+        }
+
+        let mut ops = vec![Op::PenUp];
+        if let Some(pos) = cursor.pos {
+            ops.push(Op::MoveTo(brachiograph::Point {
+                x: Fixed::from_num(pos.x),
+                y: Fixed::from_num(pos.y),
+            }));
+        }
+        if cursor.pen_down {
+            ops.push(Op::PenDown);
+        }
+        // The layer that we're resuming in the middle of starts with the repositioning ops,
+        // and the ones that were already done are gone.
+        let prefix = ops.len();
+        let layers = self
+            .layers
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !matches!(self.layers.get(i + 1), Some(next) if next.start <= index))
+            .map(|(_, layer)| Layer {
+                name: layer.name.clone(),
+                start: if layer.start <= index {
+                    0
+                } else {
+                    layer.start - index + prefix
+                },
+                pen: layer.pen.clone(),
+            })
+            .collect();
+        ops.extend_from_slice(&self.ops[index..]);
+        Job {
+            name: self.name.clone(),
+            ops,
+            layers,
+        }
+    }
+
+    pub fn stats(&self) -> JobStats {
+        let mut ret = JobStats::default();
+        let mut cursor = Cursor::default();
+        for op in &self.ops {
+            ret.add(&mut cursor, op);
+        }
+        ret
+    }
+
+    /// Breaks down the [stats](Job::stats) by layer, for finding out which parts of a drawing
+    /// take the longest.
+    ///
+    /// The travel to the start of a layer counts as part of that layer. A job without any
+    /// layers is treated as a single layer, named after the job.
+    pub fn layer_stats(&self, settings: &Settings) -> Vec<LayerStats> {
+        let whole = [Layer {
+            name: self.name.clone().unwrap_or_default(),
+            start: 0,
+            pen: None,
+        }];
+        let layers = if self.layers.is_empty() {
+            &whole[..]
+        } else {
+            &self.layers[..]
+        };
+
+        let mut cursor = Cursor::default();
+        let mut ret = Vec::with_capacity(layers.len());
+        for (i, layer) in layers.iter().enumerate() {
+            let start = if i == 0 { 0 } else { layer.start };
+            let end = layers.get(i + 1).map_or(self.ops.len(), |next| next.start);
+            let mut stats = JobStats::default();
+            for op in &self.ops[start.min(end)..end] {
+                stats.add(&mut cursor, op);
+            }
+            ret.push(LayerStats {
+                name: layer.name.clone(),
+                stats,
+                duration: stats.duration(settings),
+            });
+        }
+        ret
+    }
+
+    /// Estimates how long the job will take to plot.
+    pub fn estimate_duration(&self, settings: &Settings) -> Duration {
+        self.stats().duration(settings)
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(postcard::to_stdvec(self)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Job> {
+        Ok(postcard::from_bytes(bytes)?)
+    }
+}
+
+impl From<Vec<Op>> for Job {
+    fn from(ops: Vec<Op>) -> Job {
+        Job::new(ops)
+    }
+}
+
+impl FromIterator<Op> for Job {
+    fn from_iter<I: IntoIterator<Item = Op>>(iter: I) -> Job {
+        Job::new(iter.into_iter().collect())
+    }
+}
+
+impl IntoIterator for Job {
+    type Item = Op;
+    type IntoIter = std::vec::IntoIter<Op>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Job {
+    type Item = &'a Op;
+    type IntoIter = std::slice::Iter<'a, Op>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mv(x: i32, y: i32) -> Op {
+        Op::MoveTo(brachiograph::Point {
+            x: Fixed::from_num(x),
+            y: Fixed::from_num(y),
+        })
+    }
+
+    fn square() -> Job {
+        Job::new(vec![
+            Op::PenUp,
+            mv(0, 5),
+            Op::PenDown,
+            mv(1, 5),
+            mv(1, 6),
+            mv(0, 6),
+            mv(0, 5),
+            Op::PenUp,
+            mv(3, 9),
+        ])
+    }
+
+    #[test]
+    fn stats() {
+        let stats = square().stats();
+        assert_eq!(stats.moves, 6);
+        assert_eq!(stats.pen_ups, 2);
+        assert_eq!(stats.pen_downs, 1);
+        assert_eq!(stats.draw_distance, 4.0);
+        assert_eq!(stats.travel_distance, 5.0);
+        assert_eq!(stats.bounds, Some(Rect::new(0.0, 5.0, 3.0, 9.0)));
+
+        let settings = Settings {
+            speed: 3.0,
+            pen_lift: Duration::from_millis(500),
+            ..Settings::default()
+        };
+        assert_eq!(
+            square().estimate_duration(&settings),
+            Duration::from_millis(4500)
+        );
+    }
+
+    #[test]
+    fn curve_stats() {
+        let job = Job::new(vec![
+            mv(2, 10),
+            Op::PenDown,
+            Op::ArcTo(
+                brachiograph::Point {
+                    x: Fixed::from_num(0),
+                    y: Fixed::from_num(10),
+                },
+                brachiograph::Angle::from_degrees(180),
+            ),
+        ]);
+        let stats = job.stats();
+        assert_eq!(stats.moves, 2);
+        assert!((stats.draw_distance - 2.0 * std::f64::consts::PI).abs() < 0.01);
+        let bounds = stats.bounds.unwrap();
+        assert!((bounds.max_y() - 12.0).abs() < 0.01, "{bounds:?}");
+        assert!((bounds.min_x() + 2.0).abs() < 0.01, "{bounds:?}");
+    }
+
+    #[test]
+    fn resume() {
+        let resumed = square().resume_from(5);
+        assert_eq!(
+            resumed.ops(),
+            &[
+                Op::PenUp,
+                mv(1, 6),
+                Op::PenDown,
+                mv(0, 6),
+                mv(0, 5),
+                Op::PenUp,
+                mv(3, 9)
+            ]
+        );
+        assert_eq!(square().resume_from(0).ops()[1..], square().ops()[..]);
+    }
+
+    // The square, and then a line in another layer.
+    fn layered() -> Job {
+        let mut ops = square().ops().to_vec();
+        ops.extend([Op::PenDown, mv(3, 10), Op::PenUp]);
+        Job::new(ops).with_layers(vec![
+            Layer {
+                name: "square".into(),
+                start: 0,
+                pen: None,
+            },
+            Layer {
+                name: "line".into(),
+                start: 7,
+                pen: None,
+            },
+        ])
+    }
+
+    #[test]
+    fn layer_stats() {
+        let settings = Settings::default();
+        let job = layered();
+        let layers = job.layer_stats(&settings);
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0].name, "square");
+        assert_eq!(layers[0].stats.draw_distance, 4.0);
+        assert_eq!(layers[0].stats.travel_distance, 0.0);
+        assert_eq!(layers[0].stats.pen_ups, 1);
+        // The line layer includes getting there from the end of the square.
+        assert_eq!(layers[1].stats.draw_distance, 1.0);
+        assert_eq!(layers[1].stats.travel_distance, 5.0);
+        assert_eq!(layers[1].stats.pen_ups + layers[1].stats.pen_downs, 3);
+
+        let total: Duration = layers.iter().map(|l| l.duration).sum();
+        assert_eq!(total, job.estimate_duration(&settings));
+
+        // Without layers, everything is in one.
+        let whole = square().with_name("square").layer_stats(&settings);
+        assert_eq!(whole.len(), 1);
+        assert_eq!(whole[0].name, "square");
+        assert_eq!(whole[0].stats, square().stats());
+    }
+
+    #[test]
+    fn resume_keeps_layers() {
+        let resumed = layered().resume_from(3);
+        assert_eq!(resumed.layers()[0].start, 0);
+        assert_eq!(resumed.layers()[1].start, 7);
+        assert_eq!(resumed.ops()[7], Op::PenUp);
+
+        let resumed = layered().resume_from(8);
+        assert_eq!(resumed.layers().len(), 1);
+        assert_eq!(resumed.layers()[0].name, "line");
+        assert_eq!(resumed.layers()[0].start, 0);
+    }
+
+    #[test]
+    fn pen_changes() {
+        assert_eq!(layered().with_pen_changes(), layered());
+
+        let mut layers = layered().layers().to_vec();
+        layers[0].pen = Some("black".into());
+        layers[1].pen = Some("red".into());
+        let job = Job::new(layered().ops().to_vec())
+            .with_layers(layers)
+            .with_pen_changes();
+        assert_eq!(job.len(), layered().len() + 2);
+        assert_eq!(job.layers()[1].start, 7);
+        assert_eq!(
+            job.ops()[7..9],
+            [Op::PenUp, Op::Pause(PauseReason::ChangePen(1))]
+        );
+        assert_eq!(job.layer_at(8).unwrap().name, "line");
+        assert_eq!(job.layer_at(3).unwrap().name, "square");
+        assert_eq!(job.stats().draw_distance, layered().stats().draw_distance);
+    }
+
+    #[test]
+    fn rezeroing() {
+        assert_eq!(layered().with_rezeroing(0), layered());
+        // There's only one stroke after the first, so there's nothing to come back for.
+        assert_eq!(layered().with_rezeroing(2), layered());
+
+        let job = layered().with_rezeroing(1);
+        assert_eq!(job.len(), layered().len() + 1);
+        // The pen comes back to the next stroke from home.
+        assert_eq!(
+            job.ops()[7..11],
+            [Op::PenUp, Op::Home, mv(3, 9), Op::PenDown]
+        );
+        assert_eq!(job.layers()[1].start, 7);
+        assert_eq!(job.stats().draw_distance, layered().stats().draw_distance);
+    }
+
+    #[test]
+    fn stroke_len() {
+        let job = square();
+        assert_eq!(job.stroke_len(2), Some(5));
+        assert_eq!(job.stroke_len(3), None);
+        assert_eq!(Job::new(vec![Op::PenDown, mv(0, 0)]).stroke_len(0), Some(2));
+    }
+
+    #[test]
+    fn round_trip() {
+        let job = square().with_name("square");
+        assert_eq!(Job::from_bytes(&job.to_bytes().unwrap()).unwrap(), job);
+    }
+}
@@ -1,43 +1,40 @@
-use anyhow::bail;
 use brachiograph::{Angle, Fixed, Op, Resp};
 use brachiologo::TurtleCmd;
-use kurbo::{Point, Rect, Vec2};
-use std::io::{BufRead, BufReader};
+use kurbo::{Affine, BezPath, PathEl, Point, Vec2};
 
-use serialport::{SerialPort, SerialPortType};
+pub mod accuracy;
+#[cfg(feature = "async")]
+mod async_serial;
+pub mod calibration;
+pub mod finish;
+pub mod font;
+pub mod gcode;
+pub mod hotplug;
+mod job;
+pub mod lod;
+pub mod path;
+pub mod profile;
+pub mod raster;
+pub mod registration;
+mod serial;
+pub mod session;
+pub mod sim;
+#[cfg(feature = "svg")]
+pub mod svg;
+pub mod tile;
+pub mod transcript;
+pub mod wet;
 
-const VENDOR_ID: u16 = 0xca6d;
-const PRODUCT_ID: u16 = 0xba6d;
+#[cfg(feature = "async")]
+pub use async_serial::{AsyncSerial, Cancelled, Response};
+pub use job::{Job, JobStats, Layer, LayerStats};
+pub use serial::{BatchError, JournalEntry, Progress, Serial, Transport, DEFAULT_RESPONSE_TIMEOUT};
 
-fn detect_port() -> Option<Box<dyn SerialPort>> {
-    let ports = serialport::available_ports().ok()?;
-    for port in ports {
-        let SerialPortType::UsbPort(usb_info) = port.port_type else {
-            continue;
-        };
-        log::debug!("found usbserial port {usb_info:?}");
-
-        if usb_info.vid == VENDOR_ID && usb_info.pid == PRODUCT_ID {
-            match serialport::new(&port.port_name, 9600)
-                // I'm not completely sure what the implications of this timeout value are,
-                // but on linux read_line returns immediately, while on windows it doesn't
-                // return until the timeout is up. So keep the timeout small.
-                .timeout(std::time::Duration::from_millis(50))
-                .open()
-            {
-                Ok(port) => {
-                    return Some(port);
-                }
-                Err(e) => {
-                    log::warn!("failed to open port '{}': {}", port.port_name, e);
-                }
-            }
-        } else {
-            log::info!("skipping usb-serial {:?}", usb_info);
-        }
-    }
-
-    None
+/// Something that carries out ops: either a real brachiograph ([`Serial`]) or a pretend one
+/// ([`sim::Simulator`]).
+pub trait Plotter {
+    /// Sends a single op, and waits for the response.
+    fn send(&mut self, op: Op) -> anyhow::Result<Resp>;
 }
 
 /*
@@ -65,97 +62,319 @@ impl Op {
 }
 */
 
-pub struct Serial {
-    write: Box<dyn SerialPort>,
-    read: BufReader<Box<dyn SerialPort>>,
+/// Where the turtle is, and which way it's facing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TurtlePose {
+    pub pos: Point,
+    /// The direction the turtle is facing, measured counter-clockwise from the positive x axis.
+    pub heading: Angle,
 }
 
-impl Serial {
-    pub fn detect() -> Option<Self> {
-        detect_port().map(|s| Serial {
-            read: BufReader::with_capacity(128, s.try_clone().unwrap()),
-            write: s,
-        })
+impl Default for TurtlePose {
+    fn default() -> Self {
+        TurtlePose {
+            pos: Point::ORIGIN,
+            heading: Angle::from_degrees(90),
+        }
     }
+}
+
+/// The result of running a turtle program without a brachiograph.
+#[derive(Clone, Debug, Default)]
+pub struct Preview {
+    /// The ops that would be sent to the brachiograph.
+    pub ops: Vec<Op>,
+    /// Where the turtle ended up.
+    pub final_pose: TurtlePose,
+    /// Where the turtle was each time it lifted its pen.
+    ///
+    /// Together with `final_pose`, this is enough for a UI to draw turtle arrows
+    /// at the ends of strokes.
+    pub pen_up_poses: Vec<TurtlePose>,
+}
 
-    pub fn name(&self) -> Option<String> {
-        self.write.name()
+impl Preview {
+    /// The lines that the turtle drew, with one subpath per stroke.
+    pub fn drawing(&self) -> BezPath {
+        // The turtle starts at the origin with its pen down.
+        path::from_ops(&self.ops, Point::ORIGIN, true)
     }
 
-    pub fn send(&mut self, op: Op) -> anyhow::Result<Resp> {
-        loop {
-            let msg = postcard::to_stdvec_cobs(&op)?;
-            self.write.write_all(&msg)?;
-
-            let mut read = self.read.fill_buf()?.to_vec();
-            let (msg, remaining) = postcard::take_from_bytes_cobs(&mut read)?;
-            let remaining_len = remaining.len();
-            drop(remaining);
-            self.read.consume(read.len() - remaining_len);
-            match msg {
-                Resp::QueueFull => {
-                    std::thread::sleep(std::time::Duration::from_millis(500));
-                    continue;
-                }
-                Resp::Nack => bail!("error (TODO: better message)"),
-                other => return Ok(other),
-            }
-        }
+    /// Draws what the turtle drew as an SVG document, in turtle coordinates.
+    pub fn to_svg(&self) -> String {
+        path::to_svg_document(&self.drawing())
     }
 }
 
-pub fn interpret<'input>(steps: &[TurtleCmd]) -> Vec<Op> {
-    let mut pos = Point::ORIGIN;
-    let mut angle = Angle::from_degrees(90);
-    let mut ret = Vec::new();
+pub fn interpret(steps: &[TurtleCmd]) -> Vec<Op> {
+    preview(steps).ops
+}
 
-    let mv = |pt: Point| {
-        Op::MoveTo(brachiograph::Point {
-            x: Fixed::from_num(pt.x),
-            y: Fixed::from_num(pt.y),
-        })
-    };
+/// Like [`interpret`], but for a turtle program that is still running (see
+/// [`brachiologo::Env::run_streaming`]). The ops for each command come out as soon as it
+/// arrives.
+pub fn interpret_iter(steps: impl IntoIterator<Item = TurtleCmd>) -> impl Iterator<Item = Op> {
+    let mut turtle = Turtle::default();
+    steps.into_iter().flat_map(move |step| {
+        let mut ops = Vec::new();
+        turtle.step(step, &mut ops);
+        ops
+    })
+}
 
+pub fn preview(steps: &[TurtleCmd]) -> Preview {
+    let mut turtle = Turtle::default();
+    let mut ret = Preview::default();
     for step in steps.iter().copied() {
+        if matches!(step, TurtleCmd::PenUp) {
+            ret.pen_up_poses.push(turtle.pose);
+        }
+        turtle.step(step, &mut ret.ops);
+    }
+    ret.final_pose = turtle.pose;
+    ret
+}
+
+// The state that's needed for turning turtle commands into ops.
+struct Turtle {
+    pose: TurtlePose,
+    pen_down: bool,
+    // The pen state that the ops so far leave the brachiograph in, if they've said.
+    sent_pen: Option<bool>,
+}
+
+impl Default for Turtle {
+    fn default() -> Self {
+        Turtle {
+            pose: TurtlePose::default(),
+            pen_down: true,
+            sent_pen: None,
+        }
+    }
+}
+
+// Adds a pen op to `ops`, unless the pen is already that way.
+fn pen_op(sent_pen: &mut Option<bool>, down: bool, ops: &mut Vec<Op>) {
+    if *sent_pen != Some(down) {
+        *sent_pen = Some(down);
+        ops.push(if down { Op::PenDown } else { Op::PenUp });
+    }
+}
+
+impl Turtle {
+    // Carries out a turtle command, adding the ops for it to `ops`.
+    fn step(&mut self, step: TurtleCmd, ops: &mut Vec<Op>) {
+        let mv = |pt: Point| {
+            Op::MoveTo(brachiograph::Point {
+                x: Fixed::from_num(pt.x),
+                y: Fixed::from_num(pt.y),
+            })
+        };
+        let Turtle {
+            pose,
+            pen_down,
+            sent_pen,
+        } = self;
+        let TurtlePose { pos, heading } = pose;
         match step {
             brachiologo::TurtleCmd::Arc { degrees, radius } => {
                 // Arc does not move the turtle or change the heading.
-                let start = pos + Vec2::from_angle(angle.radians().to_num()) * radius;
-                ret.push(Op::PenUp);
-                ret.push(mv(start));
-                ret.push(Op::PenDown);
+                let start = *pos + Vec2::from_angle(heading.radians().to_num()) * radius;
+                pen_op(sent_pen, false, ops);
+                ops.push(mv(start));
+                pen_op(sent_pen, true, ops);
                 for i in (0..=(degrees as i32)).step_by(10) {
                     // Arc goes clockwise
-                    let angle = angle - Angle::from_degrees(i);
-                    let p = pos + Vec2::from_angle(angle.radians().to_num()) * radius;
-                    ret.push(mv(p));
+                    let angle = *heading - Angle::from_degrees(i);
+                    let p = *pos + Vec2::from_angle(angle.radians().to_num()) * radius;
+                    ops.push(mv(p));
                 }
-                ret.push(Op::PenUp);
-                ret.push(mv(pos));
-                ret.push(Op::PenDown);
+                pen_op(sent_pen, false, ops);
+                ops.push(mv(*pos));
+                pen_op(sent_pen, true, ops);
             }
             brachiologo::TurtleCmd::Forward(dist) => {
-                pos += Vec2::from_angle(angle.radians().to_num()) * dist;
-                ret.push(mv(pos));
+                *pos += Vec2::from_angle(heading.radians().to_num()) * dist;
+                ops.push(mv(*pos));
             }
             brachiologo::TurtleCmd::Back(dist) => {
-                pos -= Vec2::from_angle(angle.radians().to_num()) * dist;
-                ret.push(mv(pos));
+                *pos -= Vec2::from_angle(heading.radians().to_num()) * dist;
+                ops.push(mv(*pos));
             }
             brachiologo::TurtleCmd::Left(ang) => {
-                angle += Angle::from_degrees(ang);
+                *heading += Angle::from_degrees(ang);
             }
             brachiologo::TurtleCmd::Right(ang) => {
-                angle += Angle::from_degrees(ang);
+                // The heading is counter-clockwise, and right turns are clockwise.
+                *heading -= Angle::from_degrees(ang);
+            }
+            brachiologo::TurtleCmd::SetPos { x, y } => {
+                *pos = Point::new(x, y);
+                ops.push(mv(*pos));
+            }
+            brachiologo::TurtleCmd::SetHeading(h) => {
+                // Logo headings go clockwise from straight up.
+                *heading = Angle::from_degrees(90.0 - h);
             }
             brachiologo::TurtleCmd::PenUp => {
-                ret.push(Op::PenUp);
+                *pen_down = false;
+                pen_op(sent_pen, false, ops);
             }
             brachiologo::TurtleCmd::PenDown => {
-                ret.push(Op::PenDown);
+                *pen_down = true;
+                pen_op(sent_pen, true, ops);
+            }
+            brachiologo::TurtleCmd::Glyph { c, height } => {
+                // The letter stands up in the direction that the turtle is facing, so the line
+                // of text goes off to the turtle's right. Letters get written even if the pen
+                // is up, like labels in other Logos.
+                let right = heading.radians().to_num::<f64>() - std::f64::consts::FRAC_PI_2;
+                let transform = Affine::translate(pos.to_vec2()) * Affine::rotate(right);
+                for el in (transform * font::glyph(c, height)).elements() {
+                    match *el {
+                        PathEl::MoveTo(p) => {
+                            pen_op(sent_pen, false, ops);
+                            ops.push(mv(p));
+                            pen_op(sent_pen, true, ops);
+                        }
+                        PathEl::LineTo(p) => ops.push(mv(p)),
+                        _ => unreachable!("glyphs are made of lines"),
+                    }
+                }
+                *pos += Vec2::from_angle(right) * font::advance(height);
+                pen_op(sent_pen, false, ops);
+                ops.push(mv(*pos));
+                if *pen_down {
+                    pen_op(sent_pen, true, ops);
+                }
             }
         }
     }
+}
 
-    ret
+#[cfg(test)]
+mod tests {
+    use kurbo::Shape;
+
+    use super::*;
+
+    fn assert_pose(pose: TurtlePose, x: f64, y: f64, heading: f64) {
+        assert!((pose.pos.x - x).abs() < 1e-2, "{pose:?}");
+        assert!((pose.pos.y - y).abs() < 1e-2, "{pose:?}");
+        assert!(
+            (pose.heading.degrees().to_num::<f64>() - heading).abs() < 1e-2,
+            "{pose:?}"
+        );
+    }
+
+    #[test]
+    fn preview_poses() {
+        use TurtleCmd::*;
+        let preview = preview(&[Forward(10.0), Right(90.0), PenUp, Forward(5.0), Left(45.0)]);
+        assert_eq!(preview.pen_up_poses.len(), 1);
+        assert_pose(preview.pen_up_poses[0], 0.0, 10.0, 0.0);
+        assert_pose(preview.final_pose, 5.0, 10.0, 45.0);
+    }
+
+    #[test]
+    fn right_and_left_turns() {
+        use TurtleCmd::*;
+        // Starting out facing up, `right 90` faces the positive x axis.
+        let right = preview(&[Right(90.0), Forward(10.0)]);
+        assert_pose(right.final_pose, 10.0, 0.0, 0.0);
+        let left = preview(&[Left(90.0), Forward(10.0)]);
+        assert_pose(left.final_pose, -10.0, 0.0, 180.0);
+    }
+
+    #[test]
+    fn preview_absolute() {
+        use TurtleCmd::*;
+        let preview = preview(&[
+            SetPos { x: 3.0, y: 4.0 },
+            SetHeading(90.0),
+            Forward(2.0),
+            PenUp,
+            SetPos { x: 0.0, y: 0.0 },
+            SetHeading(0.0),
+        ]);
+        assert_pose(preview.pen_up_poses[0], 5.0, 4.0, 0.0);
+        assert_pose(preview.final_pose, 0.0, 0.0, 90.0);
+        // One stroke out to (3, 4) and then to the right, and nothing drawn on the way back.
+        assert_eq!(preview.drawing().segments().count(), 2);
+    }
+
+    #[test]
+    fn preview_text() {
+        use TurtleCmd::*;
+        let hi = [
+            Glyph {
+                c: 'H',
+                height: 2.0,
+            },
+            Glyph {
+                c: 'I',
+                height: 2.0,
+            },
+        ];
+        let preview = preview(&hi);
+        // The text goes to the right of the turtle, which starts out facing up.
+        assert_pose(preview.final_pose, 2.0 * font::advance(2.0), 0.0, 90.0);
+        let bbox = preview.drawing().bounding_box();
+        assert!((bbox.height() - 2.0).abs() < 1e-2, "{bbox:?}");
+        assert!(bbox.min_x() > -1e-2 && bbox.min_y() > -1e-2, "{bbox:?}");
+
+        // Turned around, the text is upside down.
+        let preview = super::preview(&[Right(180.0), hi[0], hi[1]]);
+        assert_pose(preview.final_pose, -2.0 * font::advance(2.0), 0.0, -90.0);
+        let bbox = preview.drawing().bounding_box();
+        assert!(bbox.max_y() < 1e-2, "{bbox:?}");
+    }
+
+    #[test]
+    fn pen_only_changes_when_it_needs_to() {
+        use TurtleCmd::*;
+        let mv = |x: i32, y: i32| {
+            Op::MoveTo(brachiograph::Point {
+                x: Fixed::from_num(x),
+                y: Fixed::from_num(y),
+            })
+        };
+        let ops = interpret(&[
+            PenUp,
+            SetPos { x: 1.0, y: 2.0 },
+            PenUp,
+            SetPos { x: 3.0, y: 4.0 },
+            PenDown,
+            PenDown,
+            SetPos { x: 5.0, y: 4.0 },
+        ]);
+        assert_eq!(ops, [Op::PenUp, mv(1, 2), mv(3, 4), Op::PenDown, mv(5, 4)]);
+    }
+
+    #[test]
+    fn preview_svg() {
+        use TurtleCmd::*;
+        let preview = preview(&[
+            Forward(10.0),
+            PenUp,
+            Right(90.0),
+            Forward(5.0),
+            PenDown,
+            Back(5.0),
+        ]);
+        let drawing = preview.drawing();
+        // Two strokes: one up the y axis, and one along the top.
+        assert_eq!(drawing.segments().count(), 2);
+        let bbox = drawing.bounding_box();
+        assert!((bbox.width() - 5.0).abs() < 1e-2, "{bbox:?}");
+        assert!((bbox.height() - 10.0).abs() < 1e-2, "{bbox:?}");
+
+        let svg = preview.to_svg();
+        assert!(svg.starts_with("<svg"));
+        assert_eq!(
+            svg.matches(" M").count() + svg.matches("\"M").count(),
+            2,
+            "{svg}"
+        );
+    }
 }
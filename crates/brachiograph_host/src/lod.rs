@@ -0,0 +1,143 @@
+//! Cheaper versions of big drawings, for previewing them.
+//!
+//! Generative drawings can have hundreds of thousands of lines, and when a preview is zoomed
+//! out most of them are smaller than a pixel. A [`Lod`] keeps a few simplified copies of the
+//! drawing, each coarser than the last, and hands out the coarsest one that still looks right
+//! at the preview's scale. The full drawing is kept for plotting.
+
+use kurbo::{BezPath, PathEl, Point};
+
+use crate::path;
+
+/// How far (in pixels) a simplified drawing is allowed to stray from the real one.
+pub const PIXEL_TOLERANCE: f64 = 0.5;
+
+/// Each level is allowed to stray this many times further than the one before it.
+const LEVEL_RATIO: f64 = 4.0;
+
+/// Drawings (and levels) with fewer elements than this aren't worth simplifying any further.
+const MIN_ELEMENTS: usize = 1000;
+
+/// A drawing, along with simplified copies of it for drawing at smaller scales.
+#[derive(Clone, Debug, Default)]
+pub struct Lod {
+    full: BezPath,
+    // The tolerance (in drawing units) of each simplified copy, from the finest to the
+    // coarsest.
+    levels: Vec<(f64, BezPath)>,
+}
+
+impl Lod {
+    /// Makes the simplified copies of `drawing`. The finest of them strays at most `finest`
+    /// from it, so this should be [`PIXEL_TOLERANCE`] pixels at the preview's largest scale.
+    pub fn new(drawing: BezPath, finest: f64) -> Lod {
+        let mut levels = Vec::new();
+        let mut len = drawing.elements().len();
+        let mut tolerance = finest;
+        while len >= MIN_ELEMENTS && tolerance > 0.0 {
+            let level = decimate(&drawing, tolerance);
+            let level_len = level.elements().len();
+            // If simplifying hardly helped, then coarser levels won't help much either.
+            if level_len as f64 > len as f64 * 0.9 {
+                break;
+            }
+            len = level_len;
+            levels.push((tolerance, level));
+            tolerance *= LEVEL_RATIO;
+        }
+        Lod {
+            full: drawing,
+            levels,
+        }
+    }
+
+    /// The whole drawing, for plotting.
+    pub fn full(&self) -> &BezPath {
+        &self.full
+    }
+
+    /// The coarsest copy of the drawing that looks right when drawn at `pixels_per_unit`.
+    pub fn at_scale(&self, pixels_per_unit: f64) -> &BezPath {
+        let allowed = PIXEL_TOLERANCE / pixels_per_unit;
+        self.levels
+            .iter()
+            .rev()
+            .find(|(tolerance, _)| *tolerance <= allowed)
+            .map_or(&self.full, |(_, level)| level)
+    }
+}
+
+// Simplifies `drawing` without letting it stray by more than about `tolerance`.
+//
+// Strokes that start where the previous one ended (give or take the tolerance) get joined onto
+// it, and then the lines are simplified (see `path::simplify`).
+fn decimate(drawing: &BezPath, tolerance: f64) -> BezPath {
+    let half = tolerance / 2.0;
+    let mut joined = BezPath::new();
+    let mut last: Option<Point> = None;
+    drawing.flatten(half, |el| match el {
+        PathEl::MoveTo(p) if last.is_some_and(|q| q.distance(p) <= half) => {}
+        PathEl::MoveTo(p) | PathEl::LineTo(p) => {
+            joined.push(el);
+            last = Some(p);
+        }
+        // Flattening only makes moves and lines.
+        _ => {}
+    });
+    path::simplify(&joined, half)
+}
+
+#[cfg(test)]
+mod tests {
+    use kurbo::{Shape, Vec2};
+
+    use super::*;
+
+    // A spiral made of lots of short lines, with a gap after every few of them.
+    fn spiral() -> BezPath {
+        let mut ret = BezPath::new();
+        for i in 0..20_000 {
+            let t = i as f64 / 100.0;
+            let p = Point::ORIGIN + Vec2::from_angle(t) * (1.0 + t / 10.0);
+            if i % 10 == 0 {
+                ret.move_to(p);
+            } else {
+                ret.line_to(p);
+            }
+        }
+        ret
+    }
+
+    #[test]
+    fn levels() {
+        let drawing = spiral();
+        let full_len = drawing.elements().len();
+        let lod = Lod::new(drawing.clone(), 0.001);
+        assert_eq!(lod.full(), &drawing);
+        assert!(lod.levels.len() >= 2, "{} levels", lod.levels.len());
+
+        // Zoomed in a long way, it's all there.
+        assert_eq!(lod.at_scale(PIXEL_TOLERANCE / 0.0001), &drawing);
+        // Further out, there's less and less of it.
+        let near = lod.at_scale(PIXEL_TOLERANCE / 0.001).elements().len();
+        let far = lod.at_scale(PIXEL_TOLERANCE / 0.1).elements().len();
+        assert!(far < near && near < full_len, "{far} {near} {full_len}");
+
+        // But it still looks the same, give or take the tolerance.
+        for (tolerance, level) in &lod.levels {
+            let (a, b) = (drawing.bounding_box(), level.bounding_box());
+            assert!((a.min_x() - b.min_x()).abs() <= *tolerance, "{a:?} {b:?}");
+            assert!((a.max_y() - b.max_y()).abs() <= *tolerance, "{a:?} {b:?}");
+        }
+    }
+
+    #[test]
+    fn small_drawings() {
+        let mut drawing = BezPath::new();
+        drawing.move_to((0.0, 0.0));
+        drawing.line_to((1.0, 0.0));
+        let lod = Lod::new(drawing.clone(), 0.01);
+        assert!(lod.levels.is_empty());
+        assert_eq!(lod.at_scale(1.0), &drawing);
+    }
+}
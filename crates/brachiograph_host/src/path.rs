@@ -0,0 +1,621 @@
+//! Tidying up paths before they get turned into ops.
+
+use std::time::Duration;
+
+use brachiograph::{Fixed, Op, Segment};
+use kurbo::{Affine, Arc, BezPath, Line, ParamCurveNearest, PathEl, Point, Rect, Shape, Vec2};
+
+/// Segments longer than this are never smoothed by [`smooth`].
+pub const SMOOTH_MAX_SEGMENT: f64 = 0.5;
+
+/// Corners sharper than this (in degrees) are never smoothed by [`smooth`].
+pub const SMOOTH_MAX_TURN: f64 = 30.0;
+
+/// Corners sharper than this (in degrees) get a dwell from [`dwell_at_corners`].
+pub const DWELL_MIN_TURN: f64 = 60.0;
+
+/// Collects the points of each subpath of a flattened path.
+fn subpaths(path: &BezPath) -> Vec<Vec<Point>> {
+    let mut ret: Vec<Vec<Point>> = Vec::new();
+    for el in path.elements() {
+        match *el {
+            PathEl::MoveTo(p) => ret.push(vec![p]),
+            PathEl::LineTo(p) => match ret.last_mut() {
+                Some(sub) => sub.push(p),
+                None => ret.push(vec![p]),
+            },
+            _ => panic!("expected a flattened path"),
+        }
+    }
+    ret
+}
+
+/// Long subpaths are given to the function in [`map_subpaths`] in pieces of at most this many
+/// elements.
+pub const MAP_SUBPATHS_CHUNK: usize = 64;
+
+/// Applies `f` to each subpath of a path, for using [`simplify`] or [`smooth`] on a path that
+/// is still being worked out.
+///
+/// Only a little of the path is held at a time: each subpath gets passed to `f` once the next
+/// one starts (or the path ends), and then what `f` made of it comes out. Subpaths that are
+/// longer than [`MAP_SUBPATHS_CHUNK`] are passed to `f` a piece at a time, with each piece
+/// starting where `f` left the previous one, so nothing gets simplified or smoothed across the
+/// joins between the pieces.
+pub fn map_subpaths<I, F>(els: I, f: F) -> MapSubpaths<I::IntoIter, F>
+where
+    I: IntoIterator<Item = PathEl>,
+    F: FnMut(&BezPath) -> BezPath,
+{
+    MapSubpaths {
+        els: els.into_iter().peekable(),
+        f,
+        out: Vec::new().into_iter(),
+        start: Point::ORIGIN,
+        cont: None,
+    }
+}
+
+/// The iterator returned by [`map_subpaths`].
+pub struct MapSubpaths<I: Iterator, F> {
+    els: std::iter::Peekable<I>,
+    f: F,
+    out: std::vec::IntoIter<PathEl>,
+    // Where the current subpath started, for closing it.
+    start: Point,
+    // If the current subpath was too long to do in one go, where its next piece starts.
+    cont: Option<Point>,
+}
+
+impl<I, F> Iterator for MapSubpaths<I, F>
+where
+    I: Iterator<Item = PathEl>,
+    F: FnMut(&BezPath) -> BezPath,
+{
+    type Item = PathEl;
+
+    fn next(&mut self) -> Option<PathEl> {
+        loop {
+            if let Some(el) = self.out.next() {
+                return Some(el);
+            }
+            let cont = self.cont.take();
+            let mut sub = BezPath::new();
+            match cont {
+                Some(p) => sub.move_to(p),
+                None => {
+                    let el = self.els.next()?;
+                    self.start = end_point(&el).unwrap_or(self.start);
+                    sub.push(el);
+                }
+            }
+            while sub.elements().len() < MAP_SUBPATHS_CHUNK {
+                match self.els.next_if(|el| !matches!(el, PathEl::MoveTo(_))) {
+                    // A later piece doesn't start where the subpath does.
+                    Some(PathEl::ClosePath) if cont.is_some() => sub.line_to(self.start),
+                    Some(el) => sub.push(el),
+                    None => break,
+                }
+            }
+            let more = matches!(self.els.peek(), Some(el) if !matches!(el, PathEl::MoveTo(_)));
+
+            let mut out = (self.f)(&sub).elements().to_vec();
+            // The pen is already at the start of a later piece, and lifting it would leave a gap.
+            if cont.is_some() && matches!(out.first(), Some(PathEl::MoveTo(_))) {
+                out.remove(0);
+            }
+            if more {
+                let last = sub.elements().last().and_then(end_point);
+                self.cont = out.last().and_then(end_point).or(last).or(cont);
+            }
+            self.out = out.into_iter();
+        }
+    }
+}
+
+/// Turns a path into ops, lifting the pen between subpaths.
+///
+/// Curves are sent as curves, so flatten the path first if you want to send only lines.
+pub fn to_ops(path: &BezPath) -> Vec<Op> {
+    els_to_ops(path).collect()
+}
+
+/// Like [`to_ops`], but for path elements that are still being worked out. Each element is
+/// turned into ops as soon as it arrives.
+pub fn els_to_ops(els: impl IntoIterator<Item = PathEl>) -> impl Iterator<Item = Op> {
+    let mut start = Point::ORIGIN;
+    els.into_iter().flat_map(move |el| match el {
+        PathEl::MoveTo(p) => {
+            start = p;
+            vec![Op::PenUp, point_op(p), Op::PenDown]
+        }
+        PathEl::LineTo(p) => vec![point_op(p)],
+        PathEl::QuadTo(c, p) => vec![Op::QuadTo(to_device(c), to_device(p))],
+        PathEl::CurveTo(c1, c2, p) => {
+            vec![Op::CurveTo(to_device(c1), to_device(c2), to_device(p))]
+        }
+        PathEl::ClosePath => vec![point_op(start)],
+    })
+}
+
+/// Drops ops that do nothing because they repeat the op just before them, like a second
+/// `PenUp` in a row, or a move to where the last move went.
+///
+/// These are mostly left behind by squashing a drawing into a smaller space, where lots of
+/// points can end up in the same place.
+pub fn dedup(ops: impl IntoIterator<Item = Op>) -> impl Iterator<Item = Op> {
+    let mut prev: Option<Op> = None;
+    ops.into_iter().filter(move |op| {
+        let repeat =
+            matches!(op, Op::PenUp | Op::PenDown | Op::MoveTo(_)) && prev.as_ref() == Some(op);
+        prev = Some(op.clone());
+        !repeat
+    })
+}
+
+/// The path elements that draw `segment`, if it starts at `from`.
+pub fn segment_els(from: Point, segment: &Segment) -> Vec<PathEl> {
+    match *segment {
+        Segment::Line(p) => vec![PathEl::LineTo(from_device(&p))],
+        Segment::Quad(c, p) => vec![PathEl::QuadTo(from_device(&c), from_device(&p))],
+        Segment::Cubic(c1, c2, p) => vec![PathEl::CurveTo(
+            from_device(&c1),
+            from_device(&c2),
+            from_device(&p),
+        )],
+        Segment::Arc { center, sweep } => {
+            let center = from_device(&center);
+            let v = from - center;
+            let arc = Arc {
+                center,
+                radii: Vec2::new(v.hypot(), v.hypot()),
+                start_angle: v.atan2(),
+                sweep_angle: sweep.radians().to_num(),
+                x_rotation: 0.0,
+            };
+            arc.append_iter(0.01).collect()
+        }
+    }
+}
+
+/// Collects the lines drawn by some ops into a path, with one subpath per pen-down stroke.
+///
+/// `start` and `pen_down` describe the state of the brachiograph before the first op.
+pub fn from_ops(ops: &[Op], start: Point, pen_down: bool) -> BezPath {
+    ops_to_els(ops.iter().cloned(), start, pen_down).collect()
+}
+
+/// Like [`from_ops`], but for ops that are still being worked out. The elements for each op
+/// come out as soon as it arrives.
+pub fn ops_to_els(
+    ops: impl IntoIterator<Item = Op>,
+    start: Point,
+    pen_down: bool,
+) -> impl Iterator<Item = PathEl> {
+    let mut pos = start;
+    let mut pen_down = pen_down;
+    let mut in_stroke = false;
+    ops.into_iter().flat_map(move |op| {
+        let mut ret = Vec::new();
+        match op {
+            Op::PenUp => {
+                pen_down = false;
+                in_stroke = false;
+            }
+            Op::PenDown => pen_down = true,
+            op => {
+                if let Some(segment) = op.segment() {
+                    let els = segment_els(pos, &segment);
+                    if pen_down {
+                        if !in_stroke {
+                            ret.push(PathEl::MoveTo(pos));
+                            in_stroke = true;
+                        }
+                        ret.extend_from_slice(&els);
+                    }
+                    pos = els.last().and_then(end_point).unwrap_or(pos);
+                }
+            }
+        }
+        ret
+    })
+}
+
+/// Transforms each of the paths by a common scaling and translation,
+/// so that the resulting paths all lie in `rect`.
+pub fn fit(paths: &mut [BezPath], rect: Rect) {
+    if paths.is_empty() {
+        return;
+    }
+    let mut bbox = paths[0].bounding_box();
+    for p in &paths[1..] {
+        bbox = bbox.union(p.bounding_box());
+    }
+    let transform = Affine::translate(-bbox.center().to_vec2());
+    let scale = (rect.height() / bbox.height()).min(rect.width() / bbox.width());
+    // If everything is in a single point, there's nothing to scale.
+    let scale = if scale.is_finite() { scale } else { 1.0 };
+    let transform = Affine::scale(scale) * transform;
+    let transform = Affine::translate(rect.center().to_vec2()) * transform;
+    for path in paths {
+        path.apply_affine(transform);
+    }
+}
+
+/// Makes an SVG document that draws a path (like the ones from [`from_ops`]).
+///
+/// The document is just big enough to show the path, and it's flipped vertically, because SVG
+/// is y-down and brachiograph is y-up.
+pub fn to_svg_document(path: &BezPath) -> String {
+    let bbox = if path.is_empty() {
+        Rect::ZERO
+    } else {
+        path.bounding_box()
+    };
+    // Scale the margins and the line width to the drawing, because turtle programs and the
+    // brachiograph use very different units.
+    let size = bbox.width().max(bbox.height()).max(1.0);
+    let view = bbox.inflate(size / 20.0, size / 20.0);
+    format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="{} {} {} {}">
+  <path d="{}" transform="scale(1 -1)" fill="none" stroke="black" stroke-width="{}" stroke-linecap="round" stroke-linejoin="round"/>
+</svg>
+"#,
+        view.x0,
+        -view.y1,
+        view.width(),
+        view.height(),
+        path.to_svg(),
+        size / 200.0,
+    )
+}
+
+fn end_point(el: &PathEl) -> Option<Point> {
+    match *el {
+        PathEl::MoveTo(p) | PathEl::LineTo(p) | PathEl::QuadTo(_, p) | PathEl::CurveTo(_, _, p) => {
+            Some(p)
+        }
+        PathEl::ClosePath => None,
+    }
+}
+
+pub(crate) fn from_device(p: &brachiograph::Point) -> Point {
+    (*p).into()
+}
+
+fn to_device(p: Point) -> brachiograph::Point {
+    p.into()
+}
+
+fn point_op(p: Point) -> Op {
+    Op::MoveTo(to_device(p))
+}
+
+/// Smooths out curves that were approximated by lots of short line segments.
+///
+/// Runs of segments that are shorter than [`SMOOTH_MAX_SEGMENT`] and that meet at angles
+/// gentler than [`SMOOTH_MAX_TURN`] are replaced by a Catmull-Rom spline through their
+/// endpoints, which is then flattened again to within `tolerance`. Everything else (including
+/// sharp corners) is left alone.
+pub fn smooth(path: &BezPath, tolerance: f64) -> BezPath {
+    let mut curved = BezPath::new();
+    for pts in subpaths(path) {
+        let n = pts.len();
+        let short = |i: usize| (pts[i + 1] - pts[i]).hypot() <= SMOOTH_MAX_SEGMENT;
+        // The tangent at point `i`, if it's in the middle of a smooth run.
+        let tangent = |i: usize| -> Option<Vec2> {
+            if i == 0 || i + 1 >= n || !short(i - 1) || !short(i) {
+                return None;
+            }
+            let (a, b) = (pts[i] - pts[i - 1], pts[i + 1] - pts[i]);
+            let turn = a.cross(b).atan2(a.dot(b)).abs().to_degrees();
+            (turn < SMOOTH_MAX_TURN).then(|| (pts[i + 1] - pts[i - 1]) / 2.0)
+        };
+
+        curved.move_to(pts[0]);
+        for i in 1..n {
+            match (tangent(i - 1), tangent(i)) {
+                (None, None) => curved.line_to(pts[i]),
+                (t0, t1) => {
+                    let chord = pts[i] - pts[i - 1];
+                    let c0 = pts[i - 1] + t0.unwrap_or(chord) / 3.0;
+                    let c1 = pts[i] - t1.unwrap_or(chord) / 3.0;
+                    curved.curve_to(c0, c1, pts[i]);
+                }
+            }
+        }
+    }
+
+    let mut ret = BezPath::new();
+    curved.flatten(tolerance, |el| ret.push(el));
+    ret
+}
+
+/// Drops points from a flattened path (one containing only `MoveTo` and `LineTo`) if they are
+/// within `epsilon` of the line joining their neighbors.
+///
+/// This uses the Ramer-Douglas-Peucker algorithm on each subpath.
+pub fn simplify(path: &BezPath, epsilon: f64) -> BezPath {
+    if epsilon <= 0.0 {
+        return path.clone();
+    }
+
+    fn flush(points: &mut Vec<Point>, epsilon: f64, out: &mut BezPath) {
+        let Some(&first) = points.first() else {
+            return;
+        };
+        let mut keep = vec![false; points.len()];
+        keep[0] = true;
+        keep[points.len() - 1] = true;
+        rdp(points, epsilon, &mut keep);
+
+        out.move_to(first);
+        for (p, _) in points.iter().zip(&keep).skip(1).filter(|(_, k)| **k) {
+            out.line_to(*p);
+        }
+        points.clear();
+    }
+
+    let mut ret = BezPath::new();
+    for mut points in subpaths(path) {
+        flush(&mut points, epsilon, &mut ret);
+    }
+    ret
+}
+
+/// Splits the lines of a flattened path (one containing only `MoveTo` and `LineTo`) into equal
+/// pieces, none of them longer than `max_len`. Zero leaves the path alone.
+///
+/// The firmware keeps the pen on the line between the ends of each move, by working out where
+/// it should be at every tick. This is for brachiographs whose firmware doesn't do that, and
+/// instead turns the servos evenly from one end of a move to the other: that makes long lines
+/// bow, and shorter pieces bow less.
+pub fn split_lines(path: &BezPath, max_len: f64) -> BezPath {
+    if max_len <= 0.0 {
+        return path.clone();
+    }
+    let mut ret = BezPath::new();
+    let mut last = Point::ORIGIN;
+    for el in path.elements() {
+        match *el {
+            PathEl::MoveTo(p) => {
+                ret.move_to(p);
+                last = p;
+            }
+            PathEl::LineTo(p) => {
+                let pieces = ((p - last).hypot() / max_len).ceil() as usize;
+                for i in 1..pieces {
+                    ret.line_to(last.lerp(p, i as f64 / pieces as f64));
+                }
+                ret.line_to(p);
+                last = p;
+            }
+            _ => panic!("expected a flattened path"),
+        }
+    }
+    ret
+}
+
+fn rdp(points: &[Point], epsilon: f64, keep: &mut [bool]) {
+    if points.len() <= 2 {
+        return;
+    }
+    let line = Line::new(points[0], points[points.len() - 1]);
+    let (idx, dist_sq) = points[1..points.len() - 1]
+        .iter()
+        .map(|p| line.nearest(*p, 1e-6).distance_sq)
+        .enumerate()
+        .fold(
+            (0, 0.0),
+            |best, (i, d)| if d > best.1 { (i, d) } else { best },
+        );
+    if dist_sq > epsilon * epsilon {
+        let idx = idx + 1;
+        keep[idx] = true;
+        rdp(&points[..=idx], epsilon, &mut keep[..=idx]);
+        rdp(&points[idx..], epsilon, &mut keep[idx..]);
+    }
+}
+
+/// Adds an [`Op::Dwell`] of `dwell` at each sharp corner (sharper than [`DWELL_MIN_TURN`]) that
+/// gets drawn, so that the arms stop wobbling before the pen sets off in the new direction. A
+/// zero `dwell` leaves the ops alone.
+pub fn dwell_at_corners(
+    ops: impl IntoIterator<Item = Op>,
+    dwell: Duration,
+) -> impl Iterator<Item = Op> {
+    let ms = dwell.as_millis().min(u16::MAX.into()) as u16;
+    let mut pos: Option<brachiograph::Point> = None;
+    let mut pen_down = false;
+    // Which way the pen was going at the end of the last move, if it was drawing.
+    let mut heading: Option<Vec2> = None;
+    ops.into_iter().flat_map(move |op| {
+        match op {
+            Op::PenUp | Op::PenDown => {
+                pen_down = op == Op::PenDown;
+                heading = None;
+            }
+            _ => {}
+        }
+        let mut corner = false;
+        if let Some(from) = pos {
+            if let Some(segment) = op.segment_from(from) {
+                let start = heading_vec(segment.start_heading(from));
+                if let (true, Some(prev)) = (pen_down, heading) {
+                    corner = prev.cross(start).atan2(prev.dot(start)).abs().to_degrees()
+                        > DWELL_MIN_TURN;
+                }
+                let end = heading_vec(segment.end_heading(from));
+                // A move that goes nowhere doesn't change the heading.
+                if pen_down && end != Vec2::ZERO {
+                    heading = Some(end);
+                }
+                pos = Some(segment.end(from));
+            }
+        } else if let Op::MoveTo(p) = op {
+            pos = Some(p);
+        }
+        let dwell = (corner && ms > 0).then_some(Op::Dwell(ms));
+        dwell.into_iter().chain(std::iter::once(op))
+    })
+}
+
+fn heading_vec((x, y): (Fixed, Fixed)) -> Vec2 {
+    Vec2::new(x.to_num(), y.to_num())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_collinear_points() {
+        let mut path = BezPath::new();
+        path.move_to((0.0, 0.0));
+        path.line_to((1.0, 0.01));
+        path.line_to((2.0, 0.0));
+        path.line_to((2.0, 2.0));
+        path.move_to((5.0, 5.0));
+        path.line_to((6.0, 6.0));
+
+        let mut expected = BezPath::new();
+        expected.move_to((0.0, 0.0));
+        expected.line_to((2.0, 0.0));
+        expected.line_to((2.0, 2.0));
+        expected.move_to((5.0, 5.0));
+        expected.line_to((6.0, 6.0));
+
+        assert_eq!(simplify(&path, 0.1), expected);
+        assert_eq!(simplify(&path, 0.001), path);
+    }
+
+    #[test]
+    fn smooth_circle_keeps_corners() {
+        // A 36-gon approximating a circle of radius 2 around the origin, followed by a square.
+        let mut path = BezPath::new();
+        path.move_to((2.0, 0.0));
+        for i in 1..=36 {
+            let theta = (i as f64 * 10.0).to_radians();
+            path.line_to((2.0 * theta.cos(), 2.0 * theta.sin()));
+        }
+        path.move_to((10.0, 0.0));
+        path.line_to((10.3, 0.0));
+        path.line_to((10.3, 0.3));
+        path.line_to((10.0, 0.3));
+
+        let smoothed = smooth(&path, 0.001);
+        let subs = subpaths(&smoothed);
+        assert_eq!(subs.len(), 2);
+        // The first and last segments don't have tangents at both ends, so they stay
+        // closer to straight; ignore them.
+        let worst = subs[0]
+            .iter()
+            .filter(|p| p.y.atan2(p.x).to_degrees().abs() > 10.0)
+            .map(|p| (p.to_vec2().hypot() - 2.0).abs())
+            .fold(0.0, f64::max);
+        // The polygon itself is up to 2 * (1 - cos(5 deg)) = 0.0076 away from the circle.
+        assert!(worst < 0.002, "{worst}");
+        assert_eq!(
+            subs[1],
+            vec![
+                Point::new(10.0, 0.0),
+                Point::new(10.3, 0.0),
+                Point::new(10.3, 0.3),
+                Point::new(10.0, 0.3)
+            ]
+        );
+    }
+
+    #[test]
+    fn dwells_at_sharp_corners() {
+        let mv = |x, y| point_op(Point::new(x, y));
+        // A square, with a gentle bend in its first side.
+        let mut ops = vec![Op::PenUp, mv(0.0, 8.0), Op::PenDown];
+        ops.extend([mv(0.5, 8.1), mv(1.0, 8.0), mv(1.0, 9.0)]);
+        ops.extend([mv(0.0, 9.0), mv(0.0, 8.0)]);
+        // Going back the way it came is a corner too, but travelling with the pen up isn't.
+        ops.extend([Op::PenUp, mv(3.0, 8.0), Op::PenDown]);
+        ops.extend([mv(4.0, 8.0), mv(3.5, 8.0)]);
+        let dwell = Duration::from_millis(150);
+
+        let dwelling: Vec<Op> = dwell_at_corners(ops.clone(), dwell).collect();
+        let is_dwell = |op: &Op| matches!(op, Op::Dwell(150));
+        assert_eq!(dwelling.iter().filter(|op| is_dwell(op)).count(), 4);
+        // They come just before the move that turns the corner.
+        let first = dwelling.iter().position(is_dwell);
+        assert_eq!(first, Some(5));
+        assert!(matches!(dwelling[6], Op::MoveTo(p) if p.x == 1 && p.y == 9));
+        assert!(is_dwell(&dwelling[dwelling.len() - 2]));
+
+        let none = dwell_at_corners(ops.clone(), Duration::ZERO);
+        assert_eq!(none.count(), ops.len());
+    }
+
+    #[test]
+    fn one_subpath_at_a_time() {
+        let mut path = BezPath::new();
+        path.move_to((0.0, 0.0));
+        path.line_to((1.0, 0.01));
+        path.line_to((2.0, 0.0));
+        path.move_to((5.0, 5.0));
+        path.line_to((6.0, 6.0));
+        path.move_to((7.0, 7.0));
+
+        let mut calls = 0;
+        let lazy: BezPath = map_subpaths(path.iter(), |sub| {
+            calls += 1;
+            simplify(sub, 0.1)
+        })
+        .collect();
+        assert_eq!(lazy, simplify(&path, 0.1));
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn long_subpaths_come_out_in_pieces() {
+        let n = 10 * MAP_SUBPATHS_CHUNK;
+        let mut path = BezPath::new();
+        path.move_to((0.0, 0.0));
+        for i in 1..n {
+            path.line_to((i as f64, (i % 2) as f64));
+        }
+
+        // Something comes out before the stroke is finished.
+        let pulled = std::cell::Cell::new(0);
+        let els = path.iter().inspect(|_| pulled.set(pulled.get() + 1));
+        let mut lazy = map_subpaths(els, |sub| sub.clone());
+        assert_eq!(lazy.next(), Some(PathEl::MoveTo(Point::new(0.0, 0.0))));
+        assert!(pulled.get() <= MAP_SUBPATHS_CHUNK + 1);
+
+        // And it's all still there, with the pen only going down once.
+        let mut rest = vec![PathEl::MoveTo(Point::new(0.0, 0.0))];
+        rest.extend(lazy);
+        assert_eq!(rest, path.elements());
+    }
+
+    #[test]
+    fn dedup_repeats() {
+        let mv = |x: f64| point_op(Point::new(x, 8.0));
+        let ops = [
+            Op::PenUp,
+            Op::PenUp,
+            mv(0.0),
+            mv(0.0),
+            Op::PenDown,
+            mv(1.0),
+            Op::GetQueue,
+            Op::GetQueue,
+            mv(1.0),
+        ];
+        let expected = [
+            Op::PenUp,
+            mv(0.0),
+            Op::PenDown,
+            mv(1.0),
+            Op::GetQueue,
+            Op::GetQueue,
+            mv(1.0),
+        ];
+        assert_eq!(dedup(ops).collect::<Vec<_>>(), expected);
+    }
+}
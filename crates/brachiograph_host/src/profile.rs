@@ -0,0 +1,179 @@
+//! Named bundles of settings that trade plotting time for quality.
+//!
+//! There are a lot of knobs that affect how long a plot takes and how good it looks. Rather
+//! than making users learn all of them, we offer a few presets.
+
+use std::time::Duration;
+
+use anyhow::bail;
+use brachiograph::{Fixed, Op, DEFAULT_ANGLE_INTERPOLATION};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Profile {
+    /// Fast and rough, for checking that a drawing fits on the page.
+    Draft,
+    #[default]
+    Normal,
+    /// Slow and careful.
+    Fine,
+}
+
+/// The settings that a [`Profile`] bundles together.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    /// Drawing speed, in brachiograph units per second.
+    pub speed: f64,
+    /// How long to wait for the pen to go up or down.
+    pub pen_lift: Duration,
+    /// The maximum distance between a curve and the line segments that approximate it.
+    pub flatten_tolerance: f64,
+    /// Points that are closer than this to the line joining their neighbors get dropped.
+    pub simplify_epsilon: f64,
+    /// Lines longer than this get split into pieces (see [`crate::path::split_lines`]). Zero
+    /// leaves them for the firmware to keep straight, which is what the current firmware does.
+    pub max_line: f64,
+    /// Moves at most this long interpolate between the joint angles at their ends instead of
+    /// following their segments exactly, which is less work for the firmware (see
+    /// [`Op::SetAngleInterpolation`]). `None` keeps them exact.
+    pub angle_interpolation: Option<f64>,
+    /// How long to stop at sharp corners (see [`crate::path::dwell_at_corners`]), so that the
+    /// arms stop wobbling before the pen sets off in a new direction.
+    pub corner_dwell: Duration,
+}
+
+impl Settings {
+    /// The ops that tell a brachiograph to use these settings.
+    pub fn ops(&self) -> [Op; 3] {
+        let pen_lift = self.pen_lift.as_millis().min(u16::MAX.into()) as u16;
+        [
+            Op::SetSpeed(Fixed::from_num(self.speed)),
+            Op::SetPenLift(pen_lift),
+            Op::SetAngleInterpolation(self.angle_interpolation.map(Fixed::from_num)),
+        ]
+    }
+}
+
+impl Profile {
+    pub const ALL: [Profile; 3] = [Profile::Draft, Profile::Normal, Profile::Fine];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Profile::Draft => "draft",
+            Profile::Normal => "normal",
+            Profile::Fine => "fine",
+        }
+    }
+
+    pub fn settings(self) -> Settings {
+        match self {
+            Profile::Draft => Settings {
+                speed: 8.0,
+                pen_lift: Duration::from_millis(400),
+                flatten_tolerance: 0.2,
+                simplify_epsilon: 0.1,
+                max_line: 0.0,
+                angle_interpolation: Some(DEFAULT_ANGLE_INTERPOLATION.to_num()),
+                corner_dwell: Duration::ZERO,
+            },
+            Profile::Normal => Settings {
+                speed: 4.0,
+                pen_lift: Duration::from_millis(800),
+                flatten_tolerance: 0.05,
+                simplify_epsilon: 0.02,
+                max_line: 0.0,
+                angle_interpolation: None,
+                corner_dwell: Duration::ZERO,
+            },
+            Profile::Fine => Settings {
+                speed: 2.0,
+                pen_lift: Duration::from_millis(1000),
+                flatten_tolerance: 0.01,
+                simplify_epsilon: 0.0,
+                max_line: 0.0,
+                angle_interpolation: None,
+                corner_dwell: Duration::from_millis(100),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for Profile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Profile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Profile> {
+        match Profile::ALL.into_iter().find(|p| p.name() == s) {
+            Some(p) => Ok(p),
+            None => bail!("unknown profile {s:?} (expected draft, normal, or fine)"),
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Profile::default().settings()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{path, Job};
+
+    fn mv(x: i32, y: i32) -> Op {
+        Op::MoveTo(brachiograph::Point {
+            x: Fixed::from_num(x),
+            y: Fixed::from_num(y),
+        })
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for p in Profile::ALL {
+            assert_eq!(p.name().parse::<Profile>().unwrap(), p);
+        }
+        assert!("fast".parse::<Profile>().is_err());
+    }
+
+    #[test]
+    fn angle_interpolation_is_only_for_drafts() {
+        let ops = Profile::Draft.settings().ops();
+        assert_eq!(
+            ops[2],
+            Op::SetAngleInterpolation(Some(DEFAULT_ANGLE_INTERPOLATION))
+        );
+        // Otherwise it gets turned off, in case an earlier job turned it on.
+        for p in [Profile::Normal, Profile::Fine] {
+            assert_eq!(p.settings().ops()[2], Op::SetAngleInterpolation(None));
+        }
+    }
+
+    #[test]
+    fn fine_plots_dwell_at_corners() {
+        assert!(Profile::Fine.settings().corner_dwell > Duration::ZERO);
+        // A right angle in a fine plot waits at the corner, and the estimate knows it.
+        let ops = [
+            Op::PenUp,
+            mv(0, 8),
+            Op::PenDown,
+            mv(1, 8),
+            mv(1, 9),
+            Op::PenUp,
+        ];
+        let plain: Job = ops.iter().cloned().collect();
+        for p in Profile::ALL {
+            let settings = p.settings();
+            let job: Job = path::dwell_at_corners(ops.clone(), settings.corner_dwell).collect();
+            assert_eq!(job.stats().dwell, settings.corner_dwell);
+            assert_eq!(
+                job.estimate_duration(&settings),
+                plain.estimate_duration(&settings) + settings.corner_dwell
+            );
+        }
+    }
+}
@@ -0,0 +1,437 @@
+//! Turning photos (and other pictures made of pixels) into pen strokes.
+//!
+//! A pen only makes one shade, so the grays come from how much ink goes on each part of the
+//! page: darker parts of the picture get more lines, or wigglier ones. [`Style`] says which
+//! lines. Parts of the picture that are nearly white get no ink at all, so that the pen isn't
+//! dragged across the background.
+
+use anyhow::bail;
+use kurbo::{BezPath, Point, Rect, Vec2};
+
+use crate::{accuracy::Photo, path, profile::Settings, Job};
+
+/// How to turn shades of gray into lines.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Style {
+    /// Rows of zigzags, back and forth across the page, that are taller where the picture is
+    /// darker.
+    #[default]
+    Zigzag,
+    /// One long spiral out from the middle of the picture, that wiggles more where the picture is
+    /// darker.
+    Spiral,
+    /// Straight hatching lines in up to four directions, with more directions where the picture
+    /// is darker.
+    Crosshatch,
+}
+
+impl Style {
+    pub const ALL: [Style; 3] = [Style::Zigzag, Style::Spiral, Style::Crosshatch];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Style::Zigzag => "zigzag",
+            Style::Spiral => "spiral",
+            Style::Crosshatch => "crosshatch",
+        }
+    }
+}
+
+impl std::fmt::Display for Style {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Style {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Style> {
+        match Style::ALL.into_iter().find(|style| style.name() == s) {
+            Some(style) => Ok(style),
+            None => bail!("unknown halftone style {s:?} (expected zigzag, spiral, or crosshatch)"),
+        }
+    }
+}
+
+/// How to draw a picture with lines.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Halftone {
+    pub style: Style,
+    /// How far apart the rows (or the turns of the spiral, or the hatching lines) are, in
+    /// brachiograph units. Smaller is more detailed, and takes longer.
+    pub spacing: f64,
+}
+
+impl Default for Halftone {
+    fn default() -> Self {
+        Halftone {
+            style: Style::default(),
+            spacing: 0.2,
+        }
+    }
+}
+
+// Parts of the picture that are lighter than this (with 0 for white and 1 for black) get no ink.
+const LIGHTEST: f64 = 0.15;
+
+// The directions of the hatching lines (in degrees counter-clockwise from the x axis), and how
+// dark the picture has to be for each of them to get drawn.
+const HATCHING: [(f64, f64); 4] = [(0.0, LIGHTEST), (90.0, 0.4), (45.0, 0.6), (-45.0, 0.8)];
+
+impl Halftone {
+    /// The strokes that draw `photo`, scaled to fit in `rect` (without stretching it).
+    pub fn strokes(&self, photo: &Photo, rect: Rect) -> BezPath {
+        let Some(shades) = Shades::new(photo, rect) else {
+            return BezPath::new();
+        };
+        // The lines would never get anywhere.
+        if self.spacing.is_nan() || self.spacing <= 0.0 {
+            return BezPath::new();
+        }
+        let mut pen = Pen::default();
+        match self.style {
+            Style::Zigzag => self.zigzag(&shades, &mut pen),
+            Style::Spiral => self.spiral(&shades, &mut pen),
+            Style::Crosshatch => self.crosshatch(&shades, &mut pen),
+        }
+        pen.finish()
+    }
+
+    // Each row goes the opposite way to the one before, so that the pen doesn't have to go all the
+    // way back between them.
+    fn zigzag(&self, shades: &Shades, pen: &mut Pen) {
+        let bounds = shades.bounds;
+        let step = self.spacing / 2.0;
+        let rows = (bounds.height() / self.spacing).floor() as usize;
+        let cols = (bounds.width() / step).floor() as usize;
+        for row in 0..rows {
+            let y = bounds.y1 - self.spacing * (row as f64 + 0.5);
+            for col in 0..=cols {
+                let col = if row % 2 == 0 { col } else { cols - col };
+                let p = Point::new(bounds.x0 + step * col as f64, y);
+                // Alternate up and down, by up to half the spacing so that neighboring rows only
+                // just meet where the picture is black.
+                let side = if col % 2 == 0 { 1.0 } else { -1.0 };
+                pen.wiggle(p, Vec2::new(0.0, side * self.spacing / 2.0), shades);
+            }
+            pen.lift();
+        }
+    }
+
+    fn spiral(&self, shades: &Shades, pen: &mut Pen) {
+        let bounds = shades.bounds;
+        let center = bounds.center();
+        let max_radius = (bounds.size().to_vec2() / 2.0).hypot();
+        let step = self.spacing / 2.0;
+        // The radius grows by `spacing` every turn.
+        let per_radian = self.spacing / std::f64::consts::TAU;
+        let mut theta = 0.0f64;
+        let mut side = 1.0;
+        loop {
+            // Start half a turn out, so that the middle isn't a tangle.
+            let radius = self.spacing / 2.0 + per_radian * theta;
+            if radius > max_radius {
+                break;
+            }
+            let out = Vec2::from_angle(theta);
+            let p = center + out * radius;
+            if bounds.contains(p) {
+                pen.wiggle(p, out * side * self.spacing / 2.0, shades);
+            } else {
+                pen.lift();
+            }
+            side = -side;
+            theta += step / radius;
+        }
+        pen.lift();
+    }
+
+    fn crosshatch(&self, shades: &Shades, pen: &mut Pen) {
+        let bounds = shades.bounds;
+        let step = self.spacing / 2.0;
+        let corners = [
+            Point::new(bounds.x0, bounds.y0),
+            Point::new(bounds.x1, bounds.y0),
+            Point::new(bounds.x0, bounds.y1),
+            Point::new(bounds.x1, bounds.y1),
+        ];
+        for (degrees, threshold) in HATCHING {
+            let along = Vec2::from_angle(degrees.to_radians());
+            let across = Vec2::new(-along.y, along.x);
+            // How far the picture goes along and across the lines.
+            let range = |dir: Vec2| {
+                let dots = corners.map(|c| c.to_vec2().dot(dir));
+                let min = dots.iter().copied().fold(f64::INFINITY, f64::min);
+                let max = dots.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                (min, max)
+            };
+            let (along_min, along_max) = range(along);
+            let (across_min, across_max) = range(across);
+            let lines = ((across_max - across_min) / self.spacing).floor() as usize;
+            let steps = ((along_max - along_min) / step).ceil() as usize;
+            for line in 0..lines {
+                let offset = across_min + self.spacing * (line as f64 + 0.5);
+                let mut start = None;
+                let mut end = None;
+                for i in 0..=steps {
+                    let i = if line % 2 == 0 { i } else { steps - i };
+                    let p = (across * offset + along * (along_min + step * i as f64)).to_point();
+                    let dark = bounds.contains(p) && shades.darkness(p, step) > threshold;
+                    if dark {
+                        start.get_or_insert(p);
+                        end = Some(p);
+                    } else if let (Some(start), Some(end)) = (start.take(), end.take()) {
+                        pen.line(start, end);
+                    }
+                }
+                if let (Some(start), Some(end)) = (start, end) {
+                    pen.line(start, end);
+                }
+            }
+        }
+    }
+}
+
+/// Turns a picture into a job that draws it, scaled to fit in `rect`.
+pub fn to_job(
+    photo: &Photo,
+    rect: Rect,
+    halftone: &Halftone,
+    settings: &Settings,
+) -> anyhow::Result<Job> {
+    let strokes = halftone.strokes(photo, rect);
+    if strokes.is_empty() {
+        bail!("the picture is too light (or too small) to draw anything");
+    }
+    // The zigzags are already as simple as they can be, and simplifying them would flatten the
+    // small ones in the light parts of the picture.
+    let ops = path::to_ops(&path::split_lines(&strokes, settings.max_line));
+    Ok(path::dwell_at_corners(ops, settings.corner_dwell).collect())
+}
+
+// How dark the picture is, looked up by where it goes on the page.
+struct Shades {
+    // Where the picture goes on the page, in brachiograph units.
+    bounds: Rect,
+    // How many brachiograph units there are to a pixel.
+    scale: f64,
+    width: usize,
+    height: usize,
+    // The total darkness (from 0 to 255 for each pixel) of every rectangle of pixels that starts
+    // at the top left. This has an extra row and column of zeros, at the top and the left.
+    sums: Vec<u64>,
+}
+
+impl Shades {
+    // Returns `None` if the picture has no pixels.
+    fn new(photo: &Photo, rect: Rect) -> Option<Shades> {
+        let (width, height) = (photo.width, photo.height);
+        if width == 0 || height == 0 || photo.luma.len() < width * height {
+            return None;
+        }
+        let scale = (rect.width() / width as f64).min(rect.height() / height as f64);
+        let size = Vec2::new(width as f64, height as f64) * scale;
+        let bounds = Rect::from_center_size(rect.center(), size.to_size());
+
+        let mut sums = vec![0u64; (width + 1) * (height + 1)];
+        for y in 0..height {
+            let mut row = 0;
+            for x in 0..width {
+                row += u64::from(255 - photo.luma[y * width + x]);
+                sums[(y + 1) * (width + 1) + x + 1] = sums[y * (width + 1) + x + 1] + row;
+            }
+        }
+        Some(Shades {
+            bounds,
+            scale,
+            width,
+            height,
+            sums,
+        })
+    }
+
+    // The average darkness (from 0 for white to 1 for black) of a square around `p`, that goes
+    // out by `radius` on each side. Outside the picture, it's white.
+    fn darkness(&self, p: Point, radius: f64) -> f64 {
+        if !self.bounds.contains(p) {
+            return 0.0;
+        }
+        // In pixels, with y going down.
+        let x = (p.x - self.bounds.x0) / self.scale;
+        let y = (self.bounds.y1 - p.y) / self.scale;
+        let r = radius / self.scale;
+        // Always take at least the pixel that `p` is in.
+        let range = |v: f64, len: usize| {
+            let lo = ((v - r).floor().max(0.0) as usize).min(len - 1);
+            let hi = ((v + r).ceil() as usize).clamp(lo + 1, len);
+            (lo, hi)
+        };
+        let (x0, x1) = range(x, self.width);
+        let (y0, y1) = range(y, self.height);
+        let at = |x: usize, y: usize| self.sums[y * (self.width + 1) + x];
+        let total = at(x1, y1) + at(x0, y0) - at(x0, y1) - at(x1, y0);
+        total as f64 / (255 * (x1 - x0) * (y1 - y0)) as f64
+    }
+}
+
+// Collects the strokes, leaving out the ones that are only a single point.
+#[derive(Default)]
+struct Pen {
+    path: BezPath,
+    stroke: Vec<Point>,
+}
+
+impl Pen {
+    fn draw(&mut self, p: Point) {
+        self.stroke.push(p);
+    }
+
+    fn lift(&mut self) {
+        if self.stroke.len() >= 2 {
+            self.path.move_to(self.stroke[0]);
+            for &p in &self.stroke[1..] {
+                self.path.line_to(p);
+            }
+        }
+        self.stroke.clear();
+    }
+
+    fn line(&mut self, from: Point, to: Point) {
+        self.lift();
+        self.draw(from);
+        self.draw(to);
+        self.lift();
+    }
+
+    // Draws to `p`, moved out towards `out` by however dark the picture is there (but staying in
+    // the picture), or lifts the pen if it's too light.
+    fn wiggle(&mut self, p: Point, out: Vec2, shades: &Shades) {
+        let darkness = shades.darkness(p, out.hypot());
+        if darkness < LIGHTEST {
+            self.lift();
+        } else {
+            let q = p + out * darkness;
+            let b = shades.bounds;
+            self.draw(Point::new(q.x.clamp(b.x0, b.x1), q.y.clamp(b.y0, b.y1)));
+        }
+    }
+
+    fn finish(mut self) -> BezPath {
+        self.lift();
+        self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use kurbo::{ParamCurveArclen, PathEl, PathSeg};
+
+    use super::*;
+
+    const RECT: Rect = Rect::new(-8.0, 5.0, 8.0, 13.0);
+
+    // A picture that's black on the left half and white on the right.
+    fn half_black(width: usize, height: usize) -> Photo {
+        let luma = (0..width * height)
+            .map(|i| if i % width < width / 2 { 0 } else { 255 })
+            .collect();
+        Photo {
+            width,
+            height,
+            luma,
+        }
+    }
+
+    // How much ink goes left and right of `x`.
+    fn ink(path: &BezPath, x: f64) -> (f64, f64) {
+        let (mut left, mut right) = (0.0, 0.0);
+        for seg in path.segments() {
+            let PathSeg::Line(line) = seg else {
+                panic!("expected lines, got {seg:?}");
+            };
+            if line.p0.midpoint(line.p1).x < x {
+                left += line.arclen(1e-6);
+            } else {
+                right += line.arclen(1e-6);
+            }
+        }
+        (left, right)
+    }
+
+    #[test]
+    fn dark_gets_more_ink() {
+        let photo = half_black(40, 20);
+        for style in Style::ALL {
+            let halftone = Halftone {
+                style,
+                spacing: 0.5,
+            };
+            let strokes = halftone.strokes(&photo, RECT);
+            let (left, right) = ink(&strokes, RECT.center().x);
+            assert!(left > 50.0, "{style}: {left}");
+            // The edge of the black half gets a little bit of the white half.
+            assert!(right < left / 10.0, "{style}: {left} vs {right}");
+            let bbox = strokes.elements().iter().filter_map(|el| match el {
+                PathEl::MoveTo(p) | PathEl::LineTo(p) => Some(*p),
+                _ => None,
+            });
+            for p in bbox {
+                assert!(RECT.inflate(1e-9, 1e-9).contains(p), "{style}: {p:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn white_is_empty() {
+        let photo = Photo {
+            width: 10,
+            height: 10,
+            luma: vec![250; 100],
+        };
+        for style in Style::ALL {
+            let halftone = Halftone {
+                style,
+                ..Halftone::default()
+            };
+            assert!(halftone.strokes(&photo, RECT).is_empty(), "{style}");
+        }
+        assert!(to_job(&photo, RECT, &Halftone::default(), &Settings::default()).is_err());
+    }
+
+    #[test]
+    fn keeps_its_shape() {
+        // A tall picture in a wide rect gets the full height, and is centered.
+        let photo = Photo {
+            width: 10,
+            height: 20,
+            luma: vec![0; 200],
+        };
+        let shades = Shades::new(&photo, RECT).unwrap();
+        assert_eq!(shades.bounds, Rect::new(-2.0, 5.0, 2.0, 13.0));
+        assert_eq!(shades.darkness(Point::new(0.0, 9.0), 0.1), 1.0);
+        assert_eq!(shades.darkness(Point::new(3.0, 9.0), 0.1), 0.0);
+    }
+
+    #[test]
+    fn averages_shades() {
+        // Two columns: black and white.
+        let photo = Photo {
+            width: 2,
+            height: 1,
+            luma: vec![0, 255],
+        };
+        let shades = Shades::new(&photo, Rect::new(0.0, 0.0, 2.0, 1.0)).unwrap();
+        assert_eq!(shades.darkness(Point::new(0.5, 0.5), 0.1), 1.0);
+        assert_eq!(shades.darkness(Point::new(1.5, 0.5), 0.1), 0.0);
+        assert_eq!(shades.darkness(Point::new(1.0, 0.5), 0.5), 0.5);
+    }
+
+    #[test]
+    fn style_names() {
+        for style in Style::ALL {
+            assert_eq!(style.to_string().parse::<Style>().unwrap(), style);
+        }
+        assert!("dots".parse::<Style>().is_err());
+    }
+}
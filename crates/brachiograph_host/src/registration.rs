@@ -0,0 +1,106 @@
+//! Compensating for a brachiograph that isn't mounted exactly where it should be relative to the paper.
+//!
+//! The idea is to plot a small cross at a known position, have the user measure how far from its
+//! intended position it landed, and then shift all subsequent plots by the opposite amount.
+
+use brachiograph::{Fixed, Op};
+use kurbo::{Point, Vec2};
+
+use crate::{serial::expect_ack, Serial};
+
+/// The length of each arm of the registration cross, in brachiograph units.
+pub const MARK_SIZE: f64 = 0.5;
+
+/// A translation that gets applied to every position we send to the brachiograph.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PageOffset(pub Vec2);
+
+impl PageOffset {
+    /// Computes the offset that cancels out a measured error.
+    ///
+    /// `error` is the position of the plotted mark minus the position where it was
+    /// supposed to be.
+    pub fn from_error(error: Vec2) -> PageOffset {
+        PageOffset(-error)
+    }
+
+    pub fn apply(&self, op: Op) -> Op {
+        let shift = |p: brachiograph::Point| brachiograph::Point {
+            x: p.x + Fixed::from_num(self.0.x),
+            y: p.y + Fixed::from_num(self.0.y),
+        };
+        match op {
+            Op::MoveTo(p) => Op::MoveTo(shift(p)),
+            Op::QuadTo(c, p) => Op::QuadTo(shift(c), shift(p)),
+            Op::CurveTo(c1, c2, p) => Op::CurveTo(shift(c1), shift(c2), shift(p)),
+            Op::ArcTo(center, sweep) => Op::ArcTo(shift(center), sweep),
+            op => op,
+        }
+    }
+}
+
+/// The ops for drawing a registration cross centered at `center`.
+pub fn cross(center: Point, size: f64) -> Vec<Op> {
+    let mv = |p: Point| {
+        Op::MoveTo(brachiograph::Point {
+            x: Fixed::from_num(p.x),
+            y: Fixed::from_num(p.y),
+        })
+    };
+    let dx = Vec2::new(size, 0.0);
+    let dy = Vec2::new(0.0, size);
+    vec![
+        Op::PenUp,
+        mv(center - dx),
+        Op::PenDown,
+        mv(center + dx),
+        Op::PenUp,
+        mv(center - dy),
+        Op::PenDown,
+        mv(center + dy),
+        Op::PenUp,
+    ]
+}
+
+/// Runs the registration routine.
+///
+/// This plots a cross at `mark` (ignoring any existing page offset), waits for it to be
+/// finished, then calls `measure` to find out how far off the cross was. `measure` will
+/// typically ask the user. The resulting offset is applied to `serial` for the rest of the
+/// session, and also returned.
+///
+/// This fails if the brachiograph doesn't take the cross (because it's paused, say).
+pub fn register(
+    serial: &mut Serial,
+    mark: Point,
+    measure: impl FnOnce() -> anyhow::Result<Vec2>,
+) -> anyhow::Result<PageOffset> {
+    serial.set_page_offset(PageOffset::default());
+    for op in cross(mark, MARK_SIZE) {
+        expect_ack(serial.send(op)?)?;
+    }
+    serial.wait_until_idle()?;
+    let offset = PageOffset::from_error(measure()?);
+    serial.set_page_offset(offset);
+    Ok(offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_cancels_error() {
+        let offset = PageOffset::from_error(Vec2::new(0.5, -0.25));
+        let Op::MoveTo(p) = offset.apply(Op::MoveTo(brachiograph::Point {
+            x: Fixed::from_num(1),
+            y: Fixed::from_num(9),
+        })) else {
+            panic!("expected a move");
+        };
+        assert_eq!(p.x, Fixed::from_num(0.5));
+        assert_eq!(p.y, Fixed::from_num(9.25));
+
+        assert!(matches!(offset.apply(Op::PenUp), Op::PenUp));
+    }
+}
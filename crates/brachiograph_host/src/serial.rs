@@ -0,0 +1,1235 @@
+use anyhow::bail;
+use brachiograph::{
+    geom, Angles, DeviceInfo, ErrorCode, Fixed, LimitPolicy, Op, PauseReason, QueueStatus, Reply,
+    Resp,
+};
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    calibration::ArmCalibration,
+    finish::Finish,
+    registration::PageOffset,
+    transcript::{Recorder, Replay, Transcript},
+};
+
+use serialport::{SerialPort, SerialPortType};
+
+const VENDOR_ID: u16 = 0xca6d;
+const PRODUCT_ID: u16 = 0xba6d;
+
+// No single op takes anywhere near this long, so if the queue stays the same for this long then
+// something has gone wrong.
+const STALLED_QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often to check on a full queue. The brachiograph takes ops off its queue at most once per
+// tick of its timer, so there's no point checking more often than that.
+pub(crate) const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// The most ops that `send_batch` sends before reading the responses. The brachiograph only has
+// small serial buffers, so we shouldn't get too far ahead of it.
+const MAX_BATCH: usize = 16;
+
+// How far to move a refused point towards the middle of the page before trying it again. This is
+// plenty to get past any rounding error, but too small to see.
+const NUDGE: f64 = 0.01;
+
+// How often to ask the brachiograph how far it's got, if someone wants to know (see
+// `Serial::on_progress`). Each time costs a round trip, so not too often.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The wire format used for talking to a brachiograph.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// COBS-framed postcard messages. This is what the current firmware speaks.
+    #[default]
+    Postcard,
+    /// Newline-terminated text commands (like `moveto 0 90`, or `moveby 2 45` for a
+    /// relative move in polar coordinates), answered by lines like `ack`. This is what older
+    /// firmware speaks, and it only supports pen movements and asking for the arm angles
+    /// (`angles`, answered by a line like `angles 45.5 -30`, in degrees).
+    #[cfg(feature = "text")]
+    TextLine,
+}
+
+/// How long to wait for the brachiograph to answer an op, unless asked otherwise (see
+/// [`Serial::set_response_timeout`]).
+///
+/// Some ops (like the ones that wait for the queue to empty) only get answered once the
+/// brachiograph has finished what it was doing, so this is generous.
+pub const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How long a single read from the serial port waits for something to arrive. On linux, reads
+// return as soon as there's anything to read, but on windows they wait until the timeout is up.
+// Either way, `Serial::read_reply` keeps reading until the whole reply is there (or until the
+// response timeout is up), so this only needs to be small enough to keep windows responsive.
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+
+fn open_port(name: &str) -> serialport::Result<Box<dyn SerialPort>> {
+    let mut port = serialport::new(name, 9600).timeout(READ_TIMEOUT).open()?;
+    // The brachiograph doesn't care about these, but some drivers (notably the windows one for
+    // usb serial devices) don't send anything until DTR is set, and they don't all agree on
+    // whether it should be set to start with.
+    port.write_data_terminal_ready(true)?;
+    port.write_request_to_send(true)?;
+    Ok(port)
+}
+
+// The names of the serial ports that look like brachiographs.
+pub(crate) fn brachiograph_ports() -> Vec<String> {
+    let Ok(ports) = serialport::available_ports() else {
+        return Vec::new();
+    };
+    let mut names = Vec::new();
+    for port in ports {
+        let SerialPortType::UsbPort(usb_info) = port.port_type else {
+            continue;
+        };
+        log::debug!("found usbserial port {usb_info:?}");
+
+        if usb_info.vid == VENDOR_ID && usb_info.pid == PRODUCT_ID {
+            names.push(port.port_name);
+        } else {
+            // This gets called over and over while watching for brachiographs (see
+            // `hotplug::Watcher`), so it doesn't get to be loud.
+            log::debug!("skipping usb-serial {:?}", usb_info);
+        }
+    }
+    names
+}
+
+fn detect_port() -> Option<Box<dyn SerialPort>> {
+    for name in brachiograph_ports() {
+        match open_port(&name) {
+            Ok(port) => {
+                return Some(port);
+            }
+            Err(e) => {
+                log::warn!("failed to open port '{}': {}", name, e);
+            }
+        }
+    }
+
+    None
+}
+
+/// The error returned by [`Serial::send_batch`].
+#[derive(Debug)]
+pub struct BatchError {
+    /// How many ops (from the start of the batch) were accepted before things went wrong.
+    pub sent: usize,
+    pub error: anyhow::Error,
+}
+
+impl std::fmt::Display for BatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (after sending {} ops)", self.error, self.sent)
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+/// Something that happened while talking to the brachiograph that might be worth knowing about
+/// after a plot: what we were talking to, and what we changed in order to get ops through.
+///
+/// These are recorded in [`Serial::journal`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum JournalEntry {
+    /// The brachiograph described itself (see [`Serial::info`]).
+    Device(DeviceInfo),
+    /// The brachiograph refused to move to `from` (which was right at the edge of the page), so
+    /// we moved to `to` instead.
+    Nudged {
+        from: kurbo::Point,
+        to: kurbo::Point,
+    },
+    /// We didn't send ops fast enough, and the brachiograph ran out of them in the middle of a
+    /// stroke. It lifted the pen at `at` until we caught up, and then we put it back down.
+    Starved { at: kurbo::Point },
+    /// After running out of ops, the brachiograph was slowed down to `speed` (in units per
+    /// second) to give us more time to keep up (see [`Serial::set_auto_slowdown`]).
+    SlowedDown { speed: f64 },
+}
+
+/// How far the brachiograph has got with its slow ops (see [`Serial::progress`]).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    /// How many slow ops the brachiograph has finished. This doesn't start from zero for each
+    /// plot (and it wraps around), so compare it to what it was at the start.
+    pub ops_done: u32,
+    /// How many slow ops are waiting in the brachiograph's queue.
+    pub queue_len: u16,
+}
+
+// If `op` is a move that the brachiograph might refuse because of rounding at the edge of the
+// page (as given by `config`), returns the same move nudged a little way inwards.
+fn nudge(config: &geom::Config, op: &Op) -> Option<Op> {
+    let Op::MoveTo(p) = op else {
+        return None;
+    };
+    let nudge = Fixed::from_num(NUDGE);
+    let inward = |v: Fixed, (lo, hi): (Fixed, Fixed)| {
+        if v - nudge < lo {
+            v + nudge
+        } else if v + nudge > hi {
+            v - nudge
+        } else {
+            v
+        }
+    };
+    let q = brachiograph::Point {
+        x: inward(p.x, config.x_range),
+        y: inward(p.y, config.y_range),
+    };
+    (q != *p && config.coord_is_valid(q.x, q.y)).then_some(Op::MoveTo(q))
+}
+
+// Turns anything but an `Ack` into an error.
+pub(crate) fn expect_ack(resp: Resp) -> anyhow::Result<()> {
+    match resp {
+        Resp::Ack => Ok(()),
+        Resp::Paused => bail!("the brachiograph has been plotting for too long, and paused itself"),
+        Resp::EStop => bail!("the emergency stop button was pressed"),
+        Resp::QueueFull => bail!("the brachiograph's queue was unexpectedly full"),
+        Resp::Starved { at } => {
+            bail!("the brachiograph ran out of ops, and lifted the pen at {at:?}")
+        }
+        Resp::Nack => bail!("the brachiograph refused an op"),
+        Resp::Error(code) => Err(code.into()),
+        resp => bail!("Unexpected response: {resp:?}"),
+    }
+}
+
+#[cfg(feature = "text")]
+fn parse_text_resp(line: &str) -> anyhow::Result<Resp> {
+    match line.trim() {
+        "ack" => Ok(Resp::Ack),
+        "nack" => Ok(Resp::Nack),
+        "queue full" => Ok(Resp::QueueFull),
+        resp => {
+            if let Some(angles) = resp.strip_prefix("angles ") {
+                let degs = angles
+                    .split_whitespace()
+                    .map(|s| s.parse::<f64>())
+                    .collect::<Result<Vec<_>, _>>()?;
+                if let [shoulder, elbow] = degs[..] {
+                    return Ok(Resp::Angles(Angles {
+                        shoulder: brachiograph::Angle::from_degrees(shoulder),
+                        elbow: brachiograph::Angle::from_degrees(elbow),
+                    }));
+                }
+            }
+            bail!("Unexpected response: {resp:?}")
+        }
+    }
+}
+
+// The number of ops from the start of `ops` that we can send at once, if the brachiograph's
+// queue has room for `free` more slow ops.
+fn batch_len(ops: &[Op], free: usize) -> usize {
+    let mut slow = 0;
+    ops.iter()
+        .take(MAX_BATCH)
+        .take_while(|op| {
+            if op.is_slow() {
+                slow += 1;
+            }
+            slow <= free
+        })
+        .count()
+}
+
+pub struct Serial {
+    write: Box<dyn Write + Send>,
+    read: BufReader<Box<dyn Read + Send>>,
+    // The start of a reply that has only partly arrived.
+    partial: Vec<u8>,
+    response_timeout: Duration,
+    // Whether we gave up waiting for a reply. If we did, we can't tell whether the next reply
+    // is that one or the one that we're waiting for, so the connection is no good any more.
+    gave_up: bool,
+    // The name of the serial port, if there is one.
+    name: Option<String>,
+    transport: Transport,
+    page_offset: PageOffset,
+    // The queue status from the brachiograph's most recent reply.
+    queue: Option<QueueStatus>,
+    // The brachiograph's shape and drawing area, as far as we know.
+    geom: geom::Config,
+    journal: Vec<JournalEntry>,
+    // The speed that we were last asked to draw at, if we've been asked, before slowing down.
+    speed: Option<Fixed>,
+    // How much to slow down by when the brachiograph runs out of ops.
+    auto_slowdown: Option<f64>,
+    // How much we've slowed down by so far.
+    slowdown: f64,
+    on_progress: Option<Box<dyn FnMut(Progress) + Send>>,
+    // When we last asked for progress to pass on to `on_progress`.
+    last_progress: Option<Instant>,
+}
+
+impl Serial {
+    fn from_port(port: Box<dyn SerialPort>, transport: Transport) -> Self {
+        let name = port.name();
+        Serial::from_parts(
+            Box::new(port.try_clone().unwrap()),
+            Box::new(port),
+            name,
+            transport,
+        )
+    }
+
+    fn from_parts(
+        read: Box<dyn Read + Send>,
+        write: Box<dyn Write + Send>,
+        name: Option<String>,
+        transport: Transport,
+    ) -> Self {
+        Serial {
+            read: BufReader::with_capacity(128, read),
+            partial: Vec::new(),
+            response_timeout: DEFAULT_RESPONSE_TIMEOUT,
+            gave_up: false,
+            write,
+            name,
+            transport,
+            page_offset: PageOffset::default(),
+            queue: None,
+            geom: geom::Config::default(),
+            journal: Vec::new(),
+            speed: None,
+            auto_slowdown: None,
+            slowdown: 1.0,
+            on_progress: None,
+            last_progress: None,
+        }
+    }
+
+    /// Looks for a connected brachiograph speaking the postcard protocol.
+    pub fn detect() -> Option<Self> {
+        Self::detect_with(Transport::Postcard)
+    }
+
+    /// Looks for a connected brachiograph speaking the given protocol.
+    pub fn detect_with(transport: Transport) -> Option<Self> {
+        detect_port().map(|port| Serial::from_port(port, transport))
+    }
+
+    /// Opens a brachiograph on a specific serial port, like `/dev/ttyACM0`.
+    pub fn open(name: &str, transport: Transport) -> anyhow::Result<Self> {
+        Ok(Serial::from_port(open_port(name)?, transport))
+    }
+
+    /// Pretends to talk to a brachiograph, by playing back the brachiograph's side of a
+    /// [transcript](crate::transcript).
+    ///
+    /// Everything that we send gets checked against what was sent when the transcript was
+    /// recorded, and it's an error if it's different. This is for reproducing problems in tests.
+    pub fn replay(transcript: &Transcript, transport: Transport) -> Self {
+        let replay = Replay::new(transcript);
+        Serial::from_parts(Box::new(replay.clone()), Box::new(replay), None, transport)
+    }
+
+    /// Records every byte that goes to and from the brachiograph from now on, writing a
+    /// [transcript](crate::transcript) to `out` as it goes.
+    ///
+    /// This should be called right after connecting, so that the transcript has the whole
+    /// conversation. It can be played back with [`Serial::replay`].
+    pub fn with_transcript(mut self, out: impl Write + Send + 'static) -> Self {
+        let recorder = Recorder::new(Box::new(out));
+        let empty: Box<dyn Read + Send> = Box::new(std::io::empty());
+        let read = std::mem::replace(&mut self.read, BufReader::new(empty));
+        // Anything that's already buffered has been read, so it won't be in the transcript.
+        if !read.buffer().is_empty() {
+            log::warn!("the transcript is missing some bytes that were already read");
+        }
+        self.read = BufReader::with_capacity(128, Box::new(recorder.wrap(read.into_inner())));
+        let write = std::mem::replace(&mut self.write, Box::new(std::io::sink()));
+        self.write = Box::new(recorder.wrap(write));
+        self
+    }
+
+    pub fn name(&self) -> Option<String> {
+        self.name.clone()
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
+    pub fn response_timeout(&self) -> Duration {
+        self.response_timeout
+    }
+
+    /// Sets how long to wait for the brachiograph to answer each op before giving up on it.
+    ///
+    /// The error from giving up contains an [`std::io::Error`] of kind
+    /// [`TimedOut`](std::io::ErrorKind::TimedOut). After that, the reply could still turn up
+    /// in place of the next one, so everything else fails with an [`std::io::Error`] of kind
+    /// [`NotConnected`](std::io::ErrorKind::NotConnected), and the brachiograph needs to be
+    /// opened again.
+    pub fn set_response_timeout(&mut self, timeout: Duration) {
+        self.response_timeout = timeout;
+    }
+
+    pub fn page_offset(&self) -> PageOffset {
+        self.page_offset
+    }
+
+    /// Sets an offset that will be applied to all subsequent moves.
+    ///
+    /// See the [`registration`](crate::registration) module for how to figure out
+    /// what the offset should be.
+    pub fn set_page_offset(&mut self, offset: PageOffset) {
+        self.page_offset = offset;
+    }
+
+    /// Slows the brachiograph down each time it runs out of ops in the middle of a stroke, by
+    /// multiplying its speed by `factor` (which should be between 0 and 1), or stops doing that
+    /// with `None`.
+    ///
+    /// The slowdowns add up, and they also apply to any [`Op::SetSpeed`] sent afterwards. They
+    /// only start once the speed has been set with an [`Op::SetSpeed`] sent through this
+    /// connection, because that's the only way we know what it is. Each slowdown is recorded in
+    /// the [journal](Serial::journal).
+    pub fn set_auto_slowdown(&mut self, factor: Option<f64>) {
+        self.auto_slowdown = factor;
+    }
+
+    /// Everything that we've had to change to get ops through, oldest first.
+    pub fn journal(&self) -> &[JournalEntry] {
+        &self.journal
+    }
+
+    /// Sends a single op to the brachiograph and waits for the response, blocking
+    /// (and retrying) if the brachiograph's queue is full.
+    ///
+    /// If the brachiograph refuses to move to a point at the very edge of the page (which can
+    /// happen because of rounding), the point gets nudged inwards and we try once more. The
+    /// adjustment is recorded in the [journal](Serial::journal).
+    ///
+    /// If the brachiograph refuses an op and says why, the error contains an [`ErrorCode`]
+    /// (which you can get with [`anyhow::Error::downcast_ref`]).
+    pub fn send(&mut self, op: Op) -> anyhow::Result<Resp> {
+        let op = self.page_offset.apply(op);
+        let op = self.slow_down(op);
+        // Older firmware just says `Nack`, without giving a reason.
+        let reason = match self.send_once(&op)? {
+            Resp::Nack => None,
+            Resp::Error(code) => Some(code),
+            other => return Ok(other),
+        };
+        if let (None | Some(ErrorCode::OutOfRange), Some(nudged)) = (reason, nudge(&self.geom, &op))
+        {
+            if let Resp::Ack = self.send_once(&nudged)? {
+                self.record_nudge(&op, &nudged);
+                return Ok(Resp::Ack);
+            }
+        }
+        match reason {
+            Some(code) => {
+                Err(anyhow::Error::new(code).context(format!("the brachiograph refused {op:?}")))
+            }
+            None => bail!("the brachiograph refused {op:?}"),
+        }
+    }
+
+    // Sends an op (that already has the page offset applied), waiting if the queue is full.
+    fn send_once(&mut self, op: &Op) -> anyhow::Result<Resp> {
+        log::debug!("sending {op:?}");
+        loop {
+            let resp = match self.transport {
+                Transport::Postcard => self.send_postcard(op)?,
+                #[cfg(feature = "text")]
+                Transport::TextLine => self.send_text(op)?,
+            };
+            match resp {
+                Resp::QueueFull => std::thread::sleep(QUEUE_POLL_INTERVAL),
+                Resp::Starved { at } => self.recover_from_starvation(at, op)?,
+                other => return Ok(other),
+            }
+        }
+    }
+
+    // The brachiograph ran out of ops with the pen down and lifted it at `at`, and it won't take
+    // any more until we say that we know. Then we put the pen back down, so that `next` (which
+    // it refused) carries on the stroke when we send it again.
+    fn recover_from_starvation(
+        &mut self,
+        at: brachiograph::Point,
+        next: &Op,
+    ) -> anyhow::Result<()> {
+        let entry = JournalEntry::Starved {
+            at: kurbo::Point::new(at.x.to_num(), at.y.to_num()) - self.page_offset.0,
+        };
+        log::warn!("{entry:?}");
+        self.journal.push(entry);
+        expect_ack(self.send_once(&Op::Resume)?)?;
+        if let (Some(factor), Some(speed)) = (self.auto_slowdown, self.speed) {
+            self.slowdown *= factor;
+            let slower = self.slowed(speed);
+            expect_ack(self.send_once(&Op::SetSpeed(slower))?)?;
+            let entry = JournalEntry::SlowedDown {
+                speed: slower.to_num(),
+            };
+            log::warn!("{entry:?}");
+            self.journal.push(entry);
+        }
+        if *next != Op::PenUp {
+            expect_ack(self.send_once(&Op::PenDown)?)?;
+        }
+        Ok(())
+    }
+
+    // `from` and `to` are moves with the page offset applied, but the journal is in the
+    // coordinates of the job.
+    fn record_nudge(&mut self, from: &Op, to: &Op) {
+        let point = |op: &Op| match op {
+            Op::MoveTo(p) => kurbo::Point::new(p.x.to_num(), p.y.to_num()) - self.page_offset.0,
+            _ => unreachable!("only moves get nudged"),
+        };
+        let entry = JournalEntry::Nudged {
+            from: point(from),
+            to: point(to),
+        };
+        log::warn!("{entry:?}");
+        self.journal.push(entry);
+    }
+
+    /// Asks the brachiograph how full its queue is.
+    pub fn queue_status(&mut self) -> anyhow::Result<QueueStatus> {
+        match self.send(Op::GetQueue)? {
+            Resp::Queue(status) => Ok(status),
+            resp => bail!("Unexpected response: {resp:?}"),
+        }
+    }
+
+    /// Asks the brachiograph how far it has got.
+    pub fn progress(&mut self) -> anyhow::Result<Progress> {
+        match self.send(Op::GetProgress)? {
+            Resp::Progress {
+                ops_done,
+                queue_len,
+            } => Ok(Progress {
+                ops_done,
+                queue_len,
+            }),
+            resp => bail!("Unexpected response: {resp:?}"),
+        }
+    }
+
+    /// Asks the brachiograph about how long it will take to finish everything in its queue.
+    ///
+    /// This only knows about the ops that have been sent, so while we're still sending a plot
+    /// it's less than the time that the whole plot has left.
+    pub fn time_left(&mut self) -> anyhow::Result<Duration> {
+        match self.send(Op::EstimateTime)? {
+            Resp::TimeLeft { millis } => Ok(Duration::from_millis(millis.into())),
+            resp => bail!("Unexpected response: {resp:?}"),
+        }
+    }
+
+    /// Asks for `callback` to be told how far the brachiograph has got, every so often while
+    /// we're sending it ops (with [`Serial::send_stream`] or [`Serial::send_batch`]) or waiting
+    /// for it to catch up. This is for progress bars, so that a long plot doesn't look like it's
+    /// stuck.
+    ///
+    /// The text protocol has no way to ask, so the callback never gets called there.
+    pub fn on_progress(&mut self, callback: impl FnMut(Progress) + Send + 'static) {
+        self.on_progress = Some(Box::new(callback));
+        self.last_progress = None;
+    }
+
+    // Passes on the brachiograph's progress to the `on_progress` callback, if there is one and
+    // it hasn't heard for a while.
+    fn report_progress(&mut self) -> anyhow::Result<()> {
+        if self.on_progress.is_none() || self.transport != Transport::Postcard {
+            return Ok(());
+        }
+        if matches!(self.last_progress, Some(t) if t.elapsed() < PROGRESS_INTERVAL) {
+            return Ok(());
+        }
+        self.last_progress = Some(Instant::now());
+        let progress = self.progress()?;
+        if let Some(callback) = &mut self.on_progress {
+            callback(progress);
+        }
+        Ok(())
+    }
+
+    /// Asks the brachiograph for the angles of its arms.
+    pub fn angles(&mut self) -> anyhow::Result<Angles> {
+        match self.send(Op::GetAngles)? {
+            Resp::Angles(angles) => Ok(angles),
+            resp => bail!("Unexpected response: {resp:?}"),
+        }
+    }
+
+    /// Asks the brachiograph where its pen is, in the same coordinates as the ops we send
+    /// (so the page offset has been taken into account).
+    ///
+    /// The brachiograph answers with the position that it's aiming for right now, so this
+    /// only tells you whether a move ended up in the right place once it has finished.
+    pub fn position(&mut self) -> anyhow::Result<kurbo::Point> {
+        let angles = self.angles()?;
+        let (x, y) = self.geom.coord_at_angle::<f64>(angles);
+        Ok(kurbo::Point::new(x, y) - self.page_offset.0)
+    }
+
+    /// Asks the brachiograph how long its arms are and which part of the page it draws on.
+    pub fn config(&mut self) -> anyhow::Result<geom::Config> {
+        match self.send(Op::GetConfig)? {
+            Resp::Config(config) => {
+                self.geom = config.clone();
+                Ok(config)
+            }
+            resp => bail!("Unexpected response: {resp:?}"),
+        }
+    }
+
+    /// Tells the brachiograph how long its arms are and which part of the page to draw on,
+    /// for brachiographs that aren't built like the default one.
+    ///
+    /// The brachiograph refuses (with an [`ErrorCode`]) if its arms can't reach all of the
+    /// area, or if it's in the middle of drawing something. The config doesn't survive a
+    /// restart, so send it again every time.
+    pub fn set_config(&mut self, config: geom::Config) -> anyhow::Result<()> {
+        expect_ack(self.send(Op::SetConfig(config.clone()))?)?;
+        self.geom = config;
+        Ok(())
+    }
+
+    /// Tells the brachiograph what to do with moves that go outside its drawing area: refuse
+    /// them (the default), or move as far as it can instead. Like the config, this doesn't
+    /// survive a restart.
+    pub fn set_limit_policy(&mut self, policy: LimitPolicy) -> anyhow::Result<()> {
+        expect_ack(self.send(Op::SetLimitPolicy(policy))?)
+    }
+
+    /// Finishes off a drawing in the way that `finish` says (see [`Finish::ops`]), and then
+    /// blocks until the brachiograph has done it.
+    ///
+    /// The text protocol can't ask for the config or about the queue, so there this assumes
+    /// the last config that we set (or the default one), and it doesn't wait.
+    pub fn finish(&mut self, finish: &Finish) -> anyhow::Result<()> {
+        if self.transport != Transport::Postcard {
+            for op in finish.text_ops(&self.geom) {
+                expect_ack(self.send(op)?)?;
+            }
+            return Ok(());
+        }
+        let config = self.config()?;
+        for op in finish.ops(&config) {
+            expect_ack(self.send(op)?)?;
+        }
+        self.wait_until_idle()
+    }
+
+    /// Tells the brachiograph to use these calibration tables for the arm servos, instead of
+    /// the ones that were built into its firmware.
+    ///
+    /// Like the config, the calibration doesn't survive a restart, so send it again every time
+    /// (see [`Serial::with_calibration`]).
+    pub fn upload_calibration(&mut self, calib: &ArmCalibration) -> anyhow::Result<()> {
+        for op in calib.ops()? {
+            expect_ack(self.send(op)?)?;
+        }
+        Ok(())
+    }
+
+    /// Uploads the calibration tables (see [`Serial::upload_calibration`]) right after
+    /// connecting, as in `Serial::open(port, transport)?.with_calibration(&calib)?`.
+    pub fn with_calibration(mut self, calib: &ArmCalibration) -> anyhow::Result<Self> {
+        self.upload_calibration(calib)?;
+        Ok(self)
+    }
+
+    /// Asks the brachiograph which firmware it's running and which calibration it's using.
+    ///
+    /// The answer is also recorded in the [journal](Serial::journal), so that it's clear
+    /// afterwards what produced a drawing.
+    pub fn info(&mut self) -> anyhow::Result<DeviceInfo> {
+        match self.send(Op::GetInfo)? {
+            Resp::Info(info) => {
+                log::info!("{info:?}");
+                self.journal.push(JournalEntry::Device(info.clone()));
+                Ok(info)
+            }
+            resp => bail!("Unexpected response: {resp:?}"),
+        }
+    }
+
+    /// Gets the brachiograph going again after its emergency stop button was pressed.
+    ///
+    /// This fails if the button is still held down. If there was no emergency stop, it does
+    /// nothing.
+    pub fn clear_estop(&mut self) -> anyhow::Result<()> {
+        match self.send(Op::ClearEStop)? {
+            Resp::Ack => Ok(()),
+            Resp::EStop => bail!("the emergency stop button is still pressed"),
+            resp => bail!("Unexpected response: {resp:?}"),
+        }
+    }
+
+    /// Blocks until the brachiograph's queue has room for `n` more slow ops (or until it's
+    /// empty, if it can't hold that many).
+    ///
+    /// Call this before starting a stroke, and then send the stroke's first few ops right away:
+    /// that way the brachiograph has them queued up by the time the pen touches the paper, and
+    /// it won't stall while we catch up. The text protocol has no way to ask about the queue,
+    /// so this does nothing there.
+    pub fn warm_up(&mut self, n: usize) -> anyhow::Result<()> {
+        if self.transport != Transport::Postcard {
+            return Ok(());
+        }
+        self.wait_for_queue(|status| status.len == 0 || usize::from(status.free()) >= n)
+    }
+
+    /// Blocks until the brachiograph has finished everything in its queue.
+    ///
+    /// Do this before asking the user to touch the paper or the pen, because until then the
+    /// arms might still be moving. The text protocol has no way to ask about the queue, so this
+    /// does nothing there.
+    pub fn wait_until_idle(&mut self) -> anyhow::Result<()> {
+        if self.transport != Transport::Postcard {
+            return Ok(());
+        }
+        self.wait_for_queue(|status| status.len == 0)
+    }
+
+    /// Sends an [`Op::Pause`], and then blocks until the brachiograph has finished everything
+    /// before it and is waiting for [`Serial::resume`].
+    ///
+    /// The text protocol has no way to pause, so this fails there.
+    pub fn pause(&mut self, reason: PauseReason) -> anyhow::Result<()> {
+        expect_ack(self.send(Op::Pause(reason))?)?;
+        self.wait_until_idle()
+    }
+
+    /// Gets the brachiograph going again after [`Serial::pause`], or after it paused itself
+    /// because it had been plotting for too long.
+    pub fn resume(&mut self) -> anyhow::Result<()> {
+        expect_ack(self.send(Op::Resume)?)
+    }
+
+    // Blocks until the brachiograph's queue is `done`, failing if the queue stops moving first.
+    fn wait_for_queue(&mut self, done: impl Fn(&QueueStatus) -> bool) -> anyhow::Result<()> {
+        let mut last_len = None;
+        let mut last_progress = Instant::now();
+        loop {
+            let status = self.queue_status()?;
+            if done(&status) {
+                return Ok(());
+            }
+            self.report_progress()?;
+            if last_len != Some(status.len) {
+                last_len = Some(status.len);
+                last_progress = Instant::now();
+            } else if last_progress.elapsed() > STALLED_QUEUE_TIMEOUT {
+                bail!("the brachiograph's queue isn't moving; is it paused?");
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Sends a sequence of ops, keeping the brachiograph's queue topped up.
+    ///
+    /// Rather than waiting for each op's response before sending the next one, this sends as
+    /// many ops at once as the brachiograph has room for, and then reads all their responses. It
+    /// knows how much room there is because every reply says how full the queue is. Every op
+    /// must be acknowledged; if one isn't (because the brachiograph paused itself, say) then this
+    /// stops, and the error says how many ops made it. Some of the ops after that one might have
+    /// been accepted too, because they were already on their way.
+    ///
+    /// The text protocol can't do this, so there the ops are sent one at a time.
+    pub fn send_batch(&mut self, ops: &[Op]) -> Result<(), BatchError> {
+        self.send_stream(ops.iter().cloned())
+    }
+
+    /// Like [`Serial::send_batch`], but takes the ops from an iterator.
+    ///
+    /// Ops are only taken from the iterator when there's about to be room for them in the
+    /// brachiograph's queue, so they can be worked out while the brachiograph is drawing the
+    /// ones before them. That way a huge drawing never has to be held in memory all at once,
+    /// and the brachiograph can get started before the last of it is ready.
+    pub fn send_stream(&mut self, ops: impl IntoIterator<Item = Op>) -> Result<(), BatchError> {
+        let mut ops = ops.into_iter();
+        if self.transport != Transport::Postcard {
+            for (sent, op) in ops.enumerate() {
+                self.send(op)
+                    .and_then(expect_ack)
+                    .map_err(|error| BatchError { sent, error })?;
+            }
+            return Ok(());
+        }
+
+        // The ops that we've taken from the iterator but not sent yet. There are never more of
+        // these than fit in a batch.
+        let mut pending = Vec::with_capacity(MAX_BATCH);
+        let mut sent = 0;
+        loop {
+            pending.extend(ops.by_ref().take(MAX_BATCH - pending.len()));
+            if pending.is_empty() {
+                break;
+            }
+            let len = self
+                .wait_for_room(&pending)
+                .map_err(|error| BatchError { sent, error })?;
+            let mut batch: Vec<Op> = pending[..len]
+                .iter()
+                .map(|op| self.page_offset.apply(op.clone()))
+                .collect();
+            // End the batch after any move that might need nudging, so that if it does then
+            // nothing after it has been sent yet.
+            if let Some(i) = batch.iter().position(|op| nudge(&self.geom, op).is_some()) {
+                batch.truncate(i + 1);
+            }
+            let batch: Vec<Op> = batch.into_iter().map(|op| self.slow_down(op)).collect();
+            let len = batch.len();
+            self.check_in_sync().map_err(|e| BatchError {
+                sent,
+                error: e.into(),
+            })?;
+            for op in &batch {
+                log::debug!("sending {op:?}");
+                let msg = postcard::to_stdvec_cobs(&op).map_err(|e| BatchError {
+                    sent,
+                    error: e.into(),
+                })?;
+                self.write.write_all(&msg).map_err(|e| BatchError {
+                    sent,
+                    error: e.into(),
+                })?;
+            }
+            // Read all the responses, even if one of them was bad, so that we don't get out of
+            // sync with the brachiograph.
+            let mut failure = None;
+            // If the brachiograph ran out of ops, the first one that it refused because of it.
+            let mut starved = None;
+            for (i, op) in batch.iter().enumerate() {
+                let result = self.read_reply().and_then(|resp| match resp {
+                    Resp::Starved { at } if starved.is_none() && failure.is_none() => {
+                        starved = Some((i, at));
+                        Ok(())
+                    }
+                    // Everything from the first starved op on gets sent again.
+                    _ if starved.is_some() => Ok(()),
+                    Resp::Nack | Resp::Error(ErrorCode::OutOfRange)
+                        if i + 1 == len && failure.is_none() =>
+                    {
+                        match nudge(&self.geom, op) {
+                            Some(nudged) => {
+                                let resp = self.send_once(&nudged)?;
+                                if let Resp::Ack = resp {
+                                    self.record_nudge(op, &nudged);
+                                }
+                                expect_ack(resp)
+                            }
+                            None => expect_ack(resp),
+                        }
+                    }
+                    resp => expect_ack(resp),
+                });
+                if let (Err(error), None) = (result, &failure) {
+                    failure = Some(BatchError {
+                        sent: sent + i,
+                        error,
+                    });
+                }
+            }
+            if let Some(failure) = failure {
+                return Err(failure);
+            }
+            if let Some((i, at)) = starved {
+                self.recover_from_starvation(at, &batch[i])
+                    .map_err(|error| BatchError {
+                        sent: sent + i,
+                        error,
+                    })?;
+                pending.drain(..i);
+                sent += i;
+                continue;
+            }
+            pending.drain(..len);
+            sent += len;
+            self.report_progress()
+                .map_err(|error| BatchError { sent, error })?;
+        }
+        Ok(())
+    }
+
+    // Waits until the brachiograph has room for at least the first of `ops`, and returns how
+    // many of them to send.
+    fn wait_for_room(&mut self, ops: &[Op]) -> anyhow::Result<usize> {
+        let mut status = match self.queue {
+            Some(status) => status,
+            None => self.queue_status()?,
+        };
+        let mut last_progress = Instant::now();
+        loop {
+            // A brachiograph that isn't queueing ops at all (because it's being calibrated, or
+            // it was stopped) says that its queue has no capacity. Send an op anyway, so that we
+            // find out why.
+            if status.capacity == 0 {
+                return Ok(1);
+            }
+            let len = batch_len(ops, status.free().into());
+            if len > 0 {
+                return Ok(len);
+            }
+            std::thread::sleep(QUEUE_POLL_INTERVAL);
+            self.report_progress()?;
+            let new_status = self.queue_status()?;
+            if new_status.len != status.len {
+                last_progress = Instant::now();
+            } else if last_progress.elapsed() > STALLED_QUEUE_TIMEOUT {
+                bail!("the brachiograph's queue isn't moving; is it paused?");
+            }
+            status = new_status;
+        }
+    }
+
+    fn send_postcard(&mut self, op: &Op) -> anyhow::Result<Resp> {
+        let msg = postcard::to_stdvec_cobs(op)?;
+        self.check_in_sync()?;
+        self.write.write_all(&msg)?;
+        self.read_reply()
+    }
+
+    // Slows down a change of speed if the brachiograph has been running out of ops, and
+    // remembers the speed that was asked for.
+    fn slow_down(&mut self, op: Op) -> Op {
+        match op {
+            Op::SetSpeed(speed) => {
+                self.speed = Some(speed);
+                Op::SetSpeed(self.slowed(speed))
+            }
+            op => op,
+        }
+    }
+
+    fn slowed(&self, speed: Fixed) -> Fixed {
+        if self.slowdown == 1.0 {
+            return speed;
+        }
+        // However slow it gets, it shouldn't stop altogether.
+        Fixed::from_num(speed.to_num::<f64>() * self.slowdown).max(Fixed::DELTA)
+    }
+
+    // Fails if we gave up waiting for a reply, because then we can't match the replies up with
+    // the ops any more.
+    fn check_in_sync(&self) -> std::io::Result<()> {
+        if self.gave_up {
+            return Err(std::io::Error::new(
+                ErrorKind::NotConnected,
+                "the brachiograph stopped answering, and needs to be opened again",
+            ));
+        }
+        Ok(())
+    }
+
+    // Reads the next reply, which can come in any number of pieces.
+    fn read_reply(&mut self) -> anyhow::Result<Resp> {
+        let mut frame = self.read_frame(0)?;
+        let reply: Reply = postcard::from_bytes_cobs(&mut frame)?;
+        self.queue = Some(reply.queue);
+        Ok(reply.resp)
+    }
+
+    // Reads up to (and including) the `end` byte that ends a reply (a zero for a COBS frame, or
+    // a newline for a line of text), skipping empty replies.
+    //
+    // If the reply doesn't arrive in time, we give up on the connection (see `gave_up`).
+    fn read_frame(&mut self, end: u8) -> std::io::Result<Vec<u8>> {
+        self.check_in_sync()?;
+        let deadline = Instant::now() + self.response_timeout;
+        loop {
+            if let Some(i) = self.partial.iter().position(|&b| b == end) {
+                let rest = self.partial.split_off(i + 1);
+                let frame = std::mem::replace(&mut self.partial, rest);
+                if frame.len() > 1 {
+                    return Ok(frame);
+                }
+                continue;
+            }
+            if Instant::now() >= deadline {
+                self.gave_up = true;
+                return Err(std::io::Error::new(
+                    ErrorKind::TimedOut,
+                    format!(
+                        "the brachiograph didn't answer within {:?}",
+                        self.response_timeout
+                    ),
+                ));
+            }
+            match self.read.read_until(end, &mut self.partial) {
+                // Whatever arrived is in `partial` now. Running out of time before anything
+                // arrives is an error on linux, but on windows it looks like reading nothing.
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    #[cfg(feature = "text")]
+    fn send_text(&mut self, op: &Op) -> anyhow::Result<Resp> {
+        if !op.has_text() {
+            bail!("{op:?} isn't supported by the text protocol");
+        }
+        self.check_in_sync()?;
+        writeln!(&mut self.write, "{op}")?;
+
+        let line = self.read_frame(b'\n')?;
+        let resp = String::from_utf8_lossy(&line);
+        log::debug!("read {resp:?}");
+        parse_text_resp(&resp)
+    }
+}
+
+impl crate::Plotter for Serial {
+    fn send(&mut self, op: Op) -> anyhow::Result<Resp> {
+        Serial::send(self, op)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mv(x: i32) -> Op {
+        Op::MoveTo(brachiograph::Point {
+            x: brachiograph::Fixed::from_num(x),
+            y: brachiograph::Fixed::from_num(10),
+        })
+    }
+
+    #[test]
+    fn batches_fit_in_queue() {
+        let ops = [Op::PenUp, mv(0), Op::GetQueue, Op::PenDown, mv(1)];
+        assert_eq!(batch_len(&ops, 0), 0);
+        assert_eq!(batch_len(&ops[2..], 0), 1);
+        // Fast ops don't take up room in the queue.
+        assert_eq!(batch_len(&ops, 2), 3);
+        assert_eq!(batch_len(&ops, 10), 5);
+
+        let many = vec![mv(0); 100];
+        assert_eq!(batch_len(&many, 100), MAX_BATCH);
+    }
+
+    #[test]
+    fn nudge_inwards() {
+        let at = |x: f64, y: f64| {
+            Op::MoveTo(brachiograph::Point {
+                x: Fixed::from_num(x),
+                y: Fixed::from_num(y),
+            })
+        };
+        // Points at the edge get moved towards the middle.
+        let config = geom::Config::default();
+        let nudge = |op: &Op| nudge(&config, op);
+        let Some(Op::MoveTo(p)) = nudge(&at(8.0, 5.0)) else {
+            panic!("expected a nudge");
+        };
+        assert_eq!(p.x, Fixed::from_num(8.0 - NUDGE));
+        assert_eq!(p.y, Fixed::from_num(5.0 + NUDGE));
+        // Points in the middle don't need nudging, and points far outside can't be helped.
+        assert!(nudge(&at(0.0, 9.0)).is_none());
+        assert!(nudge(&at(0.0, 20.0)).is_none());
+        assert!(nudge(&Op::PenUp).is_none());
+    }
+
+    #[test]
+    fn reply_round_trip() {
+        let reply = Reply {
+            resp: Resp::Ack,
+            queue: QueueStatus {
+                len: 3,
+                capacity: 32,
+            },
+        };
+        let mut bytes = postcard::to_stdvec_cobs(&reply).unwrap();
+        assert_eq!(bytes.last(), Some(&0));
+        let decoded: Reply = postcard::from_bytes_cobs(&mut bytes).unwrap();
+        assert!(matches!(decoded.resp, Resp::Ack));
+        assert_eq!(decoded.queue.free(), 29);
+    }
+
+    #[test]
+    fn error_codes_are_typed() {
+        let err = expect_ack(Resp::Error(ErrorCode::InvalidState)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ErrorCode>(),
+            Some(&ErrorCode::InvalidState)
+        );
+        let err = expect_ack(Resp::Nack).unwrap_err();
+        assert!(err.downcast_ref::<ErrorCode>().is_none());
+    }
+
+    #[cfg(feature = "text")]
+    #[test]
+    fn text_angles() {
+        let Resp::Angles(angles) = parse_text_resp("angles 45.5 -30\n").unwrap() else {
+            panic!("expected angles");
+        };
+        assert_eq!(angles.shoulder, brachiograph::Angle::from_degrees(45.5));
+        assert_eq!(angles.elbow, brachiograph::Angle::from_degrees(-30));
+        assert!(matches!(parse_text_resp("ack\n").unwrap(), Resp::Ack));
+        assert!(parse_text_resp("angles 45\n").is_err());
+    }
+
+    // A pretend serial port, where replies arrive a few bytes at a time whenever they're pushed
+    // onto the shared queue, and reading when there's nothing there times out.
+    #[derive(Clone, Default)]
+    struct Trickle(std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<u8>>>);
+
+    impl Trickle {
+        fn push(&self, bytes: &[u8]) {
+            self.0.lock().unwrap().extend(bytes);
+        }
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let mut bytes = self.0.lock().unwrap();
+            if bytes.is_empty() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            let n = buf.len().min(bytes.len()).min(3);
+            for (b, byte) in buf.iter_mut().zip(bytes.drain(..n)) {
+                *b = byte;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.push(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn reply(resp: Resp) -> Vec<u8> {
+        let queue = QueueStatus {
+            len: 0,
+            capacity: 32,
+        };
+        postcard::to_stdvec_cobs(&Reply { resp, queue }).unwrap()
+    }
+
+    #[test]
+    fn replies_in_pieces() {
+        let port = Trickle::default();
+        let mut serial = Serial::from_parts(
+            Box::new(port.clone()),
+            Box::new(std::io::sink()),
+            None,
+            Transport::Postcard,
+        );
+        serial.set_response_timeout(Duration::from_millis(20));
+
+        // Stray zero bytes (like the end of a frame that we missed the start of) get skipped.
+        port.push(&[0, 0]);
+        port.push(&reply(Resp::Ack));
+        assert!(matches!(serial.send(Op::PenUp).unwrap(), Resp::Ack));
+
+        // A reply can come in several pieces.
+        let denied = reply(Resp::Denied);
+        let (start, end) = denied.split_at(denied.len() / 2);
+        port.push(start);
+        port.push(end);
+        assert!(matches!(serial.send(Op::PenUp).unwrap(), Resp::Denied));
+
+        // Half a reply isn't enough. Once we've given up on it, the rest could turn up in
+        // place of the next reply, so the connection is no good any more.
+        port.push(start);
+        let err = serial.send(Op::PenUp).unwrap_err();
+        let io = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io.kind(), ErrorKind::TimedOut);
+        port.push(end);
+        port.push(&reply(Resp::Ack));
+        let err = serial.send(Op::PenUp).unwrap_err();
+        let io = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(io.kind(), ErrorKind::NotConnected);
+        assert!(serial.send_batch(&[Op::PenUp]).is_err());
+    }
+
+    #[test]
+    fn slows_down_when_starved() {
+        let (port, sent) = (Trickle::default(), Trickle::default());
+        let mut serial = Serial::from_parts(
+            Box::new(port.clone()),
+            Box::new(sent.clone()),
+            None,
+            Transport::Postcard,
+        );
+        serial.set_auto_slowdown(Some(0.5));
+        let speed = |s: f64| Op::SetSpeed(Fixed::from_num(s));
+
+        port.push(&reply(Resp::Ack));
+        serial.send(speed(2.0)).unwrap();
+        let at = brachiograph::Point {
+            x: Fixed::from_num(0),
+            y: Fixed::from_num(10),
+        };
+        port.push(&reply(Resp::Starved { at }));
+        for _ in 0..4 {
+            port.push(&reply(Resp::Ack));
+        }
+        serial.send(mv(1)).unwrap();
+        assert_eq!(
+            serial.journal(),
+            [
+                JournalEntry::Starved {
+                    at: kurbo::Point::new(0.0, 10.0)
+                },
+                JournalEntry::SlowedDown { speed: 1.0 },
+            ]
+        );
+        // Speeds that get set later are slowed down too.
+        port.push(&reply(Resp::Ack));
+        serial.send(speed(3.0)).unwrap();
+
+        let mut bytes: Vec<u8> = sent.0.lock().unwrap().drain(..).collect();
+        let ops: Vec<Op> = bytes
+            .split_inclusive_mut(|b| *b == 0)
+            .map(|msg| postcard::from_bytes_cobs(msg).unwrap())
+            .collect();
+        assert_eq!(
+            ops,
+            [
+                speed(2.0),
+                mv(1),
+                Op::Resume,
+                speed(1.0),
+                Op::PenDown,
+                mv(1),
+                speed(1.5)
+            ]
+        );
+    }
+
+    #[test]
+    fn waits_until_idle() {
+        let (port, sent) = (Trickle::default(), Trickle::default());
+        let mut serial = Serial::from_parts(
+            Box::new(port.clone()),
+            Box::new(sent.clone()),
+            None,
+            Transport::Postcard,
+        );
+        for len in [2, 1, 0] {
+            port.push(&reply(Resp::Queue(QueueStatus { len, capacity: 8 })));
+        }
+        serial.wait_until_idle().unwrap();
+        let sent = sent.0.lock().unwrap();
+        assert_eq!(sent.iter().filter(|b| **b == 0).count(), 3);
+    }
+}
@@ -0,0 +1,180 @@
+//! Sharing one brachiograph between several producers of ops.
+//!
+//! If two programs (say, a web front-end and a command line tool) both stream slow ops to the
+//! same brachiograph, their drawings will get mixed together. To prevent this, a producer has to
+//! [claim](Session::claim) the brachiograph before sending slow ops. Fast ops (like
+//! [`Op::Cancel`] and [`Op::GetPosition`]) are always allowed.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, bail};
+use brachiograph::{Op, Resp, SessionId};
+
+use crate::Serial;
+
+struct Inner {
+    serial: Serial,
+    next_id: SessionId,
+    // The session that the brachiograph last granted a claim to.
+    owner: Option<SessionId>,
+    // The session named by the last `Op::Claim` we sent. The brachiograph thinks that slow
+    // ops come from this session.
+    speaker: Option<SessionId>,
+}
+
+/// A brachiograph that can be shared between threads.
+#[derive(Clone)]
+pub struct SharedSerial {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl SharedSerial {
+    pub fn new(serial: Serial) -> Self {
+        SharedSerial {
+            inner: Arc::new(Mutex::new(Inner {
+                serial,
+                next_id: first_id(),
+                owner: None,
+                speaker: None,
+            })),
+        }
+    }
+
+    /// Starts a new session, which can be used to send ops to the brachiograph.
+    pub fn session(&self) -> Session {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id = inner.next_id.wrapping_add(1).max(1);
+        Session {
+            id,
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+// Where a `SharedSerial` starts numbering its sessions.
+//
+// The brachiograph only knows sessions by their ids, so two programs that share a brachiograph
+// (or two `SharedSerial`s in one program) need to use different ones. `RandomState` gets fresh
+// random keys every time, and the process id and the time make a collision even less likely.
+fn first_id() -> SessionId {
+    let mut hasher = RandomState::new().build_hasher();
+    std::process::id().hash(&mut hasher);
+    SystemTime::now().hash(&mut hasher);
+    // Zero isn't a valid session.
+    (hasher.finish() as SessionId).max(1)
+}
+
+/// One producer of ops for a shared brachiograph.
+///
+/// The session's claim (if it has one) is released when it is dropped.
+pub struct Session {
+    id: SessionId,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Session {
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// Tries to get the exclusive right to send slow ops.
+    ///
+    /// Returns `false` if some other session has the claim, and hasn't released it or let it
+    /// time out.
+    pub fn claim(&mut self) -> anyhow::Result<bool> {
+        let mut inner = self.inner.lock().map_err(|_| anyhow!("poisoned lock"))?;
+        if inner.owner == Some(self.id) {
+            return Ok(true);
+        }
+        inner.speaker = Some(self.id);
+        match inner.serial.send(Op::Claim(self.id))? {
+            Resp::Ack => {
+                inner.owner = Some(self.id);
+                Ok(true)
+            }
+            Resp::Denied => Ok(false),
+            resp => bail!("unexpected response {resp:?} to Claim"),
+        }
+    }
+
+    /// Gives up this session's claim, if it has one.
+    pub fn release(&mut self) -> anyhow::Result<()> {
+        let mut inner = self.inner.lock().map_err(|_| anyhow!("poisoned lock"))?;
+        if inner.owner == Some(self.id) {
+            inner.owner = None;
+            inner.serial.send(Op::Release(self.id))?;
+        }
+        Ok(())
+    }
+
+    pub fn has_claim(&self) -> bool {
+        self.inner
+            .lock()
+            .map(|inner| inner.owner == Some(self.id))
+            .unwrap_or(false)
+    }
+
+    /// Sends an op to the brachiograph.
+    ///
+    /// Slow ops will be refused (without being sent) unless this session has the claim.
+    pub fn send(&mut self, op: Op) -> anyhow::Result<Resp> {
+        let mut inner = self.inner.lock().map_err(|_| anyhow!("poisoned lock"))?;
+        if op.is_slow() && inner.owner != Some(self.id) {
+            return Ok(Resp::Denied);
+        }
+        // Another session asked for the claim since we last sent anything, so remind the
+        // brachiograph who's talking.
+        if op.is_slow() && inner.speaker != Some(self.id) {
+            inner.speaker = Some(self.id);
+            match inner.serial.send(Op::Claim(self.id))? {
+                Resp::Ack => {}
+                Resp::Denied => {
+                    inner.owner = None;
+                    return Ok(Resp::Denied);
+                }
+                resp => bail!("unexpected response {resp:?} to Claim"),
+            }
+        }
+        let resp = inner.serial.send(op)?;
+        if matches!(resp, Resp::Denied) {
+            // Our claim timed out, and someone else took it.
+            inner.owner = None;
+        }
+        Ok(resp)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Err(e) = self.release() {
+            log::warn!("failed to release session {}: {e}", self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{transcript::Transcript, Transport};
+
+    fn shared() -> SharedSerial {
+        SharedSerial::new(Serial::replay(&Transcript::default(), Transport::Postcard))
+    }
+
+    #[test]
+    fn independent_sessions_differ() {
+        // Each of these could be in a different program, talking to the same brachiograph.
+        let (a, b) = (shared(), shared());
+        let (a1, a2, b1) = (a.session(), a.session(), b.session());
+        assert_ne!(a1.id(), a2.id());
+        assert_ne!(a1.id(), b1.id());
+        assert_ne!(a2.id(), b1.id());
+        assert!([a1.id(), a2.id(), b1.id()].iter().all(|&id| id != 0));
+    }
+}
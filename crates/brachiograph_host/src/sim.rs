@@ -0,0 +1,488 @@
+//! A pretend brachiograph, for trying things out without the real one.
+//!
+//! The [`Simulator`] plans its movements in the same way as the firmware does, and it keeps
+//! track of what the pen would have drawn. It carries out each op as soon as it gets it, so
+//! it's much faster than the real thing, but [`Simulator::elapsed`] says how long a real
+//! brachiograph would have taken.
+
+use brachiograph::{
+    demo::Demo,
+    pwm::{CalibratedPosition, TogglePwm},
+    Brachiograph, DeviceInfo, Duration, ErrorCode, Instant, LimitPolicy, Op, PenState, QueueStatus,
+    Resp, DEFAULT_BLEND_ANGLE,
+};
+use kurbo::{BezPath, Point};
+
+use crate::Plotter;
+
+/// A pretend brachiograph.
+pub struct Simulator {
+    brachio: Brachiograph,
+    calib: CalibratedPosition,
+    start: Instant,
+    now: Instant,
+    pos: Point,
+    drawing: BezPath,
+    // Are we in the middle of a stroke? (If not, the next pen-down position starts a new one.)
+    in_stroke: bool,
+    // How far the pen has moved while it was up.
+    travel: f64,
+    // How many slow ops have been carried out (see `Resp::Progress`).
+    ops_done: u32,
+    limit_policy: LimitPolicy,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Simulator::new()
+    }
+}
+
+impl Simulator {
+    /// Makes a simulator that starts out like the firmware does, resting at `(-8, 8)` with the
+    /// pen up and with blending turned on.
+    ///
+    /// The simulator finishes each op before it sees the next one, so unlike the firmware it
+    /// can't tell a movement to carry straight on into the next (see
+    /// [`Brachiograph::continue_into`]). Every movement slows down to a stop, so nothing gets
+    /// blended and plots take a little longer than they would on paper.
+    pub fn new() -> Simulator {
+        let start = Instant::from_ticks(0);
+        Simulator {
+            brachio: Brachiograph::new(-8, 8).with_blend_angle(Some(DEFAULT_BLEND_ANGLE)),
+            calib: CalibratedPosition::default(),
+            start,
+            now: start,
+            pos: Point::new(-8.0, 8.0),
+            drawing: BezPath::new(),
+            in_stroke: false,
+            travel: 0.0,
+            ops_done: 0,
+            limit_policy: LimitPolicy::default(),
+        }
+    }
+
+    /// Carries out a single op, returning the response that the firmware would have sent.
+    pub fn send(&mut self, op: Op) -> Resp {
+        let now = self.now;
+        let slow = op.is_slow();
+        let resp = match op {
+            Op::MoveTo(_) | Op::QuadTo(..) | Op::CurveTo(..) | Op::ArcTo(..) | Op::MoveBy(..) => {
+                let config = self.brachio.config().clone();
+                let policy = self.limit_policy;
+                let resting = self.resting();
+                let result = match policy.apply(&config, resting.pos(), op) {
+                    Some(Op::MoveBy(r, theta)) => resting.move_by(now, r, theta),
+                    // Unwrap: the other moves all have segments.
+                    Some(op) => resting.follow(now, op.segment().unwrap()),
+                    None => Err(()),
+                };
+                match result {
+                    Ok(()) => Resp::Ack,
+                    Err(()) => Resp::Error(ErrorCode::OutOfRange),
+                }
+            }
+            Op::PenUp => {
+                self.resting().pen_up(now);
+                Resp::Ack
+            }
+            Op::PenDown | Op::SetPenAngle(_) => {
+                let angle = match op {
+                    Op::SetPenAngle(angle) => Some(angle),
+                    _ => None,
+                };
+                let down = self.brachio.pen(now) == PenState::Down;
+                if self.calib.set_pen_angle(angle) && down {
+                    self.resting().settle_pen(now);
+                } else {
+                    self.resting().pen_down(now);
+                }
+                Resp::Ack
+            }
+            Op::Dwell(ms) => {
+                self.resting().dwell(now, Duration::millis(ms.into()));
+                Resp::Ack
+            }
+            // There are no servos here to relax, so finishing is just parking.
+            Op::Home | Op::Park | Op::Finish => {
+                let config = self.brachio.config();
+                let (x, y) = if op == Op::Home {
+                    config.home
+                } else {
+                    config.park
+                };
+                self.resting().pen_up(now);
+                self.finish();
+                let now = self.now;
+                match self.resting().move_to(now, x, y) {
+                    Ok(()) => Resp::Ack,
+                    Err(()) => Resp::Error(ErrorCode::OutOfRange),
+                }
+            }
+            Op::SetSpeed(speed) if speed > 0 => {
+                self.brachio.set_speed(speed);
+                Resp::Ack
+            }
+            Op::SetSpeed(_) => Resp::Error(ErrorCode::MalformedOp),
+            Op::SetPenLift(ms) => {
+                self.brachio.set_pen_lift(Duration::millis(ms.into()));
+                Resp::Ack
+            }
+            Op::SetAngleInterpolation(Some(max_len)) if max_len < 0 => {
+                Resp::Error(ErrorCode::MalformedOp)
+            }
+            Op::SetAngleInterpolation(max_len) => {
+                self.brachio.set_angle_interpolation(max_len);
+                Resp::Ack
+            }
+            // We only know about positions, not raw servo duties.
+            Op::ChangePosition(_) | Op::ChangePenPosition(_) => Resp::Nack,
+            Op::Calibrate(_, _, calib) if !calib.is_valid() => {
+                Resp::Error(ErrorCode::CalibrationRange)
+            }
+            Op::Calibrate(joint, dir, calib) => {
+                self.calib.change_calibration(joint, dir, calib);
+                Resp::Ack
+            }
+            Op::SetInverted(joint, inverted) => {
+                self.calib.change_inversion(joint, inverted);
+                Resp::Ack
+            }
+            Op::SetTiming(timing) if !timing.is_valid() => Resp::Error(ErrorCode::CalibrationRange),
+            Op::SetTiming(timing) => {
+                self.calib.change_timing(timing);
+                Resp::Ack
+            }
+            Op::SetPenRamp(ramp) if !ramp.is_valid() => Resp::Error(ErrorCode::CalibrationRange),
+            Op::SetPenRamp(ramp) => {
+                self.calib.change_pen_ramp(ramp);
+                Resp::Ack
+            }
+            Op::CalibratePen(pen) if !pen.is_valid() => Resp::Error(ErrorCode::CalibrationRange),
+            Op::CalibratePen(pen) => {
+                self.calib.change_pen_calibration(pen);
+                Resp::Ack
+            }
+            Op::CalibratePenAngles(table) if !table.is_valid() => {
+                Resp::Error(ErrorCode::CalibrationRange)
+            }
+            Op::CalibratePenAngles(table) => {
+                self.calib.change_pen_angles(table);
+                Resp::Ack
+            }
+            Op::SetPenDuty { up, down } => {
+                let pen = TogglePwm { on: down, off: up };
+                if pen.is_valid() {
+                    self.calib.change_pen_calibration(pen);
+                    Resp::Ack
+                } else {
+                    Resp::Error(ErrorCode::CalibrationRange)
+                }
+            }
+            Op::GetPosition => {
+                let angles = self.brachio.update(self.now);
+                let pen = self.brachio.pen(self.now);
+                Resp::CurPosition(self.calib.clone().update(angles, pen))
+            }
+            Op::GetAngles => Resp::Angles(self.brachio.update(self.now)),
+            Op::SetConfig(config) if !config.is_valid() => Resp::Error(ErrorCode::InvalidConfig),
+            // We're always resting between ops, so the only problem is if the pen is somewhere
+            // that the new config doesn't allow.
+            Op::SetConfig(config) => match self.brachio.set_config(config) {
+                Ok(()) => Resp::Ack,
+                Err(()) => Resp::Error(ErrorCode::OutOfRange),
+            },
+            Op::GetConfig => Resp::Config(self.brachio.config().clone()),
+            Op::SetLimitPolicy(policy) => {
+                self.limit_policy = policy;
+                Resp::Ack
+            }
+            Op::SetJointLimits(limits) if !limits.is_valid() => Resp::Error(ErrorCode::MalformedOp),
+            Op::SetJointLimits(limits) => {
+                self.brachio.set_joint_limits(limits);
+                Resp::Ack
+            }
+            // There's no firmware to describe, but the calibration is real enough.
+            Op::GetInfo => Resp::Info(DeviceInfo {
+                calibration_crc: self.calib.calib.checksum(),
+                ..DeviceInfo::default()
+            }),
+            // Ops are carried out as soon as they arrive, so the queue is always empty. The
+            // capacity is the same as the firmware's.
+            Op::GetQueue => Resp::Queue(QueueStatus {
+                len: 0,
+                capacity: 32,
+            }),
+            Op::GetProgress => Resp::Progress {
+                ops_done: self.ops_done,
+                queue_len: 0,
+            },
+            Op::EstimateTime => Resp::TimeLeft { millis: 0 },
+            // There's no one else to keep out, so the demo gets done all at once.
+            Op::RunDemo => Demo::new()
+                .map(|op| self.send(op))
+                .find(|resp| !matches!(resp, Resp::Ack))
+                .unwrap_or(Resp::Ack),
+            // There's no one to wait for, so pauses are over as soon as they start.
+            Op::Pause(_) => Resp::Ack,
+            Op::Cancel
+            | Op::Claim(_)
+            | Op::Release(_)
+            | Op::Resume
+            | Op::SetPlotLimit(_)
+            | Op::ClearEStop => Resp::Ack,
+        };
+        self.finish();
+        if slow && matches!(resp, Resp::Ack) {
+            self.ops_done = self.ops_done.wrapping_add(1);
+        }
+        resp
+    }
+
+    /// How long a real brachiograph would have taken to do everything so far.
+    pub fn elapsed(&self) -> std::time::Duration {
+        std::time::Duration::from_micros((self.now - self.start).to_micros())
+    }
+
+    /// Everything that the pen has drawn so far, with one subpath per stroke.
+    pub fn drawing(&self) -> &BezPath {
+        &self.drawing
+    }
+
+    /// How far the pen has moved without drawing anything.
+    pub fn travel_distance(&self) -> f64 {
+        self.travel
+    }
+
+    /// Renders everything that the pen has drawn so far as an SVG document.
+    pub fn to_svg(&self) -> String {
+        crate::path::to_svg_document(&self.drawing)
+    }
+
+    fn resting(&mut self) -> brachiograph::RestingBrachiograph<'_> {
+        // Unwrap: we always finish one op before starting the next.
+        self.brachio.resting().unwrap()
+    }
+
+    // Runs the brachiograph until it's done with whatever it's doing, following the pen.
+    fn finish(&mut self) {
+        // A blended movement might be over already, like it would be in the firmware.
+        self.brachio.update(self.now);
+        // We work out where the pen is as often as the firmware does, so that curves get the same
+        // corners that they would on paper.
+        let tick = self.calib.calib.timing.tick();
+        while self.brachio.resting().is_none() {
+            self.now += tick;
+            let angles = self.brachio.update(self.now);
+            let (x, y) = self.brachio.config().coord_at_angle::<f64>(angles);
+            let pos = Point::new(x, y);
+            if self.brachio.pen(self.now) == PenState::Down {
+                if !self.in_stroke {
+                    self.drawing.move_to(self.pos);
+                    self.in_stroke = true;
+                }
+                if pos != self.pos {
+                    self.drawing.line_to(pos);
+                }
+            } else {
+                self.in_stroke = false;
+                self.travel += (pos - self.pos).hypot();
+            }
+            self.pos = pos;
+        }
+    }
+}
+
+impl Plotter for Simulator {
+    fn send(&mut self, op: Op) -> anyhow::Result<Resp> {
+        Ok(Simulator::send(self, op))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use brachiograph::{Angle, Fixed};
+    use kurbo::{PathEl, Shape};
+
+    use super::*;
+
+    fn mv(x: f64, y: f64) -> Op {
+        Op::MoveTo(brachiograph::Point {
+            x: Fixed::from_num(x),
+            y: Fixed::from_num(y),
+        })
+    }
+
+    #[test]
+    fn draws_a_square() {
+        let mut sim = Simulator::new();
+        let ops = [
+            mv(0.0, 8.0),
+            Op::PenDown,
+            mv(2.0, 8.0),
+            mv(2.0, 10.0),
+            mv(0.0, 10.0),
+            mv(0.0, 8.0),
+            Op::PenUp,
+            mv(-8.0, 8.0),
+        ];
+        for op in ops {
+            assert!(matches!(sim.send(op), Resp::Ack));
+        }
+
+        let drawing = sim.drawing();
+        let starts = drawing
+            .elements()
+            .iter()
+            .filter(|el| matches!(el, PathEl::MoveTo(_)))
+            .count();
+        assert_eq!(starts, 1);
+        let bbox = drawing.bounding_box();
+        assert!((bbox.width() - 2.0).abs() < 0.05, "{bbox:?}");
+        assert!((bbox.height() - 2.0).abs() < 0.05, "{bbox:?}");
+        assert!((bbox.min_y() - 8.0).abs() < 0.05, "{bbox:?}");
+        assert!((sim.travel_distance() - 16.0).abs() < 0.05);
+
+        // 8 units of drawing and 16 units of travel at the default speed, plus two pen lifts.
+        let expected = 24.0 / brachiograph::DEFAULT_SPEED.to_num::<f64>()
+            + 2.0 * brachiograph::DEFAULT_PEN_LIFT.to_millis() as f64 / 1000.0;
+        assert!((sim.elapsed().as_secs_f64() - expected).abs() < 0.2);
+
+        assert!(sim.to_svg().contains("<path d=\"M"));
+    }
+
+    #[test]
+    fn home_and_park() {
+        let mut sim = Simulator::new();
+        let config = brachiograph::geom::Config::default();
+        let pos = |sim: &mut Simulator| match sim.send(Op::GetAngles) {
+            Resp::Angles(angles) => Point::from(config.coord_at_angle::<f64>(angles)),
+            resp => panic!("unexpected {resp:?}"),
+        };
+
+        for op in [mv(0.0, 8.0), Op::PenDown, mv(2.0, 8.0), Op::Home] {
+            assert!(matches!(sim.send(op), Resp::Ack));
+        }
+        // The pen came up before going home, so only the line got drawn.
+        assert!((sim.drawing().perimeter(0.01) - 2.0).abs() < 0.05);
+        assert!(pos(&mut sim).distance(Point::new(0.0, 9.0)) < 0.05);
+
+        assert!(matches!(sim.send(Op::Park), Resp::Ack));
+        assert!(pos(&mut sim).distance(Point::new(-8.0, 8.0)) < 0.05);
+    }
+
+    #[test]
+    fn limit_policy() {
+        let mut sim = Simulator::new();
+        let config = brachiograph::geom::Config::default();
+        assert!(matches!(
+            sim.send(mv(12.0, 9.0)),
+            Resp::Error(ErrorCode::OutOfRange)
+        ));
+        assert!(matches!(
+            sim.send(Op::SetLimitPolicy(LimitPolicy::ClampToRect)),
+            Resp::Ack
+        ));
+        assert!(matches!(sim.send(mv(12.0, 9.0)), Resp::Ack));
+        let pos = match sim.send(Op::GetAngles) {
+            Resp::Angles(angles) => Point::from(config.coord_at_angle::<f64>(angles)),
+            resp => panic!("unexpected {resp:?}"),
+        };
+        assert!(pos.distance(Point::new(8.0, 9.0)) < 0.05, "{pos:?}");
+    }
+
+    #[test]
+    fn runs_the_demo() {
+        let mut sim = Simulator::new();
+        assert!(matches!(sim.send(Op::RunDemo), Resp::Ack));
+        let strokes = sim
+            .drawing()
+            .elements()
+            .iter()
+            .filter(|el| matches!(el, PathEl::MoveTo(_)))
+            .count();
+        assert_eq!(strokes, 4);
+        assert!(sim.drawing().bounding_box().width() > 10.0);
+    }
+
+    #[test]
+    fn counts_finished_ops() {
+        let mut sim = Simulator::new();
+        sim.send(Op::PenDown);
+        sim.send(mv(0.0, 10.0));
+        // Fast ops and refused moves don't count.
+        sim.send(Op::GetQueue);
+        sim.send(mv(0.0, 100.0));
+        assert!(matches!(
+            sim.send(Op::GetProgress),
+            Resp::Progress {
+                ops_done: 2,
+                queue_len: 0
+            }
+        ));
+    }
+
+    #[test]
+    fn pen_angles() {
+        let mut sim = Simulator::new();
+        let pen_duty = |sim: &mut Simulator| match sim.send(Op::GetPosition) {
+            Resp::CurPosition(pos) => pos.pen,
+            resp => panic!("unexpected {resp:?}"),
+        };
+        sim.send(Op::PenDown);
+        let down = pen_duty(&mut sim);
+        let before = sim.elapsed();
+        sim.send(Op::SetPenAngle(Angle::from_degrees(60)));
+        assert_ne!(pen_duty(&mut sim), down);
+        // Moving the pen to a new angle takes a little while.
+        assert!(sim.elapsed() > before);
+        sim.send(Op::PenDown);
+        assert_eq!(pen_duty(&mut sim), down);
+    }
+
+    #[test]
+    fn refuses_bad_moves() {
+        let mut sim = Simulator::new();
+        assert!(matches!(
+            sim.send(mv(0.0, 100.0)),
+            Resp::Error(ErrorCode::OutOfRange)
+        ));
+        assert!(matches!(
+            sim.send(Op::SetSpeed(Fixed::from_num(0))),
+            Resp::Error(ErrorCode::MalformedOp)
+        ));
+        // The brachiograph didn't go anywhere.
+        assert_eq!(sim.elapsed(), std::time::Duration::ZERO);
+        assert!(matches!(sim.send(mv(1.0, 9.0)), Resp::Ack));
+    }
+
+    #[test]
+    fn smaller_drawing_area() {
+        let mut sim = Simulator::new();
+        let mut config = brachiograph::geom::Config {
+            x_range: (Fixed::from_num(-4), Fixed::from_num(4)),
+            park: (Fixed::from_num(-4), Fixed::from_num(8)),
+            ..Default::default()
+        };
+        // The pen starts out resting at (-8, 8), which isn't allowed any more.
+        assert!(matches!(
+            sim.send(Op::SetConfig(config.clone())),
+            Resp::Error(ErrorCode::OutOfRange)
+        ));
+        assert!(matches!(sim.send(mv(0.0, 9.0)), Resp::Ack));
+        assert!(matches!(sim.send(Op::SetConfig(config.clone())), Resp::Ack));
+        assert!(matches!(sim.send(Op::GetConfig), Resp::Config(c) if c == config));
+        assert!(matches!(
+            sim.send(mv(6.0, 9.0)),
+            Resp::Error(ErrorCode::OutOfRange)
+        ));
+        assert!(matches!(sim.send(mv(3.0, 9.0)), Resp::Ack));
+
+        // Arms this short can't reach the far corners.
+        config.arm_len = Fixed::from_num(1);
+        assert!(matches!(
+            sim.send(Op::SetConfig(config)),
+            Resp::Error(ErrorCode::InvalidConfig)
+        ));
+    }
+}
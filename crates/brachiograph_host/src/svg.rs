@@ -0,0 +1,1063 @@
+//! Turning SVG files into jobs.
+//!
+//! The simplest way in is [`to_job`], which does everything at once. The steps are also
+//! available separately: [`parse`] reads the paths out of an SVG file, [`fit`] scales them
+//! to fit on the page, and [`flatten`] turns them into line segments.
+//!
+//! The top-level groups in an SVG file (which is how Inkscape stores its layers) become
+//! [layers](crate::Layer) in the job, so that [`Job::layer_stats`] can say how much each one
+//! costs to plot. Paths of different colors go in different layers, and the color becomes the
+//! layer's [pen](crate::Layer::pen), so that [`Job::with_pen_changes`] can stop to change pens.
+//!
+//! Stroked shapes get their outlines drawn, and filled shapes get shaded with hatching lines (see
+//! [`Hatching`]). A shape that's both gets both, each in its own color.
+//!
+//! Text gets turned into the outlines of its letters, using the [`Fonts`] that it's given, and
+//! then it's drawn like any other shape.
+
+use std::{
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+use anyhow::Context;
+use brachiograph::Op;
+use kurbo::{Affine, BezPath, PathEl, Point, Rect};
+pub use usvg::FillRule;
+use usvg::NodeExt;
+use usvg_text_layout::{TextToPath, TreeTextToPath};
+
+use crate::{path, profile::Settings, Job, Layer};
+
+/// The part of the page that we draw SVGs on, unless asked otherwise.
+pub const DEFAULT_RECT: Rect = Rect::new(-8.0, 5.0, 8.0, 13.0);
+
+/// How to shade the insides of filled shapes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hatching {
+    /// How far apart the hatching lines are, in brachiograph units. Smaller is darker, and takes
+    /// longer. Zero turns hatching off, so that filled shapes just get their outlines drawn.
+    pub spacing: f64,
+    /// The direction of the hatching lines, in degrees counter-clockwise from the x axis.
+    pub angle: f64,
+}
+
+impl Default for Hatching {
+    fn default() -> Self {
+        Hatching {
+            spacing: 0.2,
+            angle: 45.0,
+        }
+    }
+}
+
+// How closely the ends of the hatching lines follow curved outlines, for jobs that don't have
+// settings to say.
+const HATCH_TOLERANCE: f64 = 0.01;
+
+// Segments shorter than this get left out by `flatten`.
+const MIN_SEGMENT: f64 = 1e-9;
+
+impl Hatching {
+    fn is_on(&self) -> bool {
+        self.spacing > 0.0
+    }
+
+    /// The hatching lines that shade the inside of `fill`, each as its own subpath. Every other
+    /// line goes the other way, so that the pen doesn't have to go all the way back between them.
+    ///
+    /// Curves in the outline are flattened to within `tolerance` first.
+    pub fn lines(&self, fill: &Fill, tolerance: f64) -> BezPath {
+        let mut ret = BezPath::new();
+        if !self.is_on() {
+            return ret;
+        }
+        // Turn everything so that the lines are horizontal, and then turn them back at the end.
+        let turn = Affine::rotate(-self.angle.to_radians());
+        let unturn = turn.inverse();
+        // The edges of the outline, with every subpath closed off (because that's how filling
+        // works in SVG, whether or not it's closed).
+        let mut edges = Vec::new();
+        let (mut start, mut last) = (Point::ZERO, Point::ZERO);
+        fill.outline.flatten(tolerance, |el| match el {
+            PathEl::MoveTo(p) => {
+                edges.push((last, start));
+                start = turn * p;
+                last = start;
+            }
+            PathEl::LineTo(p) => {
+                let p = turn * p;
+                edges.push((last, p));
+                last = p;
+            }
+            PathEl::ClosePath => {
+                edges.push((last, start));
+                last = start;
+            }
+            _ => unreachable!(),
+        });
+        edges.push((last, start));
+
+        let (y0, y1) = edges
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(y0, y1), e| {
+                (y0.min(e.0.y).min(e.1.y), y1.max(e.0.y).max(e.1.y))
+            });
+        // The lines are lined up across the whole page, so that shapes next to each other have
+        // matching hatching.
+        let first = (y0 / self.spacing).ceil() as i64;
+        let last = (y1 / self.spacing).floor() as i64;
+        let mut crossings = Vec::new();
+        let mut spans = Vec::new();
+        for row in first..=last {
+            let y = row as f64 * self.spacing;
+            // Where the line crosses the outline, and whether the outline was going up or down.
+            crossings.clear();
+            for &(a, b) in &edges {
+                if (a.y <= y) != (b.y <= y) {
+                    let x = a.x + (y - a.y) / (b.y - a.y) * (b.x - a.x);
+                    crossings.push((x, if b.y > a.y { 1 } else { -1 }));
+                }
+            }
+            crossings.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+            spans.clear();
+            let mut winding = 0;
+            let mut entered = None;
+            for &(x, dir) in &crossings {
+                winding += dir;
+                let inside = match fill.rule {
+                    FillRule::NonZero => winding != 0,
+                    FillRule::EvenOdd => winding % 2 != 0,
+                };
+                match entered {
+                    None if inside => entered = Some(x),
+                    Some(x0) if !inside => {
+                        spans.push((x0, x));
+                        entered = None;
+                    }
+                    _ => {}
+                }
+            }
+            if row % 2 != 0 {
+                spans.reverse();
+                for span in &mut spans {
+                    *span = (span.1, span.0);
+                }
+            }
+            for &(x0, x1) in &spans {
+                if x0 != x1 {
+                    ret.move_to(unturn * Point::new(x0, y));
+                    ret.line_to(unturn * Point::new(x1, y));
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// The fonts that text in SVG files gets drawn with.
+///
+/// The default has no fonts at all, so text just gets left out; [`Fonts::system`] has the ones
+/// installed on this computer.
+#[derive(Clone, Debug)]
+pub struct Fonts {
+    // This is shared, because there are usually lots of system fonts.
+    db: Arc<fontdb::Database>,
+    /// The font family for text that doesn't say which one it wants. If this is `None`, it's
+    /// Times New Roman.
+    pub family: Option<String>,
+}
+
+impl Default for Fonts {
+    fn default() -> Self {
+        Fonts {
+            db: Arc::new(fontdb::Database::new()),
+            family: None,
+        }
+    }
+}
+
+impl Fonts {
+    /// The fonts that are installed on this computer.
+    ///
+    /// Finding them takes a while, so it only happens the first time that this is called.
+    pub fn system() -> Fonts {
+        static SYSTEM: OnceLock<Arc<fontdb::Database>> = OnceLock::new();
+        let db = SYSTEM.get_or_init(|| {
+            let mut db = fontdb::Database::new();
+            db.load_system_fonts();
+            Arc::new(db)
+        });
+        Fonts {
+            db: Arc::clone(db),
+            family: None,
+        }
+    }
+
+    /// Adds some fonts: the ones in `path` if it's a font file, or all the ones in it (and in
+    /// the directories inside it) if it's a directory.
+    pub fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        let db = Arc::make_mut(&mut self.db);
+        if path.is_dir() {
+            db.load_fonts_dir(path);
+            Ok(())
+        } else {
+            db.load_font_file(path)
+                .with_context(|| format!("couldn't load fonts from {}", path.display()))
+        }
+    }
+}
+
+/// Reads the paths out of an SVG file, in SVG coordinates (see [`parse`]).
+pub fn load(path: &Path, fonts: &Fonts) -> anyhow::Result<Vec<BezPath>> {
+    parse(&std::fs::read(path)?, fonts)
+}
+
+/// Reads the paths out of an SVG document, in SVG coordinates.
+///
+/// This is the outline of every shape that's stroked or filled, with no hatching. The
+/// coordinates are the ones that the document is drawn in, with the `viewBox` and the transforms
+/// of the paths and all the groups around them already applied.
+pub fn parse(data: &[u8], fonts: &Fonts) -> anyhow::Result<Vec<BezPath>> {
+    let mut ret = Vec::new();
+    for_each_path(data, fonts, |_, _, p, bez| {
+        if p.stroke.is_some() || p.fill.is_some() {
+            ret.push(bez);
+        }
+    })?;
+    Ok(ret)
+}
+
+/// Some of the paths in an SVG document.
+#[derive(Clone, Debug, Default)]
+pub struct SvgLayer {
+    /// The id of the group that the paths were in, or an empty string for paths that weren't in
+    /// a top-level group.
+    pub name: String,
+    /// The color that the paths are drawn in, like `#ff0000`, if they're a plain color.
+    pub color: Option<String>,
+    /// The outlines of stroked shapes.
+    pub paths: Vec<BezPath>,
+    /// Filled shapes, which get hatched.
+    pub fills: Vec<Fill>,
+}
+
+/// The outline of a filled shape, and how to tell which parts of it are inside.
+#[derive(Clone, Debug)]
+pub struct Fill {
+    pub outline: BezPath,
+    pub rule: FillRule,
+}
+
+/// Reads the paths out of an SVG document, in SVG coordinates, grouped by the top-level group
+/// that they're in.
+pub fn parse_layers(data: &[u8], fonts: &Fonts) -> anyhow::Result<Vec<SvgLayer>> {
+    let mut ret: Vec<SvgLayer> = Vec::new();
+    // The top-level node that we're in, and where its layers start.
+    let (mut current, mut first) = (None, 0);
+    for_each_path(data, fonts, |i, name, p, bez| {
+        if current != Some(i) {
+            current = Some(i);
+            first = ret.len();
+        }
+        let mut add = |paint: &usvg::Paint, stroke: Option<BezPath>, fill: Option<Fill>| {
+            let color = color(paint);
+            // Paths that aren't in a group get lumped together with their neighbors, and so do
+            // paths in the same group. Either way, they're split up when the color changes.
+            let same_group = ret.len() > first || name.is_empty();
+            let layer = match ret.last_mut() {
+                Some(last) if same_group && last.name == name && last.color == color => last,
+                _ => {
+                    ret.push(SvgLayer {
+                        name: name.to_owned(),
+                        color,
+                        ..SvgLayer::default()
+                    });
+                    // Unwrap: we just pushed it.
+                    ret.last_mut().unwrap()
+                }
+            };
+            layer.paths.extend(stroke);
+            layer.fills.extend(fill);
+        };
+        // The inside gets drawn first, like in the SVG, so that the outline goes over the ends of
+        // the hatching lines.
+        if let Some(fill) = &p.fill {
+            let fill_shape = Fill {
+                outline: bez.clone(),
+                rule: fill.rule,
+            };
+            add(&fill.paint, None, Some(fill_shape));
+        }
+        if let Some(stroke) = &p.stroke {
+            add(&stroke.paint, Some(bez), None);
+        }
+    })?;
+    Ok(ret)
+}
+
+// Calls `f` on every non-empty path in an SVG document, along with the index of the top-level
+// node that it's in, that node's id if it's a group (or an empty string, if it isn't), and the
+// path's outline in the coordinates of the whole document.
+fn for_each_path(
+    data: &[u8],
+    fonts: &Fonts,
+    mut f: impl FnMut(usize, &str, &usvg::Path, BezPath),
+) -> anyhow::Result<()> {
+    let mut opt = usvg::Options {
+        keep_named_groups: true,
+        ..usvg::Options::default()
+    };
+    if let Some(family) = &fonts.family {
+        opt.font_family = family.clone();
+    }
+    let mut tree = usvg::Tree::from_data(data, &opt)?;
+    // This replaces text with paths (in groups, so that they keep their transforms).
+    let texts: Vec<_> = tree
+        .root
+        .descendants()
+        .filter(|node| matches!(*node.borrow(), usvg::NodeKind::Text(_)))
+        .collect();
+    let mut dropped = 0;
+    for node in texts {
+        let mut transform = node
+            .parent()
+            .map_or_else(Default::default, |p| p.abs_transform());
+        let converted = match &mut *node.borrow_mut() {
+            usvg::NodeKind::Text(text) => {
+                // usvg is supposed to fall back to `opt.font_family` for text that doesn't say
+                // which font it wants, but it gives it an empty family name instead.
+                for span in text.chunks.iter_mut().flat_map(|c| c.spans.iter_mut()) {
+                    if span.font.families.iter().all(|f| f.is_empty()) {
+                        span.font.families = vec![opt.font_family.clone()];
+                    }
+                }
+                transform.append(&text.transform);
+                text.convert(&fonts.db, transform)
+            }
+            _ => None,
+        };
+        match converted {
+            Some(group) => node.insert_after(group),
+            None => dropped += 1,
+        }
+        node.detach();
+    }
+    if dropped > 0 {
+        log::warn!("left out {dropped} pieces of text, because none of the fonts could draw them");
+    }
+    // This does any text that's left (like in clip paths), and tidies up the groups.
+    tree.convert_text(&fonts.db, opt.keep_named_groups);
+    // usvg leaves it to us to scale the viewBox to the size of the document.
+    let view_box =
+        usvg::utils::view_box_to_transform(tree.view_box.rect, tree.view_box.aspect, tree.size);
+    let view_box = to_affine(&view_box);
+    for (i, child) in tree.root.children().enumerate() {
+        let name = match &*child.borrow() {
+            usvg::NodeKind::Group(g) => g.id.clone(),
+            _ => String::new(),
+        };
+        for node in child.descendants() {
+            if let usvg::NodeKind::Path(p) = &*node.borrow() {
+                if !p.data.is_empty() {
+                    // This is the path's own transform, and those of all the groups that it's in.
+                    let transform = view_box * to_affine(&node.abs_transform());
+                    f(i, &name, p, to_bez(p, transform));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// The color of some paint, if it's a plain color.
+fn color(paint: &usvg::Paint) -> Option<String> {
+    match paint {
+        usvg::Paint::Color(c) => Some(format!("#{:02x}{:02x}{:02x}", c.red, c.green, c.blue)),
+        _ => None,
+    }
+}
+
+fn to_affine(ts: &usvg::Transform) -> Affine {
+    Affine::new([ts.a, ts.b, ts.c, ts.d, ts.e, ts.f])
+}
+
+// Converts an SVG path to a `BezPath`, transformed by `transform`.
+fn to_bez(p: &usvg::Path, transform: Affine) -> BezPath {
+    let mut bez = BezPath::new();
+    for seg in p.data.segments() {
+        match seg {
+            usvg::PathSegment::MoveTo { x, y } => bez.move_to((x, y)),
+            usvg::PathSegment::LineTo { x, y } => bez.line_to((x, y)),
+            usvg::PathSegment::CurveTo {
+                x1,
+                y1,
+                x2,
+                y2,
+                x,
+                y,
+            } => bez.curve_to((x1, y1), (x2, y2), (x, y)),
+            usvg::PathSegment::ClosePath => bez.close_path(),
+        }
+    }
+    bez.apply_affine(transform);
+    bez
+}
+
+/// Transforms each of the paths by a common scaling and translation,
+/// so that the resulting paths all lie in `rect`.
+///
+/// Also flips the y coordinate, because svg is y-down and brachiograph is y-up.
+pub fn fit(paths: &mut [BezPath], rect: Rect) {
+    for path in paths.iter_mut() {
+        path.apply_affine(Affine::FLIP_Y);
+    }
+    path::fit(paths, rect);
+}
+
+/// Turns a path into line segments, staying within `tolerance` of its curves.
+///
+/// Each subpath comes out as a `MoveTo` followed by `LineTo`s, going the same way as in `path`,
+/// and closed subpaths end exactly where they started. Segments that don't go anywhere are left
+/// out, and so are subpaths that don't draw anything (like a `MoveTo` followed by another
+/// `MoveTo`). A subpath that only draws in one place (like `M 1 1 L 1 1`) becomes a dot: a
+/// `MoveTo` and a `LineTo` to the same point.
+// TODO: in the case of short (in terms of arc-length) sequences of segments, it might be
+// worth just converting them to a single line.
+pub fn flatten(path: &BezPath, tolerance: f64) -> BezPath {
+    // The points of each subpath, and whether it tried to draw anything.
+    let mut subpaths: Vec<(Vec<Point>, bool)> = Vec::new();
+    path.flatten(tolerance, |el| {
+        if let PathEl::MoveTo(p) = el {
+            subpaths.push((vec![p], false));
+            return;
+        }
+        if subpaths.is_empty() {
+            // Like `path::to_ops`, a path without a `MoveTo` starts at the origin.
+            subpaths.push((vec![Point::ORIGIN], false));
+        }
+        // Unwrap: there's at least one subpath, and each one starts with a point.
+        let (points, drawn) = subpaths.last_mut().unwrap();
+        *drawn = true;
+        let start = points[0];
+        let last = points.last_mut().unwrap();
+        match el {
+            PathEl::LineTo(p) => {
+                if last.distance(p) > MIN_SEGMENT {
+                    points.push(p);
+                }
+            }
+            PathEl::ClosePath => {
+                if last.distance(start) > MIN_SEGMENT {
+                    points.push(start);
+                } else {
+                    // It's already back at the start, but maybe not exactly.
+                    *last = start;
+                }
+                // Anything after a `ClosePath` carries on from the start of the subpath.
+                subpaths.push((vec![start], false));
+            }
+            _ => unreachable!(),
+        }
+    });
+
+    let mut ret = BezPath::new();
+    for (points, drawn) in subpaths {
+        if points.len() == 1 && drawn {
+            ret.move_to(points[0]);
+            ret.line_to(points[0]);
+        } else if points.len() > 1 {
+            ret.move_to(points[0]);
+            for &p in &points[1..] {
+                ret.line_to(p);
+            }
+        }
+    }
+    ret
+}
+
+// Reads the layers out of an SVG document, and fits all of them together into `rect`.
+fn fitted_layers(data: &[u8], rect: Rect, fonts: &Fonts) -> anyhow::Result<Vec<SvgLayer>> {
+    let mut layers = parse_layers(data, fonts)?;
+    let mut paths: Vec<BezPath> = layers
+        .iter()
+        .flat_map(|l| {
+            let fills = l.fills.iter().map(|f| f.outline.clone());
+            l.paths.iter().cloned().chain(fills)
+        })
+        .collect();
+    fit(&mut paths, rect);
+    let mut paths = paths.into_iter();
+    for layer in &mut layers {
+        let fills = layer.fills.iter_mut().map(|f| &mut f.outline);
+        for p in layer.paths.iter_mut().chain(fills) {
+            // Unwrap: `paths` has the same paths as the layers, in the same order.
+            *p = paths.next().unwrap();
+        }
+    }
+    Ok(layers)
+}
+
+// Makes a job out of layers, turning each path (and the hatching lines of each fill, or its
+// outline if hatching is off) into ops with `to_ops`.
+fn layered_job(
+    layers: &[SvgLayer],
+    hatching: &Hatching,
+    tolerance: f64,
+    mut to_ops: impl FnMut(&BezPath) -> Vec<Op>,
+) -> Job {
+    let mut ops = Vec::new();
+    let mut starts = Vec::new();
+    for (i, layer) in layers.iter().enumerate() {
+        starts.push(Layer {
+            name: if layer.name.is_empty() {
+                format!("layer {}", i + 1)
+            } else {
+                layer.name.clone()
+            },
+            start: ops.len(),
+            pen: layer.color.clone(),
+        });
+        for fill in &layer.fills {
+            if hatching.is_on() {
+                ops.extend(to_ops(&hatching.lines(fill, tolerance)));
+            } else {
+                ops.extend(to_ops(&fill.outline));
+            }
+        }
+        for p in &layer.paths {
+            ops.extend(to_ops(p));
+        }
+    }
+    Job::new(ops).with_layers(starts)
+}
+
+/// Turns an SVG document into a job that draws it, scaled to fit in `rect`.
+pub fn to_job(
+    data: &[u8],
+    rect: Rect,
+    hatching: &Hatching,
+    fonts: &Fonts,
+    settings: &Settings,
+) -> anyhow::Result<Job> {
+    let layers = fitted_layers(data, rect, fonts)?;
+    let tolerance = settings.flatten_tolerance;
+    Ok(layered_job(&layers, hatching, tolerance, |p| {
+        let flat = flatten(p, settings.flatten_tolerance);
+        let simple = path::simplify(&flat, settings.simplify_epsilon);
+        let ops = path::to_ops(&path::split_lines(&simple, settings.max_line));
+        path::dwell_at_corners(ops, settings.corner_dwell).collect()
+    }))
+}
+
+/// Like [`to_job`], but leaves curves as curves for the brachiograph to follow by itself.
+///
+/// This makes for far fewer ops, but it needs firmware that understands [`Op::CurveTo`].
+///
+/// [`Op::CurveTo`]: brachiograph::Op::CurveTo
+pub fn to_curve_job(
+    data: &[u8],
+    rect: Rect,
+    hatching: &Hatching,
+    fonts: &Fonts,
+) -> anyhow::Result<Job> {
+    let layers = fitted_layers(data, rect, fonts)?;
+    Ok(layered_job(&layers, hatching, HATCH_TOLERANCE, |p| {
+        if p.elements().len() > 1 {
+            path::to_ops(p)
+        } else {
+            Vec::new()
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use brachiograph::PauseReason;
+    use kurbo::{ParamCurveArclen, PathSeg, Point, Shape};
+
+    use super::*;
+
+    const SVG: &[u8] = br#"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="20">
+        <path d="M 0 0 L 10 0 L 10 4" fill="none" stroke="black"/>
+        <path d="M 0 4 Q 5 6 10 4" fill="none" stroke="black"/>
+    </svg>"#;
+
+    fn assert_close(a: Point, b: Point) {
+        assert!(a.distance(b) < 1e-6, "{a:?} != {b:?}");
+    }
+
+    #[test]
+    fn fit_flips_and_scales() {
+        let mut paths = parse(SVG, &Fonts::default()).unwrap();
+        assert_eq!(paths.len(), 2);
+        fit(&mut paths, Rect::new(-8.0, 5.0, 8.0, 13.0));
+
+        // The bounding box is 10 by 5, so it gets scaled by 1.6 to fill the rect, and the
+        // top-left corner ends up at the top-left of the rect.
+        let first = paths[0].elements();
+        assert_eq!(first.len(), 3);
+        let PathEl::MoveTo(p) = first[0] else {
+            panic!("expected a MoveTo");
+        };
+        assert_close(p, Point::new(-8.0, 13.0));
+        let bbox = paths[0].bounding_box().union(paths[1].bounding_box());
+        assert!((bbox.width() - 16.0).abs() < 1e-6, "{bbox:?}");
+        assert!((bbox.center().y - 9.0).abs() < 1e-6, "{bbox:?}");
+    }
+
+    #[test]
+    fn fit_single_point() {
+        let mut path = BezPath::new();
+        path.move_to((3.0, 3.0));
+        path.line_to((3.0, 3.0));
+        let mut paths = [path];
+        fit(&mut paths, DEFAULT_RECT);
+        let PathEl::MoveTo(p) = paths[0].elements()[0] else {
+            panic!("expected a MoveTo");
+        };
+        assert_close(p, DEFAULT_RECT.center());
+    }
+
+    #[test]
+    fn flatten_closes_paths() {
+        let mut path = BezPath::new();
+        path.move_to((0.0, 0.0));
+        path.quad_to((1.0, 1.0), (2.0, 0.0));
+        path.close_path();
+        let flat = flatten(&path, 0.01);
+        assert!(flat
+            .elements()
+            .iter()
+            .all(|el| matches!(el, PathEl::MoveTo(_) | PathEl::LineTo(_))));
+        assert_eq!(
+            flat.elements().last(),
+            Some(&PathEl::LineTo(Point::new(0.0, 0.0)))
+        );
+    }
+
+    // The points of each subpath of a flattened path.
+    fn polylines(path: &BezPath) -> Vec<Vec<(f64, f64)>> {
+        let mut ret: Vec<Vec<(f64, f64)>> = Vec::new();
+        for el in path.elements() {
+            match *el {
+                PathEl::MoveTo(p) => ret.push(vec![(p.x, p.y)]),
+                PathEl::LineTo(p) => ret.last_mut().unwrap().push((p.x, p.y)),
+                _ => panic!("not flat: {el:?}"),
+            }
+        }
+        ret
+    }
+
+    fn flat(d: &str) -> Vec<Vec<(f64, f64)>> {
+        polylines(&flatten(&BezPath::from_svg(d).unwrap(), 0.01))
+    }
+
+    #[test]
+    fn flatten_keeps_subpaths() {
+        // Two closed squares, going opposite ways.
+        assert_eq!(
+            flat("M 0 0 H 4 V 4 H 0 Z M 1 1 V 3 H 3 V 1 Z"),
+            vec![
+                vec![(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (0.0, 4.0), (0.0, 0.0)],
+                vec![(1.0, 1.0), (1.0, 3.0), (3.0, 3.0), (3.0, 1.0), (1.0, 1.0)],
+            ]
+        );
+        // An open subpath and a closed one.
+        assert_eq!(
+            flat("M 0 0 L 1 0 M 5 5 L 6 5 L 6 6 Z"),
+            vec![
+                vec![(0.0, 0.0), (1.0, 0.0)],
+                vec![(5.0, 5.0), (6.0, 5.0), (6.0, 6.0), (5.0, 5.0)],
+            ]
+        );
+        // Carrying on after a `ClosePath` starts from where the subpath started.
+        assert_eq!(
+            flat("M 0 0 L 1 0 L 1 1 Z L 0 2"),
+            vec![
+                vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 0.0)],
+                vec![(0.0, 0.0), (0.0, 2.0)],
+            ]
+        );
+    }
+
+    #[test]
+    fn flatten_skips_degenerate_segments() {
+        // Segments that stay put, and a close that's already at the start.
+        assert_eq!(
+            flat("M 0 0 L 1 0 L 1 0 L 1 1 L 0 0 Z"),
+            vec![vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 0.0)]]
+        );
+        // A `MoveTo` that doesn't draw anything.
+        assert_eq!(
+            flat("M 0 0 M 5 5 L 6 5"),
+            vec![vec![(5.0, 5.0), (6.0, 5.0)]]
+        );
+        assert!(flat("M 3 3").is_empty());
+        // Drawing in one place makes a dot.
+        assert_eq!(
+            flat("M 3 3 L 3 3 M 4 4 Z"),
+            vec![vec![(3.0, 3.0), (3.0, 3.0)], vec![(4.0, 4.0), (4.0, 4.0)]]
+        );
+    }
+
+    #[test]
+    fn flatten_snaps_closed_curves_shut() {
+        // A circle made of curves, which only get back to the start approximately.
+        let circle = kurbo::Circle::new((1.0, 2.0), 3.0).to_path(1e-3);
+        for sub in polylines(&flatten(&circle, 0.01)) {
+            assert_eq!(sub.first(), sub.last());
+            assert!(sub.windows(2).all(|w| w[0] != w[1]));
+        }
+    }
+
+    #[test]
+    fn layers_from_groups() {
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="20">
+            <path d="M 0 0 L 10 0" fill="none" stroke="black"/>
+            <g id="outline">
+                <path d="M 0 0 L 10 10" fill="none" stroke="black"/>
+                <path d="M 0 10 L 10 0" fill="none" stroke="black"/>
+            </g>
+            <g id="shading">
+                <path d="M 0 5 L 10 5" fill="none" stroke="black"/>
+            </g>
+        </svg>"#;
+        let layers = parse_layers(svg, &Fonts::default()).unwrap();
+        let names: Vec<_> = layers.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, ["", "outline", "shading"]);
+        assert_eq!(layers[1].paths.len(), 2);
+        assert_eq!(parse(svg, &Fonts::default()).unwrap().len(), 4);
+
+        let settings = crate::profile::Profile::Fine.settings();
+        let job = to_job(
+            svg,
+            DEFAULT_RECT,
+            &Hatching::default(),
+            &Fonts::default(),
+            &settings,
+        )
+        .unwrap();
+        let stats = job.layer_stats(&settings);
+        let names: Vec<_> = stats.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, ["layer 1", "outline", "shading"]);
+        assert_eq!(stats[1].stats.pen_downs, 2);
+        let draw: f64 = stats.iter().map(|l| l.stats.draw_distance).sum();
+        assert!((draw - job.stats().draw_distance).abs() < 1e-6);
+    }
+
+    #[test]
+    fn layers_from_colors() {
+        let svg = br##"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="20">
+            <g id="outline">
+                <path d="M 0 0 L 10 10" fill="none" stroke="black"/>
+                <path d="M 0 10 L 10 0" fill="none" stroke="red"/>
+                <path d="M 0 5 L 10 5" fill="none" stroke="red"/>
+            </g>
+            <path d="M 5 0 L 5 10 L 6 10 Z" fill="#000000"/>
+        </svg>"##;
+        let layers = parse_layers(svg, &Fonts::default()).unwrap();
+        let colors: Vec<_> = layers.iter().map(|l| l.color.as_deref()).collect();
+        assert_eq!(colors, [Some("#000000"), Some("#ff0000"), Some("#000000")]);
+        assert_eq!(layers[1].name, "outline");
+        assert_eq!(layers[1].paths.len(), 2);
+
+        // Black, then red, then back to black.
+        let settings = crate::profile::Profile::Fine.settings();
+        let job = to_job(
+            svg,
+            DEFAULT_RECT,
+            &Hatching::default(),
+            &Fonts::default(),
+            &settings,
+        )
+        .unwrap()
+        .with_pen_changes();
+        let pauses: Vec<_> = job
+            .iter()
+            .filter_map(|op| match op {
+                Op::Pause(reason) => Some(*reason),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            pauses,
+            [PauseReason::ChangePen(1), PauseReason::ChangePen(0)]
+        );
+    }
+
+    #[test]
+    fn curve_job_is_shorter() {
+        let settings = crate::profile::Profile::Fine.settings();
+        let flat = to_job(
+            SVG,
+            DEFAULT_RECT,
+            &Hatching::default(),
+            &Fonts::default(),
+            &settings,
+        )
+        .unwrap();
+        let curved =
+            to_curve_job(SVG, DEFAULT_RECT, &Hatching::default(), &Fonts::default()).unwrap();
+        assert_eq!(curved.stats().pen_downs, 2);
+        assert!(curved.len() < flat.len());
+        assert!((curved.stats().draw_distance - flat.stats().draw_distance).abs() < 0.1);
+    }
+
+    fn square(x0: f64, y0: f64, x1: f64, y1: f64) -> BezPath {
+        Rect::new(x0, y0, x1, y1).to_path(0.0)
+    }
+
+    // How far the pen goes with the pen down.
+    fn ink(path: &BezPath) -> f64 {
+        path.segments().map(|seg| seg.arclen(1e-6)).sum()
+    }
+
+    #[test]
+    fn hatch_square() {
+        let fill = Fill {
+            outline: square(0.0, 0.0, 2.0, 2.0),
+            rule: FillRule::NonZero,
+        };
+        let hatching = Hatching {
+            spacing: 0.5,
+            angle: 0.0,
+        };
+        let lines = hatching.lines(&fill, 0.01);
+        let ends: Vec<_> = lines
+            .elements()
+            .iter()
+            .map(|el| match el {
+                PathEl::MoveTo(p) | PathEl::LineTo(p) => (p.x, p.y),
+                _ => panic!("expected only lines"),
+            })
+            .collect();
+        // Back and forth, without going back to the start of each line.
+        assert_eq!(
+            ends,
+            [
+                (0.0, 0.0),
+                (2.0, 0.0),
+                (2.0, 0.5),
+                (0.0, 0.5),
+                (0.0, 1.0),
+                (2.0, 1.0),
+                (2.0, 1.5),
+                (0.0, 1.5)
+            ]
+        );
+
+        // At 90 degrees, the lines go up and down instead.
+        let hatching = Hatching {
+            spacing: 0.5,
+            angle: 90.0,
+        };
+        let lines = hatching.lines(&fill, 0.01);
+        assert_eq!(lines.elements().len(), 8);
+        for seg in lines.segments() {
+            let PathSeg::Line(line) = seg else {
+                panic!("expected only lines");
+            };
+            assert!((line.p0.x - line.p1.x).abs() < 1e-9, "{line:?}");
+            assert!((line.length() - 2.0).abs() < 1e-9, "{line:?}");
+        }
+
+        // No spacing, no hatching.
+        let off = Hatching {
+            spacing: 0.0,
+            ..Hatching::default()
+        };
+        assert!(off.lines(&fill, 0.01).is_empty());
+    }
+
+    #[test]
+    fn fill_rules() {
+        // A square with a smaller square inside it, both going the same way round.
+        let mut outline = square(0.0, 0.0, 4.0, 4.0);
+        outline.extend(square(1.0, 1.0, 3.0, 3.0));
+        let hatching = Hatching {
+            spacing: 0.1,
+            angle: 30.0,
+        };
+        let mut fill = Fill {
+            outline,
+            rule: FillRule::NonZero,
+        };
+        // The spacing is 0.1, so the length of the lines is about ten times the area.
+        let whole = ink(&hatching.lines(&fill, 0.01));
+        assert!((whole - 160.0).abs() < 2.0, "{whole}");
+        fill.rule = FillRule::EvenOdd;
+        let holey = ink(&hatching.lines(&fill, 0.01));
+        assert!((holey - 120.0).abs() < 2.0, "{holey}");
+
+        // Unclosed shapes get filled as though they were closed.
+        let mut triangle = BezPath::new();
+        triangle.move_to((0.0, 0.0));
+        triangle.line_to((4.0, 0.0));
+        triangle.line_to((0.0, 4.0));
+        let fill = Fill {
+            outline: triangle,
+            rule: FillRule::NonZero,
+        };
+        let half = ink(&hatching.lines(&fill, 0.01));
+        assert!((half - 80.0).abs() < 2.0, "{half}");
+    }
+
+    #[test]
+    fn fills_get_hatched() {
+        let svg = br##"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="20">
+            <rect x="0" y="0" width="10" height="10" fill="red" stroke="black"/>
+            <path d="M 0 5 L 10 5" fill="blue" stroke="none"/>
+        </svg>"##;
+        let layers = parse_layers(svg, &Fonts::default()).unwrap();
+        let colors: Vec<_> = layers.iter().map(|l| l.color.as_deref()).collect();
+        assert_eq!(colors, [Some("#ff0000"), Some("#000000"), Some("#0000ff")]);
+        assert_eq!((layers[0].fills.len(), layers[0].paths.len()), (1, 0));
+        assert_eq!((layers[1].fills.len(), layers[1].paths.len()), (0, 1));
+        // Every shape gets drawn once, however it's painted.
+        assert_eq!(parse(svg, &Fonts::default()).unwrap().len(), 2);
+
+        let settings = crate::profile::Profile::Fine.settings();
+        let hatching = Hatching::default();
+        let job = to_job(svg, DEFAULT_RECT, &hatching, &Fonts::default(), &settings).unwrap();
+        let stats = job.layer_stats(&settings);
+        // The fitted square is 8 units across, so its outline is 32 long and the hatching is
+        // about 64 / 0.2 long.
+        assert!((stats[1].stats.draw_distance - 32.0).abs() < 1e-6);
+        let hatched = stats[0].stats.draw_distance;
+        assert!((hatched - 320.0).abs() < 10.0, "{hatched}");
+        // A filled line has no inside.
+        assert_eq!(stats[2].stats.draw_distance, 0.0);
+
+        // Without hatching, fills are drawn as outlines.
+        let off = Hatching {
+            spacing: 0.0,
+            ..hatching
+        };
+        let job = to_job(svg, DEFAULT_RECT, &off, &Fonts::default(), &settings).unwrap();
+        let stats = job.layer_stats(&settings);
+        assert!((stats[0].stats.draw_distance - 32.0).abs() < 1e-6);
+    }
+
+    fn assert_bbox(path: &BezPath, expected: Rect) {
+        let bbox = path.bounding_box();
+        let close = (bbox.origin() - expected.origin()).hypot() < 1e-6
+            && (bbox.size() - expected.size()).to_vec2().hypot() < 1e-6;
+        assert!(close, "{bbox:?} != {expected:?}");
+    }
+
+    #[test]
+    fn nested_transforms() {
+        // The same square three times: once as it is, once moved by a group inside a group (the
+        // inner one has no id, so usvg gets rid of it), and once moved by its own transform and
+        // then by two groups.
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="40" height="40">
+            <path d="M 0 0 h 2 v 2 h -2 Z" fill="none" stroke="black"/>
+            <g id="outer" transform="translate(10 0)">
+                <g transform="translate(0 10)">
+                    <path d="M 0 0 h 2 v 2 h -2 Z" fill="none" stroke="black"/>
+                </g>
+                <g id="inner" transform="scale(2)">
+                    <path d="M 0 0 h 2 v 2 h -2 Z" transform="translate(5 5)" fill="none"
+                        stroke="black"/>
+                </g>
+            </g>
+        </svg>"#;
+        let paths = parse(svg, &Fonts::default()).unwrap();
+        assert_eq!(paths.len(), 3);
+        assert_bbox(&paths[0], Rect::new(0.0, 0.0, 2.0, 2.0));
+        assert_bbox(&paths[1], Rect::new(10.0, 10.0, 12.0, 12.0));
+        assert_bbox(&paths[2], Rect::new(20.0, 10.0, 24.0, 14.0));
+
+        let layers = parse_layers(svg, &Fonts::default()).unwrap();
+        let names: Vec<_> = layers.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, ["", "outer"]);
+        assert_bbox(&layers[1].paths[1], Rect::new(20.0, 10.0, 24.0, 14.0));
+    }
+
+    #[test]
+    fn view_box() {
+        let square = r#"<path d="M 0 0 h 10 v 10 h -10 Z" fill="none" stroke="black"/>"#;
+        let parse_one = |svg_attrs: &str| {
+            let svg =
+                format!(r#"<svg xmlns="http://www.w3.org/2000/svg" {svg_attrs}>{square}</svg>"#);
+            let paths = parse(svg.as_bytes(), &Fonts::default()).unwrap();
+            assert_eq!(paths.len(), 1);
+            paths.into_iter().next().unwrap()
+        };
+
+        // Stretched to fill the page.
+        let stretched =
+            parse_one(r#"width="20" height="10" viewBox="0 0 10 10" preserveAspectRatio="none""#);
+        assert_bbox(&stretched, Rect::new(0.0, 0.0, 20.0, 10.0));
+        // Kept square, and put in the middle.
+        let centered = parse_one(r#"width="20" height="10" viewBox="0 0 10 10""#);
+        assert_bbox(&centered, Rect::new(5.0, 0.0, 15.0, 10.0));
+        // Scaled and moved.
+        let moved = parse_one(r#"width="10" height="10" viewBox="-10 -10 20 20""#);
+        assert_bbox(&moved, Rect::new(5.0, 5.0, 10.0, 10.0));
+
+        // The shape of the drawing survives being fitted onto the page.
+        let settings = crate::profile::Profile::Fine.settings();
+        let svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="10" viewBox="0 0 10 10"
+                preserveAspectRatio="none">{square}</svg>"#
+        );
+        let job = to_job(
+            svg.as_bytes(),
+            DEFAULT_RECT,
+            &Hatching::default(),
+            &Fonts::default(),
+            &settings,
+        )
+        .unwrap();
+        // The page is 16 by 8, so the 2:1 rectangle fills it.
+        assert!((job.stats().draw_distance - 48.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn text_without_fonts() {
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="20">
+            <text x="2" y="10" font-size="5">Hi</text>
+            <path d="M 2 12 h 10" stroke="black"/>
+        </svg>"#;
+        // With nothing to draw it in, the text gets left out, but the rest still gets drawn.
+        let paths = parse(svg, &Fonts::default()).unwrap();
+        assert_eq!(paths.len(), 1);
+        assert_bbox(&paths[0], Rect::new(2.0, 12.0, 12.0, 12.0));
+    }
+
+    #[test]
+    fn text_with_fonts() {
+        // In this font, an H is a box that's 0.4 wide and 0.7 tall, starting 0.1 along from
+        // where the letter starts. The letters are 0.6 apart.
+        let mut fonts = Fonts {
+            family: Some("Box Test".to_owned()),
+            ..Fonts::default()
+        };
+        let font = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fonts/box.ttf");
+        fonts.load(&font).unwrap();
+
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="20" height="20">
+            <text x="2" y="10" font-size="10">HH</text>
+        </svg>"#;
+        let paths = parse(svg, &fonts).unwrap();
+        let outline = paths
+            .iter()
+            .map(|p| p.bounding_box())
+            .reduce(|a, b| a.union(b))
+            .unwrap();
+        let expected = Rect::new(3.0, 3.0, 13.0, 10.0);
+        assert!(
+            (outline.origin() - expected.origin()).hypot() < 1e-3
+                && (outline.size() - expected.size()).to_vec2().hypot() < 1e-3,
+            "{outline:?} != {expected:?}"
+        );
+        // The letters are there, with a gap in between them.
+        let inside = |p: Point| paths.iter().any(|path| path.winding(p) != 0);
+        assert!(inside(Point::new(5.0, 6.5)));
+        assert!(!inside(Point::new(8.0, 6.5)));
+        assert!(inside(Point::new(11.0, 6.5)));
+    }
+}
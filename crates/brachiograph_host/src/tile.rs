@@ -0,0 +1,226 @@
+//! Plotting drawings that are too tall for the page, one band at a time.
+//!
+//! The drawing gets scaled to the width of the page, and then cut into horizontal bands that
+//! are each short enough to reach. After plotting a band, the paper gets moved away from the
+//! brachiograph by [`Tiled::advance`], which brings the next part of the drawing into reach.
+
+use kurbo::{Affine, BezPath, PathEl, Point, Rect, Shape};
+
+use crate::{path, profile::Settings, registration, Job};
+
+/// The length of each arm of the registration crosses.
+const MARK_SIZE: f64 = 0.25;
+
+/// How much of the bottom of the page is kept clear for the registration crosses.
+const MARK_MARGIN: f64 = 1.0;
+
+/// A drawing, cut into bands.
+#[derive(Clone, Debug, Default)]
+pub struct Tiled {
+    /// One job for each band, in the order that they get plotted (from the top of the drawing
+    /// to the bottom).
+    pub bands: Vec<Job>,
+    /// How far to move the paper away from the brachiograph between one band and the next, in
+    /// brachiograph units.
+    pub advance: f64,
+}
+
+/// Scales `paths` (which should be y-up) to the width of `rect`, and cuts them into bands
+/// that each fit in `rect`.
+///
+/// With `marks`, each band but the last ends with registration crosses near the bottom
+/// corners of `rect`, and each band but the first starts by drawing them again where they
+/// should be once the paper has moved. If the paper was moved by the right amount, the
+/// crosses line up.
+pub fn tile(paths: &[BezPath], rect: Rect, settings: &Settings, marks: bool) -> Tiled {
+    let margin = if marks { MARK_MARGIN } else { 0.0 };
+    let advance = rect.height() - margin;
+    let Some(bbox) = paths
+        .iter()
+        .map(|p| p.bounding_box())
+        .reduce(|a, b| a.union(b))
+    else {
+        return Tiled {
+            bands: Vec::new(),
+            advance,
+        };
+    };
+
+    // Put the top-left corner of the drawing at the top-left corner of the page.
+    let scale = rect.width() / bbox.width();
+    let scale = if scale.is_finite() { scale } else { 1.0 };
+    let transform = Affine::translate((rect.x0, rect.y1))
+        * Affine::scale(scale)
+        * Affine::translate((-bbox.x0, -bbox.y1));
+    let mut flat = BezPath::new();
+    for p in paths {
+        flatten_into(
+            &(transform * p.clone()),
+            settings.flatten_tolerance,
+            &mut flat,
+        );
+    }
+
+    let height = bbox.height() * scale;
+    let count = ((height / advance).ceil() as usize).max(1);
+    let left = Point::new(rect.x0 + MARK_SIZE, rect.y0 + margin / 2.0);
+    let right = Point::new(rect.x1 - MARK_SIZE, left.y);
+    let bands = (0..count)
+        .map(|i| {
+            let shift = i as f64 * advance;
+            let top = rect.y1 - shift;
+            let band = Affine::translate((0.0, shift)) * clip(&flat, top - advance, top);
+            let band = path::simplify(&band, settings.simplify_epsilon);
+
+            let mut ops = Vec::new();
+            if marks && i > 0 {
+                for p in [left, right] {
+                    ops.extend(registration::cross(p + (0.0, advance), MARK_SIZE));
+                }
+            }
+            ops.extend(path::to_ops(&band));
+            if marks && i + 1 < count {
+                for p in [left, right] {
+                    ops.extend(registration::cross(p, MARK_SIZE));
+                }
+            }
+            Job::new(ops).with_name(format!("band {} of {count}", i + 1))
+        })
+        .collect();
+    Tiled { bands, advance }
+}
+
+// Approximates `path` by line segments, appending them to `out`.
+fn flatten_into(path: &BezPath, tolerance: f64, out: &mut BezPath) {
+    let mut start = Point::ORIGIN;
+    path.flatten(tolerance, |el| match el {
+        PathEl::MoveTo(p) => {
+            start = p;
+            out.move_to(p);
+        }
+        PathEl::LineTo(p) => out.line_to(p),
+        PathEl::ClosePath => out.line_to(start),
+        _ => unreachable!(),
+    });
+}
+
+// The parts of a flattened path with `y0 <= y <= y1`.
+fn clip(path: &BezPath, y0: f64, y1: f64) -> BezPath {
+    let mut ret = BezPath::new();
+    // The end of the last segment that we kept, so that we know whether the next one joins up.
+    let mut last: Option<Point> = None;
+    let mut prev = Point::ORIGIN;
+    for el in path.elements() {
+        match *el {
+            PathEl::MoveTo(p) => prev = p,
+            PathEl::LineTo(p) => {
+                if let Some((a, b)) = clip_line(prev, p, y0, y1) {
+                    if last != Some(a) {
+                        ret.move_to(a);
+                    }
+                    ret.line_to(b);
+                    last = Some(b);
+                }
+                prev = p;
+            }
+            _ => unreachable!("expected a flattened path"),
+        }
+    }
+    ret
+}
+
+// The part of the line from `a` to `b` with `y0 <= y <= y1`, if there is any.
+fn clip_line(a: Point, b: Point, y0: f64, y1: f64) -> Option<(Point, Point)> {
+    let dy = b.y - a.y;
+    if dy == 0.0 {
+        return (y0 <= a.y && a.y <= y1).then_some((a, b));
+    }
+    let t0 = (y0 - a.y) / dy;
+    let t1 = (y1 - a.y) / dy;
+    let (t_in, t_out) = (t0.min(t1).max(0.0), t0.max(t1).min(1.0));
+    // Lines that only touch the band at a single point aren't worth drawing.
+    (t_in < t_out).then(|| (a.lerp(b, t_in), a.lerp(b, t_out)))
+}
+
+#[cfg(test)]
+mod tests {
+    use brachiograph::Op;
+
+    use super::*;
+
+    const RECT: Rect = Rect::new(-8.0, 5.0, 8.0, 13.0);
+
+    // A zig-zag that's twice as tall as it is wide.
+    fn zig_zag() -> BezPath {
+        let mut path = BezPath::new();
+        path.move_to((0.0, 0.0));
+        path.line_to((10.0, 5.0));
+        path.line_to((0.0, 10.0));
+        path.line_to((10.0, 15.0));
+        path.line_to((0.0, 20.0));
+        path
+    }
+
+    #[test]
+    fn bands_cover_the_drawing() {
+        let settings = Settings::default();
+        let tiled = tile(&[zig_zag()], RECT, &settings, false);
+        // Scaled to 16 wide, the zig-zag is 32 tall, which is 4 pages.
+        assert_eq!(tiled.advance, 8.0);
+        assert_eq!(tiled.bands.len(), 4);
+        let drawn: f64 = tiled.bands.iter().map(|b| b.stats().draw_distance).sum();
+        let expected = zig_zag().perimeter(1e-6) * 1.6;
+        assert!((drawn - expected).abs() < 0.01, "{drawn} vs {expected}");
+        for band in &tiled.bands {
+            let bounds = band.stats().bounds.unwrap();
+            let page = RECT.inflate(1e-3, 1e-3);
+            assert_eq!(page.union(bounds), page, "{bounds:?}");
+        }
+    }
+
+    #[test]
+    fn marks_line_up() {
+        let settings = Settings::default();
+        let tiled = tile(&[zig_zag()], RECT, &settings, true);
+        assert_eq!(tiled.advance, RECT.height() - MARK_MARGIN);
+        assert_eq!(tiled.bands.len(), 5);
+
+        let moves = |job: &Job| -> Vec<Op> {
+            job.iter()
+                .filter(|op| matches!(op, Op::MoveTo(_)))
+                .cloned()
+                .collect()
+        };
+        // After moving the paper, the first band's marks are where the second band draws its.
+        let shifted: Vec<Op> = moves(&tiled.bands[0])
+            .iter()
+            .rev()
+            .take(8)
+            .map(|op| {
+                let Op::MoveTo(p) = op else { unreachable!() };
+                Op::MoveTo(brachiograph::Point {
+                    x: p.x,
+                    y: p.y + brachiograph::Fixed::from_num(tiled.advance),
+                })
+            })
+            .collect();
+        let second: Vec<Op> = moves(&tiled.bands[1]).into_iter().take(8).rev().collect();
+        assert_eq!(shifted, second);
+    }
+
+    #[test]
+    fn clipping() {
+        let a = Point::new(0.0, 0.0);
+        let b = Point::new(4.0, 4.0);
+        assert_eq!(
+            clip_line(a, b, 1.0, 2.0),
+            Some((Point::new(1.0, 1.0), Point::new(2.0, 2.0)))
+        );
+        assert_eq!(clip_line(b, a, 1.0, 2.0).unwrap().0, Point::new(2.0, 2.0));
+        assert_eq!(clip_line(a, b, 5.0, 6.0), None);
+        assert_eq!(
+            clip_line(a, Point::new(4.0, 0.0), -1.0, 1.0),
+            Some((a, Point::new(4.0, 0.0)))
+        );
+    }
+}
@@ -0,0 +1,415 @@
+//! Recording everything that goes over the serial port, and playing it back.
+//!
+//! When something goes wrong between the host and the brachiograph, a transcript of the raw bytes
+//! (see [`Serial::with_transcript`](crate::Serial::with_transcript)) shows exactly what was said.
+//! It can be played back with [`Serial::replay`](crate::Serial::replay), which stands in for the
+//! brachiograph, so that the host's side of the conversation can be reproduced without the
+//! hardware.
+//!
+//! A transcript is a text file, with one line for each chunk of bytes that was written or read:
+//!
+//! ```text
+//! 0.014285 > 020b00
+//! 0.016158 < 0101022000
+//! ```
+//!
+//! That's the time (in seconds since the recording started), `>` for bytes that the host sent or
+//! `<` for bytes that the brachiograph sent, and the bytes in hex. Lines starting with `#` are
+//! comments.
+
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    io::{self, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context};
+
+const HEADER: &str = "\
+# A transcript of a brachiograph's serial port. Each line is the time (in seconds), `>` for
+# bytes sent by the host or `<` for bytes sent by the brachiograph, and the bytes in hex.
+";
+
+/// Which way some bytes went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From the host to the brachiograph.
+    Sent,
+    /// From the brachiograph to the host.
+    Received,
+}
+
+/// Some bytes that went over the serial port in one go.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    /// How long after the recording started this was.
+    pub at: Duration,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+impl std::fmt::Display for Chunk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let arrow = match self.direction {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+        };
+        let mut hex = String::with_capacity(self.bytes.len() * 2);
+        for b in &self.bytes {
+            // Unwrap: writing to a string doesn't fail.
+            write!(&mut hex, "{b:02x}").unwrap();
+        }
+        write!(f, "{:.6} {arrow} {hex}", self.at.as_secs_f64())
+    }
+}
+
+impl std::str::FromStr for Chunk {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> anyhow::Result<Chunk> {
+        let mut fields = line.split_whitespace();
+        let (Some(at), Some(arrow), Some(hex), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            bail!("expected a time, a direction and some bytes");
+        };
+        let at = at
+            .parse::<f64>()
+            .ok()
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+            .ok_or_else(|| anyhow!("invalid time {at:?}"))?;
+        let direction = match arrow {
+            ">" => Direction::Sent,
+            "<" => Direction::Received,
+            _ => bail!("invalid direction {arrow:?}"),
+        };
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            bail!("invalid bytes {hex:?}");
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..(i + 2)], 16))
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("invalid bytes {hex:?}"))?;
+        Ok(Chunk {
+            at,
+            direction,
+            bytes,
+        })
+    }
+}
+
+/// Everything that went over the serial port, in order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Transcript {
+    pub chunks: Vec<Chunk>,
+}
+
+impl Transcript {
+    /// Reads a transcript in the format described in the [module docs](self).
+    pub fn parse(s: &str) -> anyhow::Result<Transcript> {
+        let chunks = s
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.starts_with('#') && !line.trim().is_empty())
+            .map(|(i, line)| line.parse().with_context(|| format!("line {}", i + 1)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Transcript { chunks })
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Transcript> {
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Transcript::parse(&s).with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    /// All the bytes that went one way, joined together.
+    pub fn bytes(&self, direction: Direction) -> Vec<u8> {
+        self.chunks
+            .iter()
+            .filter(|c| c.direction == direction)
+            .flat_map(|c| c.bytes.iter().copied())
+            .collect()
+    }
+}
+
+impl std::fmt::Display for Transcript {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(HEADER)?;
+        for chunk in &self.chunks {
+            writeln!(f, "{chunk}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes out chunks as they happen, shared by the reading and writing halves of a port.
+#[derive(Clone)]
+pub(crate) struct Recorder {
+    inner: Arc<Mutex<RecorderInner>>,
+}
+
+struct RecorderInner {
+    start: Instant,
+    out: Box<dyn Write + Send>,
+    // Whether writing to `out` has failed, so that we only complain once.
+    failed: bool,
+}
+
+impl Recorder {
+    pub(crate) fn new(mut out: Box<dyn Write + Send>) -> Recorder {
+        if let Err(e) = out.write_all(HEADER.as_bytes()) {
+            log::warn!("failed to write the transcript: {e}");
+        }
+        Recorder {
+            inner: Arc::new(Mutex::new(RecorderInner {
+                start: Instant::now(),
+                out,
+                failed: false,
+            })),
+        }
+    }
+
+    /// Wraps a reader or writer, so that the bytes going through it get recorded.
+    pub(crate) fn wrap<T>(&self, inner: T) -> Recording<T> {
+        Recording {
+            inner,
+            recorder: self.clone(),
+        }
+    }
+
+    fn record(&self, direction: Direction, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        // A panic while holding the lock can't leave the recorder in a bad state.
+        let mut rec = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let chunk = Chunk {
+            at: rec.start.elapsed(),
+            direction,
+            bytes: bytes.to_owned(),
+        };
+        // Flush every time, so that the transcript is all there even if we crash.
+        let res = writeln!(&mut rec.out, "{chunk}").and_then(|()| rec.out.flush());
+        if let Err(e) = res {
+            if !rec.failed {
+                log::warn!("failed to write the transcript: {e}");
+                rec.failed = true;
+            }
+        }
+    }
+}
+
+/// A reader or writer whose bytes get recorded (see [`Recorder::wrap`]).
+pub(crate) struct Recording<T> {
+    inner: T,
+    recorder: Recorder,
+}
+
+impl<T: Read> Read for Recording<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.recorder.record(Direction::Received, &buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Recording<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.recorder.record(Direction::Sent, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Pretends to be a brachiograph by playing back its side of a transcript.
+///
+/// The brachiograph's bytes come out in the same order as before, and each chunk only becomes
+/// available once the host has sent everything that came before it in the transcript. Whatever
+/// the host sends gets checked against what it sent last time, so a replay fails with an error
+/// as soon as the conversation goes differently. Reading past the end of the brachiograph's side
+/// fails too, rather than waiting forever.
+#[derive(Clone)]
+pub(crate) struct Replay {
+    inner: Arc<Mutex<ReplayInner>>,
+}
+
+struct ReplayInner {
+    // Everything that the host sent, and how much of it it has sent so far this time.
+    sent: Vec<u8>,
+    sent_pos: usize,
+    // The brachiograph's chunks that haven't been read yet, each with the amount that the host
+    // had sent before it.
+    received: VecDeque<(usize, Vec<u8>)>,
+}
+
+impl Replay {
+    pub(crate) fn new(transcript: &Transcript) -> Replay {
+        let mut sent = Vec::new();
+        let mut received = VecDeque::new();
+        for chunk in &transcript.chunks {
+            match chunk.direction {
+                Direction::Sent => sent.extend_from_slice(&chunk.bytes),
+                Direction::Received => received.push_back((sent.len(), chunk.bytes.clone())),
+            }
+        }
+        Replay {
+            inner: Arc::new(Mutex::new(ReplayInner {
+                sent,
+                sent_pos: 0,
+                received,
+            })),
+        }
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let sent_pos = inner.sent_pos;
+        let Some((after, bytes)) = inner.received.front_mut() else {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the transcript has nothing more from the brachiograph",
+            ));
+        };
+        if sent_pos < *after {
+            // Like a real port, where nothing arrives until we've asked for it.
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "the brachiograph is waiting for the host",
+            ));
+        }
+        let n = buf.len().min(bytes.len());
+        buf[..n].copy_from_slice(&bytes[..n]);
+        bytes.drain(..n);
+        if bytes.is_empty() {
+            inner.received.pop_front();
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let pos = inner.sent_pos;
+        let expected = &inner.sent[pos..(pos + buf.len()).min(inner.sent.len())];
+        if expected != buf {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the host sent {buf:02x?} at byte {pos}, but the transcript has {expected:02x?}"
+                ),
+            ));
+        }
+        inner.sent_pos += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(ms: u64, direction: Direction, bytes: &[u8]) -> Chunk {
+        Chunk {
+            at: Duration::from_millis(ms),
+            direction,
+            bytes: bytes.to_owned(),
+        }
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let transcript = Transcript {
+            chunks: vec![
+                chunk(1, Direction::Sent, &[1, 0x19, 1, 0]),
+                chunk(1500, Direction::Received, &[0xab, 0]),
+            ],
+        };
+        let s = transcript.to_string();
+        assert!(s.ends_with("0.001000 > 01190100\n1.500000 < ab00\n"), "{s}");
+        assert_eq!(Transcript::parse(&s).unwrap(), transcript);
+
+        assert!(Transcript::parse("0.1 > 0").is_err());
+        assert!(Transcript::parse("0.1 = 00").is_err());
+        assert!(Transcript::parse("soon > 00").is_err());
+    }
+
+    // Something to record into that we can look at afterwards.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn record() {
+        let out = Shared::default();
+        let recorder = Recorder::new(Box::new(out.clone()));
+        let mut write = recorder.wrap(Vec::new());
+        let mut read = recorder.wrap(&[4u8, 5, 6][..]);
+
+        write.write_all(&[1, 2, 3]).unwrap();
+        let mut buf = [0; 2];
+        read.read_exact(&mut buf).unwrap();
+
+        let out = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let transcript = Transcript::parse(&out).unwrap();
+        assert_eq!(transcript.bytes(Direction::Sent), [1, 2, 3]);
+        assert_eq!(transcript.bytes(Direction::Received), [4, 5]);
+    }
+
+    #[test]
+    fn replay() {
+        let transcript = Transcript {
+            chunks: vec![
+                chunk(0, Direction::Sent, &[1, 2]),
+                chunk(1, Direction::Received, &[3, 4, 5]),
+                chunk(2, Direction::Sent, &[6]),
+                chunk(3, Direction::Received, &[7]),
+            ],
+        };
+        let mut replay = Replay::new(&transcript);
+        let mut buf = [0; 2];
+        // Nothing comes back until the host has said something.
+        let err = replay.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // The host's bytes don't have to be split up the same way as before.
+        replay.write_all(&[1]).unwrap();
+        replay.write_all(&[2]).unwrap();
+        assert_eq!(replay.read(&mut buf).unwrap(), 2);
+        assert_eq!(buf, [3, 4]);
+        assert_eq!(replay.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 5);
+        assert!(replay.read(&mut buf).is_err());
+
+        let err = replay.write_all(&[8]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        replay.write_all(&[6]).unwrap();
+        assert_eq!(replay.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 7);
+        let err = replay.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,327 @@
+//! Keeping the pen away from ink that might still be wet.
+//!
+//! Between strokes the pen goes up and travels in a straight line to the start of the next
+//! stroke, and that line can pass right over something that was drawn a moment ago. With a
+//! slow-drying pen (or one that doesn't lift very far) this smudges. [`route_around`] works out
+//! when each line of a job gets drawn, and sends the pen around the ones that are still wet.
+
+use std::time::Duration;
+
+use brachiograph::{geom, Fixed, Op};
+use kurbo::{BezPath, Line, ParamCurveNearest, PathEl, Point, Rect, Shape};
+
+use crate::{
+    path::{from_device, segment_els},
+    profile::Settings,
+    Job, Layer,
+};
+
+/// Where the pen shouldn't go while the ink is wet.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WetZone {
+    /// How close a pen-up move may come to wet ink, in brachiograph units. This has to be
+    /// positive.
+    pub clearance: f64,
+    /// How long ink stays wet after it's drawn.
+    pub drying_time: Duration,
+}
+
+impl Default for WetZone {
+    fn default() -> Self {
+        WetZone {
+            clearance: 0.3,
+            drying_time: Duration::from_secs(10),
+        }
+    }
+}
+
+// A line that was drawn, and when (in seconds since the start of the job).
+struct Ink {
+    line: Line,
+    time: f64,
+}
+
+/// Returns a job that draws the same thing as `job`, but with its pen-up moves going around
+/// any ink that was drawn less than `zone.drying_time` earlier.
+///
+/// The timing comes from `settings`, so it's only an estimate. Detours stay inside the drawing
+/// area of `config`, and moves that can't be routed around the wet ink with a waypoint or two
+/// are left alone.
+pub fn route_around(
+    job: &Job,
+    zone: &WetZone,
+    settings: &Settings,
+    config: &geom::Config,
+) -> anyhow::Result<Job> {
+    // Zero would mean checking for wet ink at infinitely many points along each move.
+    anyhow::ensure!(
+        zone.clearance > 0.0 && zone.clearance.is_finite(),
+        "the clearance around wet ink should be a positive distance, not {}",
+        zone.clearance
+    );
+    let page = Rect::new(
+        config.x_range.0.to_num(),
+        config.y_range.0.to_num(),
+        config.x_range.1.to_num(),
+        config.y_range.1.to_num(),
+    );
+    let mut ink: Vec<Ink> = Vec::new();
+    let mut now = 0.0;
+    let mut pos: Option<Point> = None;
+    let mut pen_down = false;
+    let mut ops = Vec::with_capacity(job.len());
+    // Where each of the original ops ended up, for keeping track of the layers.
+    let mut starts = Vec::with_capacity(job.len());
+
+    for op in job {
+        starts.push(ops.len());
+        match op {
+            Op::PenUp | Op::PenDown => {
+                pen_down = *op == Op::PenDown;
+                now += settings.pen_lift.as_secs_f64();
+            }
+            _ => {}
+        }
+        let drying_time = zone.drying_time.as_secs_f64();
+        ink.retain(|i| now - i.time < drying_time);
+
+        let Some(from) = pos else {
+            if let Op::MoveTo(p) = op {
+                pos = Some(from_device(p));
+            }
+            ops.push(op.clone());
+            continue;
+        };
+        let Some(segment) = op.segment() else {
+            ops.push(op.clone());
+            continue;
+        };
+
+        let mut from = from;
+        if let (Op::MoveTo(to), false) = (op, pen_down) {
+            for waypoint in detour(from, from_device(to), &ink, zone.clearance, page) {
+                now += (waypoint - from).hypot() / settings.speed;
+                from = waypoint;
+                ops.push(Op::MoveTo(brachiograph::Point {
+                    x: Fixed::from_num(waypoint.x),
+                    y: Fixed::from_num(waypoint.y),
+                }));
+            }
+        }
+
+        let mut path = BezPath::new();
+        path.move_to(from);
+        segment_els(from, &segment)
+            .into_iter()
+            .for_each(|el| path.push(el));
+        let mut last = from;
+        path.flatten(zone.clearance / 4.0, |el| {
+            if let PathEl::LineTo(p) = el {
+                now += (p - last).hypot() / settings.speed;
+                if pen_down {
+                    ink.push(Ink {
+                        line: Line::new(last, p),
+                        time: now,
+                    });
+                }
+                last = p;
+            }
+        });
+        pos = Some(last);
+        ops.push(op.clone());
+    }
+
+    let layers = job
+        .layers()
+        .iter()
+        .map(|layer| Layer {
+            name: layer.name.clone(),
+            start: starts.get(layer.start).copied().unwrap_or(ops.len()),
+            pen: layer.pen.clone(),
+        })
+        .collect();
+    let mut ret = Job::new(ops).with_layers(layers);
+    ret.name = job.name.clone();
+    Ok(ret)
+}
+
+// Does the pen stay far enough from the ink while going from `a` to `b`? The ends of the whole
+// trip (`start` and `end`) are allowed to be close to the ink, because they're where strokes
+// start and finish.
+fn is_clear(a: Point, b: Point, start: Point, end: Point, ink: &[Ink], clearance: f64) -> bool {
+    // If we check at least every half-clearance along the way, we can't miss a crossing.
+    let steps = ((b - a).hypot() / (clearance / 2.0)).ceil().max(1.0) as usize;
+    (0..=steps).all(|i| {
+        let p = a.lerp(b, i as f64 / steps as f64);
+        if p.distance(start) < clearance || p.distance(end) < clearance {
+            return true;
+        }
+        ink.iter()
+            .all(|i| i.line.nearest(p, 1e-9).distance_sq >= clearance * clearance)
+    })
+}
+
+// The waypoints (if any) for getting from `from` to `to` without going near the ink.
+fn detour(from: Point, to: Point, ink: &[Ink], clearance: f64, page: Rect) -> Vec<Point> {
+    let clear = |a: Point, b: Point| is_clear(a, b, from, to, ink, clearance);
+    if clear(from, to) {
+        return Vec::new();
+    }
+
+    // Try going around the corners of the ink that's in the way, and of all the wet ink.
+    let blocking = ink
+        .iter()
+        .filter(|i| !is_clear(from, to, from, to, std::slice::from_ref(i), clearance))
+        .map(|i| i.line.bounding_box())
+        .reduce(|a, b| a.union(b));
+    let everything = ink
+        .iter()
+        .map(|i| i.line.bounding_box())
+        .reduce(|a, b| a.union(b));
+    let margin = clearance * 1.5;
+    let candidates: Vec<Point> = blocking
+        .into_iter()
+        .chain(everything)
+        .flat_map(|r| {
+            let r = r.inflate(margin, margin);
+            [
+                Point::new(r.x0, r.y0),
+                Point::new(r.x0, r.y1),
+                Point::new(r.x1, r.y0),
+                Point::new(r.x1, r.y1),
+            ]
+        })
+        .filter(|p| page.contains(*p))
+        .collect();
+
+    let len = |route: &[Point]| {
+        let mut prev = from;
+        let mut ret = 0.0;
+        for &p in route.iter().chain([&to]) {
+            ret += (p - prev).hypot();
+            prev = p;
+        }
+        ret
+    };
+    let shortest =
+        |routes: Vec<Vec<Point>>| routes.into_iter().min_by(|a, b| len(a).total_cmp(&len(b)));
+
+    let one = candidates
+        .iter()
+        .filter(|&&w| clear(from, w) && clear(w, to))
+        .map(|&w| vec![w])
+        .collect();
+    if let Some(route) = shortest(one) {
+        return route;
+    }
+    let mut two = Vec::new();
+    for &w1 in &candidates {
+        if !clear(from, w1) {
+            continue;
+        }
+        for &w2 in &candidates {
+            if w1 != w2 && clear(w1, w2) && clear(w2, to) {
+                two.push(vec![w1, w2]);
+            }
+        }
+    }
+    shortest(two).unwrap_or_else(|| {
+        log::debug!("couldn't find a way around the wet ink from {from:?} to {to:?}");
+        Vec::new()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mv(x: f64, y: f64) -> Op {
+        Op::MoveTo(brachiograph::Point {
+            x: Fixed::from_num(x),
+            y: Fixed::from_num(y),
+        })
+    }
+
+    // A horizontal line, then a short vertical one above it, and then a move that goes back
+    // across the horizontal one.
+    fn job() -> Job {
+        Job::new(vec![
+            mv(-2.0, 9.0),
+            Op::PenDown,
+            mv(2.0, 9.0),
+            Op::PenUp,
+            mv(0.0, 11.0),
+            Op::PenDown,
+            mv(0.0, 10.0),
+            Op::PenUp,
+            mv(0.0, 8.0),
+            Op::PenDown,
+            mv(0.0, 7.0),
+            Op::PenUp,
+        ])
+    }
+
+    #[test]
+    fn goes_around_wet_ink() {
+        let zone = WetZone::default();
+        let routed = route_around(
+            &job(),
+            &zone,
+            &Settings::default(),
+            &geom::Config::default(),
+        )
+        .unwrap();
+        assert!(routed.len() > job().len());
+
+        // Follow the pen-up moves after the second stroke, and check that they keep away from
+        // the first one.
+        let first = Line::new((-2.0, 9.0), (2.0, 9.0));
+        let ops = routed.ops();
+        let start = ops.iter().position(|op| *op == mv(0.0, 10.0)).unwrap();
+        let mut prev = Point::new(0.0, 10.0);
+        for op in &ops[start + 2..] {
+            let Op::MoveTo(p) = op else {
+                break;
+            };
+            let p = from_device(p);
+            for i in 0..=100 {
+                let q = prev.lerp(p, i as f64 / 100.0);
+                let dist = first.nearest(q, 1e-9).distance_sq.sqrt();
+                assert!(dist >= zone.clearance - 1e-3, "{q:?} is too close");
+            }
+            prev = p;
+        }
+        assert_eq!(prev, Point::new(0.0, 8.0));
+    }
+
+    #[test]
+    fn dry_ink_is_ignored() {
+        let zone = WetZone {
+            drying_time: Duration::ZERO,
+            ..WetZone::default()
+        };
+        let routed = route_around(
+            &job(),
+            &zone,
+            &Settings::default(),
+            &geom::Config::default(),
+        )
+        .unwrap();
+        assert_eq!(routed.ops(), job().ops());
+    }
+
+    #[test]
+    fn needs_some_clearance() {
+        let zone = WetZone {
+            clearance: 0.0,
+            ..WetZone::default()
+        };
+        assert!(route_around(
+            &job(),
+            &zone,
+            &Settings::default(),
+            &geom::Config::default()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,56 @@
+//! Checks that the host speaks the same wire format as the firmware.
+//!
+//! The test vectors are shared with the `brachiograph` crate; see the comments there.
+
+use brachiograph::{Op, Reply, Resp};
+
+const VECTORS: &str = include_str!("../../brachiograph/tests/protocol-vectors.txt");
+
+fn unhex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..(i + 2)], 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn wire_format() {
+    let mut count = 0;
+    for line in VECTORS.lines() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (kind, name, hex) = (
+            fields.next().unwrap(),
+            fields.next().unwrap(),
+            fields.next().unwrap(),
+        );
+        let mut bytes = unhex(hex);
+        // Decoding and then re-encoding should give back exactly the same bytes.
+        let reencoded = match kind {
+            "op" => {
+                let (op, rest) = postcard::take_from_bytes_cobs::<Op>(&mut bytes[..])
+                    .unwrap_or_else(|e| panic!("failed to decode op {name}: {e}"));
+                assert!(rest.is_empty());
+                postcard::to_stdvec_cobs(&op).unwrap()
+            }
+            "resp" => {
+                let (resp, rest) = postcard::take_from_bytes_cobs::<Resp>(&mut bytes[..])
+                    .unwrap_or_else(|e| panic!("failed to decode resp {name}: {e}"));
+                assert!(rest.is_empty());
+                postcard::to_stdvec_cobs(&resp).unwrap()
+            }
+            "reply" => {
+                let (reply, rest) = postcard::take_from_bytes_cobs::<Reply>(&mut bytes[..])
+                    .unwrap_or_else(|e| panic!("failed to decode reply {name}: {e}"));
+                assert!(rest.is_empty());
+                postcard::to_stdvec_cobs(&reply).unwrap()
+            }
+            other => panic!("unknown kind {other}"),
+        };
+        assert_eq!(unhex(hex), reencoded, "mismatch for {kind} {name}");
+        count += 1;
+    }
+    assert!(count > 0);
+}
@@ -0,0 +1,43 @@
+//! Replays transcripts of conversations with a brachiograph (see `brachiograph_host::transcript`).
+//!
+//! To add one, record it with `feeder --record-transport`, put it in `tests/transcripts` (adding
+//! a comment at the top saying what went wrong), and check here that the host copes.
+
+use brachiograph::{Fixed, Op, Point};
+use brachiograph_host::{transcript::Transcript, JournalEntry, Serial, Transport};
+
+fn replay(name: &str) -> Serial {
+    let path = format!(
+        "{}/tests/transcripts/{name}.txt",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let transcript = Transcript::load(path.as_ref()).unwrap();
+    Serial::replay(&transcript, Transport::Postcard)
+}
+
+fn move_to(x: f64, y: f64) -> Op {
+    Op::MoveTo(Point {
+        x: Fixed::from_num(x),
+        y: Fixed::from_num(y),
+    })
+}
+
+#[test]
+fn recovers_from_starvation() {
+    let mut serial = replay("starved");
+    serial.send(move_to(1.0, 10.0)).unwrap();
+    assert!(matches!(
+        serial.journal(),
+        [JournalEntry::Starved { at }] if *at == kurbo::Point::new(0.5, 10.0)
+    ));
+
+    // That's the end of the transcript.
+    assert!(serial.send(move_to(2.0, 10.0)).is_err());
+}
+
+#[test]
+fn replay_checks_what_the_host_sends() {
+    let mut serial = replay("starved");
+    let err = serial.send(move_to(2.0, 10.0)).unwrap_err();
+    assert!(err.to_string().contains("the transcript has"), "{err}");
+}
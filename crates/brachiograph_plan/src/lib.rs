@@ -0,0 +1,337 @@
+//! Turning drawings into ops.
+//!
+//! The feeder, the desktop UI and the tauri app all need to turn SVG files and Logo programs
+//! into something that the brachiograph can draw. This is where they do it, so that a drawing
+//! comes out the same whichever one of them sends it. [`plan_svg`], [`plan_logo`] and
+//! [`plan_text`] use the default settings; make a [`Planner`] to choose how closely curves get
+//! followed.
+
+#[cfg(feature = "svg")]
+use std::path::Path;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use brachiograph::Op;
+use brachiograph_host::{font, path, profile::Settings};
+use brachiologo::TurtleCmd;
+use kurbo::{Affine, BezPath, PathEl, Point, Rect, Shape};
+
+/// How to turn drawings into ops.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Planner {
+    /// The maximum distance between a curve and the line segments that approximate it.
+    pub tolerance: f64,
+    /// Points that are closer than this to the line joining their neighbors get dropped.
+    pub simplify_epsilon: f64,
+    /// Smooth out curves in Logo programs that were drawn with lots of little steps.
+    pub smooth: bool,
+    /// Lines longer than this get split into pieces (see [`Settings::max_line`]).
+    pub max_line: f64,
+    /// How long to stop at sharp corners (see [`Settings::corner_dwell`]).
+    pub corner_dwell: Duration,
+}
+
+impl Default for Planner {
+    fn default() -> Self {
+        Planner::from_settings(&Settings::default())
+    }
+}
+
+impl Planner {
+    /// A planner that flattens and simplifies in the way that a profile's settings say to.
+    pub fn from_settings(settings: &Settings) -> Planner {
+        Planner {
+            tolerance: settings.flatten_tolerance,
+            simplify_epsilon: settings.simplify_epsilon,
+            smooth: false,
+            max_line: settings.max_line,
+            corner_dwell: settings.corner_dwell,
+        }
+    }
+
+    /// Reads an SVG file, and returns the ops that draw it scaled to fit in `rect`.
+    ///
+    /// Any text in it gets drawn in the fonts that are installed on this computer.
+    #[cfg(feature = "svg")]
+    pub fn plan_svg(&self, path: &Path, rect: Rect) -> anyhow::Result<Vec<Op>> {
+        let data = std::fs::read(path)?;
+        let settings = Settings {
+            flatten_tolerance: self.tolerance,
+            simplify_epsilon: self.simplify_epsilon,
+            max_line: self.max_line,
+            corner_dwell: self.corner_dwell,
+            ..Settings::default()
+        };
+        let hatching = brachiograph_host::svg::Hatching::default();
+        let fonts = brachiograph_host::svg::Fonts::system();
+        let job = brachiograph_host::svg::to_job(&data, rect, &hatching, &fonts, &settings)?;
+        Ok(job.into_iter().collect())
+    }
+
+    /// Runs a Logo program, and returns the ops that draw what the turtle drew.
+    ///
+    /// The turtle starts in the middle of `rect`, and anything that goes outside `rect` gets
+    /// squashed onto its edge.
+    pub fn plan_logo(&self, code: &str, rect: Rect) -> anyhow::Result<Vec<Op>> {
+        let (_, program) =
+            brachiologo::parse::program(code.into()).map_err(|e| anyhow!("parse error: {e:?}"))?;
+        let mut env = logo_env(rect);
+        program.eval(&mut env)?;
+        Ok(self.plan_turtle(&env.turtle, rect))
+    }
+
+    /// Like [`Planner::plan_logo`], but the ops come out while the program is still running.
+    ///
+    /// Each stroke is planned as soon as the turtle has finished drawing it, so a huge drawing
+    /// can start being sent to the brachiograph right away, and it never has to be held in
+    /// memory all at once. Parse errors are returned straight away, but if the program fails
+    /// while running then the error comes after the ops for whatever it drew before that.
+    pub fn stream_logo(
+        &self,
+        code: &str,
+        rect: Rect,
+    ) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Op>>> {
+        let (_, program) =
+            brachiologo::parse::program(code.into()).map_err(|e| anyhow!("parse error: {e:?}"))?;
+        let steps = logo_env(rect).run_streaming(program);
+        // The planning pipeline wants plain turtle commands, so it stops at the first error and
+        // leaves it here, to come out once the pipeline has finished with the commands before
+        // it.
+        let error = Arc::new(Mutex::new(None));
+        let stash = Arc::clone(&error);
+        let steps = steps.map_while(move |step| {
+            step.map_err(|e| *stash.lock().unwrap() = Some(anyhow!("{e}")))
+                .ok()
+        });
+        let ops = self.stream_turtle(steps, rect).map(Ok);
+        Ok(ops.chain(std::iter::from_fn(move || {
+            error.lock().unwrap().take().map(Err)
+        })))
+    }
+
+    /// Like [`Planner::plan_logo`], but for a program that has already been run.
+    pub fn plan_turtle(&self, steps: &[TurtleCmd], rect: Rect) -> Vec<Op> {
+        self.stream_turtle(steps.iter().copied(), rect).collect()
+    }
+
+    /// Like [`Planner::plan_turtle`], but for a program that is still running. Strokes are
+    /// planned a piece at a time (see [`path::map_subpaths`]), while the turtle is drawing them.
+    pub fn stream_turtle(
+        &self,
+        steps: impl IntoIterator<Item = TurtleCmd>,
+        rect: Rect,
+    ) -> impl Iterator<Item = Op> {
+        let planner = *self;
+        let ops = brachiograph_host::interpret_iter(steps);
+        let center = Affine::translate(rect.center().to_vec2());
+        let els = path::ops_to_els(ops, Point::ORIGIN, true).map(move |el| center * el);
+        let els = path::map_subpaths(els, move |sub| {
+            let mut sub = if planner.smooth {
+                path::smooth(sub, planner.tolerance)
+            } else {
+                sub.clone()
+            };
+            clamp(&mut sub, rect);
+            path::split_lines(
+                &path::simplify(&sub, planner.simplify_epsilon),
+                planner.max_line,
+            )
+        });
+        path::dwell_at_corners(path::dedup(path::els_to_ops(els)), planner.corner_dwell)
+    }
+
+    /// Returns the ops that write some text (in the built-in font, see
+    /// [`brachiograph_host::font`]) as large as will fit in `rect`, and centered in it.
+    pub fn plan_text(&self, text: &str, rect: Rect) -> Vec<Op> {
+        let mut bez = font::text(text, 1.0);
+        let bbox = bez.bounding_box();
+        let scale = (rect.width() / bbox.width()).min(rect.height() / bbox.height());
+        if scale.is_finite() {
+            bez.apply_affine(
+                Affine::translate(rect.center().to_vec2())
+                    * Affine::scale(scale)
+                    * Affine::translate(-bbox.center().to_vec2()),
+            );
+        }
+        let simple = path::simplify(&bez, self.simplify_epsilon);
+        let ops = path::to_ops(&path::split_lines(&simple, self.max_line));
+        path::dwell_at_corners(ops, self.corner_dwell).collect()
+    }
+}
+
+// An environment for running a Logo program that gets drawn in `rect`.
+fn logo_env(rect: Rect) -> brachiologo::Env {
+    brachiologo::Env {
+        canvas_size: (rect.width(), rect.height()),
+        ..brachiologo::Env::default()
+    }
+}
+
+// Moves any points outside `rect` to the nearest point on its edge.
+fn clamp(bez: &mut BezPath, rect: Rect) {
+    for el in bez.elements_mut() {
+        if let PathEl::MoveTo(p) | PathEl::LineTo(p) = el {
+            *p = Point::new(
+                p.x.clamp(rect.min_x(), rect.max_x()),
+                p.y.clamp(rect.min_y(), rect.max_y()),
+            );
+        }
+    }
+}
+
+/// Reads an SVG file, and returns the ops that draw it scaled to fit in `rect`.
+#[cfg(feature = "svg")]
+pub fn plan_svg(path: &Path, rect: Rect) -> anyhow::Result<Vec<Op>> {
+    Planner::default().plan_svg(path, rect)
+}
+
+/// Runs a Logo program, and returns the ops that draw what the turtle drew in `rect`.
+pub fn plan_logo(code: &str, rect: Rect) -> anyhow::Result<Vec<Op>> {
+    Planner::default().plan_logo(code, rect)
+}
+
+/// Returns the ops that write some text as large as will fit in `rect`.
+pub fn plan_text(text: &str, rect: Rect) -> Vec<Op> {
+    Planner::default().plan_text(text, rect)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECT: Rect = Rect::new(-8.0, 5.0, 8.0, 13.0);
+
+    fn drawing(ops: &[Op]) -> BezPath {
+        path::from_ops(ops, Point::ORIGIN, false)
+    }
+
+    #[test]
+    fn logo_starts_in_the_middle() {
+        let ops = plan_logo("repeat 4 [fd 2 rt 90]", RECT).unwrap();
+        let bbox = drawing(&ops).bounding_box();
+        assert!((bbox.width() - 2.0).abs() < 1e-2, "{bbox:?}");
+        assert!((bbox.min_x() - RECT.center().x).abs() < 1e-2, "{bbox:?}");
+        assert!((bbox.min_y() - RECT.center().y).abs() < 1e-2, "{bbox:?}");
+    }
+
+    #[test]
+    fn logo_is_clamped() {
+        let ops = plan_logo("fd 100", RECT).unwrap();
+        let bbox = drawing(&ops).bounding_box();
+        assert!(bbox.max_y() <= RECT.max_y() + 1e-2, "{bbox:?}");
+    }
+
+    #[test]
+    fn logo_knows_the_canvas() {
+        let ops = plan_logo(
+            "penup setxy canvaswidth / -2 canvasheight / -2 pendown \
+             setxy canvaswidth / 2 canvasheight / 2",
+            RECT,
+        )
+        .unwrap();
+        let bbox = drawing(&ops).bounding_box();
+        assert!((bbox.origin() - RECT.origin()).hypot() < 1e-2, "{bbox:?}");
+        assert!(
+            (bbox.size() - RECT.size()).to_vec2().hypot() < 1e-2,
+            "{bbox:?}"
+        );
+    }
+
+    #[test]
+    fn logo_errors() {
+        assert!(plan_logo("fd [", RECT).is_err());
+        assert!(plan_logo("forwrd 10", RECT).is_err());
+    }
+
+    #[test]
+    fn absolute_moves_stay_single() {
+        // Moving relative to the current position with the pen up takes one move, and doesn't
+        // lift or lower the pen on the way.
+        let ops = plan_logo(
+            "penup setx xcor + 1 sety ycor - 2 pendown setxy xcor + 1 ycor",
+            RECT,
+        )
+        .unwrap();
+        let c = RECT.center();
+        let mv = |x: f64, y: f64| {
+            Op::MoveTo(brachiograph::Point {
+                x: brachiograph::Fixed::from_num(c.x + x),
+                y: brachiograph::Fixed::from_num(c.y + y),
+            })
+        };
+        assert_eq!(ops, [Op::PenUp, mv(1.0, -2.0), Op::PenDown, mv(2.0, -2.0)]);
+    }
+
+    #[test]
+    fn text_fills_the_rect() {
+        let bbox = drawing(&plan_text("HELLO", RECT)).bounding_box();
+        // The text is wider than the rect is, so it fills the width.
+        assert!((bbox.width() - RECT.width()).abs() < 1e-2, "{bbox:?}");
+        assert!(bbox.height() < RECT.height(), "{bbox:?}");
+        assert!((bbox.center() - RECT.center()).hypot() < 1e-2, "{bbox:?}");
+        assert!(plan_text("", RECT).is_empty());
+    }
+
+    #[test]
+    fn smoothing_follows_the_tolerance() {
+        let code = "repeat 36 [fd 0.3 rt 10]";
+        let smooth = |tolerance| Planner {
+            tolerance,
+            simplify_epsilon: 0.0,
+            smooth: true,
+            max_line: 0.0,
+            corner_dwell: Duration::ZERO,
+        };
+        // A loose tolerance is already met by the polygon, but a tight one needs more points.
+        let rough = Planner::default().plan_logo(code, RECT).unwrap();
+        assert_eq!(
+            smooth(0.05).plan_logo(code, RECT).unwrap().len(),
+            rough.len()
+        );
+        let fine = smooth(0.0001).plan_logo(code, RECT).unwrap();
+        assert!(fine.len() > rough.len());
+        let (a, b) = (drawing(&rough), drawing(&fine));
+        assert!((a.perimeter(1e-3) - b.perimeter(1e-3)).abs() < 0.1);
+    }
+
+    #[test]
+    fn long_lines_get_split() {
+        let planner = Planner {
+            max_line: 0.5,
+            ..Planner::default()
+        };
+        let code = "fd 2.2 rt 90 fd 0.3";
+        let ops = planner.plan_logo(code, RECT).unwrap();
+        let plain = Planner::default().plan_logo(code, RECT).unwrap();
+        let moves = |ops: &[Op]| ops.iter().filter(|op| matches!(op, Op::MoveTo(_))).count();
+        // The first line becomes five pieces, and the short one stays as it is.
+        assert_eq!(moves(&ops), moves(&plain) + 4);
+        let (a, b) = (drawing(&ops), drawing(&plain));
+        assert!((a.perimeter(1e-3) - b.perimeter(1e-3)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn streaming_matches_planning() {
+        let code = "repeat 4 [fd 2 rt 90] penup fd 5 pendown repeat 36 [fd 0.3 rt 10] fd 100";
+        let planner = Planner::default();
+        let streamed = planner
+            .stream_logo(code, RECT)
+            .unwrap()
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(streamed, planner.plan_logo(code, RECT).unwrap());
+
+        // The error comes after whatever got drawn first.
+        let results: Vec<_> = planner
+            .stream_logo("fd 2 forwrd 10", RECT)
+            .unwrap()
+            .collect();
+        assert!(results.len() > 1);
+        assert!(results[..results.len() - 1].iter().all(|r| r.is_ok()));
+        assert!(results.last().unwrap().is_err());
+        assert!(planner.stream_logo("fd [", RECT).is_err());
+    }
+}
@@ -0,0 +1,48 @@
+//! Benchmarks for the evaluator, on programs that draw a lot.
+//!
+//! Run them with `cargo bench -p brachiologo`.
+
+use brachiologo::{parse, Env};
+use criterion::{criterion_group, criterion_main, Criterion};
+
+// Each of these makes somewhere around 100,000 turtle commands.
+const PROGRAMS: &[(&str, &str)] = &[
+    // A long loop with a little arithmetic in it.
+    ("spiral", "repeat 50000 [fd repcount * 0.01 rt 89.5]"),
+    // Nested loops, with a variable.
+    (
+        "nested",
+        "make \"len 3
+         repeat 100 [repeat 250 [fd :len rt 91] rt 3.6 make \"len :len + 0.01]",
+    ),
+    // Lots of procedure calls, with inputs.
+    (
+        "tree",
+        "to tree :n :len
+           if :n > 0 [
+             fd :len lt 30 tree :n - 1 :len * 0.7
+             rt 60 tree :n - 1 :len * 0.7 lt 30 bk :len
+           ]
+         end
+         tree 14 50",
+    ),
+];
+
+fn eval(c: &mut Criterion) {
+    let mut group = c.benchmark_group("eval");
+    group.sample_size(10);
+    for (name, src) in PROGRAMS {
+        let (_, prog) = parse::program((*src).into()).unwrap();
+        group.bench_function(*name, |b| {
+            b.iter(|| {
+                let mut env = Env::default();
+                prog.eval(&mut env).unwrap();
+                env.turtle.len()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, eval);
+criterion_main!(benches);
@@ -1,14 +1,10 @@
-/*
-use brachiologo::{program, Scope};
+use brachiologo::{parse, Env};
 
 fn main() {
-    let prog = program("to square :n repeat 4 [fd :n rt 90] end square 90")
+    let prog = parse::program("to square :n repeat 4 [fd :n rt 90] end square 90".into())
         .unwrap()
         .1;
-    let mut scope = Scope::default();
-    let mut output = Vec::new();
-    scope.exec_block(&mut output, &prog).unwrap();
-    dbg!(output);
+    let mut env = Env::default();
+    prog.eval(&mut env).unwrap();
+    dbg!(env.turtle);
 }
-*/
-fn main() {}
@@ -0,0 +1,29 @@
+# Messages for errors and warnings in Logo programs.
+#
+# The English messages are the same as the ones you get from `Display`, so if you change one
+# here then change it there too.
+
+## Errors
+
+not-enough-inputs = Not enough inputs to { $proc } (got { $got }, expected { $expected })
+missing-op-input = Missing input to { $op }
+unused-val = You don't say what to do with { $val }
+unknown-val = { $ident } has no value
+unknown-proc = I don't know how to { $ident }
+unknown-proc-suggestion = I don't know how to { $ident } (did you mean { $suggestion }?)
+no-output-to = didn't output to { $proc }
+bad-arg = { $proc } doesn't like { $arg } as input
+backtrace = error { $err } when evaluating { $proc }
+empty-list = I can't eval an empty list
+out-of-steps = I gave up because this was taking too long
+too-deep = I gave up because too many procedures were running inside each other
+throw = Can't find catch tag for { $tag }
+output = Can only output { $val } from inside a procedure
+stop = Can only stop from inside a procedure
+no-input = { $proc } has nothing to read
+
+## Warnings
+
+unused-param = { $proc } doesn't use its input :{ $param }
+param-shadows-builtin = { $proc } has an input :{ $param }, which has the same name as a builtin
+redefines-builtin = { $proc } is already defined as a builtin, and you're replacing it
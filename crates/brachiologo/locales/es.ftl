@@ -0,0 +1,26 @@
+# Mensajes para los errores y avisos de los programas en Logo.
+
+## Errores
+
+not-enough-inputs = Faltan entradas para { $proc } (hay { $got }, hacen falta { $expected })
+missing-op-input = Falta una entrada para { $op }
+unused-val = No dices qué hacer con { $val }
+unknown-val = { $ident } no tiene valor
+unknown-proc = No sé hacer { $ident }
+unknown-proc-suggestion = No sé hacer { $ident } (¿querías decir { $suggestion }?)
+no-output-to = no devolvió nada a { $proc }
+bad-arg = A { $proc } no le sirve { $arg } como entrada
+backtrace = error { $err } al evaluar { $proc }
+empty-list = No puedo evaluar una lista vacía
+out-of-steps = Me rendí porque esto tardaba demasiado
+too-deep = Me rendí porque había demasiados procedimientos ejecutándose uno dentro de otro
+throw = No encuentro ningún catch con la etiqueta { $tag }
+output = Solo se puede devolver { $val } desde dentro de un procedimiento
+stop = Solo se puede parar desde dentro de un procedimiento
+no-input = { $proc } no tiene nada que leer
+
+## Avisos
+
+unused-param = { $proc } no usa su entrada :{ $param }
+param-shadows-builtin = { $proc } tiene una entrada :{ $param }, que se llama igual que una primitiva
+redefines-builtin = { $proc } ya es una primitiva, y la estás reemplazando
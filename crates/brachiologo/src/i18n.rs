@@ -0,0 +1,231 @@
+//! Errors and warnings in languages other than English.
+//!
+//! The `Display` impls for [`EvalError`] and [`Warning`](crate::lint::Warning) are always in
+//! English. To show them to someone who'd rather read something else, make a [`Messages`] for
+//! their language and ask it to describe the error. The messages live in `locales/<lang>.ftl`, in the
+//! [Fluent](https://projectfluent.org) format.
+
+use fluent_bundle::{FluentArgs, FluentBundle, FluentResource, FluentValue};
+use unic_langid::LanguageIdentifier;
+
+use crate::{lint::WarningKind, EvalError};
+
+/// The languages that we have messages for, with the default one first.
+pub const LOCALES: &[(&str, &str)] = &[
+    ("en", include_str!("../locales/en.ftl")),
+    ("es", include_str!("../locales/es.ftl")),
+];
+
+/// The messages for one language.
+pub struct Messages {
+    bundle: FluentBundle<FluentResource>,
+}
+
+impl Default for Messages {
+    fn default() -> Self {
+        Messages::new("en")
+    }
+}
+
+impl Messages {
+    /// Finds the messages for a language, like `"es"` or `"es-MX"`.
+    ///
+    /// This also understands the `LANG` environment variable's format, like `"es_MX.UTF-8"`.
+    /// If we don't know the language, the messages are in English.
+    pub fn new(lang: &str) -> Messages {
+        let lang = lang
+            .split(['.', '@'])
+            .next()
+            .unwrap_or("")
+            .replace('_', "-");
+        let requested: Option<LanguageIdentifier> = lang.parse().ok();
+        let (id, source) = LOCALES
+            .iter()
+            .find(|(id, _)| requested.as_ref().map(|r| r.language.as_str()) == Some(*id))
+            .unwrap_or(&LOCALES[0]);
+
+        // Unwraps: the message files are baked in, and the tests check that they parse.
+        let langid: LanguageIdentifier = id.parse().unwrap();
+        let resource = FluentResource::try_new(source.to_string()).unwrap();
+        let mut bundle = FluentBundle::new(vec![langid]);
+        // Fluent likes to wrap arguments in invisible direction markers, but they'd only get in
+        // the way in a code editor.
+        bundle.set_use_isolating(false);
+        bundle.add_resource(resource).unwrap();
+        Messages { bundle }
+    }
+
+    /// The language that these messages are in.
+    pub fn lang(&self) -> String {
+        self.bundle.locales[0].to_string()
+    }
+
+    fn format(&self, id: &str, args: &[(&str, FluentValue)]) -> String {
+        let Some(pattern) = self.bundle.get_message(id).and_then(|m| m.value()) else {
+            return id.to_owned();
+        };
+        let mut fluent_args = FluentArgs::new();
+        for (name, value) in args {
+            fluent_args.set(*name, value.clone());
+        }
+        let mut errors = Vec::new();
+        self.bundle
+            .format_pattern(pattern, Some(&fluent_args), &mut errors)
+            .into_owned()
+    }
+
+    /// Describes an error that happened while running a program.
+    pub fn eval_error(&self, err: &EvalError) -> String {
+        let s = |x: &dyn ToString| FluentValue::from(x.to_string());
+        match err {
+            EvalError::NotEnoughInputs { proc, args } => self.format(
+                "not-enough-inputs",
+                &[
+                    ("proc", s(&proc.name())),
+                    ("got", args.len().into()),
+                    ("expected", proc.num_args().into()),
+                ],
+            ),
+            EvalError::MissingOpInput { op } => self.format("missing-op-input", &[("op", s(op))]),
+            EvalError::UnusedVal { val } => self.format("unused-val", &[("val", s(val))]),
+            EvalError::UnknownVal { ident } => self.format("unknown-val", &[("ident", s(ident))]),
+            EvalError::UnknownProc {
+                ident,
+                suggestion: None,
+            } => self.format("unknown-proc", &[("ident", s(ident))]),
+            EvalError::UnknownProc {
+                ident,
+                suggestion: Some(suggestion),
+            } => self.format(
+                "unknown-proc-suggestion",
+                &[("ident", s(ident)), ("suggestion", s(suggestion))],
+            ),
+            EvalError::NoOutputTo { proc } => self.format("no-output-to", &[("proc", s(proc))]),
+            EvalError::BadArg { proc, arg } => {
+                self.format("bad-arg", &[("proc", s(proc)), ("arg", s(arg))])
+            }
+            EvalError::BadOpArg { op, arg } => {
+                self.format("bad-arg", &[("proc", s(&op.name())), ("arg", s(arg))])
+            }
+            EvalError::Backtrace { err, proc } => self.format(
+                "backtrace",
+                &[("err", self.eval_error(err).into()), ("proc", s(proc))],
+            ),
+            EvalError::EmptyList => self.format("empty-list", &[]),
+            EvalError::OutOfSteps => self.format("out-of-steps", &[]),
+            EvalError::TooDeep => self.format("too-deep", &[]),
+            EvalError::Throw { tag } => self.format("throw", &[("tag", s(tag))]),
+            EvalError::Output { val } => self.format("output", &[("val", s(val))]),
+            EvalError::Stop => self.format("stop", &[]),
+            EvalError::NoInput { proc } => self.format("no-input", &[("proc", s(proc))]),
+        }
+    }
+
+    /// Describes a warning from the [linter](crate::lint).
+    pub fn warning(&self, warning: &WarningKind) -> String {
+        match warning {
+            WarningKind::UnusedParam { proc, param } => self.format(
+                "unused-param",
+                &[
+                    ("proc", proc.as_str().into()),
+                    ("param", param.as_str().into()),
+                ],
+            ),
+            WarningKind::ParamShadowsBuiltin { proc, param } => self.format(
+                "param-shadows-builtin",
+                &[
+                    ("proc", proc.as_str().into()),
+                    ("param", param.as_str().into()),
+                ],
+            ),
+            WarningKind::RedefinesBuiltin { proc } => {
+                self.format("redefines-builtin", &[("proc", proc.as_str().into())])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lint::lint, parse::program, Env};
+
+    // Our message files only have simple one-line messages.
+    fn message_ids(source: &str) -> Vec<&str> {
+        let mut ids: Vec<_> = source
+            .lines()
+            .filter_map(|line| Some(line.split_once(" = ")?.0))
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn every_locale_has_every_message() {
+        let english = message_ids(LOCALES[0].1);
+        for (id, source) in LOCALES {
+            assert!(FluentResource::try_new(source.to_string()).is_ok(), "{id}");
+            assert_eq!(message_ids(source), english, "{id}");
+        }
+    }
+
+    fn eval_err(code: &str) -> EvalError {
+        let (_, prog) = program(code.into()).expect(code);
+        prog.eval(&mut Env::default()).expect_err(code)
+    }
+
+    #[test]
+    fn english_matches_display() {
+        let en = Messages::new("en_US.UTF-8");
+        assert_eq!(en.lang(), "en");
+        for code in [
+            "forward",
+            "repeat 2 [ 1 ]",
+            "fd :x",
+            "fowrard 10",
+            "frobnicate",
+            "forward [1]",
+            "print 1 + [1]",
+            "throw \"oops",
+            "output 1",
+            "stop",
+            "readword",
+            "to square :size\nrepeat 4 [ fd :sise rt 90 ]\nend\nsquare 10",
+        ] {
+            let err = eval_err(code);
+            assert_eq!(en.eval_error(&err), err.to_string(), "{code}");
+        }
+        for err in [
+            EvalError::EmptyList,
+            EvalError::OutOfSteps,
+            EvalError::TooDeep,
+        ] {
+            assert_eq!(en.eval_error(&err), err.to_string());
+        }
+
+        let (_, prog) = program("to forward :fd :size\nprint 1\nend".into()).unwrap();
+        for w in lint(&prog) {
+            assert_eq!(en.warning(&w.kind), w.kind.to_string());
+        }
+    }
+
+    #[test]
+    fn spanish() {
+        let es = Messages::new("es-MX");
+        assert_eq!(es.lang(), "es");
+        assert_eq!(
+            es.eval_error(&eval_err("fowrard 10")),
+            "No sé hacer fowrard (¿querías decir forward?)"
+        );
+        assert_eq!(
+            es.warning(&WarningKind::RedefinesBuiltin {
+                proc: "forward".to_owned()
+            }),
+            "forward ya es una primitiva, y la estás reemplazando"
+        );
+
+        // We fall back to English for languages we don't know.
+        assert_eq!(Messages::new("tlh").lang(), "en");
+        assert_eq!(Messages::new("").lang(), "en");
+    }
+}
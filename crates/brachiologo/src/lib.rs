@@ -1,5 +1,8 @@
+pub mod i18n;
+pub mod lint;
 pub mod parse;
 pub mod proc;
+pub mod repl;
 pub mod typ;
 
 pub use typ::{Env, EvalError, Expr, TurtleCmd};
@@ -0,0 +1,170 @@
+//! Warnings about programs that are probably mistakes, even though they aren't errors.
+
+use crate::{
+    proc::UserProc,
+    typ::{ExprKind, Span},
+    Env, Expr,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WarningKind {
+    /// A procedure has a parameter that it never uses.
+    UnusedParam { proc: String, param: String },
+    /// A procedure has a parameter with the same name as a builtin procedure.
+    ParamShadowsBuiltin { proc: String, param: String },
+    /// A procedure has the same name as a builtin procedure, which it replaces.
+    RedefinesBuiltin { proc: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub span: Span,
+}
+
+impl std::fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WarningKind::UnusedParam { proc, param } => {
+                write!(f, "{proc} doesn't use its input :{param}")
+            }
+            WarningKind::ParamShadowsBuiltin { proc, param } => {
+                write!(f, "{proc} has an input :{param}, which has the same name as a builtin")
+            }
+            WarningKind::RedefinesBuiltin { proc } => {
+                write!(f, "{proc} is already defined as a builtin, and you're replacing it")
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+/// Calls `f` on `expr` and all of its sub-expressions, including the bodies of procedure definitions.
+fn walk<'a>(expr: &'a Expr, f: &mut impl FnMut(&'a Expr)) {
+    f(expr);
+    match &expr.e {
+        ExprKind::List(list) => {
+            for e in list.iter() {
+                walk(e, f);
+            }
+        }
+        ExprKind::Quote(e) => walk(e, f),
+        ExprKind::DefProc(p) => {
+            if let Some(user) = p.as_user() {
+                walk(&user.body, f);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn lint_proc(proc: &UserProc, builtins: &Env, warnings: &mut Vec<Warning>) {
+    if builtins.lookup_proc(&proc.name).is_some() {
+        warnings.push(Warning {
+            kind: WarningKind::RedefinesBuiltin {
+                proc: proc.name.clone(),
+            },
+            span: proc.name_span,
+        });
+    }
+
+    let mut used = Vec::new();
+    walk(&proc.body, &mut |e| {
+        if let ExprKind::Var(v) = &e.e {
+            used.push(v.as_str());
+        }
+    });
+
+    for (param, span) in proc.args.iter().zip(&proc.arg_spans) {
+        if builtins.lookup_proc(param).is_some() {
+            warnings.push(Warning {
+                kind: WarningKind::ParamShadowsBuiltin {
+                    proc: proc.name.clone(),
+                    param: param.clone(),
+                },
+                span: *span,
+            });
+        }
+        if !used.contains(&param.as_str()) {
+            warnings.push(Warning {
+                kind: WarningKind::UnusedParam {
+                    proc: proc.name.clone(),
+                    param: param.clone(),
+                },
+                span: *span,
+            });
+        }
+    }
+}
+
+/// Looks through a program for things that are probably mistakes.
+pub fn lint(program: &Expr) -> Vec<Warning> {
+    let builtins = Env::default();
+    let mut warnings = Vec::new();
+    walk(program, &mut |e| {
+        if let ExprKind::DefProc(p) = &e.e {
+            if let Some(user) = p.as_user() {
+                lint_proc(user, &builtins, &mut warnings);
+            }
+        }
+    });
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint_str(s: &str) -> Vec<Warning> {
+        let (_, prog) = crate::parse::program(s.into()).unwrap();
+        lint(&prog)
+    }
+
+    #[test]
+    fn unused_param() {
+        let warnings = lint_str("to square :n :m\nrepeat 4 [fd :n rt 90]\nend");
+        assert_eq!(
+            warnings,
+            vec![Warning {
+                kind: WarningKind::UnusedParam {
+                    proc: "square".to_owned(),
+                    param: "m".to_owned(),
+                },
+                span: Span { start: 13, end: 15 },
+            }]
+        );
+    }
+
+    #[test]
+    fn builtin_names() {
+        let warnings = lint_str("to fd :rt fd :rt end");
+        assert_eq!(
+            warnings,
+            vec![
+                Warning {
+                    kind: WarningKind::RedefinesBuiltin {
+                        proc: "fd".to_owned(),
+                    },
+                    span: Span { start: 3, end: 5 },
+                },
+                Warning {
+                    kind: WarningKind::ParamShadowsBuiltin {
+                        proc: "fd".to_owned(),
+                        param: "rt".to_owned(),
+                    },
+                    span: Span { start: 6, end: 9 },
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_use() {
+        assert!(lint_str("to f :n if :n > 0 [fd :n] end").is_empty());
+    }
+}
@@ -1,22 +1,43 @@
-use brachiologo::Env;
+use brachiologo::{i18n::Messages, repl::Repl, Env};
 use clap::Parser;
 use std::{path::PathBuf, process::exit};
 
 #[derive(Parser)]
 struct Args {
-    input: PathBuf,
+    /// The program to run. Without one, programs get typed in a line at a time, and the turtle
+    /// commands that each line makes get printed.
+    input: Option<PathBuf>,
+}
+
+// Anything that the program asks for gets typed in.
+fn read_stdin() -> Option<String> {
+    let mut line = String::new();
+    match std::io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line),
+    }
 }
 
 pub fn main() {
     let args = Args::parse();
-    let input = match std::fs::read_to_string(&args.input) {
+    let Some(path) = args.input else {
+        let mut repl = Repl::new(Env {
+            input: Some(Box::new(read_stdin)),
+            ..Env::default()
+        });
+        let printed = repl.run_stdin(|_env, turtle| {
+            for cmd in turtle {
+                println!("  {cmd:?}");
+            }
+            Ok::<_, std::convert::Infallible>(())
+        });
+        let Ok(()) = printed;
+        return;
+    };
+    let input = match std::fs::read_to_string(&path) {
         Ok(x) => x,
         Err(e) => {
-            println!(
-                "Failed to open input file {}: {}",
-                args.input.into_os_string().to_string_lossy(),
-                e
-            );
+            println!("Failed to open input file {}: {}", path.display(), e);
             exit(1);
         }
     };
@@ -24,24 +45,10 @@ pub fn main() {
     let (remaining, prog) = match brachiologo::parse::program(input.as_str().into()) {
         Ok(prog) => prog,
         Err(e) => {
-            let (nom::Err::Failure(mut e) | nom::Err::Error(mut e)) = e else {
+            let (nom::Err::Failure(e) | nom::Err::Error(e)) = e else {
                 panic!("unexpected error");
             };
-            println!(
-                "Parse error at {}:{}: {:?}",
-                e.input.location_line(),
-                e.input.get_utf8_column(),
-                e.kind,
-            );
-            while let Some(cause) = e.cause {
-                e = *cause;
-                println!(
-                    "Caused by (at {}:{}) {:?}",
-                    e.input.location_line(),
-                    e.input.get_utf8_column(),
-                    e.kind,
-                );
-            }
+            println!("{e}");
             exit(1);
         }
     };
@@ -49,14 +56,18 @@ pub fn main() {
     // The parser returns an error if the input isn't consumed.
     assert!(remaining.is_empty());
 
-    let mut env = Env::default();
+    let mut env = Env {
+        input: Some(Box::new(read_stdin)),
+        ..Env::default()
+    };
     match prog.eval(&mut env) {
         Ok(None) => {}
         Ok(Some(e)) => {
             println!("Warning: program evaluated to an unexpected value: {}", e);
         }
         Err(e) => {
-            println!("Evaluation error: {e}");
+            let messages = Messages::new(&std::env::var("LANG").unwrap_or_default());
+            println!("Evaluation error: {}", messages.eval_error(&e));
             exit(1);
         }
     }
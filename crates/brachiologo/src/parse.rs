@@ -1,9 +1,13 @@
+use std::{cell::Cell, sync::Arc};
+
 use nom::{
     branch::alt,
     bytes::complete::tag,
-    character::complete::{alpha1, anychar, char, line_ending, multispace0, multispace1, space0},
-    combinator::{all_consuming, consumed, cut, map, map_opt, verify},
-    multi::{many0, separated_list1},
+    character::complete::{
+        alpha1, anychar, char, line_ending, multispace1, not_line_ending, space0, space1,
+    },
+    combinator::{all_consuming, consumed, cut, map, map_opt, value, verify},
+    multi::{many0, many0_count, many1_count, separated_list1},
     number::complete::double,
     sequence::{delimited, preceded, terminated, tuple},
     IResult,
@@ -42,6 +46,21 @@ impl<'a> ParseError<'a> {
     }
 }
 
+impl std::fmt::Display for ParseError<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let at = |e: &ParseError| (e.input.location_line(), e.input.get_utf8_column());
+        let (line, col) = at(self);
+        write!(f, "Parse error at {line}:{col}: {:?}", self.kind)?;
+        let mut e = self;
+        while let Some(cause) = &e.cause {
+            e = cause;
+            let (line, col) = at(e);
+            write!(f, "\nCaused by (at {line}:{col}) {:?}", e.kind)?;
+        }
+        Ok(())
+    }
+}
+
 impl<'a> nom::error::ParseError<Span<'a>> for ParseError<'a> {
     fn from_error_kind(input: Span<'a>, kind: nom::error::ErrorKind) -> Self {
         ParseError::new(input, ErrorKind::Nom(kind))
@@ -60,9 +79,31 @@ pub enum ErrorKind {
     UnendedProc,
     UnclosedList,
     UnclosedQuoteList,
+    /// Lists, quotes or procedure definitions were nested more than [`MAX_DEPTH`] deep.
+    TooDeep,
     Nom(nom::error::ErrorKind),
 }
 
+/// How deeply expressions (lists, quotes, and procedure definitions) can be nested.
+///
+/// The parser is recursive, so without a limit a long enough run of `[`s would overflow the
+/// stack. No sensible program comes anywhere near this.
+pub const MAX_DEPTH: usize = 100;
+
+thread_local! {
+    // How many calls to `expr` we're currently inside.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+// Restores the nesting depth when an `expr` finishes (even if it panics).
+struct DepthGuard(usize);
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        DEPTH.with(|d| d.set(self.0));
+    }
+}
+
 fn err_ctx<'a, F, O>(kind: ErrorKind, mut f: F) -> impl FnMut(Span<'a>) -> PResult<'a, O>
 where
     F: nom::Parser<Span<'a>, O, ParseError<'a>>,
@@ -70,23 +111,58 @@ where
     move |input: Span<'a>| match f.parse(input.clone()) {
         Ok(o) => Ok(o),
         Err(nom::Err::Incomplete(i)) => Err(nom::Err::Incomplete(i)),
+        // If the nesting is too deep then every level would add some context, and there'd be
+        // hundreds of them. They wouldn't be useful anyway, so just report the depth.
+        Err(nom::Err::Failure(e)) if matches!(e.kind, ErrorKind::TooDeep) => {
+            Err(nom::Err::Failure(e))
+        }
         Err(nom::Err::Error(e)) => Err(nom::Err::Error(ParseError::with_cause(input, kind, e))),
         Err(nom::Err::Failure(e)) => Err(nom::Err::Failure(ParseError::with_cause(input, kind, e))),
     }
 }
 
+// A comment, from a `;` to the end of the line. The newline isn't part of it.
+fn comment(input: Span) -> PResult<()> {
+    value((), tuple((char(';'), not_line_ending)))(input)
+}
+
+// A `~` at the end of a line, which carries the line on onto the next one.
+fn continuation(input: Span) -> PResult<()> {
+    value((), tuple((char('~'), space0, line_ending)))(input)
+}
+
+// Anything that separates expressions: whitespace (including newlines), comments and line
+// continuations.
+fn blank(input: Span) -> PResult<()> {
+    alt((value((), multispace1), comment, continuation))(input)
+}
+
+// Like `blank`, but without newlines (except for the ones after a `~`).
+fn blank_no_newline(input: Span) -> PResult<()> {
+    alt((value((), space1), comment, continuation))(input)
+}
+
+fn blank0(input: Span) -> PResult<()> {
+    value((), many0_count(blank))(input)
+}
+
+fn blank1(input: Span) -> PResult<()> {
+    value((), many1_count(blank))(input)
+}
+
 fn ws<'a, F: 'a, O>(inner: F) -> impl FnMut(Span<'a>) -> PResult<O>
 where
     F: FnMut(Span<'a>) -> PResult<O>,
 {
-    delimited(multispace0, inner, multispace0)
+    delimited(blank0, inner, blank0)
 }
 
 fn ws_no_newline<'a, F: 'a, O>(inner: F) -> impl FnMut(Span<'a>) -> PResult<O>
 where
     F: FnMut(Span<'a>) -> PResult<O>,
 {
-    delimited(space0, inner, space0)
+    let blank_no_newline0 = || many0_count(blank_no_newline);
+    delimited(blank_no_newline0(), inner, blank_no_newline0())
 }
 
 fn with_span<'a, F: 'a>(inner: F) -> impl FnMut(Span<'a>) -> PResult<Expr>
@@ -126,8 +202,8 @@ pub fn op(input: Span) -> PResult<Expr> {
 }
 
 pub fn bare_list(input: Span) -> PResult<Expr> {
-    with_span(map(separated_list1(multispace1, expr), |exprs| {
-        ExprKind::List(exprs)
+    with_span(map(separated_list1(blank1, expr), |exprs| {
+        ExprKind::List(exprs.into())
     }))(input)
 }
 
@@ -141,14 +217,18 @@ pub fn list(input: Span) -> PResult<Expr> {
 }
 
 pub fn quoted_list(input: Span) -> PResult<Expr> {
+    // Unlike a list in parentheses, a quoted list can be empty.
+    let empty = with_span(map(blank0, |()| ExprKind::List(Arc::from([]))));
     let rest = terminated(
-        ws(bare_list),
+        alt((ws(bare_list), empty)),
         err_ctx(ErrorKind::UnclosedQuoteList, char(']')),
     );
 
     err_ctx(
         ErrorKind::QuoteList,
-        with_span(map(preceded(char('['), cut(rest)), |expr| expr.e)),
+        with_span(map(preceded(char('['), cut(rest)), |expr| {
+            ExprKind::Quote(Box::new(expr))
+        })),
     )(input)
 }
 
@@ -159,10 +239,11 @@ pub fn quote(input: Span) -> PResult<Expr> {
 }
 
 pub fn proc_def(input: Span) -> PResult<Expr> {
+    // The name and the params must be on the same line as the `to` (or be carried onto the
+    // next one with a `~`), but the body can start either on the next line or on the same line.
     let rest = tuple((
-        ws(word),
+        ws_no_newline(word),
         many0(ws_no_newline(param)),
-        line_ending,
         ws(bare_list),
         err_ctx(ErrorKind::UnendedProc, tag("end")),
     ));
@@ -170,11 +251,16 @@ pub fn proc_def(input: Span) -> PResult<Expr> {
     err_ctx(
         ErrorKind::Proc,
         with_span(map(
-            preceded(tag("to"), cut(rest)),
-            |(name, args, _newline, body, _end)| {
+            preceded(
+                terminated(tag("to"), many1_count(blank_no_newline)),
+                cut(rest),
+            ),
+            |(name, args, body, _end)| {
+                let name_span = name.span;
                 let ExprKind::Word(name) = name.e else {
                 panic!("name should be a word");
             };
+                let arg_spans = args.iter().map(|p| p.span).collect();
                 let args: Vec<String> = args
                     .into_iter()
                     .map(|p| {
@@ -182,13 +268,31 @@ pub fn proc_def(input: Span) -> PResult<Expr> {
                         p
                     })
                     .collect();
-                ExprKind::DefProc(UserProc { name, args, body }.into())
+                ExprKind::DefProc(
+                    UserProc {
+                        name,
+                        args,
+                        body,
+                        name_span,
+                        arg_spans,
+                    }
+                    .into(),
+                )
             },
         )),
     )(input)
 }
 
 pub fn expr(input: Span) -> PResult<Expr> {
+    let depth = DEPTH.with(|d| d.get());
+    if depth >= MAX_DEPTH {
+        return Err(nom::Err::Failure(ParseError::new(
+            input,
+            ErrorKind::TooDeep,
+        )));
+    }
+    DEPTH.with(|d| d.set(depth + 1));
+    let _guard = DepthGuard(depth);
     alt((proc_def, num, op, list, quoted_list, quote, word, param))(input)
 }
 
@@ -1,7 +1,7 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
 use crate::{
-    typ::{EvalResult, ExprKind, ProcExpr, Span, TurtleCmd},
+    typ::{eval_values, EvalResult, ExprKind, ProcExpr, Span, TurtleCmd, MAX_CALL_DEPTH},
     Env, EvalError, Expr,
 };
 
@@ -10,22 +10,48 @@ pub struct UserProc {
     pub args: Vec<String>,
     pub body: Expr,
     pub name: String,
+    pub name_span: Span,
+    pub arg_spans: Vec<Span>,
+}
+
+impl UserProc {
+    /// The first line of the procedure's definition, like `to square :size`.
+    pub fn title(&self) -> String {
+        let mut ret = format!("to {}", self.name);
+        for arg in &self.args {
+            ret += " :";
+            ret += arg;
+        }
+        ret
+    }
 }
 
 impl From<UserProc> for ProcExpr {
     fn from(p: UserProc) -> Self {
-        ProcExpr { inner: Rc::new(p) }
+        ProcExpr { inner: Arc::new(p) }
     }
 }
 
 impl Proc for UserProc {
     fn eval(&self, args: &[Expr], env: &mut Env) -> EvalResult {
         assert_eq!(args.len(), self.args.len());
+        if env.stack.len() > MAX_CALL_DEPTH {
+            return Err(EvalError::TooDeep);
+        }
         env.scoped(|env| {
             for (name, e) in self.args.iter().zip(args) {
                 env.def_var(name, e.clone());
             }
-            self.body.eval(env)
+            // `output` and `stop` work by throwing errors, which we catch here. This is where
+            // they stop: a procedure only ever outputs to whoever called it.
+            match self.body.eval(env) {
+                Err(err) => match err.root() {
+                    EvalError::Output { val } => Ok(Some(val.clone())),
+                    EvalError::Stop => Ok(None),
+                    _ => Err(err),
+                },
+                ok => ok,
+            }
         })
     }
 
@@ -36,12 +62,30 @@ impl Proc for UserProc {
     fn name(&self) -> &str {
         &self.name
     }
+
+    fn as_user(&self) -> Option<&UserProc> {
+        Some(self)
+    }
 }
 
-pub trait Proc {
+pub trait Proc: Send + Sync {
     fn eval(&self, args: &[Expr], env: &mut Env) -> EvalResult;
     fn num_args(&self) -> usize;
     fn name(&self) -> &str;
+
+    /// How many more inputs this procedure can take after the [`num_args`](Proc::num_args)
+    /// that it needs.
+    ///
+    /// An optional input only gets taken if it's a list in square brackets (like the else-list
+    /// in `if :x > 0 [fd 10] [bk 10]`), so that it can't be mistaken for the next command.
+    fn num_optional_args(&self) -> usize {
+        0
+    }
+
+    /// If this procedure was defined in Logo (as opposed to being a builtin), returns its definition.
+    fn as_user(&self) -> Option<&UserProc> {
+        None
+    }
 }
 
 struct FnZero<F: Fn(&mut Env) -> EvalResult> {
@@ -51,18 +95,18 @@ struct FnZero<F: Fn(&mut Env) -> EvalResult> {
 
 struct FnOne<T, F: Fn(T, &mut Env) -> EvalResult> {
     f: F,
-    marker: std::marker::PhantomData<T>,
+    marker: std::marker::PhantomData<fn(T)>,
     name: &'static str,
 }
 
 struct FnTwo<S, T, F: Fn(S, T, &mut Env) -> EvalResult> {
     f: F,
-    marker1: std::marker::PhantomData<S>,
-    marker2: std::marker::PhantomData<T>,
+    marker1: std::marker::PhantomData<fn(S)>,
+    marker2: std::marker::PhantomData<fn(T)>,
     name: &'static str,
 }
 
-impl<F: Fn(&mut Env) -> EvalResult> Proc for FnZero<F> {
+impl<F: Fn(&mut Env) -> EvalResult + Send + Sync> Proc for FnZero<F> {
     fn eval(&self, _args: &[Expr], env: &mut Env) -> EvalResult {
         (self.f)(env)
     }
@@ -75,7 +119,7 @@ impl<F: Fn(&mut Env) -> EvalResult> Proc for FnZero<F> {
         self.name
     }
 }
-impl<T: TryFrom<Expr>, F: Fn(T, &mut Env) -> EvalResult> Proc for FnOne<T, F> {
+impl<T: TryFrom<Expr>, F: Fn(T, &mut Env) -> EvalResult + Send + Sync> Proc for FnOne<T, F> {
     fn eval(&self, args: &[Expr], env: &mut Env) -> EvalResult {
         match args[0].clone().try_into() {
             Ok(x) => (self.f)(x, env),
@@ -99,7 +143,7 @@ impl<S, T, F> Proc for FnTwo<S, T, F>
 where
     S: TryFrom<Expr>,
     T: TryFrom<Expr>,
-    F: Fn(S, T, &mut Env) -> EvalResult,
+    F: Fn(S, T, &mut Env) -> EvalResult + Send + Sync,
 {
     fn eval(&self, args: &[Expr], env: &mut Env) -> EvalResult {
         match (args[0].clone().try_into(), args[1].clone().try_into()) {
@@ -124,6 +168,49 @@ where
     }
 }
 
+// `if cond [then]` and `ifelse cond [then] [else]`. Like some other Logos, `if` also takes an
+// optional else-list.
+struct If {
+    name: &'static str,
+    needs_else: bool,
+}
+
+impl Proc for If {
+    fn eval(&self, args: &[Expr], env: &mut Env) -> EvalResult {
+        let Ok(cond) = bool::try_from(args[0].clone()) else {
+            return Err(EvalError::BadArg {
+                proc: self.name.to_owned(),
+                arg: args[0].clone(),
+            });
+        };
+        match (cond, args.get(2)) {
+            (true, _) => args[1].eval(env),
+            (false, Some(otherwise)) => otherwise.eval(env),
+            (false, None) => Ok(None),
+        }
+    }
+
+    fn num_args(&self) -> usize {
+        if self.needs_else {
+            3
+        } else {
+            2
+        }
+    }
+
+    fn num_optional_args(&self) -> usize {
+        if self.needs_else {
+            0
+        } else {
+            1
+        }
+    }
+
+    fn name(&self) -> &str {
+        self.name
+    }
+}
+
 trait IntoEvalResult {
     fn into_eval_result(self) -> EvalResult;
 }
@@ -150,13 +237,117 @@ impl IntoEvalResult for f64 {
     }
 }
 
+impl IntoEvalResult for bool {
+    fn into_eval_result(self) -> EvalResult {
+        Ok(Some(Expr {
+            e: ExprKind::Bool(self),
+            span: Span { start: 0, end: 0 },
+        }))
+    }
+}
+
+// Checks that an input is a number satisfying some condition (like being positive, for `ln`).
+fn num_where(proc: &str, arg: Expr, cond: impl Fn(f64) -> bool) -> Result<f64, EvalError> {
+    match arg.e {
+        ExprKind::Num(x) if cond(x) => Ok(x),
+        _ => Err(EvalError::BadArg {
+            proc: proc.to_owned(),
+            arg,
+        }),
+    }
+}
+
+// A value that didn't come from anywhere in the program.
+fn unspanned(e: ExprKind) -> Expr {
+    Expr {
+        e,
+        span: Span { start: 0, end: 0 },
+    }
+}
+
+// The things that the list procedures (like `first` and `count`) work on. Like in UCB Logo, they
+// also work on the letters of a word, and a number counts as the word made of its digits.
+enum Things {
+    List(Arc<[Expr]>),
+    Word(Vec<char>),
+}
+
+impl Things {
+    fn new(proc: &str, arg: Expr) -> Result<Things, EvalError> {
+        match arg.e {
+            ExprKind::List(list) => Ok(Things::List(list)),
+            ExprKind::Word(w) => Ok(Things::Word(w.chars().collect())),
+            ExprKind::Num(x) => Ok(Things::Word(x.to_string().chars().collect())),
+            _ => Err(EvalError::BadArg {
+                proc: proc.to_owned(),
+                arg,
+            }),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Things::List(list) => list.len(),
+            Things::Word(chars) => chars.len(),
+        }
+    }
+
+    // The `i`th thing (counting from zero), which must exist.
+    fn item(&self, i: usize) -> Expr {
+        match self {
+            Things::List(list) => thing(list[i].clone()),
+            Things::Word(chars) => word(chars[i..=i].iter().collect()),
+        }
+    }
+
+    // The things in a range, as a list or a word.
+    fn slice(&self, range: std::ops::Range<usize>) -> Expr {
+        match self {
+            Things::List(list) => unspanned(ExprKind::List(list[range].into())),
+            Things::Word(chars) => word(chars[range].iter().collect()),
+        }
+    }
+}
+
+// Lists in the program (like `[[1 2] "a]`) have their sublists and words quoted, but once they
+// come out of the list they're just values.
+fn thing(e: Expr) -> Expr {
+    match e.e {
+        ExprKind::Quote(inner) => *inner,
+        _ => e,
+    }
+}
+
+// A word that came from pulling a word apart. If it looks like a number then it is one, so that
+// `first 42` is 4.
+fn word(w: String) -> Expr {
+    match w.parse::<f64>() {
+        Ok(x) if x.is_finite() => unspanned(ExprKind::Num(x)),
+        _ => unspanned(ExprKind::Word(w)),
+    }
+}
+
+// The contents of a list for `sentence`: the things in it if it's a list, and just itself if it
+// isn't.
+fn sentence_parts(e: Expr) -> Vec<Expr> {
+    match e.e {
+        ExprKind::List(list) => list.iter().cloned().map(thing).collect(),
+        _ => vec![e],
+    }
+}
+
+// Checks that the second input to `quotient`, `remainder` or `modulo` is a non-zero number.
+fn divisor(proc: &str, arg: Expr) -> Result<f64, EvalError> {
+    num_where(proc, arg, |x| x != 0.0)
+}
+
 fn fn_zero<U, F>(name: &'static str, f: F) -> ProcExpr
 where
     U: IntoEvalResult + 'static,
-    F: Fn(&mut Env) -> U + 'static,
+    F: Fn(&mut Env) -> U + Send + Sync + 'static,
 {
     ProcExpr {
-        inner: Rc::new(FnZero {
+        inner: Arc::new(FnZero {
             f: move |env| f(env).into_eval_result(),
             name,
         }),
@@ -165,13 +356,13 @@ where
 fn fn_one<
     T: TryFrom<Expr> + 'static,
     U: IntoEvalResult + 'static,
-    F: Fn(T, &mut Env) -> U + 'static,
+    F: Fn(T, &mut Env) -> U + Send + Sync + 'static,
 >(
     name: &'static str,
     f: F,
 ) -> ProcExpr {
     ProcExpr {
-        inner: Rc::new(FnOne {
+        inner: Arc::new(FnOne {
             f: move |x, env| f(x, env).into_eval_result(),
             marker: std::marker::PhantomData,
             name,
@@ -184,10 +375,10 @@ where
     S: TryFrom<Expr> + 'static,
     T: TryFrom<Expr> + 'static,
     U: IntoEvalResult + 'static,
-    F: Fn(S, T, &mut Env) -> U + 'static,
+    F: Fn(S, T, &mut Env) -> U + Send + Sync + 'static,
 {
     ProcExpr {
-        inner: Rc::new(FnTwo {
+        inner: Arc::new(FnTwo {
             f: move |x, y, env| f(x, y, env).into_eval_result(),
             marker1: std::marker::PhantomData,
             marker2: std::marker::PhantomData,
@@ -203,27 +394,287 @@ pub fn add_builtins(env: &mut Env) {
     env.def_proc(fn_one("fd", |x, env| env.turtle_do(TurtleCmd::Forward(x))));
     env.def_proc(fn_one("back", |x, env| env.turtle_do(TurtleCmd::Back(x))));
     env.def_proc(fn_one("bk", |x, env| env.turtle_do(TurtleCmd::Back(x))));
+    env.def_proc(fn_one("right", |x, env| env.turtle_do(TurtleCmd::Right(x))));
+    env.def_proc(fn_one("rt", |x, env| env.turtle_do(TurtleCmd::Right(x))));
+    env.def_proc(fn_one("left", |x, env| env.turtle_do(TurtleCmd::Left(x))));
+    env.def_proc(fn_one("lt", |x, env| env.turtle_do(TurtleCmd::Left(x))));
+    env.def_proc(fn_two("arc", |degrees, radius, env| {
+        env.turtle_do(TurtleCmd::Arc { degrees, radius })
+    }));
 
     env.def_proc(fn_one("print", |x: Expr, env| {
         let _ = writeln!(&mut env.out, "{}", x);
     }));
+    // `readword` and `readnumber` wait for a line of input, so that a program can ask questions
+    // (after `print`ing them). Where the answers come from is up to whoever is running the
+    // program (see `Env::input`).
+    env.def_proc(fn_zero("readword", |env| -> EvalResult {
+        let line = env.read_line("readword")?;
+        Ok(Some(Expr {
+            e: ExprKind::Word(line),
+            span: Span { start: 0, end: 0 },
+        }))
+    }));
+    env.def_proc(fn_zero("readnumber", |env| -> EvalResult {
+        let line = env.read_line("readnumber")?;
+        match line.trim().parse::<f64>() {
+            Ok(n) if n.is_finite() => n.into_eval_result(),
+            _ => Err(EvalError::BadArg {
+                proc: "readnumber".to_owned(),
+                arg: Expr {
+                    e: ExprKind::Word(line),
+                    span: Span { start: 0, end: 0 },
+                },
+            }),
+        }
+    }));
+
+    // Absolute positions are measured from where the turtle started, with y pointing up. Headings
+    // are in degrees clockwise from straight up, so `setheading 90` faces right.
+    env.def_proc(fn_two("setxy", |x, y, env| {
+        env.turtle_do(TurtleCmd::SetPos { x, y })
+    }));
+    env.def_proc(fn_one("setx", |x, env| {
+        let y = env.turtle_pos.1;
+        env.turtle_do(TurtleCmd::SetPos { x, y })
+    }));
+    env.def_proc(fn_one("sety", |y, env| {
+        let x = env.turtle_pos.0;
+        env.turtle_do(TurtleCmd::SetPos { x, y })
+    }));
+    env.def_proc(fn_one("setpos", |pos: Expr, env| {
+        let xy = match &pos.e {
+            ExprKind::List(xy) => xy
+                .iter()
+                .map(|e| match e.e {
+                    ExprKind::Num(n) => Some(n),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>(),
+            _ => None,
+        };
+        let Some(&[x, y]) = xy.as_deref() else {
+            return Err(EvalError::BadArg {
+                proc: "setpos".to_owned(),
+                arg: pos,
+            });
+        };
+        env.turtle_do(TurtleCmd::SetPos { x, y });
+        Ok(None)
+    }));
+    env.def_proc(fn_one("setheading", |h, env| {
+        env.turtle_do(TurtleCmd::SetHeading(h))
+    }));
+    env.def_proc(fn_one("seth", |h, env| {
+        env.turtle_do(TurtleCmd::SetHeading(h))
+    }));
+    env.def_proc(fn_zero("home", |env| {
+        env.turtle_do(TurtleCmd::SetPos { x: 0.0, y: 0.0 });
+        env.turtle_do(TurtleCmd::SetHeading(0.0));
+    }));
+    env.def_proc(fn_zero("pos", |env| {
+        let (x, y) = env.turtle_pos;
+        let num = |n| Expr {
+            e: ExprKind::Num(n),
+            span: Span { start: 0, end: 0 },
+        };
+        Ok(Some(Expr {
+            e: ExprKind::List(vec![num(x), num(y)].into()),
+            span: Span { start: 0, end: 0 },
+        }))
+    }));
+    env.def_proc(fn_zero("xcor", |env| env.turtle_pos.0));
+    env.def_proc(fn_zero("ycor", |env| env.turtle_pos.1));
+    env.def_proc(fn_zero("heading", |env| env.turtle_heading));
+    // The size of the drawing area, so that `fd canvasheight / 4` goes a quarter of the way up
+    // the paper whatever paper it is. The turtle starts in the middle, so it can go half of
+    // these in each direction.
+    env.def_proc(fn_zero("canvaswidth", |env| env.canvas_size.0));
+    env.def_proc(fn_zero("canvasheight", |env| env.canvas_size.1));
 
     env.def_proc(fn_zero("penup", |env| env.turtle_do(TurtleCmd::PenUp)));
     env.def_proc(fn_zero("pendown", |env| env.turtle_do(TurtleCmd::PenDown)));
 
+    // `text "hello` writes a word, one letter at a time, and leaves the turtle at the end of it.
+    env.def_proc(fn_one("text", |word: String, env| {
+        let height = env.text_height;
+        for c in word.chars() {
+            env.turtle_do(TurtleCmd::Glyph { c, height });
+        }
+    }));
+    env.def_proc(fn_one("settextheight", |height: Expr, env| {
+        env.text_height = num_where("settextheight", height, |h| h > 0.0)?;
+        Ok(None)
+    }));
+
+    // Logo is dynamically scoped: a procedure can see the variables of whoever called it. `make`
+    // changes the innermost variable with the right name (creating a global one if there isn't
+    // one), while `localmake` creates a new variable that disappears when the current procedure
+    // finishes.
     env.def_proc(fn_two("make", |sym: String, val, env| {
+        env.set_var(&sym, val)
+    }));
+    env.def_proc(fn_two("localmake", |sym: String, val, env| {
         env.def_var(&sym, val)
     }));
     env.def_proc(fn_two("sum", |x: f64, y: f64, _env| x + y));
+    env.def_proc(fn_two("difference", |x: f64, y: f64, _env| x - y));
     env.def_proc(fn_two("prod", |x: f64, y: f64, _env| x * y));
+    env.def_proc(fn_two("quotient", |x: f64, y: Expr, _env| {
+        let y = divisor("quotient", y)?;
+        (x / y).into_eval_result()
+    }));
+    // `=` overlooks tiny rounding errors (see `Env::eq_tolerance`), but this doesn't.
+    env.def_proc(fn_two("exactequalp", |x: f64, y: f64, _env| x == y));
+    env.def_proc(fn_one("minus", |x: f64, _env| -x));
+    env.def_proc(fn_one("abs", |x: f64, _env| x.abs()));
+    env.def_proc(fn_one("sqrt", |x: Expr, _env| {
+        num_where("sqrt", x, |x| x >= 0.0)?
+            .sqrt()
+            .into_eval_result()
+    }));
+    env.def_proc(fn_two("power", |x: f64, y: Expr, _env| {
+        // Things like `power -1 0.5` don't have an answer.
+        let y_num = num_where("power", y.clone(), |_| true)?;
+        let p = x.powf(y_num);
+        if p.is_finite() {
+            p.into_eval_result()
+        } else {
+            Err(EvalError::BadArg {
+                proc: "power".to_owned(),
+                arg: y,
+            })
+        }
+    }));
+    env.def_proc(fn_one("exp", |x: f64, _env| x.exp()));
+    env.def_proc(fn_one("ln", |x: Expr, _env| {
+        num_where("ln", x, |x| x > 0.0)?.ln().into_eval_result()
+    }));
+    env.def_proc(fn_one("log10", |x: Expr, _env| {
+        num_where("log10", x, |x| x > 0.0)?
+            .log10()
+            .into_eval_result()
+    }));
+
+    // Like the turtle, the trigonometric functions measure angles in degrees.
+    env.def_proc(fn_one("sin", |x: f64, _env| x.to_radians().sin()));
+    env.def_proc(fn_one("cos", |x: f64, _env| x.to_radians().cos()));
+    env.def_proc(fn_one("tan", |x: f64, _env| x.to_radians().tan()));
+    env.def_proc(fn_one("arctan", |x: f64, _env| x.atan().to_degrees()));
+
+    // `random n` picks a whole number from 0 to n - 1. The numbers come from a seeded generator,
+    // and `rerandom` starts the sequence over again.
+    env.def_proc(fn_one("random", |n: Expr, env| {
+        let n = num_where("random", n, |n| {
+            n >= 1.0 && n.trunc() == n && n <= u64::MAX as f64
+        })?;
+        (env.random(n as u64) as f64).into_eval_result()
+    }));
+    env.def_proc(fn_zero("rerandom", |env| {
+        env.random_state = crate::typ::RANDOM_SEED;
+    }));
 
-    env.def_proc(fn_two("if", |cond: bool, body: Expr, env| {
-        if dbg!(cond) {
-            dbg!(dbg!(body).eval(env))
+    // These follow UCB Logo: the result of `remainder` has the same sign as the dividend,
+    // while the result of `modulo` has the same sign as the divisor.
+    env.def_proc(fn_two("remainder", |x: f64, y: Expr, _env| {
+        let y = divisor("remainder", y)?;
+        (x % y).into_eval_result()
+    }));
+    env.def_proc(fn_two("modulo", |x: f64, y: Expr, _env| {
+        let y = divisor("modulo", y)?;
+        let r = x % y;
+        let m = if r != 0.0 && (r < 0.0) != (y < 0.0) {
+            r + y
         } else {
-            Ok(None)
+            r
+        };
+        m.into_eval_result()
+    }));
+    // `int` truncates towards zero, and `round` rounds halfway cases away from zero.
+    env.def_proc(fn_one("int", |x: f64, _env| x.trunc()));
+    env.def_proc(fn_one("round", |x: f64, _env| x.round()));
+
+    // The list procedures work on words too, and `first "hello` is `"h`. None of them change
+    // their inputs: `butfirst` and friends make new lists.
+    for name in ["first", "last", "butfirst", "bf", "butlast", "bl"] {
+        env.def_proc(fn_one(name, move |arg: Expr, _env| -> EvalResult {
+            let things = Things::new(name, arg.clone())?;
+            let n = things.len();
+            if n == 0 {
+                return Err(EvalError::BadArg {
+                    proc: name.to_owned(),
+                    arg,
+                });
+            }
+            Ok(Some(match name {
+                "first" => things.item(0),
+                "last" => things.item(n - 1),
+                "butfirst" | "bf" => things.slice(1..n),
+                _ => things.slice(0..n - 1),
+            }))
+        }));
+    }
+    // `item 1 :list` is the first thing in the list.
+    env.def_proc(fn_two("item", |i: Expr, arg: Expr, _env| -> EvalResult {
+        let things = Things::new("item", arg)?;
+        let i = num_where("item", i, |i| {
+            i >= 1.0 && i.trunc() == i && i <= things.len() as f64
+        })?;
+        Ok(Some(things.item(i as usize - 1)))
+    }));
+    env.def_proc(fn_one("count", |arg: Expr, _env| -> EvalResult {
+        (Things::new("count", arg)?.len() as f64).into_eval_result()
+    }));
+    // `list` makes a list of its two inputs, while `sentence` joins them together into one list
+    // (so `sentence [1 2] 3` is `[1 2 3]`, but `list [1 2] 3` is `[[1 2] 3]`).
+    env.def_proc(fn_two("list", |a: Expr, b: Expr, _env| -> EvalResult {
+        Ok(Some(unspanned(ExprKind::List(vec![a, b].into()))))
+    }));
+    for name in ["sentence", "se"] {
+        env.def_proc(fn_two(name, |a: Expr, b: Expr, _env| -> EvalResult {
+            let mut parts = sentence_parts(a);
+            parts.extend(sentence_parts(b));
+            Ok(Some(unspanned(ExprKind::List(parts.into()))))
+        }));
+    }
+
+    // `run` evaluates a list right where it is, so a block passed to a procedure sees that
+    // procedure's inputs (and, through them, its caller's variables) like any other code would.
+    env.def_proc(fn_one("run", |block: Expr, env| block.eval(env)));
+    for (name, needs_else) in [("if", false), ("ifelse", true)] {
+        env.def_proc(ProcExpr {
+            inner: Arc::new(If { name, needs_else }),
+        });
+    }
+    // `throw "tag` stops everything up to the nearest `catch "tag [...]`. A `catch "error [...]`
+    // also catches any errors (except for running out of steps, because then we'd never stop,
+    // and for `output` and `stop`, which are on their way to the procedure that they're in).
+    env.def_proc(fn_two("catch", |tag: String, body: Expr, env| {
+        match body.eval(env) {
+            Err(err) => match err.root() {
+                EvalError::Throw { tag: thrown } if *thrown == tag => Ok(None),
+                EvalError::Throw { .. }
+                | EvalError::OutOfSteps
+                | EvalError::Output { .. }
+                | EvalError::Stop => Err(err),
+                _ if tag == "error" => Ok(None),
+                _ => Err(err),
+            },
+            ok => ok,
         }
     }));
+    env.def_proc(fn_one("throw", |tag: String, _env| -> EvalResult {
+        Err(EvalError::Throw { tag })
+    }));
+    // `output` and `stop` finish the procedure that they're in, with or without a value.
+    for name in ["output", "op"] {
+        env.def_proc(fn_one(name, |val: Expr, _env| -> EvalResult {
+            Err(EvalError::Output { val })
+        }));
+    }
+    env.def_proc(fn_zero("stop", |_env| -> EvalResult {
+        Err(EvalError::Stop)
+    }));
     env.def_proc(fn_two("repeat", |count: Expr, body: Expr, env| {
         let ExprKind::Num(count_num) = count.e.clone() else {
                 return Err(EvalError::BadArg { proc: "repeat".to_owned(), arg: count });
@@ -234,11 +685,131 @@ pub fn add_builtins(env: &mut Env) {
                 arg: count,
             });
         }
-        for _ in 0..(count_num as u64) {
-            if let Some(res) = body.eval(env)? {
-                return Err(EvalError::UnusedVal { val: res });
+        env.repcounts.push(0);
+        let mut run = || {
+            for i in 1..=(count_num as u64) {
+                *env.repcounts.last_mut().unwrap() = i;
+                if let Some(res) = body.eval(env)? {
+                    return Err(EvalError::UnusedVal { val: res });
+                }
             }
+            Ok(None)
+        };
+        let ret = run();
+        env.repcounts.pop();
+        ret
+    }));
+    // `for [i 1 10 2] [...]` runs the body with `:i` set to 1, 3, 5, 7 and 9. The step is
+    // optional, and defaults to 1 or -1 depending on which way the loop goes. The loop variable
+    // only exists inside the loop, and it's worked out afresh from the start and the step each
+    // time around, so `make "i ...` in the body doesn't change how many times the loop runs.
+    env.def_proc(fn_two("for", |control: Expr, body: Expr, env| {
+        let bad_control = || EvalError::BadArg {
+            proc: "for".to_owned(),
+            arg: control.clone(),
+        };
+        let ExprKind::List(parts) = &control.e else {
+            return Err(bad_control());
+        };
+        let Some((
+            Expr {
+                e: ExprKind::Word(var),
+                ..
+            },
+            range,
+        )) = parts.split_first()
+        else {
+            return Err(bad_control());
+        };
+        let mut range = eval_values(range, "for", env)?.into_iter();
+        let (Some(start), Some(end), step, None) =
+            (range.next(), range.next(), range.next(), range.next())
+        else {
+            return Err(bad_control());
+        };
+        let start = num_where("for", start, |_| true)?;
+        let end = num_where("for", end, |_| true)?;
+        let step = match step {
+            Some(step) => num_where("for", step, |s| s != 0.0)?,
+            None if start <= end => 1.0,
+            None => -1.0,
+        };
+        env.scoped(|env| {
+            for k in 0.. {
+                let i = start + k as f64 * step;
+                if (step > 0.0 && i > end) || (step < 0.0 && i < end) {
+                    break;
+                }
+                env.def_var(var, unspanned(ExprKind::Num(i)));
+                if let Some(res) = body.eval(env)? {
+                    return Err(EvalError::UnusedVal { val: res });
+                }
+            }
+            Ok(None)
+        })
+    }));
+    // `while [:x < 10] [...]` runs the body for as long as the condition is true, checking it
+    // before each time around.
+    env.def_proc(fn_two("while", |cond: Expr, body: Expr, env| loop {
+        let val = cond.eval(env)?;
+        match val.as_ref().map(|v| &v.e) {
+            Some(ExprKind::Bool(true)) => {}
+            Some(ExprKind::Bool(false)) => return Ok(None),
+            _ => {
+                return Err(EvalError::BadArg {
+                    proc: "while".to_owned(),
+                    arg: val.unwrap_or(cond),
+                })
+            }
+        }
+        if let Some(res) = body.eval(env)? {
+            return Err(EvalError::UnusedVal { val: res });
+        }
+    }));
+    // `repcount` is the iteration number of the innermost `repeat`, or -1 outside of any
+    // `repeat`. Like variables, it's dynamically scoped: a procedure called from inside a
+    // `repeat` sees that loop's count.
+    env.def_proc(fn_zero("repcount", |env| {
+        env.repcounts.last().map_or(-1.0, |&i| i as f64)
+    }));
+
+    // Looking after the workspace, which is mostly for the REPL. `pots` prints the first line
+    // of each procedure that the program has defined, `po` prints the whole of one of them, and
+    // `erall` forgets them all, along with the variables.
+    env.def_proc(fn_zero("pots", |env| {
+        for proc in env.user_procs() {
+            if let Some(user) = proc.as_user() {
+                let _ = writeln!(&mut env.out, "{}", user.title());
+            }
+        }
+    }));
+    env.def_proc(fn_one("po", |name: Expr, env| -> EvalResult {
+        let ExprKind::Word(w) = &name.e else {
+            return Err(EvalError::BadArg {
+                proc: "po".to_owned(),
+                arg: name,
+            });
+        };
+        let Some(proc) = env.lookup_proc(w) else {
+            return Err(EvalError::UnknownProc {
+                suggestion: env.suggest_proc(w),
+                ident: name,
+            });
+        };
+        // Built-in procedures have no source to print.
+        if proc.as_user().is_none() {
+            return Err(EvalError::BadArg {
+                proc: "po".to_owned(),
+                arg: name,
+            });
+        }
+        let source = Expr {
+            e: ExprKind::DefProc(proc),
+            span: name.span,
         }
+        .to_source();
+        let _ = writeln!(&mut env.out, "{source}");
         Ok(None)
     }));
+    env.def_proc(fn_zero("erall", |env| env.erase_all()));
 }
@@ -0,0 +1,264 @@
+//! Running Logo a line at a time, for trying things out and drawing live.
+//!
+//! A [`Repl`] keeps one [`Env`] for the whole session, so procedures and variables from one line
+//! are still there on the next. Lines that don't finish what they start (like a `to` without its
+//! `end`, or an unclosed `[`) wait for the lines after them.
+
+use std::io::Write;
+
+use crate::{
+    i18n::Messages,
+    parse::{self, ParseError},
+    typ::EvalResult,
+    Env, TurtleCmd,
+};
+
+/// What happened to a line that was entered.
+#[derive(Debug)]
+pub enum Entry {
+    /// The line didn't finish what it started, so it's waiting for more.
+    Incomplete,
+    /// The program so far couldn't be parsed, and got thrown away.
+    ParseError(String),
+    /// The program so far got run. `turtle` is what it drew, which is there even if it failed
+    /// part way through.
+    Ran {
+        result: EvalResult,
+        turtle: Vec<TurtleCmd>,
+    },
+}
+
+/// A Logo session that gets its program a line at a time.
+pub struct Repl {
+    pub env: Env,
+    // The lines that are waiting for more before they can be run.
+    pending: String,
+}
+
+impl Repl {
+    pub fn new(env: Env) -> Repl {
+        Repl {
+            env,
+            pending: String::new(),
+        }
+    }
+
+    /// What to show when asking for a line: `"? "` for a new program, or `"> "` when the ones
+    /// before are waiting for more.
+    pub fn prompt(&self) -> &'static str {
+        if self.pending.is_empty() {
+            "? "
+        } else {
+            "> "
+        }
+    }
+
+    /// Enters a line, running it (along with any lines that were waiting for it) if it
+    /// finishes a program.
+    pub fn enter(&mut self, line: &str) -> Entry {
+        self.pending.push_str(line.trim_end_matches(['\r', '\n']));
+        self.pending.push('\n');
+        if self.pending.trim().is_empty() {
+            self.pending.clear();
+            return Entry::Ran {
+                result: Ok(None),
+                turtle: Vec::new(),
+            };
+        }
+        // A `~` at the end of a line continues it onto the next one.
+        if self.pending.trim_end().ends_with('~') {
+            return Entry::Incomplete;
+        }
+        let program = match parse::program(self.pending.as_str().into()) {
+            Ok((_, program)) => program,
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) if is_unfinished(&e) => {
+                return Entry::Incomplete;
+            }
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+                let msg = e.to_string();
+                self.pending.clear();
+                return Entry::ParseError(msg);
+            }
+            // The parsers are all complete, so they never ask for more input.
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        };
+        self.pending.clear();
+        let drawn = self.env.turtle.len();
+        let result = program.eval(&mut self.env);
+        Entry::Ran {
+            result,
+            turtle: self.env.turtle[drawn..].to_vec(),
+        }
+    }
+
+    /// Reads lines from stdin until there aren't any more, printing what each program outputs
+    /// (and any errors, in the language from `LANG`). Whatever each program draws gets passed
+    /// to `drew`, along with the environment after it has run; if that fails, so does this.
+    pub fn run_stdin<E>(
+        &mut self,
+        mut drew: impl FnMut(&Env, &[TurtleCmd]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let messages = Messages::new(&std::env::var("LANG").unwrap_or_default());
+        loop {
+            print!("{}", self.prompt());
+            let _ = std::io::stdout().flush();
+            let mut line = String::new();
+            match std::io::stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => return Ok(()),
+                Ok(_) => {}
+            }
+            match self.enter(&line) {
+                Entry::Incomplete => {}
+                Entry::ParseError(msg) => println!("{msg}"),
+                Entry::Ran { result, turtle } => {
+                    match result {
+                        Ok(None) => {}
+                        Ok(Some(val)) => println!("{val}"),
+                        Err(e) => println!("{}", messages.eval_error(&e)),
+                    }
+                    if !turtle.is_empty() {
+                        drew(&self.env, &turtle)?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Whether a parse error is only because the input stopped too soon, which looks like an error
+// with nothing but whitespace after it.
+fn is_unfinished(err: &ParseError) -> bool {
+    let mut innermost = err;
+    while let Some(cause) = &innermost.cause {
+        innermost = cause;
+    }
+    innermost.input.fragment().trim().is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EvalError;
+
+    fn ran(entry: Entry) -> (EvalResult, Vec<TurtleCmd>) {
+        match entry {
+            Entry::Ran { result, turtle } => (result, turtle),
+            e => panic!("expected it to run, got {e:?}"),
+        }
+    }
+
+    #[test]
+    fn remembers_things() {
+        let mut repl = Repl::new(Env::default());
+        let (result, turtle) = ran(repl.enter("make \"size 10"));
+        assert!(matches!(result, Ok(None)));
+        assert!(turtle.is_empty());
+
+        let (_, turtle) = ran(repl.enter("fd :size rt 90\n"));
+        assert_eq!(turtle, [TurtleCmd::Forward(10.0), TurtleCmd::Right(90.0)]);
+        // Only what this line drew.
+        let (_, turtle) = ran(repl.enter("fd 1"));
+        assert_eq!(turtle, [TurtleCmd::Forward(1.0)]);
+
+        let (result, _) = ran(repl.enter("sum :size 1"));
+        assert_eq!(result.unwrap().unwrap().to_string(), "11");
+    }
+
+    #[test]
+    fn waits_for_the_end() {
+        let mut repl = Repl::new(Env::default());
+        assert!(matches!(repl.enter("to square :n"), Entry::Incomplete));
+        assert_eq!(repl.prompt(), "> ");
+        assert!(matches!(repl.enter("  repeat 4 [fd :n"), Entry::Incomplete));
+        assert!(matches!(repl.enter("  rt 90]"), Entry::Incomplete));
+        let (result, turtle) = ran(repl.enter("end"));
+        assert!(result.is_ok());
+        assert!(turtle.is_empty());
+        assert_eq!(repl.prompt(), "? ");
+
+        assert!(matches!(repl.enter("square ~"), Entry::Incomplete));
+        let (_, turtle) = ran(repl.enter("5"));
+        assert_eq!(turtle.len(), 8);
+    }
+
+    // Somewhere for `print` and friends to write to, that the test can read afterwards.
+    #[derive(Clone, Default)]
+    struct Printed(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Printed {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Printed {
+        fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+        }
+    }
+
+    #[test]
+    fn workspace() {
+        let printed = Printed::default();
+        let mut repl = Repl::new(Env {
+            out: Box::new(printed.clone()),
+            ..Env::default()
+        });
+        ran(repl.enter("to square :n repeat 4 [fd :n rt 90] end"))
+            .0
+            .unwrap();
+        ran(repl.enter("to fd :n print :n end")).0.unwrap();
+        ran(repl.enter("make \"size 3")).0.unwrap();
+
+        ran(repl.enter("pots")).0.unwrap();
+        assert_eq!(printed.take(), "to fd :n\nto square :n\n");
+        ran(repl.enter("po \"square")).0.unwrap();
+        assert_eq!(
+            printed.take(),
+            "to square :n\nrepeat 4 [fd :n rt 90]\nend\n"
+        );
+        // Built-in procedures don't have anything to print.
+        let (result, _) = ran(repl.enter("po \"rt"));
+        let err = result.unwrap_err();
+        assert!(matches!(err.root(), EvalError::BadArg { .. }));
+        let (result, _) = ran(repl.enter("po \"sqare"));
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err.root(),
+            EvalError::UnknownProc { suggestion: Some(s), .. } if s == "square"
+        ));
+
+        // `fd` goes back to drawing.
+        ran(repl.enter("erall")).0.unwrap();
+        ran(repl.enter("pots")).0.unwrap();
+        assert_eq!(printed.take(), "");
+        let (result, turtle) = ran(repl.enter("fd 1 square 1"));
+        assert!(matches!(
+            result.unwrap_err().root(),
+            EvalError::UnknownProc { .. }
+        ));
+        assert_eq!(turtle, [TurtleCmd::Forward(1.0)]);
+        let (result, _) = ran(repl.enter("print :size"));
+        assert!(matches!(
+            result.unwrap_err().root(),
+            EvalError::UnknownVal { .. }
+        ));
+    }
+
+    #[test]
+    fn carries_on_after_errors() {
+        let mut repl = Repl::new(Env::default());
+        assert!(matches!(repl.enter("fd 1 ]"), Entry::ParseError(_)));
+        let (result, turtle) = ran(repl.enter("fd 2 froward 3"));
+        assert!(result.is_err());
+        assert_eq!(turtle, [TurtleCmd::Forward(2.0)]);
+        let (result, turtle) = ran(repl.enter("fd 3"));
+        assert!(result.is_ok());
+        assert_eq!(turtle, [TurtleCmd::Forward(3.0)]);
+        assert!(matches!(repl.enter(""), Entry::Ran { .. }));
+    }
+}
@@ -1,4 +1,10 @@
-use std::{collections::HashMap, io::Write, rc::Rc};
+use std::{
+    io::Write,
+    sync::{mpsc::SyncSender, Arc},
+};
+
+use rustc_hash::FxHashMap;
+use smallvec::SmallVec;
 
 use crate::proc::Proc;
 
@@ -12,7 +18,7 @@ pub struct Span {
 
 #[derive(Clone)]
 pub struct ProcExpr {
-    pub inner: Rc<dyn Proc>,
+    pub inner: Arc<dyn Proc>,
 }
 
 impl std::fmt::Debug for ProcExpr {
@@ -23,7 +29,7 @@ impl std::fmt::Debug for ProcExpr {
 
 impl PartialEq for ProcExpr {
     fn eq(&self, other: &Self) -> bool {
-        Rc::ptr_eq(&self.inner, &other.inner)
+        Arc::ptr_eq(&self.inner, &other.inner)
     }
 }
 
@@ -32,13 +38,21 @@ impl ProcExpr {
         self.inner.eval(args, env)
     }
 
-    fn num_args(&self) -> usize {
+    pub(crate) fn num_args(&self) -> usize {
         self.inner.num_args()
     }
 
-    fn name(&self) -> &str {
+    fn num_optional_args(&self) -> usize {
+        self.inner.num_optional_args()
+    }
+
+    pub(crate) fn name(&self) -> &str {
         self.inner.name()
     }
+
+    pub fn as_user(&self) -> Option<&crate::proc::UserProc> {
+        self.inner.as_user()
+    }
 }
 
 impl Span {
@@ -91,19 +105,21 @@ impl Op {
         }
     }
 
-    pub fn eval(&self, lhs: &Expr, rhs: &Expr) -> Result<Expr, EvalError> {
+    /// Applies the operator to two numbers. `=` allows for rounding errors, as set by
+    /// [`Env::eq_tolerance`].
+    pub fn eval(&self, lhs: &Expr, rhs: &Expr, env: &Env) -> Result<Expr, EvalError> {
         let ExprKind::Num(l) = lhs.e else {
-            return Err(EvalError::BadOpArg { op: self.clone(), arg: lhs.clone() });
+            return Err(EvalError::BadOpArg { op: *self, arg: lhs.clone() });
         };
         let ExprKind::Num(r) = rhs.e else {
-            return Err(EvalError::BadOpArg { op: self.clone(), arg: rhs.clone() });
+            return Err(EvalError::BadOpArg { op: *self, arg: rhs.clone() });
         };
         let e = match self {
             Op::Add => ExprKind::Num(l + r),
             Op::Sub => ExprKind::Num(l - r),
             Op::Mul => ExprKind::Num(l * r),
             Op::Div => ExprKind::Num(l / r), // TODO: check for zero
-            Op::Eq => ExprKind::Bool(l == r),
+            Op::Eq => ExprKind::Bool(nearly_equal(l, r, env.eq_tolerance)),
             Op::Lt => ExprKind::Bool(l < r),
             Op::Gt => ExprKind::Bool(l > r),
         };
@@ -112,6 +128,15 @@ impl Op {
     }
 }
 
+/// Are `x` and `y` the same, apart from rounding errors of up to `tolerance`?
+///
+/// Turtle maths often leaves numbers a tiny bit off (like `0.1 + 0.2`, which is just over
+/// `0.3`), and someone learning to program shouldn't have to know that. The tolerance is
+/// relative for numbers bigger than 1, because their rounding errors are bigger too.
+pub fn nearly_equal(x: f64, y: f64, tolerance: f64) -> bool {
+    x == y || (x - y).abs() <= tolerance * x.abs().max(y.abs()).max(1.0)
+}
+
 impl TryFrom<char> for Op {
     type Error = ();
 
@@ -138,7 +163,9 @@ pub enum ExprKind {
     Proc(ProcExpr),
     DefProc(ProcExpr),
     Op(Op),
-    List(Vec<Expr>),
+    /// A list. Lists never change once they've been made, so they're shared instead of being
+    /// copied every time that they're evaluated or passed around.
+    List(Arc<[Expr]>),
     Quote(Box<Expr>),
 }
 
@@ -183,28 +210,109 @@ impl TryFrom<Expr> for String {
 
 #[derive(Copy, Debug, Clone, PartialEq)]
 pub enum TurtleCmd {
-    Arc { degrees: f64, radius: f64 },
+    Arc {
+        degrees: f64,
+        radius: f64,
+    },
     Forward(f64),
     Back(f64),
     Right(f64),
     Left(f64),
     PenUp,
     PenDown,
+    /// Writes a letter, `height` tall, and moves on to where the next one goes.
+    Glyph {
+        c: char,
+        height: f64,
+    },
+    /// Moves straight to a position (drawing a line, if the pen is down).
+    SetPos {
+        x: f64,
+        y: f64,
+    },
+    /// Turns to face a direction, in degrees clockwise from straight up.
+    SetHeading(f64),
 }
 
 pub struct Env {
     // Invariant: this is always non-empty.
     pub stack: Vec<Frame>,
     pub turtle: Vec<TurtleCmd>,
-    pub out: Box<dyn Write>,
+    pub out: Box<dyn Write + Send>,
+    /// If set, the number of expressions we're still allowed to evaluate. When it runs out,
+    /// evaluation fails with [`EvalError::OutOfSteps`].
+    pub step_budget: Option<u64>,
+    /// The state of the random number generator behind `random`. It starts out the same every
+    /// time, so a program draws the same picture every time it runs.
+    pub random_state: u64,
+    /// The iteration numbers (starting from 1) of the `repeat` loops that we're in, innermost
+    /// last.
+    pub repcounts: Vec<u64>,
+    /// How tall the capital letters are when `text` writes something.
+    pub text_height: f64,
+    /// Where the turtle is, in the same coordinates as [`TurtleCmd::SetPos`]. It starts at
+    /// the origin.
+    pub turtle_pos: (f64, f64),
+    /// The direction the turtle is facing, in degrees clockwise from straight up (like in
+    /// [`TurtleCmd::SetHeading`]).
+    pub turtle_heading: f64,
+    /// If set, turtle commands get sent here as soon as they're made, instead of being collected
+    /// in `turtle` (see [`Env::run_streaming`]).
+    pub turtle_stream: Option<SyncSender<Result<TurtleCmd, EvalError>>>,
+    /// How far apart two numbers can be for `=` to say that they're equal (see
+    /// [`nearly_equal`]). Set it to zero to make `=` exact; `exactequalp` is always exact.
+    pub eq_tolerance: f64,
+    /// Where `readword` and `readnumber` get their answers: this gets called for each line that
+    /// the program wants to read, and it returns `None` if there's nothing more to read. If it
+    /// isn't set (which is the default, so that a program can't get stuck waiting for someone
+    /// who isn't there), reading fails with [`EvalError::NoInput`].
+    pub input: Option<Box<dyn FnMut() -> Option<String> + Send>>,
+    /// The width and height of the area that the turtle draws in, which is centered on where
+    /// the turtle starts. Whoever runs the program should set this to the size of their
+    /// drawing area, so that programs can fit themselves to it with `canvaswidth` and
+    /// `canvasheight`.
+    pub canvas_size: (f64, f64),
 }
 
+/// How many turtle commands [`Env::run_streaming`] gets ahead of whoever is reading them.
+const STREAM_CAPACITY: usize = 1024;
+
+/// How many procedure calls can be running inside each other before evaluation fails with
+/// [`EvalError::TooDeep`], instead of overflowing the stack.
+pub const MAX_CALL_DEPTH: usize = 1000;
+
+/// The stack size of the thread that [`Env::run_streaming`] runs programs on. Each procedure
+/// call takes a few kilobytes of stack (more in debug builds), so this leaves room for
+/// [`MAX_CALL_DEPTH`] of them.
+const STREAM_STACK_SIZE: usize = 64 << 20;
+
+/// The initial value of [`Env::random_state`], which `rerandom` also goes back to.
+pub const RANDOM_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+/// The initial value of [`Env::eq_tolerance`]: big enough to hide the rounding errors from
+/// adding up a few decimals, and small enough that nobody would notice it otherwise.
+pub const DEFAULT_EQ_TOLERANCE: f64 = 1e-9;
+
+/// The initial value of [`Env::canvas_size`]: the area that a brachiograph usually draws Logo
+/// programs in.
+pub const DEFAULT_CANVAS_SIZE: (f64, f64) = (16.0, 8.0);
+
 impl Default for Env {
     fn default() -> Self {
         let mut ret = Env {
             stack: vec![Frame::default()],
             turtle: Vec::new(),
             out: Box::new(std::io::stdout()),
+            step_budget: None,
+            random_state: RANDOM_SEED,
+            repcounts: Vec::new(),
+            text_height: 1.0,
+            turtle_pos: (0.0, 0.0),
+            turtle_heading: 0.0,
+            turtle_stream: None,
+            eq_tolerance: DEFAULT_EQ_TOLERANCE,
+            input: None,
+            canvas_size: DEFAULT_CANVAS_SIZE,
         };
         crate::proc::add_builtins(&mut ret);
         ret
@@ -213,20 +321,63 @@ impl Default for Env {
 
 #[derive(Default)]
 pub struct Frame {
-    vars: HashMap<String, Expr>,
-    procs: HashMap<String, ProcExpr>,
+    vars: FxHashMap<String, Expr>,
+    procs: FxHashMap<String, ProcExpr>,
 }
 
 impl Env {
+    // Lookups start from the innermost frame, so that (for example) a recursive procedure
+    // sees its own inputs rather than its caller's. Most frames don't define anything, and
+    // skipping them saves hashing the name over and over in deep recursions.
     pub fn lookup_proc(&self, name: &str) -> Option<ProcExpr> {
         self.stack
             .iter()
+            .rev()
+            .filter(|frame| !frame.procs.is_empty())
             .find_map(|frame| frame.procs.get(name).cloned())
     }
 
+    /// The name of the procedure that looks most like `name`, if any of them look enough like
+    /// it to be worth suggesting (for when someone types `forwrd 100`).
+    pub fn suggest_proc(&self, name: &str) -> Option<String> {
+        // Allow one typo in short names, and two in longer ones.
+        let max_distance = if name.chars().count() < 6 { 1 } else { 2 };
+        self.stack
+            .iter()
+            .flat_map(|frame| frame.procs.keys())
+            .map(|known| (edit_distance(name, known), known))
+            .filter(|(dist, _)| *dist <= max_distance)
+            .min()
+            .map(|(_, known)| known.clone())
+    }
+
+    /// The procedures that the program has defined (as opposed to the built-in ones) and that
+    /// are visible from here, sorted by name.
+    pub fn user_procs(&self) -> Vec<ProcExpr> {
+        // Later frames shadow earlier ones.
+        let mut procs = std::collections::BTreeMap::new();
+        for frame in &self.stack {
+            procs.extend(frame.procs.iter());
+        }
+        procs
+            .into_values()
+            .filter(|p| p.as_user().is_some())
+            .cloned()
+            .collect()
+    }
+
+    /// Forgets all of the procedures and variables that the program has defined at the top
+    /// level, like `erall`. Built-in procedures that got redefined go back to how they were.
+    pub fn erase_all(&mut self) {
+        let mut fresh = Env::default();
+        self.stack[0] = fresh.stack.swap_remove(0);
+    }
+
     pub fn lookup_var(&self, name: &str) -> Option<Expr> {
         self.stack
             .iter()
+            .rev()
+            .filter(|frame| !frame.vars.is_empty())
             .find_map(|frame| frame.vars.get(name).cloned())
     }
 
@@ -237,6 +388,7 @@ impl Env {
         res
     }
 
+    /// Defines a variable in the innermost frame, shadowing any variables with the same name.
     pub fn def_var(&mut self, name: &str, val: Expr) {
         self.stack
             .last_mut()
@@ -245,6 +397,22 @@ impl Env {
             .insert(name.to_owned(), val);
     }
 
+    /// Changes the value of a variable, like `make` does in UCB Logo.
+    ///
+    /// If the variable is defined in any frame, this changes the innermost definition. Otherwise,
+    /// it defines a new variable in the outermost frame.
+    pub fn set_var(&mut self, name: &str, val: Expr) {
+        let frame = match self
+            .stack
+            .iter_mut()
+            .rposition(|frame| frame.vars.contains_key(name))
+        {
+            Some(idx) => &mut self.stack[idx],
+            None => &mut self.stack[0],
+        };
+        frame.vars.insert(name.to_owned(), val);
+    }
+
     pub fn def_proc(&mut self, proc: ProcExpr) {
         self.stack
             .last_mut()
@@ -253,8 +421,100 @@ impl Env {
             .insert(proc.name().to_owned(), proc);
     }
 
+    /// Returns a pseudo-random number between zero (inclusive) and `n` (exclusive).
+    pub fn random(&mut self, n: u64) -> u64 {
+        // This is xorshift64*, which is plenty random enough for drawing pictures.
+        let mut x = self.random_state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.random_state = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d) % n
+    }
+
+    /// Reads a line of input for the procedure `proc`, without the line ending.
+    pub fn read_line(&mut self, proc: &str) -> Result<String, EvalError> {
+        let no_input = || EvalError::NoInput {
+            proc: proc.to_owned(),
+        };
+        let input = self.input.as_mut().ok_or_else(no_input)?;
+        let mut line = input().ok_or_else(no_input)?;
+        let len = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(len);
+        Ok(line)
+    }
+
     pub fn turtle_do(&mut self, cmd: TurtleCmd) {
-        self.turtle.push(cmd);
+        let (x, y) = &mut self.turtle_pos;
+        let heading = &mut self.turtle_heading;
+        match cmd {
+            TurtleCmd::Forward(d) => {
+                let (sin, cos) = sin_cos_degrees(*heading);
+                (*x, *y) = (*x + d * sin, *y + d * cos);
+            }
+            TurtleCmd::Back(d) => {
+                let (sin, cos) = sin_cos_degrees(*heading);
+                (*x, *y) = (*x - d * sin, *y - d * cos);
+            }
+            TurtleCmd::Right(a) => *heading = wrap_degrees(*heading + a),
+            TurtleCmd::Left(a) => *heading = wrap_degrees(*heading - a),
+            // The built-in font puts letters as far apart as they are tall, and writes them
+            // off to the turtle's right.
+            TurtleCmd::Glyph { height, .. } => {
+                let (sin, cos) = sin_cos_degrees(*heading);
+                (*x, *y) = (*x + height * cos, *y - height * sin);
+            }
+            TurtleCmd::SetPos { x: new_x, y: new_y } => (*x, *y) = (new_x, new_y),
+            TurtleCmd::SetHeading(h) => *heading = wrap_degrees(h),
+            TurtleCmd::Arc { .. } | TurtleCmd::PenUp | TurtleCmd::PenDown => {}
+        }
+        match &self.turtle_stream {
+            Some(tx) => {
+                if tx.send(Ok(cmd)).is_err() {
+                    // Nobody wants the rest of the drawing, so stop as soon as we can.
+                    self.step_budget = Some(0);
+                }
+            }
+            None => self.turtle.push(cmd),
+        }
+    }
+
+    /// Runs a program on another thread, handing over each turtle command as soon as it's made.
+    ///
+    /// This is for starting to draw before a slow program has finished. If the program fails,
+    /// the error comes after the commands that it made before failing. Dropping the iterator
+    /// stops the program.
+    ///
+    /// The thread gets a big enough stack for programs that recurse as deeply as
+    /// [`MAX_CALL_DEPTH`] allows.
+    pub fn run_streaming(
+        mut self,
+        program: Expr,
+    ) -> impl Iterator<Item = Result<TurtleCmd, EvalError>> {
+        let (tx, rx) = std::sync::mpsc::sync_channel(STREAM_CAPACITY);
+        self.turtle_stream = Some(tx.clone());
+        std::thread::Builder::new()
+            .stack_size(STREAM_STACK_SIZE)
+            .spawn(move || {
+                if let Err(e) = program.eval(&mut self) {
+                    // If this fails then nobody is listening, which is probably why we stopped.
+                    let _ = tx.send(Err(e));
+                }
+            })
+            // `std::thread::spawn` would panic here too.
+            .expect("failed to spawn a thread");
+        rx.into_iter()
+    }
+
+    fn take_step(&mut self) -> Result<(), EvalError> {
+        match &mut self.step_budget {
+            Some(0) => Err(EvalError::OutOfSteps),
+            Some(n) => {
+                *n -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
     }
 }
 
@@ -283,6 +543,72 @@ impl std::fmt::Display for Expr {
     }
 }
 
+impl Expr {
+    /// Prints this expression as Logo source code.
+    ///
+    /// Parsing the output gives back the same expression (except for the spans), as long as the
+    /// expression is something that the parser could have produced. In particular, there's no
+    /// syntax for booleans or for procedures that have already been looked up.
+    pub fn to_source(&self) -> String {
+        let mut ret = String::new();
+        self.write_source(&mut ret);
+        ret
+    }
+
+    fn write_source(&self, out: &mut String) {
+        fn write_bare(list: &[Expr], out: &mut String) {
+            for (i, e) in list.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                e.write_source(out);
+            }
+        }
+
+        match &self.e {
+            ExprKind::Num(x) => *out += &x.to_string(),
+            ExprKind::Bool(x) => *out += &x.to_string(),
+            ExprKind::Var(s) => {
+                out.push(':');
+                *out += s;
+            }
+            ExprKind::Word(w) => *out += w,
+            ExprKind::Proc(p) => *out += p.name(),
+            ExprKind::DefProc(p) => match p.as_user() {
+                Some(user) => {
+                    *out += &user.title();
+                    // The body goes on its own line, because otherwise a body starting
+                    // with a variable would look like another parameter.
+                    out.push('\n');
+                    match &user.body.e {
+                        ExprKind::List(body) => write_bare(body, out),
+                        _ => user.body.write_source(out),
+                    }
+                    *out += "\nend";
+                }
+                None => *out += p.name(),
+            },
+            ExprKind::Op(op) => *out += op.name(),
+            ExprKind::List(list) => {
+                out.push('(');
+                write_bare(list, out);
+                out.push(')');
+            }
+            ExprKind::Quote(e) => match &e.e {
+                ExprKind::List(list) => {
+                    out.push('[');
+                    write_bare(list, out);
+                    out.push(']');
+                }
+                _ => {
+                    out.push('"');
+                    e.write_source(out);
+                }
+            },
+        }
+    }
+}
+
 // TODO: improve the context of errors. Maybe have them chain? So for example a BadArg error will
 // capture the arg that was bad, but the one level higher we'll also capture the procedure whose evaluation
 // complained about the arg...
@@ -296,8 +622,12 @@ pub enum EvalError {
     UnusedVal { val: Expr },
     #[error("{ident} has no value")]
     UnknownVal { ident: Expr },
-    #[error("I don't know how to {ident}")]
-    UnknownProc { ident: Expr },
+    #[error("I don't know how to {ident}{}", did_you_mean(.suggestion))]
+    UnknownProc {
+        ident: Expr,
+        /// The name of a procedure that we do know about, and that looks a lot like `ident`.
+        suggestion: Option<String>,
+    },
     #[error("didn't output to {proc}")]
     NoOutputTo { proc: String },
     // TODO: location info
@@ -311,17 +641,80 @@ pub enum EvalError {
     // TODO: How does ucblogo handle empty lists?
     #[error("I can't eval an empty list")]
     EmptyList,
+    #[error("I gave up because this was taking too long")]
+    OutOfSteps,
+    /// More than [`MAX_CALL_DEPTH`] procedure calls were running inside each other.
+    #[error("I gave up because too many procedures were running inside each other")]
+    TooDeep,
+    /// Thrown by `throw`, and caught by a `catch` with the same tag.
+    #[error("Can't find catch tag for {tag}")]
+    Throw { tag: String },
+    /// Thrown by `output`, and caught by the procedure that it's in.
+    #[error("Can only output {val} from inside a procedure")]
+    Output { val: Expr },
+    /// Thrown by `stop`, and caught by the procedure that it's in.
+    #[error("Can only stop from inside a procedure")]
+    Stop,
+    /// A program tried to read some input, but there was none (see [`Env::input`]).
+    #[error("{proc} has nothing to read")]
+    NoInput { proc: String },
+}
+
+fn did_you_mean(suggestion: &Option<String>) -> String {
+    match suggestion {
+        Some(s) => format!(" (did you mean {s}?)"),
+        None => String::new(),
+    }
+}
+
+impl EvalError {
+    /// The error that started it all, skipping over any backtraces.
+    pub fn root(&self) -> &EvalError {
+        match self {
+            EvalError::Backtrace { err, .. } => err.root(),
+            err => err,
+        }
+    }
+
+    /// The part of the program that caused the error, if we know.
+    ///
+    /// For errors that happened inside a procedure call, this is the innermost part that we
+    /// know about.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            EvalError::NotEnoughInputs { args, .. } => {
+                args.iter().map(|a| a.span).reduce(|a, b| a.union(b))
+            }
+            EvalError::MissingOpInput { op: e }
+            | EvalError::UnusedVal { val: e }
+            | EvalError::UnknownVal { ident: e }
+            | EvalError::UnknownProc { ident: e, .. }
+            | EvalError::BadArg { arg: e, .. }
+            | EvalError::BadOpArg { arg: e, .. } => Some(e.span),
+            EvalError::Backtrace { err, proc } => err.span().or(Some(proc.span)),
+            EvalError::NoOutputTo { .. }
+            | EvalError::EmptyList
+            | EvalError::OutOfSteps
+            | EvalError::TooDeep
+            | EvalError::Throw { .. }
+            | EvalError::Output { .. }
+            | EvalError::Stop
+            | EvalError::NoInput { .. } => None,
+        }
+    }
 }
 
 impl Expr {
     pub fn eval(&self, env: &mut Env) -> Result<Option<Expr>, EvalError> {
+        env.take_step()?;
         let e = match &self.e {
-            ExprKind::Num(_) => Some(self.e.clone()),
-            ExprKind::Bool(_) => Some(self.e.clone()),
+            ExprKind::Num(x) => Some(ExprKind::Num(*x)),
+            ExprKind::Bool(x) => Some(ExprKind::Bool(*x)),
             ExprKind::Quote(v) => Some(ExprKind::clone(&v.e)),
             ExprKind::Word(w) => Some(ExprKind::Proc(env.lookup_proc(&w).ok_or_else(|| {
                 EvalError::UnknownProc {
                     ident: self.clone(),
+                    suggestion: env.suggest_proc(w),
                 }
             })?)),
             ExprKind::Var(w) => Some(
@@ -331,7 +724,7 @@ impl Expr {
                     })?
                     .e,
             ),
-            ExprKind::List(list) => eval_list(list.as_slice(), env)?.map(|ex| ex.e),
+            ExprKind::List(list) => eval_list(list, env)?.map(|ex| ex.e),
             ExprKind::Proc(p) => Err(EvalError::NotEnoughInputs {
                 proc: p.clone(),
                 args: vec![],
@@ -347,6 +740,58 @@ impl Expr {
     }
 }
 
+// An angle in degrees, brought into the range [0, 360). Turns usually stay in range, and checking
+// for that first is much quicker than `rem_euclid`.
+fn wrap_degrees(deg: f64) -> f64 {
+    if (0.0..360.0).contains(&deg) {
+        deg
+    } else {
+        deg.rem_euclid(360.0)
+    }
+}
+
+// The sine and cosine of an angle in degrees, exactly when the angle is a multiple of 90 degrees
+// (so that `fd 3 rt 90 fd 4` ends up at exactly `[4 3]`).
+fn sin_cos_degrees(deg: f64) -> (f64, f64) {
+    let quarters = deg / 90.0;
+    if quarters.trunc() != quarters {
+        return deg.to_radians().sin_cos();
+    }
+    match quarters.rem_euclid(4.0) as u8 {
+        0 => (0.0, 1.0),
+        1 => (1.0, 0.0),
+        2 => (0.0, -1.0),
+        _ => (-1.0, 0.0),
+    }
+}
+
+// The number of single-character insertions, deletions, substitutions, and swaps of neighbors
+// needed to turn `a` into `b`. (This is the "optimal string alignment" distance, which is a bit
+// more forgiving of typos than the Levenshtein distance.)
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // dist[i][j] is the distance between a[..i] and b[..j].
+    let mut dist = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in dist.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    dist[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut d = (dist[i - 1][j] + 1)
+                .min(dist[i][j - 1] + 1)
+                .min(dist[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d = d.min(dist[i - 2][j - 2] + 1);
+            }
+            dist[i][j] = d;
+        }
+    }
+    dist[a.len()][b.len()]
+}
+
 // Operator precedence, with the loosest-binding ones first.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd)]
 pub enum Priority {
@@ -372,7 +817,6 @@ pub enum Priority {
 /// If a function doesn't use up the whole list (like `f` in the example above) but it returns a value, that's an error.
 fn eval_list(mut list: &[Expr], env: &mut Env) -> EvalResult {
     loop {
-        // TODO: break on stop if we're in a procedure
         let (val, rest) = eval_list_once(list, Priority::Stop, env)?;
 
         match (val, rest.is_empty()) {
@@ -388,7 +832,7 @@ fn eval_list(mut list: &[Expr], env: &mut Env) -> EvalResult {
                 ) = rest.first()
                 {
                     let (val, remainder) = eval_list_op(v.clone(), *op, op_expr, &rest[1..], env)?;
-                    if dbg!(remainder.is_empty()) {
+                    if remainder.is_empty() {
                         return Ok(Some(val));
                     } else {
                         return Err(EvalError::UnusedVal { val: v });
@@ -403,6 +847,25 @@ fn eval_list(mut list: &[Expr], env: &mut Env) -> EvalResult {
     }
 }
 
+/// Evaluates each of the expressions in a list, like the `1 :n - 1` in `for [i 1 :n - 1] [...]`.
+///
+/// Every part of the list has to output something; `proc` is who to blame if one doesn't.
+pub(crate) fn eval_values(
+    mut list: &[Expr],
+    proc: &str,
+    env: &mut Env,
+) -> Result<Vec<Expr>, EvalError> {
+    let mut vals = Vec::new();
+    while !list.is_empty() {
+        let (val, rest) = eval_list_once(list, Priority::Stop, env)?;
+        vals.push(val.ok_or_else(|| EvalError::NoOutputTo {
+            proc: proc.to_owned(),
+        })?);
+        list = rest;
+    }
+    Ok(vals)
+}
+
 /// Having already evaluated the left hand side of a binary operator, read the right hand side
 /// from a list and evaluate the operator. Returns the result of evaluating the operator, and also
 /// the remainder of the list.
@@ -418,7 +881,7 @@ fn eval_list_op<'a>(
         let rhs = rhs.ok_or_else(|| EvalError::MissingOpInput {
             op: op_expr.clone(),
         })?;
-        lhs = op.eval(&lhs, &rhs)?;
+        lhs = op.eval(&lhs, &rhs, env)?;
 
         if let Some(Expr {
             e: ExprKind::Op(next_op),
@@ -459,12 +922,13 @@ fn eval_list_once<'a>(
                 ..
             },
         ) => {
-            let mut args = Vec::with_capacity(p.num_args());
+            // Most procedures take just a few inputs, so this usually avoids allocating.
+            let mut args = SmallVec::<[Expr; 4]>::new();
             while args.len() < p.num_args() {
                 if list.is_empty() {
                     return Err(EvalError::NotEnoughInputs {
                         proc: p.clone(),
-                        args,
+                        args: args.into_vec(),
                     });
                 }
                 let (arg, remainder) = eval_list_once(list, priority, env)?;
@@ -474,33 +938,60 @@ fn eval_list_once<'a>(
                 })?;
                 args.push(arg);
             }
-            Ok((
-                p.eval(&args, env).map_err(|err| EvalError::Backtrace {
-                    proc: proc_expr.clone(),
-                    err: Box::new(err),
-                })?,
-                list,
-            ))
-        }
-        Some(x) => {
-            if let Some(
-                op_expr @ Expr {
-                    e: ExprKind::Op(op),
-                    ..
-                },
-            ) = list.first()
-            {
-                if op.priority() > priority {
-                    let (val, remainder) = eval_list_op(x, *op, op_expr, &list[1..], env)?;
-                    Ok((Some(val), remainder))
-                } else {
-                    Ok((Some(x), list))
+            // Optional inputs have to be bracketed lists, which can't be commands by themselves.
+            while args.len() < p.num_args() + p.num_optional_args() {
+                let Some(
+                    arg @ Expr {
+                        e: ExprKind::Quote(quoted),
+                        ..
+                    },
+                ) = list.first()
+                else {
+                    break;
+                };
+                if !matches!(quoted.e, ExprKind::List(_)) {
+                    break;
                 }
-            } else {
-                Ok((Some(x), list))
+                // Unwrap: a quoted list always evaluates to the list.
+                args.push(arg.eval(env)?.unwrap());
+                list = &list[1..];
             }
+            let val = p.eval(&args, env).map_err(|err| EvalError::Backtrace {
+                proc: proc_expr.clone(),
+                err: Box::new(err),
+            })?;
+            // A procedure's last input will already have grabbed any operator after it, but a
+            // procedure with no inputs (like `repcount * 2`) won't have.
+            match val {
+                Some(x) => eval_trailing_op(x, list, priority, env),
+                None => Ok((None, list)),
+            }
+        }
+        Some(x) => eval_trailing_op(x, list, priority, env),
+    }
+}
+
+/// Having evaluated `x` from the start of a list, evaluates the binary operator that comes
+/// next in the list (if there is one, and if it has priority higher than `priority`).
+fn eval_trailing_op<'a>(
+    x: Expr,
+    list: &'a [Expr],
+    priority: Priority,
+    env: &mut Env,
+) -> Result<(Option<Expr>, &'a [Expr]), EvalError> {
+    if let Some(
+        op_expr @ Expr {
+            e: ExprKind::Op(op),
+            ..
+        },
+    ) = list.first()
+    {
+        if op.priority() > priority {
+            let (val, remainder) = eval_list_op(x, *op, op_expr, &list[1..], env)?;
+            return Ok((Some(val), remainder));
         }
     }
+    Ok((Some(x), list))
 }
 
 #[cfg(test)]
@@ -533,17 +1024,54 @@ mod tests {
         let plus = op(Op::Add);
         let times = op(Op::Mul);
         let expr = Expr {
-            e: ExprKind::List(vec![x.clone(), plus.clone(), y.clone(), times, z.clone()]),
+            e: ExprKind::List(vec![x.clone(), plus.clone(), y.clone(), times, z.clone()].into()),
             span: Span { start: 0, end: 0 },
         };
 
         assert_eq!(expr.eval(&mut env).unwrap().unwrap(), num(56.0));
 
         let expr = Expr {
-            e: ExprKind::List(vec![x, plus.clone(), y, plus, z]),
+            e: ExprKind::List(vec![x, plus.clone(), y, plus, z].into()),
             span: Span { start: 0, end: 0 },
         };
 
         assert_eq!(expr.eval(&mut env).unwrap().unwrap(), num(51.0));
     }
+
+    #[test]
+    fn tolerant_equality() {
+        let mut env = Env::default();
+        let eq = |env: &Env, x: f64, y: f64| Op::Eq.eval(&num(x), &num(y), env).unwrap().e;
+        assert_eq!(eq(&env, 0.1 + 0.2, 0.3), ExprKind::Bool(true));
+        assert_eq!(eq(&env, 1e12 + 1e-4, 1e12), ExprKind::Bool(true));
+        assert_eq!(eq(&env, 0.3001, 0.3), ExprKind::Bool(false));
+        assert_eq!(eq(&env, f64::INFINITY, f64::INFINITY), ExprKind::Bool(true));
+        assert_eq!(eq(&env, f64::NAN, f64::NAN), ExprKind::Bool(false));
+
+        env.eq_tolerance = 0.0;
+        assert_eq!(eq(&env, 0.1 + 0.2, 0.3), ExprKind::Bool(false));
+        env.eq_tolerance = 0.01;
+        assert_eq!(eq(&env, 0.3001, 0.3), ExprKind::Bool(true));
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("forward", "forward"), 0);
+        assert_eq!(edit_distance("forwrd", "forward"), 1);
+        assert_eq!(edit_distance("fowrard", "forward"), 1);
+        assert_eq!(edit_distance("", "fd"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn suggestions() {
+        let mut env = Env::default();
+        assert_eq!(env.suggest_proc("forwrd").as_deref(), Some("forward"));
+        assert_eq!(env.suggest_proc("rigth").as_deref(), Some("right"));
+        assert_eq!(env.suggest_proc("squirrel"), None);
+
+        let (_, prog) = crate::parse::program("to squirel\nfd 1\nend".into()).unwrap();
+        prog.eval(&mut env).unwrap();
+        assert_eq!(env.suggest_proc("squirrel").as_deref(), Some("squirel"));
+    }
 }
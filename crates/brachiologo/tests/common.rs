@@ -1,6 +1,6 @@
 use std::path::Path;
 
-use brachiologo::{Env, EvalError, Expr};
+use brachiologo::{Env, EvalError, Expr, TurtleCmd};
 
 #[derive(Default, Clone)]
 pub struct TestCase {
@@ -15,6 +15,18 @@ fn exec_expr(s: &str) -> Result<Option<Expr>, EvalError> {
     dbg!(expr).eval(&mut env)
 }
 
+fn parse_program(s: &str) -> Expr {
+    let (remaining, prog) = brachiologo::parse::program(dbg!(s).into()).unwrap();
+    assert!(remaining.is_empty());
+    prog
+}
+
+fn exec_program(s: &str) -> Result<Vec<TurtleCmd>, EvalError> {
+    let mut env = Env::default();
+    dbg!(parse_program(s)).eval(&mut env)?;
+    Ok(env.turtle)
+}
+
 /*
 fn exec_one(s: &str) -> Result<(), brachiologo::Error> {
     let (remaining, prog) = brachiologo::parse::program(dbg!(s)).unwrap();
@@ -27,6 +39,33 @@ fn exec_one(s: &str) -> Result<(), brachiologo::Error> {
 }
 */
 
+// Renders an error the way that a learner would see it: the message, followed by the line that
+// it came from with the offending part underlined.
+fn render_error(src: &str, err: &EvalError) -> String {
+    let mut ret = format!("{err}\n");
+    let Some(span) = err.span() else {
+        return ret;
+    };
+    let line_start = src[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = src[span.start..]
+        .find('\n')
+        .map_or(src.len(), |i| span.start + i);
+    let line_num = (src[..span.start].matches('\n').count() + 1).to_string();
+    let gutter = " ".repeat(line_num.len());
+    // Keep the tabs, so that the underline lines up with the line above it.
+    let indent: String = src[line_start..span.start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let len = src[span.start..span.end.min(line_end)]
+        .chars()
+        .count()
+        .max(1);
+    ret += &format!("{line_num} | {}\n", &src[line_start..line_end]);
+    ret += &format!("{gutter} | {indent}{}\n", "^".repeat(len));
+    ret
+}
+
 fn parse_loc(s: &str) -> (usize, u32, &str) {
     let mut split = s.trim().splitn(3, ' ');
     let offset = split.next().unwrap().parse().unwrap();
@@ -42,13 +81,33 @@ impl TestCase {
         assert_eq!(a.map(|e| e.e), b.map(|e| e.e));
     }
 
-    /*
+    fn parse(&self) {
+        parse_program(&self.input);
+        parse_program(&self.expected);
+    }
+
     fn exec(&self) {
-        let a = exec_one(&self.input).unwrap();
-        let b = exec_one(&self.expected).unwrap();
+        let a = exec_program(&self.input).unwrap();
+        let b = exec_program(&self.expected).unwrap();
         assert_eq!(a, b);
     }
 
+    fn rendered_error(&self) -> String {
+        let err = exec_program(&self.input).unwrap_err();
+        render_error(&self.input, &err)
+    }
+
+    fn exec_error(&self) {
+        let rendered = self.rendered_error();
+        assert_eq!(
+            rendered.trim_end(),
+            self.expected.trim_end(),
+            "wrong error for {:?}",
+            self.input
+        );
+    }
+
+    /*
     fn exec_failure(&self) {
         let a = exec_one(&self.input).unwrap_err();
         let spn = a.span();
@@ -98,7 +157,14 @@ fn test_exprs() {
     }
 }
 
-/*
+#[test]
+fn parse_tests() {
+    let tests = read_tests("tests/basic.txt");
+    for test in tests {
+        test.parse();
+    }
+}
+
 #[test]
 fn text_tests() {
     let tests = read_tests("tests/basic.txt");
@@ -107,6 +173,28 @@ fn text_tests() {
     }
 }
 
+// These are the messages that learners see, so check them exactly. If they change on purpose,
+// regenerate `errors.txt` by running this with `BRACHIOLOGO_BLESS=1` (and then read the diff, to
+// check that the new messages still make sense).
+#[test]
+fn error_messages() {
+    if std::env::var_os("BRACHIOLOGO_BLESS").is_some() {
+        let mut blessed = String::new();
+        for test in read_tests("tests/errors.txt") {
+            blessed += &test.input;
+            blessed += "------------\n";
+            blessed += &test.rendered_error();
+            blessed += "============\n";
+        }
+        std::fs::write("tests/errors.txt", blessed).unwrap();
+    }
+    let tests = read_tests("tests/errors.txt");
+    for test in tests {
+        test.exec_error();
+    }
+}
+
+/*
 #[test]
 fn exec_failures() {
     let tests = read_tests("tests/exec-failures.txt");
@@ -0,0 +1,238 @@
+//! Property tests for the parser and the evaluator.
+
+use brachiologo::{
+    parse::ErrorKind,
+    proc::UserProc,
+    typ::{ExprKind, Op, Span},
+    Env, EvalError, Expr, TurtleCmd,
+};
+use proptest::prelude::*;
+
+// Names are drawn from a fixed pool, because lots of strings aren't valid identifiers
+// (like `to`, or `inf`, which gets parsed as a number).
+const NAMES: &[&str] = &["x", "y", "size", "square", "star"];
+const BUILTINS: &[&str] = &[
+    "fd", "bk", "rt", "lt", "arc", "penup", "pendown", "sum", "prod", "make", "if", "repeat",
+    "quotient", "sqrt", "power", "ln", "random", "repcount", "output", "stop", "readword", "first",
+    "butlast", "item", "count", "sentence", "for", "while", "po", "pots", "erall",
+];
+const OPS: &[Op] = &[Op::Add, Op::Sub, Op::Mul, Op::Div, Op::Eq, Op::Lt, Op::Gt];
+
+fn expr(e: ExprKind) -> Expr {
+    Expr {
+        e,
+        span: Span { start: 0, end: 0 },
+    }
+}
+
+fn leaf(words: &'static [&'static str]) -> impl Strategy<Value = Expr> {
+    prop_oneof![
+        (-1000.0..1000.0f64).prop_map(|x| expr(ExprKind::Num(x))),
+        (0..20u8).prop_map(|x| expr(ExprKind::Num(x as f64))),
+        prop::sample::select(NAMES).prop_map(|v| expr(ExprKind::Var(v.to_owned()))),
+        prop::sample::select(words).prop_map(|w| expr(ExprKind::Word(w.to_owned()))),
+        prop::sample::select(OPS).prop_map(|op| expr(ExprKind::Op(op))),
+    ]
+}
+
+fn arb_expr(words: &'static [&'static str]) -> impl Strategy<Value = Expr> {
+    leaf(words).prop_recursive(4, 32, 4, move |inner| {
+        let list = prop::collection::vec(inner.clone(), 1..4);
+        prop_oneof![
+            list.clone().prop_map(|l| expr(ExprKind::List(l.into()))),
+            list.clone()
+                .prop_map(|l| expr(ExprKind::Quote(Box::new(expr(ExprKind::List(l.into())))))),
+            inner.prop_map(|e| expr(ExprKind::Quote(Box::new(e)))),
+            (
+                prop::sample::select(NAMES),
+                prop::sample::subsequence(NAMES, 0..3),
+                list,
+            )
+                .prop_map(|(name, args, body)| {
+                    expr(ExprKind::DefProc(
+                        UserProc {
+                            name: name.to_owned(),
+                            args: args.into_iter().map(str::to_owned).collect(),
+                            body: expr(ExprKind::List(body.into())),
+                            name_span: Span { start: 0, end: 0 },
+                            arg_spans: Vec::new(),
+                        }
+                        .into(),
+                    ))
+                }),
+        ]
+    })
+}
+
+fn arb_program(words: &'static [&'static str]) -> impl Strategy<Value = String> {
+    prop::collection::vec(arb_expr(words), 1..6).prop_map(|exprs| {
+        exprs
+            .iter()
+            .map(Expr::to_source)
+            .collect::<Vec<_>>()
+            .join(" ")
+    })
+}
+
+// Compares two expressions, ignoring their spans.
+fn same(a: &Expr, b: &Expr) -> bool {
+    let same_list =
+        |a: &[Expr], b: &[Expr]| a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same(a, b));
+    match (&a.e, &b.e) {
+        (ExprKind::List(a), ExprKind::List(b)) => same_list(a, b),
+        (ExprKind::Quote(a), ExprKind::Quote(b)) => same(a, b),
+        (ExprKind::DefProc(a), ExprKind::DefProc(b)) => {
+            let (a, b) = (a.as_user().unwrap(), b.as_user().unwrap());
+            a.name == b.name && a.args == b.args && same(&a.body, &b.body)
+        }
+        (a, b) => a == b,
+    }
+}
+
+fn parse(s: &str) -> Expr {
+    let (remaining, prog) = brachiologo::parse::program(s.into())
+        .unwrap_or_else(|e| panic!("failed to parse {s:?}: {e:?}"));
+    assert!(remaining.is_empty());
+    prog
+}
+
+proptest! {
+    #[test]
+    fn print_then_parse(e in arb_expr(NAMES)) {
+        let source = e.to_source();
+        let prog = parse(&source);
+        let ExprKind::List(parsed) = &prog.e else {
+            panic!("program should be a list");
+        };
+        prop_assert_eq!(parsed.len(), 1);
+        prop_assert!(same(&e, &parsed[0]), "{:?} became {:?}", source, parsed[0].to_source());
+    }
+
+    #[test]
+    fn eval_never_panics(source in arb_program(BUILTINS)) {
+        let prog = parse(&source);
+        let mut env = Env {
+            out: Box::new(std::io::sink()),
+            step_budget: Some(500),
+            ..Env::default()
+        };
+        // We don't care whether it succeeds, only that it doesn't panic.
+        let _: Result<_, EvalError> = prog.eval(&mut env);
+    }
+}
+
+#[test]
+fn step_budget() {
+    let prog = parse(&"fd 1 ".repeat(10000));
+    let mut env = Env {
+        step_budget: Some(1000),
+        ..Env::default()
+    };
+    let err = prog.eval(&mut env).unwrap_err();
+    assert!(matches!(err, EvalError::OutOfSteps), "{err:?}");
+}
+
+#[test]
+fn reading_input() {
+    let prog = parse("make \"size readnumber make \"name readword fd :size text :name");
+    let mut lines = vec!["lots", "Turtle\n", " 12.5\r\n"];
+    let mut env = Env {
+        input: Some(Box::new(move || lines.pop().map(str::to_owned))),
+        ..Env::default()
+    };
+    prog.eval(&mut env).unwrap();
+    assert_eq!(env.turtle[0], TurtleCmd::Forward(12.5));
+    assert_eq!(env.turtle.len(), 1 + "Turtle".len());
+
+    let no_input = |err: EvalError| matches!(err.root(), EvalError::NoInput { .. });
+    let err = parse("fd readnumber").eval(&mut env).unwrap_err();
+    assert!(matches!(err.root(), EvalError::BadArg { .. }), "{err:?}");
+    // That used up the last line.
+    assert!(no_input(parse("fd readnumber").eval(&mut env).unwrap_err()));
+    // By default, there's nowhere to read from.
+    assert!(no_input(
+        parse("make \"x readword")
+            .eval(&mut Env::default())
+            .unwrap_err()
+    ));
+}
+
+#[test]
+fn random_is_reproducible() {
+    let draw = |env: &mut Env| {
+        let prog = parse("repeat 20 [fd random 10]");
+        prog.eval(env).unwrap();
+        std::mem::take(&mut env.turtle)
+    };
+    let mut env = Env::default();
+    let first = draw(&mut env);
+    assert!(first.iter().all(
+        |cmd| matches!(cmd, TurtleCmd::Forward(x) if (0.0..10.0).contains(x) && x.trunc() == *x)
+    ));
+    assert_ne!(draw(&mut env), first);
+
+    parse("rerandom").eval(&mut env).unwrap();
+    assert_eq!(draw(&mut env), first);
+    assert_eq!(draw(&mut Env::default()), first);
+}
+
+#[test]
+fn streaming() {
+    let source = "repeat 3 [fd repcount rt 90] fd \"oops";
+    let mut env = Env::default();
+    let batch_err = parse(source).eval(&mut env).unwrap_err();
+    let streamed: Vec<_> = Env::default().run_streaming(parse(source)).collect();
+    let (last, cmds) = streamed.split_last().unwrap();
+    let cmds: Vec<TurtleCmd> = cmds.iter().map(|c| *c.as_ref().unwrap()).collect();
+    assert_eq!(cmds, env.turtle);
+    assert_eq!(
+        last.as_ref().unwrap_err().to_string(),
+        batch_err.to_string()
+    );
+}
+
+#[test]
+fn streaming_starts_early() {
+    // This would take forever to finish, but we only want the start of it.
+    let prog = parse("repeat 1000000000 [fd 1 rt 1]");
+    let start: Vec<_> = Env::default().run_streaming(prog).take(4).collect();
+    assert_eq!(start.len(), 4);
+    assert!(matches!(start[0], Ok(TurtleCmd::Forward(_))));
+}
+
+#[test]
+fn nesting_depth() {
+    let nested = |depth: usize| format!("print {}1{}", "[".repeat(depth), "]".repeat(depth));
+    // The `1` in the middle counts as a level too.
+    parse(&nested(brachiologo::parse::MAX_DEPTH - 1));
+    assert!(
+        brachiologo::parse::program(nested(brachiologo::parse::MAX_DEPTH).as_str().into()).is_err()
+    );
+
+    let source = nested(100_000);
+    let Err(nom::Err::Failure(err)) = brachiologo::parse::program(source.as_str().into()) else {
+        panic!("should have failed");
+    };
+    assert!(matches!(err.kind, ErrorKind::TooDeep), "{err:?}");
+    assert!(err.cause.is_none());
+
+    // The depth gets reset afterwards.
+    parse(&nested(50));
+}
+
+#[test]
+fn deep_recursion() {
+    let source = |n: usize| format!("to f :n\nif :n > 0 [fd 1 f :n - 1]\nend\nf {n}");
+    let steps: Vec<_> = Env::default()
+        .run_streaming(parse(&source(800)))
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(steps.len(), 800);
+
+    // Going too deep is an error, not a stack overflow.
+    let steps: Vec<_> = Env::default()
+        .run_streaming(parse(&source(100_000)))
+        .collect();
+    let err = steps.last().unwrap().as_ref().unwrap_err();
+    assert!(matches!(err.root(), EvalError::TooDeep), "{err}");
+}
@@ -0,0 +1,105 @@
+//! The `accuracy-grid` and `check-accuracy` subcommands, for measuring how accurately the
+//! brachiograph draws with the help of a camera.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use brachiograph_host::{
+    accuracy::{Accuracy, Grid, Photo},
+    finish::Finish,
+    Serial, Transport,
+};
+
+/// Draws a grid of crosses, for photographing and then checking with `check-accuracy`.
+#[derive(clap::Args, Debug)]
+pub struct GridArgs {
+    /// The serial port that the brachiograph is on.
+    tty: String,
+}
+
+/// Checks a photo of the grid drawn by `accuracy-grid`, and says how far off it was.
+///
+/// Take the photo from straight above, with the brachiograph's base at the bottom of the
+/// picture. Any error that's the same all over the page gets lined up away along with the
+/// camera's position, so this finds the errors that come from the calibration.
+#[derive(clap::Args, Debug)]
+pub struct CheckArgs {
+    /// The serial port that the brachiograph is on (for asking it how long its arms are, to
+    /// work out the joint angles).
+    tty: String,
+
+    /// The photo (as a PNG or JPEG file).
+    photo: PathBuf,
+
+    /// Save a picture of the errors to this SVG file.
+    #[clap(long)]
+    heatmap: Option<PathBuf>,
+
+    /// Point out the joint angles where the joints are off by more than this many degrees.
+    #[clap(long, default_value_t = 0.5)]
+    max_error: f64,
+}
+
+pub fn draw_grid(args: GridArgs) -> anyhow::Result<()> {
+    let mut serial = Serial::open(&args.tty, Transport::Postcard)?;
+    let ops = Grid::default().ops();
+    println!("Drawing {} ops", ops.len());
+    serial.send_stream(ops).map_err(|e| e.error)?;
+    serial.finish(&Finish::default())
+}
+
+/// Reads a PNG or JPEG file, in grayscale.
+pub fn load_photo(path: &Path) -> anyhow::Result<Photo> {
+    let image = image::open(path)
+        .with_context(|| format!("failed to read {}", path.display()))?
+        .to_luma8();
+    Ok(Photo {
+        width: image.width() as usize,
+        height: image.height() as usize,
+        luma: image.into_raw(),
+    })
+}
+
+pub fn check(args: CheckArgs) -> anyhow::Result<()> {
+    let photo = load_photo(&args.photo)?;
+    let grid = Grid::default();
+    let accuracy = Accuracy::measure(&grid, &photo)?;
+    let config = Serial::open(&args.tty, Transport::Postcard)?.config()?;
+
+    let missing = accuracy.marks.iter().filter(|m| m.found.is_none()).count();
+    if missing > 0 {
+        println!(
+            "Couldn't find {missing} of the {} marks",
+            accuracy.marks.len()
+        );
+    }
+    println!("Off by {:.3} on average", accuracy.rms_error());
+    if let Some(worst) = accuracy.worst() {
+        let error = worst.error().unwrap_or_default();
+        println!(
+            "Worst at ({:.1}, {:.1}): off by {:.3} ({:+.3}, {:+.3})",
+            worst.target.x,
+            worst.target.y,
+            error.hypot(),
+            error.x,
+            error.y
+        );
+    }
+    for error in accuracy.joint_errors(&config) {
+        if error.error.abs() > args.max_error {
+            println!(
+                "{:?}: turns {:.1} degrees too {} around {} degrees; \
+                 try calibrating again there",
+                error.joint,
+                error.error.abs(),
+                if error.error > 0.0 { "far" } else { "little" },
+                error.angle
+            );
+        }
+    }
+    if let Some(path) = &args.heatmap {
+        std::fs::write(path, accuracy.heatmap_svg(&grid))?;
+        println!("Saved a picture of the errors to {}", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,627 @@
+//! The `calibrate` subcommand, for measuring the servos.
+
+use std::{io::Write, path::PathBuf};
+
+use anyhow::{bail, Context};
+use brachiograph::{
+    geom,
+    pwm::{PenRamp, Timing, TogglePwm},
+    Angle, Angles, Direction, Fixed, Joint, Op, Point, Resp, ServoPositionDelta,
+};
+use brachiograph_host::{
+    calibration,
+    calibration::{ArmCalibration, PenDuties},
+    Serial, Transport,
+};
+use termion::{event::Key, input::TermRead, raw::IntoRawMode};
+
+/// Measures the servos, by moving them around under the user's control.
+///
+/// By default, this goes through the ticks on the calibration template, asking for each joint
+/// to be lined up with them, and saves the calibration tables both as a postcard file and as
+/// Rust source for building into the firmware.
+#[derive(clap::Args, Debug)]
+pub struct CalibrateArgs {
+    /// The serial port that the brachiograph is on.
+    tty: String,
+
+    /// Where to save the calibration. For the arm servos, the tables also get written next to
+    /// this as Rust source, with the extension changed to `.rs`, and in the Python
+    /// BrachioGraph's format, with the extension changed to `.json`.
+    ///
+    /// For the pen servo, this is a file of pen duties for any number of pens (for the
+    /// --pen-duties option when plotting). If it's already there, the duties for this pen get
+    /// added to it.
+    #[clap(short)]
+    output: PathBuf,
+
+    /// Calibrate the pen servo instead of the arm servos, saving its duties under this name
+    /// (the name to pass to --pen when plotting).
+    #[clap(long, value_name = "NAME", num_args = 0..=1, default_missing_value = "default")]
+    pen: Option<String>,
+
+    /// When calibrating the pen, how much further (in duty units) to push the pen
+    /// after it first touches the paper.
+    #[clap(long, default_value_t = 50)]
+    press: u16,
+
+    /// Check which way the arm servos turn, for finding out whether any of them are mounted
+    /// the other way around. The answer gets added to the calibration in the output file (which
+    /// doesn't need to have any tables in it yet), and uploaded with it.
+    #[clap(long, conflicts_with = "pen")]
+    directions: bool,
+
+    /// Instead of calibrating, set the time between servo pulses in microseconds. Digital
+    /// servos can usually go down to 3000; analog ones need 20000. This gets added to the
+    /// calibration in the output file, and uploaded with it.
+    #[clap(long, requires = "tick_ms", conflicts_with_all = ["pen", "directions"])]
+    frame_us: Option<u16>,
+
+    /// With --frame-us, how often (in milliseconds) to update the servo positions.
+    #[clap(long, requires = "frame_us")]
+    tick_ms: Option<u16>,
+
+    /// Instead of calibrating, set how much of the first half of the pen lifting time the pen
+    /// servo spends easing between up and down, in percent. At 0 (the default) it jumps straight
+    /// there. This gets added to the calibration in the output file, and uploaded with it.
+    #[clap(long, conflicts_with_all = ["pen", "directions", "frame_us"])]
+    pen_ramp: Option<u8>,
+
+    /// Instead of calibrating, measure the pen servo's duties for pointing it at each of these
+    /// angles (in degrees, separated by commas, with 0 being up), for putting the pen down at an
+    /// angle. This gets added to the calibration in the output file, and uploaded with it.
+    #[clap(
+        long,
+        value_delimiter = ',',
+        conflicts_with_all = ["pen", "directions", "frame_us", "pen_ramp"]
+    )]
+    pen_angles: Vec<i16>,
+}
+
+fn duty_delta(c: char) -> Option<ServoPositionDelta> {
+    let mag = if c.is_ascii_uppercase() { 10 } else { 1 };
+    let (shoulder, elbow) = match c.to_ascii_lowercase() {
+        'k' => (1, 0),
+        'j' => (-1, 0),
+        'f' => (0, 1),
+        'd' => (0, -1),
+        _ => return None,
+    };
+    Some(ServoPositionDelta {
+        shoulder: shoulder * mag,
+        elbow: elbow * mag,
+    })
+}
+
+/// How much to move the pen for a key press, in the direction of the paper.
+fn pen_delta(c: char) -> Option<i16> {
+    let mag = if c.is_ascii_uppercase() { 10 } else { 1 };
+    match c.to_ascii_lowercase() {
+        'j' => Some(mag),
+        'k' => Some(-mag),
+        _ => None,
+    }
+}
+
+// The labels of the ticks on the calibration template, which go every 15 degrees through each
+// joint's range (see the `template` crate).
+static SHOULDER_NAMES: &[&str] = &["0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "10"];
+static ELBOW_NAMES: &[&str] = &["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"];
+
+// The angles of the ticks on the calibration template, with their labels.
+fn template_ticks(
+    range: (Angle, Angle),
+    names: &'static [&'static str],
+) -> Vec<(i16, &'static str)> {
+    let start = range.0.degrees().to_num::<i16>();
+    let end = range.1.degrees().to_num::<i16>();
+    (start..=end)
+        .step_by(15)
+        .zip(names.iter().copied())
+        .collect()
+}
+
+struct Instruction {
+    joint: Joint,
+    direction: Direction,
+    target_angle: i16,
+    target_name: &'static str,
+}
+
+fn calibration_instructions() -> impl Iterator<Item = Instruction> {
+    fn one(
+        angles: &[(i16, &'static str)],
+        joint: Joint,
+        direction: Direction,
+    ) -> impl Iterator<Item = Instruction> {
+        let mut angles = angles.to_vec();
+        if direction == Direction::Decreasing {
+            angles.reverse();
+        }
+        angles
+            .into_iter()
+            .map(move |(target_angle, target_name)| Instruction {
+                joint,
+                direction,
+                target_angle,
+                target_name,
+            })
+    }
+    let config = geom::Config::default();
+    let shoulder = template_ticks(config.shoulder_range, SHOULDER_NAMES);
+    let elbow = template_ticks(config.elbow_range, ELBOW_NAMES);
+    one(&shoulder, Joint::Shoulder, Direction::Increasing)
+        .chain(one(&shoulder, Joint::Shoulder, Direction::Decreasing))
+        .chain(one(&elbow, Joint::Elbow, Direction::Increasing))
+        .chain(one(&elbow, Joint::Elbow, Direction::Decreasing))
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let direction = if self.direction == Direction::Increasing {
+            "increase"
+        } else {
+            "decrease"
+        };
+        let joint = if self.joint == Joint::Shoulder {
+            "shoulder"
+        } else {
+            "elbow"
+        };
+        let name = self.target_name;
+        f.write_fmt(format_args!("{direction} {joint} to \"{name}\""))
+    }
+}
+
+fn get_position(serial: &mut Serial) -> anyhow::Result<brachiograph::ServoPosition> {
+    match serial.send(Op::GetPosition)? {
+        Resp::CurPosition(duties) => Ok(duties),
+        resp => bail!("unexpected response {:?} to GetPosition", resp),
+    }
+}
+
+/// Which of the arm servos are mounted the other way around.
+#[derive(Debug, Default)]
+struct Inverted {
+    shoulder: bool,
+    elbow: bool,
+}
+
+fn move_to(serial: &mut Serial, angles: Angles) -> anyhow::Result<()> {
+    let (x, y) = geom::Config::default().coord_at_angle::<Fixed>(angles);
+    match serial.send(Op::MoveTo(Point { x, y }))? {
+        Resp::Ack => {}
+        resp => bail!("unexpected response {:?} to MoveTo", resp),
+    }
+    // Wait for the move to finish, so that the user can tell the moves apart.
+    while serial.queue_status()?.len > 0 {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+/// Moves one joint at a time by a small amount, and asks the user which way it went.
+///
+/// Returns `None` if the user quit.
+fn check_directions(
+    serial: &mut Serial,
+    raw: &mut impl Write,
+    keys: &mut impl Iterator<Item = std::io::Result<Key>>,
+) -> anyhow::Result<Option<Inverted>> {
+    // Start from scratch, in case the brachiograph was already told that something is inverted.
+    serial.send(Op::SetInverted(Joint::Shoulder, false))?;
+    serial.send(Op::SetInverted(Joint::Elbow, false))?;
+    serial.send(Op::PenUp)?;
+
+    // This is where the brachiograph rests when it starts up: the upper arm is pointing
+    // straight up, and the elbow is at a right angle.
+    let rest = Angles::default();
+    let checks = [
+        (
+            Joint::Shoulder,
+            Angles {
+                shoulder: Angle::from_degrees(15),
+                ..rest
+            },
+            "Did the whole arm swing clockwise?",
+        ),
+        (
+            Joint::Elbow,
+            Angles {
+                elbow: Angle::from_degrees(-15),
+                ..rest
+            },
+            "Did the elbow bend, bringing the pen closer to the shoulder?",
+        ),
+    ];
+
+    let mut ret = Inverted::default();
+    for (joint, angles, question) in checks {
+        move_to(serial, rest)?;
+        move_to(serial, angles)?;
+        write!(raw, "\r{question} (y/n) ")?;
+        raw.flush()?;
+        let inverted = loop {
+            match keys.next().transpose()? {
+                Some(Key::Char('y')) => break false,
+                Some(Key::Char('n')) => break true,
+                Some(Key::Char('q')) | None => return Ok(None),
+                _ => {}
+            }
+        };
+        write!(raw, "{}\r\n", if inverted { "n" } else { "y" })?;
+        // Put it back the way it was before flipping it, so that it doesn't jump.
+        move_to(serial, rest)?;
+        serial.send(Op::SetInverted(joint, inverted))?;
+        match joint {
+            Joint::Shoulder => ret.shoulder = inverted,
+            Joint::Elbow => ret.elbow = inverted,
+        }
+    }
+    Ok(Some(ret))
+}
+
+/// Finds the pen duties by lowering the pen until the user says it touches the paper, and then
+/// raising it until they say it's high enough to stay clear of the paper.
+///
+/// Returns `None` if the user quit.
+fn calibrate_pen(
+    serial: &mut Serial,
+    raw: &mut impl Write,
+    keys: &mut impl Iterator<Item = std::io::Result<Key>>,
+    press: u16,
+) -> anyhow::Result<Option<TogglePwm>> {
+    // The default duties tell us which way is "down."
+    let default = TogglePwm::pen();
+    let sign: i16 = if default.on >= default.off { 1 } else { -1 };
+
+    write!(
+        raw,
+        "\rLower the pen (j/J) until it just touches the paper, then press enter. \
+         Use k/K to raise it again.\r\n"
+    )?;
+    raw.flush()?;
+    let Some(touch) = adjust_pen(serial, raw, keys, sign)? else {
+        return Ok(None);
+    };
+    let down = (touch as i32 + sign as i32 * press as i32).clamp(0, u16::MAX as i32) as u16;
+
+    write!(
+        raw,
+        "{}\rNow raise the pen (k/K) until it's high enough to clear the paper, \
+         then press enter.\r\n",
+        termion::clear::CurrentLine
+    )?;
+    raw.flush()?;
+    let Some(up) = adjust_pen(serial, raw, keys, sign)? else {
+        return Ok(None);
+    };
+
+    let pen = TogglePwm { on: down, off: up };
+    if !pen.is_valid() {
+        bail!("the pen duties ({up} up and {down} down) are out of range");
+    }
+    serial.send(Op::CalibratePen(pen))?;
+    Ok(Some(pen))
+}
+
+fn calibrate_pen_angles(
+    serial: &mut Serial,
+    raw: &mut impl Write,
+    keys: &mut impl Iterator<Item = std::io::Result<Key>>,
+    angles: &[i16],
+) -> anyhow::Result<Option<Vec<(i16, u16)>>> {
+    let default = TogglePwm::pen();
+    let sign: i16 = if default.on >= default.off { 1 } else { -1 };
+
+    let mut table = Vec::new();
+    for &deg in angles {
+        write!(
+            raw,
+            "{}\rTurn the pen servo (j/J towards the paper, k/K away) until it's at {deg} \
+             degrees, then press enter.\r\n",
+            termion::clear::CurrentLine
+        )?;
+        raw.flush()?;
+        let Some(duty) = adjust_pen(serial, raw, keys, sign)? else {
+            return Ok(None);
+        };
+        table.push((deg, duty));
+    }
+    table.sort();
+    Ok(Some(table))
+}
+
+// Moves the pen servo around until the user presses enter, and returns the duty that it got to.
+// `sign` is the direction (in duty) of the paper. Returns `None` if the user quit.
+fn adjust_pen(
+    serial: &mut Serial,
+    raw: &mut impl Write,
+    keys: &mut impl Iterator<Item = std::io::Result<Key>>,
+    sign: i16,
+) -> anyhow::Result<Option<u16>> {
+    while let Some(key) = keys.next().transpose()? {
+        match key {
+            Key::Char('q') => return Ok(None),
+            Key::Char('\n') => return Ok(Some(get_position(serial)?.pen)),
+            Key::Char(c) => {
+                if let Some(delta) = pen_delta(c) {
+                    serial.send(Op::ChangePenPosition(sign * delta))?;
+                    let duty = get_position(serial)?.pen;
+                    write!(raw, "{}\r[pen duty {duty}] ", termion::clear::CurrentLine)?;
+                    raw.flush()?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+/// Says how good a saved calibration is, without needing the brachiograph.
+#[derive(clap::Args, Debug)]
+pub struct CheckArgs {
+    /// A calibration saved by the calibrate subcommand (or a `.json` file from the Python
+    /// BrachioGraph).
+    input: PathBuf,
+
+    /// Another calibration of the same servos, measured at more angles, for seeing how far off
+    /// the first one is in between its entries.
+    #[clap(long)]
+    dense: Option<PathBuf>,
+
+    /// Point out the places where the calibration could be off by more than this many degrees,
+    /// because it needs more entries there.
+    #[clap(long, default_value_t = 0.5)]
+    max_error: f64,
+}
+
+pub fn check(args: CheckArgs) -> anyhow::Result<()> {
+    let calib = ArmCalibration::load(&args.input)?;
+    if let Err(e) = calib.check() {
+        println!("Warning: {e}");
+    }
+    let dense = args
+        .dense
+        .as_deref()
+        .map(ArmCalibration::load)
+        .transpose()?;
+    for report in calib.analyze(dense.as_ref(), args.max_error) {
+        if let Some((deg, error)) = report.worst {
+            println!(
+                "{}: off by up to {error:.1} degrees (at {deg} degrees)",
+                report.name
+            );
+        }
+        for span in report.coarse {
+            println!(
+                "{}: could be off by about {:.1} degrees between {} and {} degrees; \
+                 try adding an entry at {} degrees",
+                report.name,
+                span.error,
+                span.from,
+                span.to,
+                (span.from + span.to) / 2
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Goes through the ticks on the calibration template, asking the user to line up the arm
+/// with each one, and then fills in any that were skipped.
+///
+/// Returns `None` if the user quit.
+fn calibrate_arm(
+    serial: &mut Serial,
+    raw: &mut impl Write,
+    keys: &mut impl Iterator<Item = std::io::Result<Key>>,
+) -> anyhow::Result<Option<ArmCalibration>> {
+    write!(
+        raw,
+        "\rUse j/k (J/K for bigger steps) to turn the shoulder and d/f to turn the elbow. \
+         Press enter when the arm lines up with the tick, or s if it can't get there.\r\n"
+    )?;
+    // The duty at each tick, or `None` if it was skipped.
+    let mut measured = Vec::new();
+    for inst in calibration_instructions() {
+        write!(raw, "{}\r[{}] ", termion::clear::CurrentLine, inst)?;
+        raw.flush()?;
+        let duty = loop {
+            let Some(key) = keys.next().transpose()? else {
+                return Ok(None);
+            };
+            match key {
+                Key::Char('q') => return Ok(None),
+                Key::Char('s') => break None,
+                Key::Char('\n') => {
+                    let duties = get_position(serial)?;
+                    let duty = if inst.joint == Joint::Shoulder {
+                        duties.shoulder
+                    } else {
+                        duties.elbow
+                    };
+                    break Some(duty);
+                }
+                Key::Char(c) => {
+                    if let Some(delta) = duty_delta(c) {
+                        serial.send(Op::ChangePosition(delta))?;
+                        let duties = get_position(serial)?;
+                        write!(
+                            raw,
+                            "{}\r[{}] [shoulder duty {}, elbow duty {}] ",
+                            termion::clear::CurrentLine,
+                            inst,
+                            duties.shoulder,
+                            duties.elbow
+                        )?;
+                        raw.flush()?;
+                    }
+                }
+                _ => {}
+            }
+        };
+        measured.push((inst.joint, inst.direction, inst.target_angle, duty));
+    }
+
+    let mut calib = ArmCalibration::default();
+    for joint in [Joint::Shoulder, Joint::Elbow] {
+        for dir in [Direction::Increasing, Direction::Decreasing] {
+            let mut entries: Vec<_> = measured
+                .iter()
+                .filter(|m| m.0 == joint && m.1 == dir)
+                .map(|m| (m.2, m.3))
+                .collect();
+            entries.sort();
+            *calib.table_mut(joint, dir) = calibration::fill_gaps(&entries)?;
+        }
+    }
+    Ok(Some(calib))
+}
+
+pub fn run(args: CalibrateArgs) -> anyhow::Result<()> {
+    let mut serial = Serial::open(&args.tty, Transport::Postcard)?;
+
+    if let (Some(frame_us), Some(tick_ms)) = (args.frame_us, args.tick_ms) {
+        let timing = Timing { frame_us, tick_ms };
+        if !timing.is_valid() {
+            bail!(
+                "the frame period must be between {} and {} microseconds, and the tick between {} \
+                 and {} milliseconds",
+                brachiograph::pwm::MIN_FRAME_US,
+                brachiograph::pwm::MAX_FRAME_US,
+                brachiograph::pwm::MIN_TICK_MS,
+                brachiograph::pwm::MAX_TICK_MS
+            );
+        }
+        // This goes with the rest of the calibration, so that it gets uploaded along with it.
+        let mut calib = ArmCalibration::load_or_default(&args.output)?;
+        calib.timing = Some(timing);
+        serial.upload_calibration(&calib)?;
+        std::fs::write(&args.output, postcard::to_allocvec(&calib)?)?;
+        println!("Saved the timing to {}", args.output.display());
+        return Ok(());
+    }
+
+    if let Some(percent) = args.pen_ramp {
+        let ramp = PenRamp { percent };
+        if !ramp.is_valid() {
+            bail!("the pen ramp must be between 0 and 100 percent");
+        }
+        let mut calib = ArmCalibration::load_or_default(&args.output)?;
+        calib.pen_ramp = Some(ramp);
+        serial.upload_calibration(&calib)?;
+        std::fs::write(&args.output, postcard::to_allocvec(&calib)?)?;
+        println!("Saved the pen ramp to {}", args.output.display());
+        return Ok(());
+    }
+
+    let stdout = std::io::stdout();
+    let stdout = stdout.lock();
+    let stdin = std::io::stdin();
+    let stdin = stdin.lock();
+    let mut raw = stdout.into_raw_mode()?;
+    let mut keys = stdin.keys();
+
+    if let Some(name) = &args.pen {
+        let Some(pen) = calibrate_pen(&mut serial, &mut raw, &mut keys, args.press)? else {
+            write!(&mut raw, "{}\rGoodbye!\r\n", termion::clear::CurrentLine)?;
+            return Ok(());
+        };
+        write!(
+            &mut raw,
+            "{}\rPen up: {}, pen down: {}\r\n",
+            termion::clear::CurrentLine,
+            pen.off,
+            pen.on
+        )?;
+        drop(raw);
+        let mut duties = PenDuties::load_or_default(&args.output)?;
+        duties.pens.insert(name.clone(), pen);
+        duties.save(&args.output)?;
+        println!(
+            "Saved the duties for {:?} to {}",
+            name,
+            args.output.display()
+        );
+        return Ok(());
+    }
+
+    if !args.pen_angles.is_empty() {
+        let Some(table) = calibrate_pen_angles(&mut serial, &mut raw, &mut keys, &args.pen_angles)?
+        else {
+            write!(&mut raw, "{}\rGoodbye!\r\n", termion::clear::CurrentLine)?;
+            return Ok(());
+        };
+        write!(&mut raw, "{}\r", termion::clear::CurrentLine)?;
+        drop(raw);
+        // This goes with the rest of the calibration, so that it gets uploaded along with it.
+        let mut calib = ArmCalibration::load_or_default(&args.output)?;
+        calib.pen_angles = table;
+        serial.upload_calibration(&calib)?;
+        std::fs::write(&args.output, postcard::to_allocvec(&calib)?)?;
+        println!("Saved the pen angles to {}", args.output.display());
+        return Ok(());
+    }
+
+    if args.directions {
+        let Some(inverted) = check_directions(&mut serial, &mut raw, &mut keys)? else {
+            write!(&mut raw, "{}\rGoodbye!\r\n", termion::clear::CurrentLine)?;
+            return Ok(());
+        };
+        write!(
+            &mut raw,
+            "{}\rShoulder inverted: {}, elbow inverted: {}\r\n",
+            termion::clear::CurrentLine,
+            inverted.shoulder,
+            inverted.elbow
+        )?;
+        drop(raw);
+        // This goes with the rest of the calibration, so that it gets uploaded along with it.
+        let mut calib = ArmCalibration::load_or_default(&args.output)?;
+        calib.shoulder_inverted = inverted.shoulder;
+        calib.elbow_inverted = inverted.elbow;
+        std::fs::write(&args.output, postcard::to_allocvec(&calib)?)?;
+        println!(
+            "Saved which way the servos are to {}",
+            args.output.display()
+        );
+        return Ok(());
+    }
+
+    let Some(mut calib) = calibrate_arm(&mut serial, &mut raw, &mut keys)? else {
+        write!(&mut raw, "{}\rGoodbye!\r\n", termion::clear::CurrentLine)?;
+        return Ok(());
+    };
+    write!(&mut raw, "{}\r", termion::clear::CurrentLine)?;
+    // Leave raw mode, so that errors and messages print normally.
+    drop(raw);
+    calib
+        .check()
+        .context("the calibration doesn't look right, so it wasn't saved")?;
+    // Keep whatever else was already saved there, like which way the servos are mounted.
+    match ArmCalibration::load_or_default(&args.output) {
+        Ok(old) => {
+            calib.shoulder_inverted = old.shoulder_inverted;
+            calib.elbow_inverted = old.elbow_inverted;
+            calib.timing = old.timing;
+            calib.pen_ramp = old.pen_ramp;
+            calib.pen_angles = old.pen_angles;
+        }
+        Err(e) => println!("Warning: replacing {}: {e:#}", args.output.display()),
+    }
+
+    let data = postcard::to_allocvec(&calib)?;
+    std::fs::write(&args.output, data)?;
+    let rust = args.output.with_extension("rs");
+    std::fs::write(&rust, calib.to_rust())?;
+    let json = args.output.with_extension("json");
+    std::fs::write(&json, calib.to_python_json()?)?;
+    println!(
+        "Saved the calibration to {}, {} and {}",
+        args.output.display(),
+        rust.display(),
+        json.display()
+    );
+    Ok(())
+}
@@ -1,247 +1,875 @@
 use std::{
-    io::{BufRead, BufReader},
+    ops::Range,
     path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, bail};
-use brachiograph::{Angle, Fixed, Op, Resp};
-use clap::Parser;
-use kurbo::{Affine, BezPath, PathEl, Point, Rect, Shape, Vec2};
-use serialport::SerialPort;
+use anyhow::{bail, Context};
+use brachiograph::{geom, Op, PauseReason, Resp};
+use brachiograph_host::{
+    calibration::{ArmCalibration, PenDuties},
+    finish::Finish,
+    gcode,
+    profile::{Profile, Settings},
+    raster::{self, Halftone, Style},
+    registration,
+    sim::Simulator,
+    svg::{self, Fonts, Hatching},
+    tile::{self, Tiled},
+    wet::{self, WetZone},
+    BatchError, Job, JournalEntry, Serial, Transport, DEFAULT_RESPONSE_TIMEOUT,
+};
+use brachiograph_plan::Planner;
+use clap::{Parser, Subcommand};
+use kurbo::{Affine, BezPath, Point, Rect, Shape, Vec2};
+
+mod accuracy;
+mod calibrate;
+mod repl;
 
+/// Plots drawings (SVG, G-code, text or picture files, or Logo programs) on a brachiograph.
 #[derive(Parser, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    plot: Option<Args>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    Calibrate(calibrate::CalibrateArgs),
+    CheckCalibration(calibrate::CheckArgs),
+    Repl(repl::ReplArgs),
+    AccuracyGrid(accuracy::GridArgs),
+    CheckAccuracy(accuracy::CheckArgs),
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
-    tty: String,
+    /// The serial port that the brachiograph is on. Leave it out with --dry-run.
+    #[clap(value_name = "TTY")]
+    tty: Option<String>,
+    /// The file to plot.
+    #[clap(value_name = "INPUT")]
+    file: Option<PathBuf>,
+    // The file to plot, once it's been worked out which of the arguments it is (see
+    // `Args::resolve_input`).
+    #[clap(skip)]
     input: PathBuf,
+
+    /// Before plotting, draw a registration cross and ask how far off it was.
+    #[clap(long)]
+    register: bool,
+
+    /// How to trade plotting time for quality: draft, normal, or fine.
+    #[clap(long, default_value_t = Profile::Normal)]
+    profile: Profile,
+
+    /// Stop for this many milliseconds at sharp corners, so that the arms stop wobbling before
+    /// the pen sets off in a new direction. The default comes from the profile.
+    #[clap(long)]
+    corner_dwell: Option<u64>,
+
+    /// Smooth out curves in Logo programs that were drawn with lots of little steps.
+    #[clap(long)]
+    smooth: bool,
+
+    /// Send the curves in SVG and G-code files as curves, instead of breaking them into lots of
+    /// little lines. This needs firmware that understands curves.
+    #[clap(long)]
+    curves: bool,
+
+    /// How far apart the hatching lines that shade filled shapes in SVG files are. Zero draws
+    /// just their outlines.
+    #[clap(long, default_value_t = Hatching::default().spacing)]
+    hatch_spacing: f64,
+
+    /// The direction of the hatching lines in SVG files, in degrees counter-clockwise from
+    /// horizontal.
+    #[clap(long, default_value_t = Hatching::default().angle)]
+    hatch_angle: f64,
+
+    /// A font file, or a directory of them, to draw the text in SVG files with, as well as the
+    /// fonts installed on this computer. Can be given more than once.
+    #[clap(long)]
+    font: Vec<PathBuf>,
+
+    /// The font for text in SVG files that doesn't say which font it wants.
+    #[clap(long)]
+    font_family: Option<String>,
+
+    /// How to draw PNG and JPEG pictures: zigzag, spiral, or crosshatch.
+    #[clap(long, default_value_t = Style::Zigzag)]
+    halftone: Style,
+
+    /// How far apart the lines are in PNG and JPEG pictures. Smaller shows more detail, but
+    /// takes longer.
+    #[clap(long, default_value_t = Halftone::default().spacing)]
+    line_spacing: f64,
+
+    /// Before each stroke, wait until the brachiograph has room to queue up this many of the
+    /// stroke's ops, so that it doesn't stall with the pen down. Zero turns this off.
+    #[clap(long, default_value_t = 8)]
+    warm_up: usize,
+
+    /// Skip the first this-many ops, for resuming a plot that was interrupted.
+    #[clap(long, default_value_t = 0)]
+    resume_from: usize,
+
+    /// Keep pen-up moves away from anything drawn in the last this-many seconds, so that they
+    /// don't smudge wet ink. Zero turns this off.
+    #[clap(long, default_value_t = 0.0)]
+    drying_time: f64,
+
+    /// How far pen-up moves should keep from wet ink (see --drying-time). This has to be more
+    /// than zero.
+    #[clap(long, default_value_t = WetZone::default().clearance)]
+    wet_clearance: f64,
+
+    /// Plot SVG or G-code drawings that are taller than the page in bands, stopping after each
+    /// one so that the paper can be moved.
+    #[clap(long)]
+    tile: bool,
+
+    /// With --tile, draw registration crosses for checking that the paper was moved by the
+    /// right amount.
+    #[clap(long)]
+    tile_marks: bool,
+
+    /// With --tile, skip the first this-many bands.
+    #[clap(long, default_value_t = 0)]
+    start_band: usize,
+
+    /// If the emergency stop button was pressed, get going again (after checking that it has
+    /// been released).
+    #[clap(long)]
+    clear_estop: bool,
+
+    /// Start plotting a Logo program while it's still running, instead of working out the whole
+    /// drawing first. This is for huge drawings; there's no time estimate, and no way to resume.
+    #[clap(long)]
+    stream: bool,
+
+    /// Don't plot anything, but pretend to and say how it would go: how long it would take,
+    /// how far the pen would move, and where it would draw.
+    #[clap(long)]
+    dry_run: bool,
+
+    /// With --dry-run, save a picture of what would be drawn to this SVG file.
+    #[clap(long)]
+    preview: Option<PathBuf>,
+
+    /// Draw everything with the same pen. Otherwise, drawings with more than one color stop
+    /// before each change of color, so that the pen can be swapped.
+    #[clap(long)]
+    one_pen: bool,
+
+    /// Go back to the home position (wherever the brachiograph's configuration puts it) after
+    /// every this-many strokes, so that slipping servo horns don't knock long plots out of line.
+    /// Zero turns this off.
+    #[clap(long, default_value_t = 0)]
+    rezero_every: usize,
+
+    /// Split drawn lines longer than this into pieces, for firmware that doesn't keep long
+    /// moves straight by itself. Zero (the default) leaves them alone.
+    #[clap(long, default_value_t = 0.0)]
+    max_line: f64,
+
+    /// Let moves up to this long interpolate between joint angles instead of following their
+    /// lines exactly, which is less work for the firmware. Zero keeps every move exact; the
+    /// default comes from the profile.
+    #[clap(long)]
+    angle_interpolation: Option<f64>,
+
+    /// Before plotting, send the brachiograph the arm calibration in this file (as saved by
+    /// `feeder calibrate`, or a `.json` file from the Python BrachioGraph), instead of using
+    /// the one in its firmware.
+    #[clap(long)]
+    calibration: Option<PathBuf>,
+
+    /// Before plotting, send the brachiograph the pen duties for the pen named by --pen, from
+    /// this file (as saved by `feeder calibrate --pen`).
+    #[clap(long)]
+    pen_duties: Option<PathBuf>,
+
+    /// With --pen-duties, which pen is going to be drawing.
+    #[clap(long, default_value = "default", requires = "pen_duties")]
+    pen: String,
+
+    /// Sign the drawing with this (like your initials), written small in the bottom right-hand
+    /// corner of the drawing area.
+    #[clap(long)]
+    signature: Option<String>,
+
+    /// Keep the servos holding the arms still once the drawing is done, instead of letting
+    /// them relax.
+    #[clap(long)]
+    no_relax: bool,
+
+    /// Save everything that goes to and from the brachiograph to this file, for attaching to bug
+    /// reports.
+    #[clap(long)]
+    record_transport: Option<PathBuf>,
+
+    /// How many seconds to wait for the brachiograph to answer before giving up on it.
+    #[clap(long, default_value_t = DEFAULT_RESPONSE_TIMEOUT.as_secs_f64())]
+    response_timeout: f64,
+
+    /// Each time the brachiograph runs out of ops in the middle of a stroke (because this
+    /// computer didn't keep up), slow it down by this many percent. At 0 (the default) it
+    /// keeps going at the same speed.
+    #[clap(long, default_value_t = 0.0)]
+    auto_slowdown: f64,
 }
 
-struct Serial {
-    write: Box<dyn SerialPort>,
-    read: BufReader<Box<dyn SerialPort>>,
-}
-
-fn load_svg(path: &Path) -> anyhow::Result<Vec<BezPath>> {
-    // TODO: apparently git master usvg supports text-to-path?
-    let data = std::fs::read(path)?;
-    let opt = usvg::Options::default();
-    let tree = usvg::Tree::from_data(&data, &opt)?;
-    let mut ret = Vec::new();
-
-    for node in tree.root.descendants() {
-        let mut bez = BezPath::new();
-        if let usvg::NodeKind::Path(p) = &*node.borrow() {
-            // TODO: do we need to apply the transform in p.transform or has that been done
-            // already? FIXME: yes, I think we do need it
-            for seg in p.data.segments() {
-                match seg {
-                    usvg::PathSegment::MoveTo { x, y } => {
-                        let (x, y) = p.transform.apply(x, y);
-                        bez.move_to((x, y));
-                    }
-                    usvg::PathSegment::LineTo { x, y } => {
-                        let (x, y) = p.transform.apply(x, y);
-                        bez.line_to((x, y));
-                    }
-                    usvg::PathSegment::CurveTo {
-                        x1,
-                        y1,
-                        x2,
-                        y2,
-                        x,
-                        y,
-                    } => {
-                        let (x, y) = p.transform.apply(x, y);
-                        let (x1, y1) = p.transform.apply(x1, y1);
-                        let (x2, y2) = p.transform.apply(x2, y2);
-                        bez.curve_to((x1, y1), (x2, y2), (x, y));
-                    }
-                    usvg::PathSegment::ClosePath => bez.close_path(),
-                }
-            }
+impl Args {
+    // Works out which file to plot. Clap can't have an optional argument before a required one,
+    // so with --dry-run the only file name (which clap thinks is the serial port) is the input.
+    fn resolve_input(&mut self) -> anyhow::Result<()> {
+        if self.file.is_none() && self.dry_run {
+            self.file = self.tty.take().map(PathBuf::from);
         }
-        if !bez.is_empty() {
-            ret.push(bez);
+        self.input = self.file.clone().context("which file should be plotted?")?;
+        Ok(())
+    }
+
+    // The serial port that the brachiograph is on.
+    fn tty(&self) -> anyhow::Result<&str> {
+        self.tty
+            .as_deref()
+            .context("which serial port is the brachiograph on? (or use --dry-run)")
+    }
+
+    // How to finish off the drawing.
+    fn finish(&self) -> Finish {
+        Finish {
+            relax: !self.no_relax,
+            signature: self.signature.clone(),
+            ..Finish::default()
+        }
+    }
+
+    // The fonts for drawing text in SVG files.
+    fn fonts(&self) -> anyhow::Result<Fonts> {
+        let mut fonts = Fonts::system();
+        for path in &self.font {
+            fonts.load(path)?;
         }
+        fonts.family = self.font_family.clone();
+        Ok(fonts)
     }
-    Ok(ret)
 }
 
-// Transform each of the paths by a common scaling and translation,
-// so that the resulting paths all lie in `rect`.
-//
-// Also flips the y coordinate, because svg is y-down and brachiograph is y-up.
-fn transform(paths: &mut [BezPath], rect: Rect) {
-    if paths.is_empty() {
-        return;
+// Where Logo programs get drawn.
+const LOGO_RECT: Rect = Rect::new(-8.0, 5.0, 8.0, 13.0);
+
+// Send a single op element to brachiograph, blocking if necessary.
+fn send(serial: &mut Serial, op: Op) -> anyhow::Result<()> {
+    println!("{:?}", op);
+    match serial.send(op)? {
+        Resp::Ack => Ok(()),
+        Resp::Paused => bail!("the brachiograph has been plotting for too long, and paused itself"),
+        Resp::EStop => bail!(
+            "the emergency stop button was pressed; release it and try again with --clear-estop"
+        ),
+        resp => bail!("Unexpected response: {resp:?}"),
     }
-    let mut bbox = paths[0].bounding_box();
-    for p in &paths[1..] {
-        bbox = bbox.union(p.bounding_box());
+}
+
+// Ask the user how far the registration mark was from where it should have been.
+fn ask_for_offset() -> anyhow::Result<Vec2> {
+    println!(
+        "Measure how far the center of the cross is from your registration mark, \
+         and enter it as \"dx dy\" (or just press enter if it's spot on):"
+    );
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    let nums = line
+        .split_whitespace()
+        .map(|s| s.parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()?;
+    match nums[..] {
+        [] => Ok(Vec2::ZERO),
+        [dx, dy] => Ok(Vec2::new(dx, dy)),
+        _ => bail!("expected two numbers, got {line:?}"),
     }
-    let transform = Affine::FLIP_Y * Affine::translate(-bbox.center().to_vec2());
-    let scale = (rect.height() / bbox.height()).min(rect.width() / bbox.width());
-    let transform = Affine::scale(scale) * transform;
-    let transform = Affine::translate(rect.center().to_vec2()) * transform;
-    for path in paths {
-        path.apply_affine(transform);
+}
+
+// Prints a table of how much each layer of the job costs, so that it's easier to tell which
+// parts of a drawing are worth simplifying.
+fn print_layer_stats(job: &Job, settings: &Settings) {
+    let layers = job.layer_stats(settings);
+    let width = layers
+        .iter()
+        .map(|l| l.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(5);
+    println!(
+        "{:width$}  {:>8}  {:>8}  {:>6}  {:>8}",
+        "layer", "drawing", "moving", "pen", "time"
+    );
+    for layer in layers {
+        let stats = layer.stats;
+        println!(
+            "{:width$}  {:>8.1}  {:>8.1}  {:>6}  {:>7.0}s",
+            layer.name,
+            stats.draw_distance,
+            stats.travel_distance,
+            stats.pen_ups + stats.pen_downs,
+            layer.duration.as_secs_f64()
+        );
     }
 }
 
-// TODO: in the case of short (in terms of arc-length) sequences of segments, it might be
-// worth just converting them to a single line.
-fn flatten(path: &BezPath) -> BezPath {
-    if path.elements().len() <= 1 {
-        return BezPath::new();
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Calibrate(args)) => return calibrate::run(args),
+        Some(Command::CheckCalibration(args)) => return calibrate::check(args),
+        Some(Command::Repl(args)) => return repl::run(args),
+        Some(Command::AccuracyGrid(args)) => return accuracy::draw_grid(args),
+        Some(Command::CheckAccuracy(args)) => return accuracy::check(args),
+        None => {}
     }
-    let mut start = (0., 0.).into();
-    let mut ret = BezPath::new();
-    path.flatten(0.05, |el| match el {
-        PathEl::LineTo(_) => ret.push(el),
-        PathEl::MoveTo(p) => {
-            start = p;
-            ret.push(el);
-        }
-        PathEl::ClosePath => ret.push(PathEl::LineTo(start)),
-        _ => unreachable!(),
-    });
-    ret
-}
-
-fn load_logo(path: &Path) -> anyhow::Result<Vec<brachiologo::BuiltIn>> {
-    let data = std::fs::read_to_string(path)?;
-    let program = brachiologo::program(&data)
-        .map_err(|e| anyhow!("parse error: {e}"))?
-        .1;
-    let mut scope = brachiologo::Scope::default();
-    let mut output = Vec::new();
-    scope.exec_block(&mut output, &program)?;
-    Ok(output)
-}
-
-fn run_turtle(steps: &[brachiologo::BuiltIn], rect: Rect) -> Vec<Op> {
-    let mut pos = rect.center();
-    let mut angle = Angle::from_degrees(90);
-    let mut ret = Vec::new();
-
-    let clamp = |pt: Point| {
-        Point::new(
-            pt.x.clamp(rect.min_x(), rect.max_x()),
-            pt.y.clamp(rect.min_y(), rect.max_y()),
-        )
+    let Some(mut args) = cli.plot else {
+        bail!("nothing to plot (see --help)");
+    };
+    args.resolve_input()?;
+    let profile = args.profile.settings();
+    let settings = Settings {
+        max_line: args.max_line,
+        corner_dwell: args
+            .corner_dwell
+            .map_or(profile.corner_dwell, Duration::from_millis),
+        angle_interpolation: match args.angle_interpolation {
+            Some(len) if len <= 0.0 => None,
+            Some(len) => Some(len),
+            None => profile.angle_interpolation,
+        },
+        ..profile
     };
+    if args.drying_time > 0.0 && !(args.wet_clearance.is_finite() && args.wet_clearance > 0.0) {
+        bail!("--wet-clearance should be a positive distance");
+    }
+    if args.dry_run {
+        if args.register
+            || args.tile
+            || args.stream
+            || args.clear_estop
+            || args.record_transport.is_some()
+        {
+            bail!(
+                "--dry-run doesn't work with --register, --tile, --stream, --clear-estop or \
+                 --record-transport"
+            );
+        }
+        // The simulator draws on the default brachiograph's page.
+        let job = load_job(&args, &settings, &geom::Config::default())?;
+        print_stats(&job, &settings);
+        return dry_run(&job, &args, &settings);
+    }
+    let response_timeout = Duration::try_from_secs_f64(args.response_timeout)
+        .ok()
+        .filter(|timeout| !timeout.is_zero())
+        .context("--response-timeout should be a positive number of seconds")?;
+    let mut serial = Serial::open(args.tty()?, Transport::Postcard)?;
+    serial.set_response_timeout(response_timeout);
+    if !(0.0..100.0).contains(&args.auto_slowdown) {
+        bail!("--auto-slowdown should be at least 0 and less than 100");
+    }
+    if args.auto_slowdown > 0.0 {
+        serial.set_auto_slowdown(Some(1.0 - args.auto_slowdown / 100.0));
+    }
+    if let Some(path) = &args.record_transport {
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("failed to create {}", path.display()))?;
+        serial = serial.with_transcript(file);
+    }
+    if let Some(calib) = load_calibration(&args)? {
+        serial.upload_calibration(&calib)?;
+    }
+    if let Some(op) = pen_duty_op(&args)? {
+        send(&mut serial, op)?;
+    }
+    let info = serial.info()?;
+    println!(
+        "Firmware {} (built {}), calibration checksum {:08x}",
+        info.git_hash, info.build_date, info.calibration_crc
+    );
+    if args.clear_estop {
+        serial.clear_estop()?;
+    }
+    if args.register {
+        let offset = registration::register(&mut serial, Point::new(0., 9.), ask_for_offset)?;
+        println!("Shifting the plot by {:?}", offset.0);
+    }
 
-    for step in steps {
-        match step {
-            brachiologo::BuiltIn::Forward(dist) => {
-                pos += Vec2::from_angle(angle.radians().to_num()) * *dist;
-                ret.push(p_to_op(clamp(pos)));
-            }
-            brachiologo::BuiltIn::Back(dist) => {
-                pos -= Vec2::from_angle(angle.radians().to_num()) * *dist;
-                ret.push(p_to_op(clamp(pos)));
-            }
-            brachiologo::BuiltIn::Left(ang) => {
-                angle += Angle::from_degrees(*ang);
-            }
-            brachiologo::BuiltIn::Right(ang) => {
-                angle += Angle::from_degrees(*ang);
-            }
-            brachiologo::BuiltIn::ClearScreen => {}
-            brachiologo::BuiltIn::PenUp => {
-                ret.push(Op::PenUp);
-            }
-            brachiologo::BuiltIn::PenDown => {
-                ret.push(Op::PenDown);
-            }
+    if args.tile {
+        let paths = load_paths(&args.input, &args.fonts()?)?;
+        let tiled = tile::tile(&paths, svg::DEFAULT_RECT, &settings, args.tile_marks);
+        return plot_tiled(&mut serial, &tiled, &args, &settings);
+    }
+
+    let ext = args.input.extension().and_then(|s| s.to_str());
+    if args.stream {
+        if ext != Some("logo") {
+            bail!("only Logo programs can be streamed");
         }
+        return plot_stream(&mut serial, &args, &settings);
     }
+    let config = serial.config()?;
+    let job = load_job(&args, &settings, &config)?;
+    print_stats(&job, &settings);
 
-    ret
+    for op in settings.ops() {
+        send(&mut serial, op)?;
+    }
+    let started = Instant::now();
+    if let Err(e) = plot_from(&mut serial, &job, args.resume_from, args.warm_up) {
+        let stopped = e.sent;
+        println!("Stopped at op {stopped}; use --resume-from {stopped} to continue");
+        return Err(e.error);
+    }
+    finish(&mut serial, &args)?;
+    if let Some(report) = args.finish().report(&job.stats(), started.elapsed()) {
+        println!("{report}");
+    }
+    Ok(())
 }
 
-fn to_ops(path: &BezPath) -> Vec<Op> {
-    let mut ret = Vec::new();
+// Reads the calibration file, if there is one.
+fn load_calibration(args: &Args) -> anyhow::Result<Option<ArmCalibration>> {
+    args.calibration
+        .as_deref()
+        .map(ArmCalibration::load)
+        .transpose()
+}
 
-    for el in path {
-        match el {
-            PathEl::MoveTo(p) => {
-                ret.push(Op::PenUp);
-                ret.push(p_to_op(p));
-                ret.push(Op::PenDown);
-            }
-            PathEl::LineTo(p) => ret.push(p_to_op(p)),
-            _ => unreachable!(),
+// Reads the pen duties file, if there is one, and makes the op for the chosen pen.
+fn pen_duty_op(args: &Args) -> anyhow::Result<Option<Op>> {
+    args.pen_duties
+        .as_deref()
+        .map(|path| PenDuties::load(path)?.op(&args.pen))
+        .transpose()
+}
+
+// Reads the input file and turns it into a job for a brachiograph with the configuration
+// `config`.
+fn load_job(args: &Args, settings: &Settings, config: &geom::Config) -> anyhow::Result<Job> {
+    let ext = args.input.extension().and_then(|s| s.to_str());
+    let job: Job = if ext == Some("svg") {
+        let data = std::fs::read(&args.input)?;
+        // TODO: make the rect configurable
+        let rect = svg::DEFAULT_RECT;
+        let hatching = Hatching {
+            spacing: args.hatch_spacing,
+            angle: args.hatch_angle,
+        };
+        let fonts = args.fonts()?;
+        if args.curves {
+            svg::to_curve_job(&data, rect, &hatching, &fonts)?
+        } else {
+            svg::to_job(&data, rect, &hatching, &fonts, settings)?
+        }
+    } else if matches!(ext, Some("gcode" | "nc" | "ngc" | "gc")) {
+        let text = std::fs::read_to_string(&args.input)?;
+        let rect = svg::DEFAULT_RECT;
+        if args.curves {
+            gcode::to_curve_job(&text, rect)?
+        } else {
+            gcode::to_job(&text, rect, settings)?
         }
+    } else if ext == Some("logo") {
+        let code = std::fs::read_to_string(&args.input)?;
+        let planner = Planner {
+            smooth: args.smooth,
+            ..Planner::from_settings(settings)
+        };
+        planner.plan_logo(&code, LOGO_RECT)?.into()
+    } else if matches!(ext, Some("png" | "jpg" | "jpeg")) {
+        let photo = accuracy::load_photo(&args.input)?;
+        let halftone = Halftone {
+            style: args.halftone,
+            spacing: args.line_spacing,
+        };
+        raster::to_job(&photo, svg::DEFAULT_RECT, &halftone, settings)?
+    } else if ext == Some("txt") {
+        let text = std::fs::read_to_string(&args.input)?;
+        Planner::from_settings(settings)
+            .plan_text(text.trim_end(), svg::DEFAULT_RECT)
+            .into()
+    } else {
+        bail!("didn't recognize input file type");
+    };
+    let job = job.with_name(args.input.display().to_string());
+    let job = if args.one_pen {
+        job
+    } else {
+        job.with_pen_changes()
+    };
+    let job = job.with_rezeroing(args.rezero_every);
+    dry(job, args, settings, config)
+}
+
+// Says how big the job is, and how long it should take.
+fn print_stats(job: &Job, settings: &Settings) {
+    let stats = job.stats();
+    println!(
+        "{} ops, drawing {:.1} and moving {:.1}; this should take about {:?}",
+        job.len(),
+        stats.draw_distance,
+        stats.travel_distance,
+        job.estimate_duration(settings)
+    );
+    if job.layers().len() > 1 {
+        print_layer_stats(job, settings);
+    }
+    let pen_changes = job.iter().filter(|op| matches!(op, Op::Pause(_))).count();
+    if pen_changes > 0 {
+        println!("The pen will need changing {pen_changes} times (use --one-pen to skip that)");
     }
-    ret
 }
 
-// Send a single op element to brachiograph, blocking if necessary.
-fn send(serial: &mut Serial, op: Op) -> anyhow::Result<()> {
-    println!("{:?}", op);
-    loop {
-        let msg = postcard::to_stdvec_cobs(&op)?;
-        serial.write.write_all(&msg)?;
-
-        let mut read = serial.read.fill_buf()?.to_vec();
-        let (msg, remaining) = postcard::take_from_bytes_cobs(&mut read)?;
-        let remaining_len = remaining.len();
-        drop(remaining);
-        serial.read.consume(read.len() - remaining_len);
-        match dbg!(msg) {
-            Resp::Ack => break,
-            Resp::QueueFull => {
-                std::thread::sleep(std::time::Duration::from_millis(500));
-                continue;
-            }
-            resp => bail!("Unexpected response: {resp:?}"),
+// Plots the job on a simulator instead of the brachiograph, and reports what happened.
+fn dry_run(job: &Job, args: &Args, settings: &Settings) -> anyhow::Result<()> {
+    let mut sim = Simulator::new();
+    let expect_ack = |sim: &mut Simulator, op: &Op| match sim.send(op.clone()) {
+        Resp::Ack => Ok(()),
+        resp => Err(anyhow::anyhow!("{op:?} would fail with {resp:?}")),
+    };
+    if let Some(calib) = load_calibration(args)? {
+        for op in calib.ops()? {
+            expect_ack(&mut sim, &op)?;
         }
     }
+    if let Some(op) = pen_duty_op(args)? {
+        expect_ack(&mut sim, &op)?;
+    }
+    for op in settings.ops() {
+        expect_ack(&mut sim, &op)?;
+    }
+    for (i, op) in job.ops().iter().enumerate() {
+        if let Err(e) = expect_ack(&mut sim, op) {
+            return Err(e.context(format!("the brachiograph would stop at op {i}")));
+        }
+    }
+    let Resp::Config(config) = sim.send(Op::GetConfig) else {
+        bail!("the simulator didn't say what its drawing area is");
+    };
+    for op in args.finish().ops(&config) {
+        expect_ack(&mut sim, &op)?;
+    }
 
+    let drawing = sim.drawing();
+    println!(
+        "Plotting would take {:?}, drawing {:.1} and moving {:.1} with the pen up",
+        sim.elapsed(),
+        drawing.perimeter(0.01),
+        sim.travel_distance()
+    );
+    if drawing.elements().is_empty() {
+        println!("Nothing would be drawn");
+    } else {
+        let bbox = drawing.bounding_box();
+        println!(
+            "The drawing would cover x from {:.2} to {:.2}, and y from {:.2} to {:.2}",
+            bbox.x0, bbox.x1, bbox.y0, bbox.y1
+        );
+    }
+    if let Some(path) = &args.preview {
+        std::fs::write(path, sim.to_svg())?;
+        println!("Saved a preview to {}", path.display());
+    }
     Ok(())
 }
 
-fn p_to_op(p: impl Into<Point>) -> Op {
-    let p = p.into();
-    Op::MoveTo(brachiograph::Point {
-        x: Fixed::from_num(p.x),
-        y: Fixed::from_num(p.y),
-    })
+// Finishes off the drawing (see `Args::finish`) once the brachiograph has had everything else,
+// saying how long that will take, and reports anything that had to be changed to get the ops
+// through.
+fn finish(serial: &mut Serial, args: &Args) -> anyhow::Result<()> {
+    // Older firmware can't say how long it has left.
+    if let Ok(left) = serial.time_left() {
+        println!(
+            "Sent everything; it should be done in about {:.0}s",
+            left.as_secs_f64()
+        );
+    }
+    serial.finish(&args.finish())?;
+    for entry in serial.journal() {
+        match entry {
+            JournalEntry::Nudged { from, to } => {
+                println!("Moved {from:?} to {to:?} because the brachiograph refused it");
+            }
+            JournalEntry::SlowedDown { speed } => {
+                println!("Slowed down to {speed:.2} because the brachiograph ran out of ops");
+            }
+            _ => {}
+        }
+    }
+    Ok(())
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let serial = serialport::new(&args.tty, 9600)
-        .timeout(std::time::Duration::from_secs(60))
-        .open()?;
-    let mut serial = Serial {
-        read: BufReader::with_capacity(128, serial.try_clone().unwrap()),
-        write: serial,
+// Plots a Logo program while it's still running.
+fn plot_stream(serial: &mut Serial, args: &Args, settings: &Settings) -> anyhow::Result<()> {
+    let code = std::fs::read_to_string(&args.input)?;
+    let planner = Planner {
+        smooth: args.smooth,
+        ..Planner::from_settings(settings)
     };
+    let ops = planner.stream_logo(&code, LOGO_RECT)?;
+    for op in settings.ops() {
+        send(serial, op)?;
+    }
+    // If the program fails, keep what it drew before that and lift the pen.
+    let mut error = None;
+    let sent = serial.send_stream(ops.map_while(|op| op.map_err(|e| error = Some(e)).ok()));
+    if let Err(e) = sent {
+        println!("Stopped at op {}", e.sent);
+        return Err(e.error);
+    }
+    finish(serial, args)?;
+    error.map_or(Ok(()), Err)
+}
 
-    let ext = args.input.extension().and_then(|s| s.to_str());
-    let ops = if ext == Some("svg") {
-        let mut paths = load_svg(&args.input)?;
-        // TODO: make the rect configurable
-        transform(&mut paths, Rect::new(-8.0, 5.0, 8.0, 13.0));
-        paths
-            .iter()
-            .map(flatten)
-            .flat_map(|bez| to_ops(&bez).into_iter())
-            .collect()
-    } else if ext == Some("logo") {
-        let turtle = load_logo(&args.input)?;
-        send(&mut serial, p_to_op((0., 9.)))?;
-        send(&mut serial, Op::PenDown)?;
-        run_turtle(&turtle, Rect::new(-8.0, 5.0, 8.0, 13.0))
+// Keeps the job's pen-up moves away from wet ink, if asked to.
+fn dry(job: Job, args: &Args, settings: &Settings, config: &geom::Config) -> anyhow::Result<Job> {
+    if args.drying_time > 0.0 {
+        let zone = WetZone {
+            clearance: args.wet_clearance,
+            drying_time: Duration::from_secs_f64(args.drying_time),
+        };
+        wet::route_around(&job, &zone, settings, config)
     } else {
-        bail!("didn't recognize input file type");
-    };
-    for op in ops {
-        send(&mut serial, op)?;
+        Ok(job)
     }
-    send(&mut serial, Op::PenUp)?;
-    send(&mut serial, p_to_op((-8., 8.)))?;
+}
 
+// Plots `job`, starting from op number `start`. If something goes wrong, the error says which
+// op to resume from.
+fn plot_from(
+    serial: &mut Serial,
+    job: &Job,
+    start: usize,
+    warm_up: usize,
+) -> Result<(), BatchError> {
+    let total = job.len();
+    let start = start.min(total);
+    let job = if start > 0 {
+        job.resume_from(start)
+    } else {
+        job.clone()
+    };
+    // Resuming adds a few ops at the beginning to get the pen into position.
+    let prefix = job.len() - (total - start);
+    let ops = job.ops();
+    show_progress(serial, ops.len());
+    for piece in pieces(&job) {
+        let i = piece.start;
+        let sent = if let Op::Pause(reason) = ops[i] {
+            change_pen(serial, &job, i, reason).map_err(|error| BatchError { sent: 0, error })
+        } else {
+            let warmed = match job.stroke_len(i) {
+                Some(len) if warm_up > 0 => serial.warm_up(len.min(warm_up)),
+                _ => Ok(()),
+            };
+            warmed
+                .map_err(|error| BatchError { sent: 0, error })
+                .and_then(|_| serial.send_batch(&ops[piece]))
+        };
+        if let Err(e) = sent {
+            return Err(BatchError {
+                sent: start + (i + e.sent).saturating_sub(prefix),
+                error: e.error,
+            });
+        }
+    }
     Ok(())
 }
+
+// Splits a job into the pieces that `plot_from` sends: one stroke at a time, so that we can warm
+// up the queue before each stroke. Pen changes are pieces of their own, because we have to wait
+// for the brachiograph to get to them.
+fn pieces(job: &Job) -> Vec<Range<usize>> {
+    let ops = job.ops();
+    let mut pieces = Vec::new();
+    let mut i = 0;
+    while i < ops.len() {
+        let end = if matches!(ops[i], Op::Pause(_)) {
+            i + 1
+        } else {
+            (i + 1..ops.len())
+                .find(|&j| job.stroke_len(j).is_some() || matches!(ops[j], Op::Pause(_)))
+                .unwrap_or(ops.len())
+        };
+        pieces.push(i..end);
+        i = end;
+    }
+    pieces
+}
+
+// Says how much of the `total` ops that we're about to send have been drawn, every time that
+// another percent gets done. Older firmware can't say, so then this stays quiet.
+fn show_progress(serial: &mut Serial, total: usize) {
+    let Ok(start) = serial.progress() else {
+        return;
+    };
+    // Anything that's already in the queue isn't ours.
+    let start = start.ops_done.wrapping_add(start.queue_len.into());
+    let mut shown = None;
+    serial.on_progress(move |progress| {
+        // Until the ops that were there before us are done, this is negative.
+        let done = progress.ops_done.wrapping_sub(start) as i32;
+        let done = usize::try_from(done).unwrap_or(0).min(total);
+        let percent = 100 * done / total.max(1);
+        if shown != Some(percent) {
+            shown = Some(percent);
+            println!("drawn {done} of {total} ops ({percent}%)");
+        }
+    });
+}
+
+// Waits for the brachiograph to finish everything before the pause at op number `index`, and
+// then for someone to put in the pen that comes next.
+fn change_pen(
+    serial: &mut Serial,
+    job: &Job,
+    index: usize,
+    reason: PauseReason,
+) -> anyhow::Result<()> {
+    serial.pause(reason)?;
+    let layer = job.layer_at(index);
+    let pen = match reason {
+        PauseReason::ChangePen(n) => format!("pen {}", usize::from(n) + 1),
+        PauseReason::Other => "the next pen".to_owned(),
+    };
+    let color = layer
+        .and_then(|l| l.pen.as_deref())
+        .map_or(String::new(), |c| format!(" ({c})"));
+    let name = layer.map_or(String::new(), |l| format!(" for {}", l.name));
+    println!("Put in {pen}{color}{name}, then press enter");
+    std::io::stdin().read_line(&mut String::new())?;
+    serial.resume()
+}
+
+// Reads the drawing in an SVG or G-code file for tiling, y-up but not yet scaled.
+fn load_paths(input: &Path, fonts: &Fonts) -> anyhow::Result<Vec<BezPath>> {
+    let ext = input.extension().and_then(|s| s.to_str());
+    if ext == Some("svg") {
+        let mut paths = svg::load(input, fonts)?;
+        for p in &mut paths {
+            p.apply_affine(Affine::FLIP_Y);
+        }
+        Ok(paths)
+    } else if matches!(ext, Some("gcode" | "nc" | "ngc" | "gc")) {
+        Ok(vec![gcode::parse(&std::fs::read_to_string(input)?)?])
+    } else {
+        bail!("only SVG and G-code files can be tiled");
+    }
+}
+
+// Plots a tiled drawing one band at a time, waiting for the paper to be moved in between.
+fn plot_tiled(
+    serial: &mut Serial,
+    tiled: &Tiled,
+    args: &Args,
+    settings: &Settings,
+) -> anyhow::Result<()> {
+    let count = tiled.bands.len();
+    println!(
+        "Plotting in {count} bands, moving the paper by {:.2} between them",
+        tiled.advance
+    );
+    for op in settings.ops() {
+        send(serial, op)?;
+    }
+    let config = serial.config()?;
+    for (i, band) in tiled.bands.iter().enumerate().skip(args.start_band) {
+        if i > args.start_band {
+            send(serial, Op::Park)?;
+            serial.wait_until_idle()?;
+            println!(
+                "Finished band {i} of {count}. Move the paper {:.2} away from the brachiograph{}, \
+                 then press enter",
+                tiled.advance,
+                if args.tile_marks {
+                    " (the crosses will be drawn again, on top of the old ones if it's right)"
+                } else {
+                    ""
+                }
+            );
+            std::io::stdin().read_line(&mut String::new())?;
+        }
+        // Resuming only makes sense in the band that we stopped in.
+        let start = if i == args.start_band {
+            args.resume_from
+        } else {
+            0
+        };
+        let band = dry(band.clone(), args, settings, &config)?;
+        if let Err(e) = plot_from(serial, &band, start, args.warm_up) {
+            let stopped = e.sent;
+            println!(
+                "Stopped at op {stopped} of band {}; use --start-band {i} --resume-from {stopped} \
+                 to continue",
+                i + 1
+            );
+            return Err(e.error);
+        }
+    }
+    finish(serial, args)
+}
+
+#[cfg(test)]
+mod tests {
+    use brachiograph::{Fixed, Point};
+    use brachiograph_host::Layer;
+
+    use super::*;
+
+    fn mv(x: i32) -> Op {
+        Op::MoveTo(Point {
+            x: Fixed::from_num(x),
+            y: Fixed::from_num(10),
+        })
+    }
+
+    #[test]
+    fn pieces_after_a_pen_change() {
+        let ops = vec![
+            Op::PenUp,
+            mv(0),
+            Op::PenDown,
+            mv(1),
+            Op::PenUp,
+            mv(2),
+            Op::PenDown,
+            mv(3),
+        ];
+        let layer = |name: &str, start, pen: &str| Layer {
+            name: name.into(),
+            start,
+            pen: Some(pen.into()),
+        };
+        let job = Job::new(ops)
+            .with_layers(vec![layer("black", 0, "black"), layer("red", 4, "red")])
+            .with_pen_changes();
+        let pieces = pieces(&job);
+        // Every op gets sent, in order.
+        assert_eq!(pieces.first().unwrap().start, 0);
+        assert_eq!(pieces.last().unwrap().end, job.len());
+        assert!(pieces.windows(2).all(|w| w[0].end == w[1].start));
+
+        // The pen change is on its own, and the red layer starts by getting into position.
+        let pause = pieces
+            .iter()
+            .position(|p| matches!(job.ops()[p.start], Op::Pause(_)))
+            .unwrap();
+        assert_eq!(pieces[pause].len(), 1);
+        assert_eq!(job.ops()[pieces[pause + 1].clone()], [Op::PenUp, mv(2)]);
+        assert_eq!(job.ops()[pieces[pause + 2].clone()], [Op::PenDown, mv(3)]);
+    }
+}
@@ -0,0 +1,96 @@
+//! The `repl` subcommand, for drawing Logo programs as they get typed in.
+
+use brachiograph_host::{finish::Finish, profile::Profile, Serial, Transport};
+use brachiograph_plan::Planner;
+use brachiologo::{repl::Repl, Env, TurtleCmd};
+
+/// Reads Logo a line at a time, and draws what each line draws as soon as it has been typed in.
+///
+/// Procedures and variables stick around from one line to the next. The pen gets lifted at the
+/// end of each line, while the next one is being typed.
+#[derive(clap::Args, Debug)]
+pub struct ReplArgs {
+    /// The serial port that the brachiograph is on.
+    tty: String,
+
+    /// How to trade plotting time for quality: draft, normal, or fine.
+    #[clap(long, default_value_t = Profile::Normal)]
+    profile: Profile,
+
+    /// Smooth out curves that were drawn with lots of little steps.
+    #[clap(long)]
+    smooth: bool,
+}
+
+// Where the turtle was when a line started, so that the line's drawing can carry on from there.
+struct Start {
+    pos: (f64, f64),
+    heading: f64,
+    pen_down: bool,
+}
+
+impl Start {
+    // The turtle commands that get a fresh turtle to here, without drawing anything.
+    fn steps(&self) -> Vec<TurtleCmd> {
+        let (x, y) = self.pos;
+        let mut steps = vec![
+            TurtleCmd::PenUp,
+            TurtleCmd::SetPos { x, y },
+            TurtleCmd::SetHeading(self.heading),
+        ];
+        if self.pen_down {
+            steps.push(TurtleCmd::PenDown);
+        }
+        steps
+    }
+}
+
+pub fn run(args: ReplArgs) -> anyhow::Result<()> {
+    let settings = args.profile.settings();
+    let planner = Planner {
+        smooth: args.smooth,
+        ..Planner::from_settings(&settings)
+    };
+    let mut serial = Serial::open(&args.tty, Transport::Postcard)?;
+    for op in settings.ops() {
+        crate::send(&mut serial, op)?;
+    }
+
+    let rect = crate::LOGO_RECT;
+    let mut repl = Repl::new(Env {
+        input: Some(Box::new(|| {
+            let mut line = String::new();
+            match std::io::stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => None,
+                Ok(_) => Some(line),
+            }
+        })),
+        canvas_size: (rect.width(), rect.height()),
+        ..Env::default()
+    });
+    let mut start = Start {
+        pos: (0.0, 0.0),
+        heading: 0.0,
+        pen_down: true,
+    };
+    repl.run_stdin(|env, turtle| {
+        let mut steps = start.steps();
+        steps.extend_from_slice(turtle);
+        steps.push(TurtleCmd::PenUp);
+        let ops = planner.plan_turtle(&steps, rect);
+        serial.send_stream(ops).map_err(|e| e.error)?;
+
+        let pen_down = turtle.iter().rev().find_map(|cmd| match cmd {
+            TurtleCmd::PenUp => Some(false),
+            TurtleCmd::PenDown => Some(true),
+            _ => None,
+        });
+        start = Start {
+            pos: env.turtle_pos,
+            heading: env.turtle_heading,
+            pen_down: pen_down.unwrap_or(start.pen_down),
+        };
+        anyhow::Ok(())
+    })?;
+    serial.finish(&Finish::default())
+}
@@ -3,94 +3,30 @@
 
 //#![cfg_attr(target_os = "windows", windows_subsystem = "windows")]
 
-use std::{
-    cell::RefCell,
-    io::{BufRead, BufReader},
-    sync::Arc,
+use std::{cell::RefCell, rc::Rc, sync::Arc};
+
+use anyhow::bail;
+use brachiograph::{Op, Resp};
+use brachiograph_host::{
+    finish::Finish,
+    lod::{self, Lod},
+    path, Serial, Transport,
 };
-
-use anyhow::{anyhow, bail};
-use brachiograph::Angle;
-use brachiologo::Program;
 use dioxus::prelude::*;
 use dioxus_desktop::{
     tao::menu::{MenuBar, MenuItem},
     Config, WindowBuilder,
 };
-use kurbo::{Point, Rect, Vec2};
-use serialport::{SerialPort, SerialPortType};
-
-const VENDOR_ID: u16 = 0xca6d;
-const PRODUCT_ID: u16 = 0xba6d;
-
-fn detect_port() -> Option<Box<dyn SerialPort>> {
-    let ports = serialport::available_ports().ok()?;
-    for port in ports {
-        let SerialPortType::UsbPort(usb_info) = port.port_type else {
-            continue;
-        };
-        log::debug!("found usbserial port {usb_info:?}");
-
-        if usb_info.vid == VENDOR_ID && usb_info.pid == PRODUCT_ID {
-            match serialport::new(&port.port_name, 9600)
-                // I'm not completely sure what the implications of this timeout value are,
-                // but on linux read_line returns immediately, while on windows it doesn't
-                // return until the timeout is up. So keep the timeout small.
-                .timeout(std::time::Duration::from_millis(50))
-                .open()
-            {
-                Ok(port) => {
-                    return Some(port);
-                }
-                Err(e) => {
-                    log::warn!("failed to open port '{}': {}", port.port_name, e);
-                }
-            }
-        } else {
-            log::info!("skipping usb-serial {:?}", usb_info);
-        }
-    }
-
-    None
-}
+use kurbo::{Point, Rect};
 
-struct Serial {
-    write: Box<dyn SerialPort>,
-    read: BufReader<Box<dyn SerialPort>>,
-}
-
-// Send a single op element to brachiograph, blocking if necessary.
-fn send(serial: &mut Serial, op: Op) -> anyhow::Result<()> {
-    log::debug!("{:?}", op);
-    let mut resp = String::new();
-    loop {
-        match op {
-            Op::PenDown => {
-                writeln!(&mut serial.write, "pendown")?;
-            }
-            Op::PenUp => {
-                writeln!(&mut serial.write, "penup")?;
-            }
-            Op::MoveTo { x, y } => {
-                writeln!(&mut serial.write, "moveto {x} {y}")?;
-            }
-        }
+// Where Logo programs get drawn.
+const LOGO_RECT: Rect = Rect::new(-8., 5., 8., 13.);
 
-        resp.clear();
-        serial.read.read_line(&mut resp)?;
-        log::debug!("read {resp:?}");
-        match resp.trim() {
-            "ack" => break,
-            "queue full" => {
-                std::thread::sleep(std::time::Duration::from_millis(500));
-                continue;
-            }
-            resp => bail!("Unexpected response: {resp:?}"),
-        }
-    }
+// How many pixels there are per brachiograph unit in the preview, before zooming in.
+const PREVIEW_SCALE: f64 = 40.0;
 
-    Ok(())
-}
+// How far the preview can be zoomed in.
+const MAX_ZOOM: f64 = 64.0;
 
 struct Inner {
     port: Option<Serial>,
@@ -98,18 +34,17 @@ struct Inner {
 
 impl Default for Inner {
     fn default() -> Inner {
-        let serial = detect_port();
-        let serial = serial.map(|s| {
-            log::info!(
-                "opened serial port with flow control {:?}",
-                s.flow_control()
-            );
-            Serial {
-                read: BufReader::with_capacity(128, s.try_clone().unwrap()),
-                write: s,
-            }
-        });
-        Inner { port: serial }
+        Inner {
+            port: Serial::detect_with(Transport::TextLine),
+        }
+    }
+}
+
+// Send a single op element to brachiograph, blocking if necessary.
+fn send(serial: &mut Serial, op: Op) -> anyhow::Result<()> {
+    match serial.send(op)? {
+        Resp::Ack => Ok(()),
+        resp => bail!("Unexpected response: {resp:?}"),
     }
 }
 
@@ -123,13 +58,10 @@ impl State {
         let ops = interpret(code)?;
         let mut serial = self.inner.borrow_mut();
         if let Some(serial) = &mut serial.port {
-            send(serial, Op::MoveTo { x: 0, y: 90 })?;
-            send(serial, Op::PenDown)?;
             for op in ops {
                 send(serial, op)?;
             }
-            send(serial, Op::PenUp)?;
-            send(serial, Op::MoveTo { x: -80, y: 80 })?;
+            serial.finish(&Finish::default())?;
         }
 
         Ok(())
@@ -153,77 +85,18 @@ impl State {
     }
 }
 
-#[derive(Debug)]
-enum Op {
-    PenUp,
-    PenDown,
-    MoveTo { x: i32, y: i32 },
+fn interpret(code: &str) -> anyhow::Result<Vec<Op>> {
+    brachiograph_plan::plan_logo(code, LOGO_RECT)
 }
 
-fn interpret<'input>(code: &'input str) -> anyhow::Result<Vec<Op>> {
-    let program: Program<'input> = Program::parse(code).map_err(|e| anyhow!("parse error: {e}"))?;
-    let steps = program.exec().map_err(|e| anyhow!("interp error: {e}"))?;
-
-    let rect = Rect::new(-80., 50., 80., 130.);
-    let mut pos = rect.center();
-    let mut angle = Angle::from_degrees(90);
-    let mut ret = Vec::new();
-
-    let clamp = |pt: Point| {
-        (
-            pt.x.clamp(rect.min_x(), rect.max_x()).round() as i32,
-            pt.y.clamp(rect.min_y(), rect.max_y()).round() as i32,
-        )
-    };
-
-    for step in steps {
-        match step {
-            brachiologo::BuiltIn::Arc { degrees, radius } => {
-                // Arc does not move the turtle or change the heading.
-                let start = pos + Vec2::from_angle(angle.radians().to_num()) * radius;
-                let (x, y) = clamp(start);
-                ret.push(Op::PenUp);
-                ret.push(Op::MoveTo { x, y });
-                ret.push(Op::PenDown);
-                for i in (0..=(degrees as i32)).step_by(10) {
-                    // Arc goes clockwise
-                    let angle = angle - Angle::from_degrees(i);
-                    let p = pos + Vec2::from_angle(angle.radians().to_num()) * radius;
-                    let (x, y) = clamp(p);
-                    ret.push(Op::MoveTo { x, y });
-                }
-                let (x, y) = clamp(pos);
-                ret.push(Op::PenUp);
-                ret.push(Op::MoveTo { x, y });
-                ret.push(Op::PenDown);
-            }
-            brachiologo::BuiltIn::Forward(dist) => {
-                pos += Vec2::from_angle(angle.radians().to_num()) * dist;
-                let (x, y) = clamp(pos);
-                ret.push(Op::MoveTo { x, y });
-            }
-            brachiologo::BuiltIn::Back(dist) => {
-                pos -= Vec2::from_angle(angle.radians().to_num()) * dist;
-                let (x, y) = clamp(pos);
-                ret.push(Op::MoveTo { x, y });
-            }
-            brachiologo::BuiltIn::Left(ang) => {
-                angle += Angle::from_degrees(ang);
-            }
-            brachiologo::BuiltIn::Right(ang) => {
-                angle += Angle::from_degrees(ang);
-            }
-            brachiologo::BuiltIn::ClearScreen => {}
-            brachiologo::BuiltIn::PenUp => {
-                ret.push(Op::PenUp);
-            }
-            brachiologo::BuiltIn::PenDown => {
-                ret.push(Op::PenDown);
-            }
-        }
-    }
-
-    Ok(ret)
+// Works out what a program would draw. Big drawings get simplified for showing zoomed out,
+// because the preview gets sluggish with more than a few thousand lines in it.
+fn preview(code: &str) -> anyhow::Result<Lod> {
+    let drawing = path::from_ops(&interpret(code)?, Point::ORIGIN, false);
+    Ok(Lod::new(
+        drawing,
+        lod::PIXEL_TOLERANCE / (PREVIEW_SCALE * MAX_ZOOM),
+    ))
 }
 
 fn main() {
@@ -270,6 +143,31 @@ fn app(cx: Scope<State>) -> Element {
         String::from("(No brachiograph found)")
     };
 
+    let drawing = use_state(cx, || None::<Rc<Lod>>);
+    let zoom = use_state(cx, || 1.0);
+    let drawing_svg = match drawing.get() {
+        Some(lod) => lod.at_scale(PREVIEW_SCALE * zoom.get()).to_svg(),
+        None => String::new(),
+    };
+    // The view stays centered on the drawing area, and gets smaller as it zooms in. The
+    // drawing is flipped, because SVG is y-down and brachiograph is y-up.
+    let view = Rect::from_center_size(
+        LOGO_RECT.center(),
+        (
+            LOGO_RECT.width() / zoom.get(),
+            LOGO_RECT.height() / zoom.get(),
+        ),
+    );
+    let view_box = format!(
+        "{} {} {} {}",
+        view.x0,
+        -view.y1,
+        view.width(),
+        view.height()
+    );
+    let preview_width = LOGO_RECT.width() * PREVIEW_SCALE;
+    let preview_height = LOGO_RECT.height() * PREVIEW_SCALE;
+
     let flash = use_state(&cx, || true);
     let spn = if *flash.get() {
         rsx!(span {
@@ -313,8 +211,35 @@ fn app(cx: Scope<State>) -> Element {
                 },
                 button_text
             }
+            button {
+                onclick: move |_| match preview(text.get()) {
+                    Ok(lod) => drawing.set(Some(Rc::new(lod))),
+                    Err(e) => {
+                        log::error!("error {e}");
+                        flash.set(!*flash.get());
+                    }
+                },
+                "Preview"
+            }
 
             spn
         }
+        svg {
+            width: "{preview_width}",
+            height: "{preview_height}",
+            view_box: "{view_box}",
+            onwheel: move |ev| {
+                let factor = if ev.delta().strip_units().y < 0.0 { 1.25 } else { 0.8 };
+                zoom.set((zoom.get() * factor).clamp(1.0, MAX_ZOOM));
+            },
+            path {
+                d: "{drawing_svg}",
+                transform: "scale(1 -1)",
+                fill: "none",
+                stroke: "black",
+                stroke_width: "1.5",
+                vector_effect: "non-scaling-stroke",
+            }
+        }
     ))
 }
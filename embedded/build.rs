@@ -0,0 +1,49 @@
+//! Records which commit the firmware was built from, and when, so that it can report them
+//! (see `brachiograph::DeviceInfo`).
+
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Turns days since 1970-01-01 into a (year, month, day). This is Howard Hinnant's
+// `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn main() {
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|out| out.status.success())
+        .and_then(|out| String::from_utf8(out.stdout).ok());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=BRACHIOGRAPH_GIT_HASH={}", hash.trim());
+    }
+
+    // Respect SOURCE_DATE_EPOCH, for reproducible builds.
+    let secs = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .or_else(|| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+            i64::try_from(now.as_secs()).ok()
+        });
+    if let Some(secs) = secs {
+        let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+        println!("cargo:rustc-env=BRACHIOGRAPH_BUILD_DATE={year:04}-{month:02}-{day:02}");
+    }
+
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
@@ -1,20 +1,28 @@
-#![no_main]
-#![no_std]
+#![cfg_attr(target_os = "none", no_main)]
+#![cfg_attr(not(test), no_std)]
 
+pub mod logic;
+#[cfg(target_os = "none")]
 pub mod serial;
+pub mod time;
 
+#[cfg(target_os = "none")]
 use defmt_rtt as _; // global logger
+#[cfg(target_os = "none")]
 use panic_probe as _;
+#[cfg(target_os = "none")]
 use stm32f1xx_hal as _; // memory layout
 
 // same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
+#[cfg(target_os = "none")]
 #[defmt::panic_handler]
 fn panic() -> ! {
     cortex_m::asm::udf()
 }
 
 /// Terminates the application and makes `probe-run` exit with exit-code = 0
+#[cfg(target_os = "none")]
 pub fn exit() -> ! {
     loop {
         cortex_m::asm::bkpt();
@@ -24,7 +32,7 @@ pub fn exit() -> ! {
 // defmt-test 0.3.0 has the limitation that this `#[tests]` attribute can only be used
 // once within a crate. the module can be in any file but there can only be at most
 // one `#[tests]` module in this library crate
-#[cfg(test)]
+#[cfg(all(test, target_os = "none"))]
 #[defmt_test::tests]
 mod unit_tests {
     use defmt::assert;
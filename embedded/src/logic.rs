@@ -0,0 +1,1850 @@
+//! Everything that the firmware does apart from talking to the hardware: the op queue, the
+//! switching between raw and cooked mode, and what happens to each op and on each tick.
+//!
+//! The servos are behind the [`ServoDriver`] trait and time gets passed in, so this also builds
+//! for the computer that's doing the building, and its tests run there:
+//! `cargo test --lib --target x86_64-unknown-linux-gnu` (or whatever your computer is).
+
+use crate::time;
+use brachiograph::{
+    demo::Demo,
+    estimate::Estimate,
+    geom,
+    pwm::{CalibratedPosition, Pwm, TogglePwm},
+    Brachiograph, DeviceInfo, ErrorCode, Fixed, JointLimits, LimitPolicy, Op, PenState,
+    QueueStatus, Resp, RestingBrachiograph, ServoPosition, SessionId, DEFAULT_BLEND_ANGLE,
+    DEFAULT_JOINT_LIMITS,
+};
+use ringbuffer::{
+    ConstGenericRingBuffer as RingBuffer, RingBuffer as _, RingBufferExt, RingBufferRead,
+    RingBufferWrite,
+};
+
+// defmt only works on the brachiograph, so elsewhere (like in the tests) this says nothing.
+macro_rules! log {
+    ($fmt:literal $(, $arg:expr)*) => {
+        #[cfg(target_os = "none")]
+        defmt::println!($fmt $(, $arg)*);
+        #[cfg(not(target_os = "none"))]
+        {
+            $(let _ = &$arg;)*
+        }
+    };
+}
+
+pub use crate::time::{Duration, Instant, TICK_HZ};
+
+/// How long we'll plot without a break, unless the host tells us otherwise.
+const DEFAULT_PLOT_LIMIT: Duration = Duration::secs(2 * 60 * 60);
+
+/// How long we'll wait for the host with the pen down before lifting it (see [`Starvation`]).
+/// The ink starts to spread after not much longer than this.
+const STARVATION_GRACE: Duration = Duration::millis(300);
+
+/// How long a claim lasts (see [`Claim`]) after its holder has gone quiet and everything it
+/// sent is finished. This is long, because changing the pen can take a while.
+const CLAIM_TIMEOUT: Duration = Duration::secs(10 * 60);
+
+/// The servos, as far as the logic is concerned. On the brachiograph, these are PWM channels.
+pub trait ServoDriver {
+    /// Sets all of the servo duties.
+    fn set(&mut self, pos: ServoPosition);
+
+    /// The duties that were last set.
+    fn get(&self) -> ServoPosition;
+
+    /// Changes the time between servo pulses, leaving the pulse widths alone.
+    fn set_frame_period(&mut self, frame_us: u16);
+}
+
+// The build info comes from build.rs.
+fn device_info(calib: &CalibratedPosition) -> DeviceInfo {
+    let info = |s: Option<&str>| arrayvec::ArrayString::from(s.unwrap_or("")).unwrap_or_default();
+    DeviceInfo {
+        git_hash: info(option_env!("BRACHIOGRAPH_GIT_HASH")),
+        build_date: info(option_env!("BRACHIOGRAPH_BUILD_DATE")),
+        calibration_crc: calib.calib.checksum(),
+    }
+}
+
+fn validate_slow_op(
+    geom_config: &geom::Config,
+    policy: LimitPolicy,
+    op: &Op,
+) -> Result<(), ErrorCode> {
+    let in_range = match op {
+        // Out-of-range moves get fixed up when they're carried out.
+        Op::MoveTo(_) | Op::QuadTo(..) | Op::CurveTo(..) if policy != LimitPolicy::Reject => true,
+        Op::MoveTo(p) => geom_config.coord_is_valid(p.x, p.y),
+        // The workspace is a rectangle, so if the control points are in it then so is the
+        // whole curve. We can't check arcs until we know where they start.
+        Op::QuadTo(c, p) => [c, p].iter().all(|q| geom_config.coord_is_valid(q.x, q.y)),
+        Op::CurveTo(c1, c2, p) => [c1, c2, p]
+            .iter()
+            .all(|q| geom_config.coord_is_valid(q.x, q.y)),
+        Op::SetSpeed(speed) if *speed <= 0 => return Err(ErrorCode::MalformedOp),
+        Op::SetAngleInterpolation(Some(max_len)) if *max_len < 0 => {
+            return Err(ErrorCode::MalformedOp)
+        }
+        Op::SetPenDuty { up, down }
+            if !TogglePwm {
+                on: *down,
+                off: *up,
+            }
+            .is_valid() =>
+        {
+            return Err(ErrorCode::CalibrationRange)
+        }
+        _ => true,
+    };
+    if in_range {
+        Ok(())
+    } else {
+        Err(ErrorCode::OutOfRange)
+    }
+}
+
+// Starts on a movement op, after applying the limit policy to it.
+fn start_move(
+    resting: RestingBrachiograph<'_>,
+    now: brachiograph::Instant,
+    config: &geom::Config,
+    policy: LimitPolicy,
+    op: &Op,
+) -> Result<(), ()> {
+    match policy.apply(config, resting.pos(), op.clone()) {
+        // We only know where this ends up now that we know where it starts.
+        Some(Op::MoveBy(r, theta)) => resting.move_by(now, r, theta),
+        Some(op) => resting.follow(now, op.segment().ok_or(())?),
+        None => Err(()),
+    }
+}
+
+/// Drives the shoulder and elbow servos towards some raw duties, without turning the joints
+/// faster, or speeding them up or slowing them down more quickly, than the joint limits allow
+/// (see `JointLimits`). This is how we move when we don't know the angles, like while
+/// calibrating. The pen servo goes straight to its duty.
+#[derive(Clone, Copy, Debug)]
+pub struct Slew {
+    target: ServoPosition,
+    // Each joint's duty (including the fraction that the servos don't get to see), and how fast
+    // it's changing, in duties per second.
+    shoulder: (Fixed, Fixed),
+    elbow: (Fixed, Fixed),
+    // When we last moved.
+    at: Instant,
+}
+
+// If we haven't moved for longer than this, we don't try to catch up all at once.
+const MAX_SLEW_STEP: Duration = Duration::millis(100);
+
+impl Slew {
+    /// Starts out at rest at `pos`.
+    fn new(pos: ServoPosition, now: Instant) -> Slew {
+        Slew {
+            target: pos,
+            shoulder: (Fixed::from_num(pos.shoulder), Fixed::ZERO),
+            elbow: (Fixed::from_num(pos.elbow), Fixed::ZERO),
+            at: now,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        let done = |(pos, vel): (Fixed, Fixed), target: u16| pos == target && vel == 0;
+        done(self.shoulder, self.target.shoulder) && done(self.elbow, self.target.elbow)
+    }
+
+    /// Moves on to `now`, and says where the servos should be.
+    fn step(
+        &mut self,
+        now: Instant,
+        calib: &CalibratedPosition,
+        limits: JointLimits,
+    ) -> ServoPosition {
+        let dt = now
+            .checked_duration_since(self.at)
+            .unwrap_or(Duration::from_ticks(0))
+            .min(MAX_SLEW_STEP);
+        let dt = Fixed::from_num(dt.to_millis()) / 1000;
+        self.at = now;
+        let joint = |state, target, pwm: &Pwm| {
+            let per_degree = pwm.duty_per_degree();
+            let max_speed = limits.max_speed.saturating_mul(per_degree);
+            let max_accel = limits.max_accel.saturating_mul(per_degree);
+            slew_joint(state, target, dt, max_speed, max_accel)
+        };
+        self.shoulder = joint(self.shoulder, self.target.shoulder, &calib.calib.shoulder);
+        self.elbow = joint(self.elbow, self.target.elbow, &calib.calib.elbow);
+        ServoPosition {
+            shoulder: self.shoulder.0.round().to_num(),
+            elbow: self.elbow.0.round().to_num(),
+            pen: self.target.pen,
+        }
+    }
+
+    /// Roughly how long it will take to get to the target. It's a bit more than the real time,
+    /// because it counts the joints as speeding up all the way to top speed and then slowing
+    /// back down.
+    fn time_left(&self, calib: &CalibratedPosition, limits: JointLimits) -> Duration {
+        let joint = |(pos, _): (Fixed, Fixed), target: u16, pwm: &Pwm| {
+            let degrees = (Fixed::from_num(target) - pos).abs() / pwm.duty_per_degree();
+            degrees.saturating_div(limits.max_speed)
+                + limits.max_speed.saturating_div(limits.max_accel)
+        };
+        let secs = joint(self.shoulder, self.target.shoulder, &calib.calib.shoulder).max(joint(
+            self.elbow,
+            self.target.elbow,
+            &calib.calib.elbow,
+        ));
+        if self.is_done() {
+            Duration::from_ticks(0)
+        } else {
+            Duration::millis(secs.saturating_mul_int(1000).to_num())
+        }
+    }
+}
+
+// Moves one joint's duty on by `dt` seconds towards `target`, going no faster than `max_speed`
+// and changing speed no faster than `max_accel` (in duties per second, and per second per
+// second), and slowing down in time to stop there.
+fn slew_joint(
+    (pos, vel): (Fixed, Fixed),
+    target: u16,
+    dt: Fixed,
+    max_speed: Fixed,
+    max_accel: Fixed,
+) -> (Fixed, Fixed) {
+    let target = Fixed::from_num(target);
+    let left = target - pos;
+    // Within a duty of the target, the servos can't tell the difference.
+    if left.abs() < 1 && vel.abs() <= max_accel.saturating_mul(dt) {
+        return (target, Fixed::ZERO);
+    }
+    // The fastest we can be going and still stop in time, slowing down by `change` on each of
+    // the following ticks.
+    let change = max_accel.saturating_mul(dt);
+    let step = change.to_num::<u64>();
+    let squared = 2 * max_accel.to_num::<u64>() * left.abs().to_num::<u64>() + step * step / 4;
+    let stoppable = Fixed::saturating_from_num(squared.isqrt()) - change / 2;
+    let want = stoppable.max(Fixed::ZERO).min(max_speed);
+    let want = if left < 0 { -want } else { want };
+    let vel = vel.saturating_add(want.saturating_sub(vel).clamp(-change, change));
+    let pos = pos.saturating_add(vel.saturating_mul(dt));
+    if (target - pos).signum() != left.signum() {
+        // We went past it, but by less than we could have stopped in.
+        (target, Fixed::ZERO)
+    } else {
+        (pos, vel)
+    }
+}
+
+// An op in the queue, along with the limit policy that was in force when it was queued (see
+// `Op::SetLimitPolicy`). Changing the policy doesn't change what happens to moves that were
+// already accepted.
+struct Queued {
+    op: Op,
+    policy: LimitPolicy,
+}
+
+#[derive(Default)]
+pub struct OpQueue {
+    // TODO: would be sort of nice if we can make this big, but it overflows the stack. We can
+    // probably shrink `Op` by a factor of 2 or more. It isn't a huge deal, though: we're unlikely
+    // to process more than a handful of ops per second, so there's no need to queue up too many.
+    queue: RingBuffer<Queued, QUEUE_CAPACITY>,
+    // If the demo is running, the rest of its ops. They get fed into the queue as it empties.
+    demo: Option<Demo>,
+    // How many ops have been taken off the queue and carried out (see `Resp::Progress`).
+    done: u32,
+}
+
+const QUEUE_CAPACITY: usize = 32;
+
+impl OpQueue {
+    fn enqueue(&mut self, op: Op, policy: LimitPolicy) -> Result<(), ()> {
+        if self.queue.is_full() {
+            Err(())
+        } else {
+            self.queue.push(Queued { op, policy });
+            Ok(())
+        }
+    }
+
+    // Takes the op at the front of the queue, once it's been carried out.
+    fn dequeue(&mut self) {
+        if self.queue.dequeue().is_some() {
+            self.done = self.done.wrapping_add(1);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.queue.clear();
+        self.demo = None;
+    }
+
+    fn start_demo(&mut self) {
+        self.demo = Some(Demo::new());
+    }
+
+    fn is_running_demo(&self) -> bool {
+        self.demo.is_some()
+    }
+
+    /// Tops up the queue with the demo's ops, if it's running.
+    fn refill(&mut self) {
+        let Some(demo) = &mut self.demo else {
+            return;
+        };
+        while !self.queue.is_full() {
+            let Some(op) = demo.next() else {
+                self.demo = None;
+                return;
+            };
+            // The demo stays inside the drawing area.
+            let policy = LimitPolicy::default();
+            self.queue.push(Queued { op, policy });
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty() && self.demo.is_none()
+    }
+
+    /// Tells the brachiograph about the next queued op, if it's a movement, so that the
+    /// current movement can carry straight on into it instead of slowing down to a stop (see
+    /// `Brachiograph::continue_into`).
+    fn look_ahead(
+        &self,
+        brachio: &mut Brachiograph,
+        now: brachiograph::Instant,
+        config: &geom::Config,
+    ) {
+        let Some(Queued { op, policy }) = self.queue.peek() else {
+            return;
+        };
+        let from = brachio.destination();
+        let next = policy.apply(config, from, op.clone());
+        if let Some(segment) = next.and_then(|op| op.segment_from(from)) {
+            brachio.continue_into(now, &segment);
+        }
+    }
+
+    fn status(&self) -> QueueStatus {
+        QueueStatus {
+            len: self.queue.len() as u16,
+            capacity: QUEUE_CAPACITY as u16,
+        }
+    }
+
+    /// Starts any queued movements that should already have finished by `now`.
+    ///
+    /// When movements are blended (see `Brachiograph::set_blend_angle`), a short one can
+    /// start and finish within a single tick. Without this, every movement would take at least
+    /// a tick, which is a lot slower than it should be for flattened curves. We stop at the
+    /// first op that isn't a movement, and leave it for the tick.
+    fn chain_movements(
+        &mut self,
+        brachio: &mut Brachiograph,
+        now: brachiograph::Instant,
+        config: &geom::Config,
+    ) {
+        for _ in 0..QUEUE_CAPACITY {
+            self.look_ahead(brachio, now, config);
+            brachio.update(now);
+            let Some(resting) = brachio.resting() else {
+                return;
+            };
+            let result = match self.queue.peek() {
+                Some(Queued {
+                    op:
+                        op @ (Op::MoveBy(..)
+                        | Op::MoveTo(_)
+                        | Op::QuadTo(..)
+                        | Op::CurveTo(..)
+                        | Op::ArcTo(..)),
+                    policy,
+                }) => start_move(resting, now, config, *policy, op),
+                _ => return,
+            };
+            if result.is_err() {
+                log!("failed to move");
+            }
+            self.dequeue();
+        }
+    }
+}
+
+pub enum State {
+    // We do not maintain an op queue, and commands are given in raw pwm duties. This is the mode
+    // we use while calibrating.
+    Raw(Slew),
+    // We accept commands in terms of positions.
+    Cooked {
+        op_queue: OpQueue,
+        brachio: Brachiograph,
+    },
+    // We are transitioning from raw to cooked mode.
+    Cooking {
+        op_queue: OpQueue,
+        slew: Slew,
+    },
+    // The emergency stop button was pressed, and we won't move until we're told to carry on. If
+    // we were in cooked mode, we keep the brachiograph so that we can go back to it.
+    Stopped(Option<Brachiograph>),
+}
+
+impl State {
+    /// Have we finished everything we were asked to do?
+    fn is_idle(&mut self) -> bool {
+        match self {
+            State::Raw(slew) => slew.is_done(),
+            State::Cooked { op_queue, brachio } => {
+                op_queue.is_empty() && brachio.resting().is_some()
+            }
+            State::Cooking { .. } => false,
+            State::Stopped(_) => true,
+        }
+    }
+
+    fn queue_status(&self) -> QueueStatus {
+        match self {
+            State::Raw(_) | State::Stopped(_) => QueueStatus::default(),
+            State::Cooked { op_queue, .. } | State::Cooking { op_queue, .. } => op_queue.status(),
+        }
+    }
+
+    fn progress(&self) -> Resp {
+        let (ops_done, queue_len) = match self {
+            State::Raw(_) | State::Stopped(_) => (0, 0),
+            State::Cooked { op_queue, .. } | State::Cooking { op_queue, .. } => {
+                (op_queue.done, op_queue.status().len)
+            }
+        };
+        Resp::Progress {
+            ops_done,
+            queue_len,
+        }
+    }
+
+    fn time_left(
+        &self,
+        now: Instant,
+        config: &geom::Config,
+        joint_limits: JointLimits,
+        calib: &CalibratedPosition,
+    ) -> Resp {
+        let geom_now = time::to_geom(now);
+        let pen_angle = calib.pen_angle;
+        let (mut estimate, op_queue, cooking) = match self {
+            State::Raw(_) | State::Stopped(_) => return Resp::TimeLeft { millis: 0 },
+            State::Cooked { op_queue, brachio } => (
+                Estimate::new(brachio, geom_now).with_pen_angle(pen_angle),
+                op_queue,
+                Duration::from_ticks(0),
+            ),
+            // The queue starts once we've finished cooking.
+            State::Cooking { op_queue, slew } => (
+                Estimate::new(&cooked_brachiograph(config, joint_limits), geom_now)
+                    .with_pen_angle(pen_angle),
+                op_queue,
+                slew.time_left(calib, joint_limits),
+            ),
+        };
+        for queued in op_queue.queue.iter() {
+            estimate.add(&queued.op);
+        }
+        let millis = estimate.total().to_millis() + cooking.to_millis();
+        Resp::TimeLeft {
+            millis: millis.try_into().unwrap_or(u32::MAX),
+        }
+    }
+}
+
+// The brachiograph that we start with once we've finished cooking, with the drawing area that
+// the host set (if it set one), resting at its park position.
+fn cooked_brachiograph(config: &geom::Config, joint_limits: JointLimits) -> Brachiograph {
+    let (x, y) = config.park;
+    let mut brachio = Brachiograph::new(x, y)
+        .with_blend_angle(Some(DEFAULT_BLEND_ANGLE))
+        .with_joint_limits(joint_limits);
+    // The config was checked when it was set, and a valid config can always reach its park
+    // position, so this can't fail.
+    if brachio.set_config(config.clone()).is_err() {
+        log!("failed to restore the config");
+    }
+    brachio
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Pause {
+    Running,
+    // We've been plotting for too long. This is the pen state to go back to when we resume.
+    Paused(PenState),
+    // We've been asked to resume, but we haven't restored the pen yet.
+    Resuming(PenState),
+}
+
+/// Pauses plotting (with the pen up) if we've been busy for too long, so that an unattended
+/// plot can't wear out the servos.
+pub struct PlotTimer {
+    limit: Option<Duration>,
+    busy_since: Option<Instant>,
+    pause: Pause,
+}
+
+impl Default for PlotTimer {
+    fn default() -> Self {
+        PlotTimer {
+            limit: Some(DEFAULT_PLOT_LIMIT),
+            busy_since: None,
+            pause: Pause::Running,
+        }
+    }
+}
+
+impl PlotTimer {
+    fn update(&mut self, now: Instant, busy: bool, pen: PenState) {
+        if self.pause != Pause::Running {
+            return;
+        }
+        if !busy {
+            self.busy_since = None;
+            return;
+        }
+        let since = *self.busy_since.get_or_insert(now);
+        if let Some(limit) = self.limit {
+            if now >= since + limit {
+                log!("plotted for too long, pausing");
+                self.pause = Pause::Paused(pen);
+                self.busy_since = None;
+            }
+        }
+    }
+
+    /// Pauses because the host asked us to (with `Op::Pause`), lifting the pen until we resume.
+    fn pause(&mut self, pen: PenState) {
+        self.pause = Pause::Paused(pen);
+        self.busy_since = None;
+    }
+
+    fn is_paused(&self) -> bool {
+        matches!(self.pause, Pause::Paused(_))
+    }
+
+    fn resume(&mut self) {
+        if let Pause::Paused(pen) = self.pause {
+            self.pause = Pause::Resuming(pen);
+        }
+    }
+
+    fn set_limit(&mut self, limit: Option<Duration>) {
+        self.limit = limit;
+    }
+}
+
+/// Lifts the pen if we run out of ops in the middle of a stroke (because the host stalled, say),
+/// so that it doesn't leave a blob of ink while we wait. We tell the host about it with
+/// `Resp::Starved`, and it can put the pen back down and carry on.
+#[derive(Default)]
+pub struct Starvation {
+    // Whether someone has claimed the brachiograph. If not, then the pen is probably being put
+    // down by hand (while calibrating, say), and it should stay there.
+    armed: bool,
+    // When we ran out of ops with the pen down.
+    idle_since: Option<Instant>,
+    // Where we lifted the pen, until the host sends `Op::Resume`.
+    starved_at: Option<brachiograph::Point>,
+}
+
+impl Starvation {
+    /// Returns true if we've been starving (with nothing to do and the pen down) for long
+    /// enough that it's time to lift the pen.
+    fn update(&mut self, now: Instant, starving: bool) -> bool {
+        if !self.armed || !starving || self.starved_at.is_some() {
+            self.idle_since = None;
+            return false;
+        }
+        let since = *self.idle_since.get_or_insert(now);
+        now >= since + STARVATION_GRACE
+    }
+
+    fn starve(&mut self, at: brachiograph::Point) {
+        self.starved_at = Some(at);
+        self.idle_since = None;
+    }
+
+    fn resume(&mut self) {
+        self.starved_at = None;
+    }
+}
+
+/// Which session has the right to send slow ops (see [`Op::Claim`]).
+///
+/// Ops don't say who sent them, so we take the ops after an `Op::Claim` to come from the session
+/// that it named. If someone else holds the claim, their slow ops are denied until the holder
+/// claims again.
+#[derive(Default)]
+pub struct Claim {
+    holder: Option<SessionId>,
+    // The session named by the last `Op::Claim`, whether or not it got the claim.
+    speaker: Option<SessionId>,
+    // The last time that the holder claimed or sent a slow op.
+    last_seen: Option<Instant>,
+}
+
+impl Claim {
+    /// Gives the claim to `session` if no one else has it, or if the holder's claim has timed
+    /// out. `busy` is whether we're still drawing (or paused), which keeps the claim alive.
+    fn claim(&mut self, session: SessionId, now: Instant, busy: bool) -> bool {
+        self.speaker = Some(session);
+        let timed_out = !busy && matches!(self.last_seen, Some(t) if now >= t + CLAIM_TIMEOUT);
+        if self.holder.is_none() || self.holder == Some(session) || timed_out {
+            self.holder = Some(session);
+            self.last_seen = Some(now);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns true if `session` held the claim, and now doesn't.
+    fn release(&mut self, session: SessionId) -> bool {
+        if self.holder != Some(session) {
+            return false;
+        }
+        self.holder = None;
+        self.last_seen = None;
+        true
+    }
+
+    /// Whether a slow op (from whoever claimed last) is allowed.
+    fn allows_slow_op(&mut self, now: Instant) -> bool {
+        match self.holder {
+            None => true,
+            Some(holder) if self.speaker == Some(holder) => {
+                self.last_seen = Some(now);
+                true
+            }
+            Some(_) => false,
+        }
+    }
+}
+
+/// The whole state of the brachiograph, apart from the serial port and the emergency stop
+/// button.
+pub struct Machine<S> {
+    servos: S,
+    state: State,
+    calib: CalibratedPosition,
+    plot_timer: PlotTimer,
+    starvation: Starvation,
+    geom_config: geom::Config,
+    limit_policy: LimitPolicy,
+    // How fast the joints may turn. This outlives the brachiograph in `state`, which gets
+    // replaced when we go from raw to cooked mode.
+    joint_limits: JointLimits,
+    // Whether `Op::Finish` has let go of the servos. They stay relaxed (getting no pulses at
+    // all) until there's something else to do.
+    relaxed: bool,
+    claim: Claim,
+}
+
+impl<S: ServoDriver> Machine<S> {
+    /// Starts out in cooked mode, resting at the home position.
+    pub fn new(mut servos: S) -> Self {
+        // The same as after cooking, so that movements get blended from the start.
+        let geom_config = geom::Config::default();
+        let mut brachio = cooked_brachiograph(&geom_config, DEFAULT_JOINT_LIMITS);
+        let mut calib = CalibratedPosition::default();
+        let now = time::to_geom(Instant::from_ticks(0));
+        servos.set(calib.update(brachio.update(now), brachio.pen(now)));
+        Machine {
+            servos,
+            state: State::Cooked {
+                brachio,
+                op_queue: OpQueue::default(),
+            },
+            calib,
+            plot_timer: PlotTimer::default(),
+            starvation: Starvation::default(),
+            geom_config,
+            limit_policy: LimitPolicy::default(),
+            joint_limits: DEFAULT_JOINT_LIMITS,
+            relaxed: false,
+            claim: Claim::default(),
+        }
+    }
+
+    pub fn servos(&self) -> &S {
+        &self.servos
+    }
+
+    pub fn queue_status(&self) -> QueueStatus {
+        self.state.queue_status()
+    }
+
+    /// How long to wait before the next [`Machine::tick`].
+    pub fn tick_period(&self) -> Duration {
+        Duration::millis(self.calib.calib.timing.tick_ms.into())
+    }
+
+    /// Starts the built-in demo drawing, if we aren't busy with something else.
+    pub fn run_demo(&mut self) -> Resp {
+        match &mut self.state {
+            State::Stopped(_) => Resp::EStop,
+            State::Raw(_) => Resp::Error(ErrorCode::InvalidState),
+            State::Cooked { op_queue, brachio } if op_queue.is_empty() && brachio.is_resting() => {
+                op_queue.start_demo();
+                Resp::Ack
+            }
+            State::Cooked { .. } | State::Cooking { .. } => Resp::Error(ErrorCode::Busy),
+        }
+    }
+
+    /// Lifts the pen and stops everything, because the emergency stop button was pressed.
+    pub fn emergency_stop(&mut self, now: Instant) {
+        // Lift the pen right away, and leave the other servos where they are.
+        self.servos.set(ServoPosition {
+            pen: self.calib.pen_duty(PenState::Up),
+            ..self.servos.get()
+        });
+        let brachio = match core::mem::replace(&mut self.state, State::Stopped(None)) {
+            State::Cooked { mut brachio, .. } => {
+                brachio.stop(time::to_geom(now));
+                Some(brachio)
+            }
+            State::Stopped(brachio) => brachio,
+            State::Raw(_) | State::Cooking { .. } => None,
+        };
+        self.state = State::Stopped(brachio);
+        log!("emergency stop");
+    }
+
+    /// Carries out (or queues up) an op from the host, and says how it went.
+    ///
+    /// `estop_pressed` is whether the emergency stop button is being held down right now.
+    pub fn handle_op(&mut self, op: Op, now: Instant, estop_pressed: bool) -> Resp {
+        let Machine {
+            servos,
+            state,
+            calib,
+            plot_timer,
+            starvation: starve,
+            geom_config,
+            limit_policy,
+            joint_limits,
+            // The servos wake up on the next tick that has something to do.
+            relaxed: _,
+            claim,
+        } = self;
+        if op.is_slow() && !claim.allows_slow_op(now) {
+            return Resp::Denied;
+        }
+        match op {
+            Op::Cancel => {
+                match state {
+                    State::Raw(_) | State::Stopped(_) => {}
+                    State::Cooked { op_queue, .. } | State::Cooking { op_queue, .. } => {
+                        op_queue.clear();
+                        // Get the pen out of the way, in case whoever cancelled
+                        // doesn't. Unwrap: the queue is empty.
+                        op_queue.enqueue(Op::Park, *limit_policy).unwrap();
+                    }
+                }
+                starve.resume();
+                Resp::Ack
+            }
+            Op::Calibrate(_, _, joint_calib) if !joint_calib.is_valid() => {
+                Resp::Error(ErrorCode::CalibrationRange)
+            }
+            Op::Calibrate(joint, dir, joint_calib) => {
+                calib.change_calibration(joint, dir, joint_calib);
+                Resp::Ack
+            }
+            Op::GetPosition => match state {
+                // The joints might still be on their way, but that's where they're going.
+                State::Raw(slew) => Resp::CurPosition(ServoPosition {
+                    pen: servos.get().pen,
+                    ..slew.target
+                }),
+                _ => Resp::CurPosition(servos.get()),
+            },
+            Op::GetAngles => match state {
+                State::Cooked { brachio, .. } | State::Stopped(Some(brachio)) => {
+                    Resp::Angles(brachio.update(time::to_geom(now)))
+                }
+                // We don't know the angles when we're being driven by raw duties.
+                _ => Resp::Error(ErrorCode::InvalidState),
+            },
+            Op::Claim(session) => {
+                let busy = !state.is_idle() || plot_timer.is_paused();
+                if claim.claim(session, now, busy) {
+                    starve.armed = true;
+                    Resp::Ack
+                } else {
+                    Resp::Denied
+                }
+            }
+            Op::Release(session) => {
+                if claim.release(session) {
+                    starve.armed = false;
+                    starve.resume();
+                }
+                Resp::Ack
+            }
+            Op::ChangePosition(_) | Op::ChangePenPosition(_)
+                if matches!(state, State::Stopped(_)) =>
+            {
+                Resp::EStop
+            }
+            Op::ChangePosition(delta) => {
+                // The joints get there on the following ticks.
+                let mut slew = match state {
+                    State::Raw(slew) => *slew,
+                    _ => Slew::new(servos.get(), now),
+                };
+                slew.target = slew.target + delta;
+                *state = State::Raw(slew);
+                Resp::Ack
+            }
+            Op::ChangePenPosition(delta) => {
+                servos.set(servos.get().with_pen_delta(delta));
+                let mut slew = match state {
+                    State::Raw(slew) => *slew,
+                    _ => Slew::new(servos.get(), now),
+                };
+                slew.target = slew.target.with_pen_delta(delta);
+                *state = State::Raw(slew);
+                Resp::Ack
+            }
+            Op::CalibratePen(pen) if !pen.is_valid() => Resp::Error(ErrorCode::CalibrationRange),
+            Op::CalibratePen(pen) => {
+                calib.change_pen_calibration(pen);
+                Resp::Ack
+            }
+            Op::CalibratePenAngles(table) if !table.is_valid() => {
+                Resp::Error(ErrorCode::CalibrationRange)
+            }
+            Op::CalibratePenAngles(table) => {
+                calib.change_pen_angles(table);
+                Resp::Ack
+            }
+            Op::SetInverted(joint, inverted) => {
+                calib.change_inversion(joint, inverted);
+                Resp::Ack
+            }
+            Op::SetTiming(timing) if !timing.is_valid() => Resp::Error(ErrorCode::CalibrationRange),
+            Op::SetTiming(timing) => {
+                calib.change_timing(timing);
+                servos.set_frame_period(timing.frame_us);
+                Resp::Ack
+            }
+            Op::SetPenRamp(ramp) if !ramp.is_valid() => Resp::Error(ErrorCode::CalibrationRange),
+            Op::SetPenRamp(ramp) => {
+                calib.change_pen_ramp(ramp);
+                Resp::Ack
+            }
+            Op::Resume => {
+                plot_timer.resume();
+                starve.resume();
+                Resp::Ack
+            }
+            Op::SetPlotLimit(secs) => {
+                plot_timer.set_limit(secs.map(|s| Duration::secs(s.into())));
+                Resp::Ack
+            }
+            Op::GetQueue => Resp::Queue(state.queue_status()),
+            Op::GetProgress => state.progress(),
+            Op::EstimateTime => state.time_left(now, geom_config, *joint_limits, calib),
+            Op::GetInfo => Resp::Info(device_info(calib)),
+            Op::ClearEStop => {
+                if estop_pressed {
+                    Resp::EStop
+                } else {
+                    if let State::Stopped(brachio) = state {
+                        *state = match brachio.take() {
+                            Some(brachio) => State::Cooked {
+                                brachio,
+                                op_queue: OpQueue::default(),
+                            },
+                            None => State::Raw(Slew::new(servos.get(), now)),
+                        };
+                    }
+                    Resp::Ack
+                }
+            }
+            Op::SetConfig(config) if !config.is_valid() => Resp::Error(ErrorCode::InvalidConfig),
+            // The config only changes between drawings, so that we don't need to re-check
+            // anything that's already queued.
+            Op::SetConfig(config) => match state {
+                State::Cooked { op_queue, brachio }
+                    if op_queue.is_empty() && brachio.is_resting() =>
+                {
+                    match brachio.set_config(config.clone()) {
+                        Ok(()) => {
+                            *geom_config = config;
+                            Resp::Ack
+                        }
+                        // The pen is resting outside the new drawing area.
+                        Err(()) => Resp::Error(ErrorCode::OutOfRange),
+                    }
+                }
+                State::Stopped(_) => Resp::EStop,
+                _ => Resp::Error(ErrorCode::InvalidState),
+            },
+            Op::GetConfig => Resp::Config(geom_config.clone()),
+            Op::SetLimitPolicy(policy) => {
+                *limit_policy = policy;
+                Resp::Ack
+            }
+            Op::SetJointLimits(limits) if !limits.is_valid() => Resp::Error(ErrorCode::MalformedOp),
+            Op::SetJointLimits(limits) => {
+                *joint_limits = limits;
+                if let State::Cooked { brachio, .. } | State::Stopped(Some(brachio)) = state {
+                    brachio.set_joint_limits(limits);
+                }
+                Resp::Ack
+            }
+            Op::RunDemo => self.run_demo(),
+            op => match state {
+                State::Raw(_) => Resp::Error(ErrorCode::InvalidState),
+                State::Stopped(_) => Resp::EStop,
+                State::Cooked { .. } | State::Cooking { .. } if plot_timer.is_paused() => {
+                    Resp::Paused
+                }
+                State::Cooked { op_queue, .. } | State::Cooking { op_queue, .. }
+                    if op_queue.is_running_demo() =>
+                {
+                    Resp::Error(ErrorCode::Busy)
+                }
+                // Until the host knows that we lifted the pen, the ops that it sends were meant
+                // to be drawn with the pen down.
+                State::Cooked { .. } | State::Cooking { .. } if starve.starved_at.is_some() => {
+                    // Unwrap: we just checked.
+                    Resp::Starved {
+                        at: starve.starved_at.unwrap(),
+                    }
+                }
+                State::Cooked { op_queue, .. } | State::Cooking { op_queue, .. } => {
+                    match validate_slow_op(geom_config, *limit_policy, &op) {
+                        Ok(()) => match op_queue.enqueue(op, *limit_policy) {
+                            Ok(()) => Resp::Ack,
+                            Err(()) => Resp::QueueFull,
+                        },
+                        Err(code) => Resp::Error(code),
+                    }
+                }
+            },
+        }
+    }
+
+    /// Moves the servos to where they should be at `now`, and starts on the next queued op if
+    /// the last one is finished.
+    pub fn tick(&mut self, now: Instant) {
+        let Machine {
+            servos,
+            state,
+            calib,
+            plot_timer,
+            starvation,
+            geom_config,
+            joint_limits,
+            relaxed,
+            ..
+        } = self;
+        match state {
+            State::Raw(slew) => {
+                if !slew.is_done() {
+                    servos.set(slew.step(now, calib, *joint_limits));
+                }
+            }
+            State::Stopped(_) => {}
+            State::Cooked { brachio, op_queue } => {
+                op_queue.refill();
+                let geom_now = time::to_geom(now);
+                // Unless we're about to stop, the current movement might not need to slow down
+                // at the end.
+                if plot_timer.pause == Pause::Running && starvation.starved_at.is_none() {
+                    op_queue.look_ahead(brachio, geom_now, geom_config);
+                }
+                let angles = brachio.update(geom_now);
+                let pen = brachio.pen(geom_now);
+                if !op_queue.is_empty() {
+                    *relaxed = false;
+                }
+                // The pen servo eases between up and down, instead of jumping.
+                let duties = match brachio.lift_progress(geom_now) {
+                    Some((to, progress)) => calib.update_lifting(angles, to, progress),
+                    None => calib.update(angles, pen),
+                };
+                if !*relaxed {
+                    servos.set(duties);
+                }
+
+                let (home, park) = (brachio.config().home, brachio.config().park);
+                let busy = !op_queue.is_empty() || brachio.resting().is_none();
+                plot_timer.update(now, busy, pen);
+                let starving = !busy && pen == PenState::Down && plot_timer.pause == Pause::Running;
+                let lift = starvation.update(now, starving);
+
+                let Some(resting) = brachio.resting() else {
+                    return;
+                };
+                if let Pause::Paused(_) = plot_timer.pause {
+                    if pen == PenState::Down {
+                        resting.pen_up(geom_now);
+                    }
+                } else if let Pause::Resuming(restore) = plot_timer.pause {
+                    if restore == PenState::Down {
+                        resting.pen_down(geom_now);
+                    }
+                    plot_timer.pause = Pause::Running;
+                } else if lift {
+                    log!("ran out of ops with the pen down, lifting it");
+                    starvation.starve(resting.pos());
+                    resting.pen_up(geom_now);
+                } else if let Some(Queued { op, policy }) = op_queue.queue.peek() {
+                    match op {
+                        Op::SetSpeed(speed) => {
+                            brachio.set_speed(*speed);
+                            op_queue.dequeue();
+                        }
+                        Op::SetPenLift(ms) => {
+                            brachio.set_pen_lift(brachiograph::Duration::millis((*ms).into()));
+                            op_queue.dequeue();
+                        }
+                        Op::SetAngleInterpolation(max_len) => {
+                            brachio.set_angle_interpolation(*max_len);
+                            op_queue.dequeue();
+                        }
+                        Op::SetPenDuty { up, down } => {
+                            calib.change_pen_calibration(TogglePwm {
+                                on: *down,
+                                off: *up,
+                            });
+                            op_queue.dequeue();
+                        }
+                        Op::PenUp => {
+                            resting.pen_up(geom_now);
+                            op_queue.dequeue();
+                        }
+                        Op::PenDown | Op::SetPenAngle(_) => {
+                            let angle = match op {
+                                Op::SetPenAngle(angle) => Some(*angle),
+                                _ => None,
+                            };
+                            // If the pen is already down, it just moves to its new angle.
+                            if calib.set_pen_angle(angle) && pen == PenState::Down {
+                                resting.settle_pen(geom_now);
+                            } else {
+                                resting.pen_down(geom_now);
+                            }
+                            op_queue.dequeue();
+                        }
+                        Op::Dwell(ms) => {
+                            resting.dwell(geom_now, brachiograph::Duration::millis((*ms).into()));
+                            op_queue.dequeue();
+                        }
+                        Op::Home | Op::Park => {
+                            // The pen goes up first, and then we come back here for the move
+                            // once it's up.
+                            if pen == PenState::Down {
+                                resting.pen_up(geom_now);
+                            } else {
+                                let (x, y) = if matches!(op, Op::Home) { home } else { park };
+                                if resting.move_to(geom_now, x, y).is_err() {
+                                    log!("failed to move");
+                                }
+                                op_queue.dequeue();
+                            }
+                        }
+                        Op::Finish => {
+                            // Like `Park`, but once we're there the servos get no more pulses,
+                            // which lets them go limp.
+                            let park_point = brachiograph::Point {
+                                x: park.0,
+                                y: park.1,
+                            };
+                            if pen == PenState::Down {
+                                resting.pen_up(geom_now);
+                            } else if resting.pos() != park_point {
+                                if resting.move_to(geom_now, park.0, park.1).is_err() {
+                                    log!("failed to move");
+                                    op_queue.dequeue();
+                                }
+                            } else {
+                                servos.set(ServoPosition {
+                                    shoulder: 0,
+                                    elbow: 0,
+                                    pen: 0,
+                                });
+                                *relaxed = true;
+                                op_queue.dequeue();
+                            }
+                        }
+                        Op::Pause(reason) => {
+                            log!("pausing: {:?}", reason);
+                            plot_timer.pause(pen);
+                            op_queue.dequeue();
+                        }
+                        Op::MoveTo(_)
+                        | Op::QuadTo(..)
+                        | Op::CurveTo(..)
+                        | Op::ArcTo(..)
+                        | Op::MoveBy(..) => {
+                            if start_move(resting, geom_now, geom_config, *policy, op).is_err() {
+                                log!("failed to move");
+                            }
+                            op_queue.dequeue();
+                            op_queue.chain_movements(brachio, geom_now, geom_config);
+                        }
+                        op => {
+                            log!("unexpected queued op {:?}", op);
+                        }
+                    }
+                }
+            }
+            State::Cooking { op_queue, slew } => {
+                if slew.is_done() {
+                    *state = State::Cooked {
+                        brachio: cooked_brachiograph(geom_config, *joint_limits),
+                        op_queue: core::mem::take(op_queue),
+                    };
+                } else {
+                    servos.set(slew.step(now, calib, *joint_limits));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use brachiograph::{pwm::PenRamp, Angle, Point, ServoCalibration, ServoPositionDelta};
+
+    #[derive(Debug)]
+    struct FakeServos {
+        pos: ServoPosition,
+        frame_us: u16,
+    }
+
+    impl ServoDriver for FakeServos {
+        fn set(&mut self, pos: ServoPosition) {
+            self.pos = pos;
+        }
+
+        fn get(&self) -> ServoPosition {
+            self.pos
+        }
+
+        fn set_frame_period(&mut self, frame_us: u16) {
+            self.frame_us = frame_us;
+        }
+    }
+
+    fn machine() -> Machine<FakeServos> {
+        Machine::new(FakeServos {
+            pos: ServoPosition {
+                shoulder: 0,
+                elbow: 0,
+                pen: 0,
+            },
+            frame_us: 0,
+        })
+    }
+
+    fn ms(ms: u64) -> Instant {
+        Instant::from_ticks(0) + Duration::millis(ms)
+    }
+
+    // A point that's `dx` to the right of home.
+    fn home_plus(m: &Machine<FakeServos>, dx: i32) -> Point {
+        let (x, y) = m.geom_config.home;
+        Point {
+            x: x + Fixed::from_num(dx),
+            y,
+        }
+    }
+
+    // Ticks (as often as the firmware would) from `from` until `until`.
+    fn run(m: &mut Machine<FakeServos>, from: Instant, until: Instant) {
+        let mut now = from;
+        while now < until {
+            m.tick(now);
+            now += m.tick_period();
+        }
+    }
+
+    fn progress(m: &mut Machine<FakeServos>, now: Instant) -> (u32, u16) {
+        match m.handle_op(Op::GetProgress, now, false) {
+            Resp::Progress {
+                ops_done,
+                queue_len,
+            } => (ops_done, queue_len),
+            resp => panic!("unexpected {resp:?}"),
+        }
+    }
+
+    #[test]
+    fn queue_fills_up() {
+        let mut m = machine();
+        for _ in 0..QUEUE_CAPACITY {
+            assert!(matches!(m.handle_op(Op::PenUp, ms(0), false), Resp::Ack));
+        }
+        assert!(matches!(
+            m.handle_op(Op::PenUp, ms(0), false),
+            Resp::QueueFull
+        ));
+        assert_eq!(m.queue_status().len, QUEUE_CAPACITY as u16);
+    }
+
+    #[test]
+    fn rejects_bad_ops() {
+        let mut m = machine();
+        let far = home_plus(&m, 1000);
+        assert!(matches!(
+            m.handle_op(Op::MoveTo(far), ms(0), false),
+            Resp::Error(ErrorCode::OutOfRange)
+        ));
+        assert!(matches!(
+            m.handle_op(Op::SetSpeed(Fixed::ZERO), ms(0), false),
+            Resp::Error(ErrorCode::MalformedOp)
+        ));
+        assert!(matches!(
+            m.handle_op(Op::SetAngleInterpolation(Some(-Fixed::ONE)), ms(0), false),
+            Resp::Error(ErrorCode::MalformedOp)
+        ));
+        assert_eq!(m.queue_status().len, 0);
+    }
+
+    #[test]
+    fn pen_duty_is_queued() {
+        let mut m = machine();
+        let target = home_plus(&m, 1);
+        let duty = Op::SetPenDuty {
+            up: 700,
+            down: 1400,
+        };
+        for op in [Op::MoveTo(target), Op::PenDown, duty] {
+            assert!(matches!(m.handle_op(op, ms(0), false), Resp::Ack));
+        }
+        assert!(matches!(
+            m.handle_op(Op::SetPenDuty { up: 0, down: 1400 }, ms(0), false),
+            Resp::Error(ErrorCode::CalibrationRange)
+        ));
+
+        // The old duty holds the pen down until the new one's turn comes.
+        m.tick(ms(0));
+        let old_down = m.servos().pos.pen;
+        assert_ne!(old_down, 1400);
+        run(&mut m, ms(0), ms(10000));
+        assert_eq!(progress(&mut m, ms(10000)), (3, 0));
+        assert_eq!(m.servos().pos.pen, 1400);
+    }
+
+    #[test]
+    fn pen_ramps_down() {
+        let mut m = machine();
+        assert!(matches!(
+            m.handle_op(Op::SetPenRamp(PenRamp { percent: 101 }), ms(0), false),
+            Resp::Error(ErrorCode::CalibrationRange)
+        ));
+        assert!(matches!(
+            m.handle_op(Op::SetPenRamp(PenRamp { percent: 100 }), ms(0), false),
+            Resp::Ack
+        ));
+        let up = m.calib.pen_duty(PenState::Up);
+        let down = m.calib.pen_duty(PenState::Down);
+        assert!(matches!(m.handle_op(Op::PenDown, ms(0), false), Resp::Ack));
+
+        // A quarter of the way through lowering the pen, its servo is halfway there.
+        run(&mut m, ms(0), ms(200));
+        m.tick(ms(200));
+        let halfway = m.servos().pos.pen;
+        assert!(halfway.abs_diff((up + down) / 2) <= 1, "{halfway}");
+        m.tick(ms(400));
+        assert_eq!(m.servos().pos.pen, down);
+
+        // Without a ramp, it jumps at the halfway point.
+        assert!(matches!(
+            m.handle_op(Op::SetPenRamp(PenRamp { percent: 0 }), ms(1000), false),
+            Resp::Ack
+        ));
+        assert!(matches!(m.handle_op(Op::PenUp, ms(1000), false), Resp::Ack));
+        m.tick(ms(1000));
+        m.tick(ms(1390));
+        assert_eq!(m.servos().pos.pen, down);
+        m.tick(ms(1400));
+        assert_eq!(m.servos().pos.pen, up);
+    }
+
+    #[test]
+    fn pen_angle_is_queued() {
+        let mut m = machine();
+        let bad = ServoCalibration {
+            data: [(0, 750)].into_iter().collect(),
+        };
+        assert!(matches!(
+            m.handle_op(Op::CalibratePenAngles(bad), ms(0), false),
+            Resp::Error(ErrorCode::CalibrationRange)
+        ));
+
+        let angle = Angle::from_degrees(60);
+        let angle_duty = m.calib.calib.pen_angles.duty(angle);
+        assert_ne!(angle_duty, m.calib.pen_duty(PenState::Down));
+        assert!(matches!(
+            m.handle_op(Op::SetPenAngle(angle), ms(0), false),
+            Resp::Ack
+        ));
+        run(&mut m, ms(0), ms(5000));
+        assert_eq!(progress(&mut m, ms(5000)), (1, 0));
+        assert_eq!(m.servos().pos.pen, angle_duty);
+
+        // Putting the pen down the ordinary way forgets the angle.
+        assert!(matches!(
+            m.handle_op(Op::PenDown, ms(5000), false),
+            Resp::Ack
+        ));
+        run(&mut m, ms(5000), ms(10000));
+        assert_eq!(m.servos().pos.pen, m.calib.pen_duty(PenState::Down));
+    }
+
+    fn time_left(m: &mut Machine<FakeServos>, now: Instant) -> u32 {
+        match m.handle_op(Op::EstimateTime, now, false) {
+            Resp::TimeLeft { millis } => millis,
+            resp => panic!("unexpected {resp:?}"),
+        }
+    }
+
+    #[test]
+    fn estimates_time_left() {
+        let mut m = machine();
+        assert!(matches!(
+            m.handle_op(Op::MoveTo(home_plus(&m, 0)), ms(0), false),
+            Resp::Ack
+        ));
+        run(&mut m, ms(0), ms(10000));
+        assert_eq!(time_left(&mut m, ms(10000)), 0);
+
+        let ops = [
+            Op::SetSpeed(Fixed::from_num(2)),
+            Op::MoveTo(home_plus(&m, 4)),
+            Op::PenDown,
+            Op::SetPenLift(100),
+            Op::PenUp,
+        ];
+        for op in ops {
+            assert!(matches!(m.handle_op(op, ms(10000), false), Resp::Ack));
+        }
+        // Two seconds of moving (and a few milliseconds to speed up and slow down), and then the
+        // pen goes down (at the old speed) and up.
+        let total = time_left(&mut m, ms(10000));
+        assert!((2900..2910).contains(&total), "{total}");
+        // Part of the way through the move, the rest of it still counts. The move started a tick
+        // late, after the speed change.
+        run(&mut m, ms(10000), ms(11000));
+        let left = time_left(&mut m, ms(11000));
+        assert!((1900..2010).contains(&left), "{left}");
+        run(&mut m, ms(11000), ms(20000));
+        assert_eq!(time_left(&mut m, ms(20000)), 0);
+    }
+
+    #[test]
+    fn limits_joint_speed() {
+        let mut m = machine();
+        let invalid = JointLimits {
+            max_speed: Fixed::ZERO,
+            ..DEFAULT_JOINT_LIMITS
+        };
+        assert!(matches!(
+            m.handle_op(Op::SetJointLimits(invalid), ms(0), false),
+            Resp::Error(ErrorCode::MalformedOp)
+        ));
+
+        assert!(matches!(
+            m.handle_op(Op::MoveTo(home_plus(&m, 4)), ms(0), false),
+            Resp::Ack
+        ));
+        let quick = time_left(&mut m, ms(0));
+        // The limits apply to the move that's already queued.
+        let slow = JointLimits {
+            max_speed: Fixed::from_num(5),
+            ..DEFAULT_JOINT_LIMITS
+        };
+        assert!(matches!(
+            m.handle_op(Op::SetJointLimits(slow), ms(0), false),
+            Resp::Ack
+        ));
+        let slowed = time_left(&mut m, ms(0));
+        assert!(slowed > 2 * quick, "{slowed} vs {quick}");
+
+        // They outlast the brachiograph, which gets replaced after cooking.
+        let pos = m.servos().pos;
+        m.state = State::Cooking {
+            op_queue: OpQueue::default(),
+            slew: Slew::new(pos, ms(0)),
+        };
+        m.tick(ms(100));
+        let State::Cooked { brachio, .. } = &m.state else {
+            panic!("still cooking");
+        };
+        assert_eq!(brachio.joint_limits(), slow);
+    }
+
+    #[test]
+    fn cooking_keeps_the_config() {
+        let mut m = machine();
+        let config = geom::Config {
+            arm_len: Fixed::from_num(9),
+            park: (Fixed::from_num(-6), Fixed::from_num(9)),
+            ..geom::Config::default()
+        };
+        assert!(config.is_valid());
+        assert!(matches!(
+            m.handle_op(Op::SetConfig(config.clone()), ms(0), false),
+            Resp::Ack
+        ));
+
+        let pos = m.servos().pos;
+        let mut op_queue = OpQueue::default();
+        op_queue
+            .enqueue(Op::MoveTo(home_plus(&m, 1)), LimitPolicy::default())
+            .unwrap();
+        m.state = State::Cooking {
+            op_queue,
+            slew: Slew::new(pos, ms(0)),
+        };
+        // The estimate for after cooking uses the same config as the brachiograph will.
+        let cooked = cooked_brachiograph(&config, DEFAULT_JOINT_LIMITS);
+        let mut estimate = Estimate::new(&cooked, time::to_geom(ms(100)));
+        estimate.add(&Op::MoveTo(home_plus(&m, 1)));
+        // We're already there, so cooking finishes on the next tick.
+        let expected = estimate.total().to_millis() as u32;
+        assert_eq!(time_left(&mut m, ms(0)), expected);
+
+        m.tick(ms(100));
+        let State::Cooked { brachio, .. } = &m.state else {
+            panic!("still cooking");
+        };
+        assert_eq!(*brachio.config(), config);
+    }
+
+    #[test]
+    fn finish_relaxes() {
+        let mut m = machine();
+        let target = home_plus(&m, 1);
+        for op in [Op::MoveTo(target), Op::PenDown, Op::Finish] {
+            assert!(matches!(m.handle_op(op, ms(0), false), Resp::Ack));
+        }
+        run(&mut m, ms(0), ms(10000));
+        assert_eq!(progress(&mut m, ms(10000)), (3, 0));
+
+        // The pen went up and got parked, and then the servos let go.
+        let park = m.geom_config.park;
+        let State::Cooked { brachio, .. } = &mut m.state else {
+            panic!("not cooked");
+        };
+        let pos = brachio.resting().unwrap().pos();
+        assert_eq!((pos.x, pos.y), park);
+        assert_eq!(brachio.pen(time::to_geom(ms(10000))), PenState::Up);
+        let relaxed = ServoPosition {
+            shoulder: 0,
+            elbow: 0,
+            pen: 0,
+        };
+        assert_eq!(m.servos().pos, relaxed);
+
+        // They stay that way until there's something else to do.
+        run(&mut m, ms(10000), ms(11000));
+        assert_eq!(m.servos().pos, relaxed);
+        assert!(matches!(
+            m.handle_op(Op::MoveTo(target), ms(11000), false),
+            Resp::Ack
+        ));
+        m.tick(ms(11000));
+        assert_ne!(m.servos().pos.shoulder, 0);
+    }
+
+    #[test]
+    fn clamps_moves() {
+        let mut m = machine();
+        let far = home_plus(&m, 1000);
+        assert!(matches!(
+            m.handle_op(Op::SetLimitPolicy(LimitPolicy::ClampToRect), ms(0), false),
+            Resp::Ack
+        ));
+        assert!(matches!(
+            m.handle_op(Op::MoveTo(far), ms(0), false),
+            Resp::Ack
+        ));
+        run(&mut m, ms(0), ms(5000));
+        assert_eq!(progress(&mut m, ms(5000)), (1, 0));
+
+        // The pen stopped at the right-hand edge.
+        let State::Cooked { brachio, .. } = &mut m.state else {
+            panic!("not cooked");
+        };
+        let pos = brachio.resting().unwrap().pos();
+        let x_max = m.geom_config.x_range.1;
+        assert!((pos.x - x_max).abs() < Fixed::from_num(0.01));
+        assert_eq!(pos.y, far.y);
+    }
+
+    #[test]
+    fn queued_moves_keep_their_limit_policy() {
+        let mut m = machine();
+        let far = home_plus(&m, 1000);
+        assert!(matches!(
+            m.handle_op(Op::SetLimitPolicy(LimitPolicy::ClampToRect), ms(0), false),
+            Resp::Ack
+        ));
+        assert!(matches!(
+            m.handle_op(Op::MoveTo(far), ms(0), false),
+            Resp::Ack
+        ));
+        // This comes too late for the move that was already queued.
+        assert!(matches!(
+            m.handle_op(Op::SetLimitPolicy(LimitPolicy::Reject), ms(0), false),
+            Resp::Ack
+        ));
+        run(&mut m, ms(0), ms(5000));
+
+        let State::Cooked { brachio, .. } = &mut m.state else {
+            panic!("not cooked");
+        };
+        let pos = brachio.resting().unwrap().pos();
+        let x_max = m.geom_config.x_range.1;
+        assert!((pos.x - x_max).abs() < Fixed::from_num(0.01));
+    }
+
+    #[test]
+    fn raw_mode() {
+        let mut m = machine();
+        let before = m.servos().pos;
+        let delta = ServoPositionDelta {
+            shoulder: 10,
+            elbow: -10,
+        };
+        assert!(matches!(
+            m.handle_op(Op::ChangePosition(delta), ms(0), false),
+            Resp::Ack
+        ));
+        // The joints don't jump, but that's where they're going.
+        assert_eq!(m.servos().pos, before);
+        assert!(matches!(
+            m.handle_op(Op::GetPosition, ms(0), false),
+            Resp::CurPosition(pos) if pos == before + delta
+        ));
+        assert!(matches!(m.state, State::Raw(_)));
+
+        // We don't know where the pen is, so we can't take positions.
+        let p = home_plus(&m, 1);
+        assert!(matches!(
+            m.handle_op(Op::MoveTo(p), ms(0), false),
+            Resp::Error(ErrorCode::InvalidState)
+        ));
+        assert!(matches!(
+            m.handle_op(Op::GetAngles, ms(0), false),
+            Resp::Error(ErrorCode::InvalidState)
+        ));
+        assert!(matches!(
+            m.handle_op(Op::RunDemo, ms(0), false),
+            Resp::Error(ErrorCode::InvalidState)
+        ));
+
+        // They get there on the following ticks.
+        run(&mut m, ms(0), ms(100));
+        assert_eq!(m.servos().pos, before + delta);
+    }
+
+    #[test]
+    fn carries_straight_on() {
+        // Slow to speed up and slow down, so that stopping in between takes a while.
+        let limits = JointLimits {
+            max_accel: Fixed::from_num(20),
+            ..DEFAULT_JOINT_LIMITS
+        };
+        let done_by = |m: &mut Machine<FakeServos>, mut now: Instant| loop {
+            assert!(now < ms(60_000), "never finished");
+            m.tick(now);
+            if progress(m, now).1 == 0 && time_left(m, now) == 0 {
+                return now;
+            }
+            now += m.tick_period();
+        };
+        let mut m = machine();
+        m.handle_op(Op::SetJointLimits(limits), ms(0), false);
+        let (a, b) = (home_plus(&m, 1), home_plus(&m, 2));
+
+        // When the second move is already queued, the first one goes straight into it.
+        for op in [Op::MoveTo(a), Op::MoveTo(b)] {
+            assert!(matches!(m.handle_op(op, ms(0), false), Resp::Ack));
+        }
+        let together = done_by(&mut m, ms(0));
+
+        let mut m = machine();
+        m.handle_op(Op::SetJointLimits(limits), ms(0), false);
+        m.handle_op(Op::MoveTo(a), ms(0), false);
+        let first = done_by(&mut m, ms(0));
+        m.handle_op(Op::MoveTo(b), first, false);
+        let apart = done_by(&mut m, first);
+        assert!(
+            together + Duration::millis(100) < apart,
+            "{together} vs {apart}"
+        );
+    }
+
+    #[test]
+    fn raw_mode_respects_joint_limits() {
+        let mut m = machine();
+        let before = m.servos().pos;
+        let limits = JointLimits {
+            max_speed: Fixed::from_num(100),
+            max_accel: Fixed::from_num(1000),
+        };
+        m.handle_op(Op::SetJointLimits(limits), ms(0), false);
+        let delta = ServoPositionDelta {
+            shoulder: 1000,
+            elbow: 0,
+        };
+        m.handle_op(Op::ChangePosition(delta), ms(0), false);
+
+        let per_degree = m.calib.calib.shoulder.duty_per_degree().to_num::<f64>();
+        let (mut now, mut prev, mut prev_speed) = (ms(0), before.shoulder, 0.0);
+        while m.servos().pos.shoulder != before.shoulder + 1000 {
+            assert!(now < ms(10_000), "never got there");
+            m.tick(now);
+            let dt = m.tick_period().to_millis() as f64 / 1000.0;
+            let speed = f64::from(m.servos().pos.shoulder - prev) / per_degree / dt;
+            // Allowing for the rounding to whole duties.
+            let slack = 1.0 / per_degree / dt;
+            assert!(speed <= 100.0 + slack, "{speed}");
+            assert!(
+                (speed - prev_speed).abs() <= 1000.0 * dt + 2.0 * slack,
+                "{speed}"
+            );
+            (prev, prev_speed) = (m.servos().pos.shoulder, speed);
+            now += m.tick_period();
+        }
+        // That's about 90 degrees, so nearly a second at top speed.
+        assert!(now > ms(900), "{now}");
+    }
+
+    #[test]
+    fn emergency_stop() {
+        let mut m = machine();
+        let p = home_plus(&m, 1);
+        m.handle_op(Op::PenDown, ms(0), false);
+        m.handle_op(Op::MoveTo(p), ms(0), false);
+        run(&mut m, ms(0), ms(50));
+        m.emergency_stop(ms(50));
+        assert_eq!(m.servos().pos.pen, m.calib.pen_duty(PenState::Up));
+        assert_eq!(m.queue_status().len, 0);
+
+        assert!(matches!(
+            m.handle_op(Op::MoveTo(p), ms(60), false),
+            Resp::EStop
+        ));
+        assert!(matches!(
+            m.handle_op(Op::ChangePenPosition(5), ms(60), false),
+            Resp::EStop
+        ));
+        // We still know the angles, because we were in cooked mode.
+        assert!(matches!(
+            m.handle_op(Op::GetAngles, ms(60), false),
+            Resp::Angles(_)
+        ));
+
+        // It stays stopped while the button is held down.
+        assert!(matches!(
+            m.handle_op(Op::ClearEStop, ms(70), true),
+            Resp::EStop
+        ));
+        assert!(matches!(
+            m.handle_op(Op::ClearEStop, ms(80), false),
+            Resp::Ack
+        ));
+        assert!(matches!(m.state, State::Cooked { .. }));
+        assert!(matches!(
+            m.handle_op(Op::MoveTo(p), ms(90), false),
+            Resp::Ack
+        ));
+    }
+
+    #[test]
+    fn carries_out_queued_ops() {
+        let mut m = machine();
+        let start = m.servos().pos;
+        let p = home_plus(&m, 2);
+        for op in [Op::PenDown, Op::MoveTo(p), Op::PenUp] {
+            assert!(matches!(m.handle_op(op, ms(0), false), Resp::Ack));
+        }
+        assert_eq!(progress(&mut m, ms(0)), (0, 3));
+
+        run(&mut m, ms(0), ms(5000));
+        assert_eq!(progress(&mut m, ms(5000)), (3, 0));
+        assert!(m.state.is_idle());
+        assert_ne!(m.servos().pos.shoulder, start.shoulder);
+        assert_eq!(m.servos().pos.pen, m.calib.pen_duty(PenState::Up));
+    }
+
+    #[test]
+    fn starvation() {
+        let mut m = machine();
+        // Without a claim, the pen stays down.
+        m.handle_op(Op::PenDown, ms(0), false);
+        run(&mut m, ms(0), ms(2000));
+        assert_eq!(m.servos().pos.pen, m.calib.pen_duty(PenState::Down));
+
+        m.handle_op(Op::Claim(1), ms(2000), false);
+        run(&mut m, ms(2000), ms(4000));
+        assert_eq!(m.servos().pos.pen, m.calib.pen_duty(PenState::Up));
+        let p = home_plus(&m, 1);
+        assert!(matches!(
+            m.handle_op(Op::MoveTo(p), ms(4000), false),
+            Resp::Starved { .. }
+        ));
+
+        m.handle_op(Op::Resume, ms(4000), false);
+        assert!(matches!(
+            m.handle_op(Op::MoveTo(p), ms(4000), false),
+            Resp::Ack
+        ));
+    }
+
+    #[test]
+    fn claims() {
+        let mut m = machine();
+        assert!(matches!(m.handle_op(Op::Claim(1), ms(0), false), Resp::Ack));
+        m.handle_op(Op::Dwell(100), ms(0), false);
+        assert!(matches!(
+            m.handle_op(Op::Claim(2), ms(0), false),
+            Resp::Denied
+        ));
+        // Session 2 named itself last, so its slow ops are refused. Fast ops are fine.
+        assert!(matches!(
+            m.handle_op(Op::Dwell(100), ms(0), false),
+            Resp::Denied
+        ));
+        assert!(matches!(
+            m.handle_op(Op::GetQueue, ms(0), false),
+            Resp::Queue(_)
+        ));
+        // Being done isn't enough to hand the claim over.
+        run(&mut m, ms(0), ms(1000));
+        assert!(matches!(
+            m.handle_op(Op::Claim(2), ms(1000), false),
+            Resp::Denied
+        ));
+        // The holder can carry on after claiming again.
+        assert!(matches!(
+            m.handle_op(Op::Claim(1), ms(1000), false),
+            Resp::Ack
+        ));
+        assert!(matches!(
+            m.handle_op(Op::Dwell(100), ms(1000), false),
+            Resp::Ack
+        ));
+        m.handle_op(Op::Release(1), ms(1000), false);
+        assert!(matches!(
+            m.handle_op(Op::Claim(2), ms(1000), false),
+            Resp::Ack
+        ));
+    }
+
+    #[test]
+    fn claims_time_out() {
+        let mut m = machine();
+        m.handle_op(Op::Claim(1), ms(0), false);
+        m.handle_op(Op::Dwell(100), ms(0), false);
+        run(&mut m, ms(0), ms(1000));
+        let later = ms(1000) + CLAIM_TIMEOUT;
+        assert!(matches!(m.handle_op(Op::Claim(2), later, false), Resp::Ack));
+        assert!(matches!(
+            m.handle_op(Op::Dwell(100), later, false),
+            Resp::Ack
+        ));
+    }
+
+    #[test]
+    fn plot_limit() {
+        let mut m = machine();
+        m.handle_op(Op::SetPlotLimit(Some(1)), ms(0), false);
+        let (a, b) = (home_plus(&m, 1), home_plus(&m, 2));
+        let mut now = ms(0);
+        let mut paused_at = None;
+        let mut next = a;
+        while now < ms(5000) {
+            match m.handle_op(Op::MoveTo(next), now, false) {
+                Resp::Ack => next = if next == a { b } else { a },
+                Resp::QueueFull => {}
+                Resp::Paused => {
+                    paused_at = Some(now);
+                    break;
+                }
+                resp => panic!("unexpected {resp:?}"),
+            }
+            m.tick(now);
+            now += m.tick_period();
+        }
+        let paused_at = paused_at.unwrap();
+        assert!(paused_at >= ms(1000));
+
+        // The queue stays where it is until we resume.
+        run(&mut m, now, now + Duration::secs(2));
+        let stuck = progress(&mut m, now);
+        run(&mut m, now, now + Duration::secs(2));
+        assert_eq!(progress(&mut m, now), stuck);
+
+        m.handle_op(Op::Resume, now, false);
+        m.handle_op(Op::SetPlotLimit(None), now, false);
+        run(&mut m, now, now + Duration::secs(10));
+        assert_eq!(progress(&mut m, now).1, 0);
+    }
+
+    #[test]
+    fn cooking() {
+        let mut m = machine();
+        let init = ServoPosition {
+            shoulder: 1000,
+            elbow: 2000,
+            pen: 1500,
+        };
+        let target = ServoPosition {
+            shoulder: 2000,
+            elbow: 1000,
+            pen: 1500,
+        };
+        let mut slew = Slew::new(init, ms(0));
+        slew.target = target;
+        m.state = State::Cooking {
+            op_queue: OpQueue::default(),
+            slew,
+        };
+        m.tick(ms(0));
+        assert_eq!(m.servos().pos, init);
+        let left = time_left(&mut m, ms(0));
+        let halfway = u64::from(left) / 2;
+        // The joints swing a long way, so it takes a while, and both of them move steadily
+        // towards where they're going.
+        assert!(left > 300, "{left}");
+        let mut prev = init;
+        for t in (0..halfway).step_by(20) {
+            m.tick(ms(t));
+            let pos = m.servos().pos;
+            assert!(pos.shoulder >= prev.shoulder && pos.elbow <= prev.elbow);
+            prev = pos;
+        }
+        assert!(prev.shoulder > init.shoulder && prev.shoulder < target.shoulder);
+        // It gets there, and then it's cooked.
+        let mut now = ms(halfway);
+        while matches!(m.state, State::Cooking { .. }) {
+            assert!(now < ms(u64::from(left) + 100), "took too long");
+            prev = m.servos().pos;
+            m.tick(now);
+            now += m.tick_period();
+        }
+        assert_eq!(prev, target);
+    }
+}
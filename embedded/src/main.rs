@@ -3,59 +3,28 @@
 
 use brachiograph_runner as _;
 
-use brachiograph::{Brachiograph, Op, ServoPosition};
-use ringbuffer::{
-    ConstGenericRingBuffer as RingBuffer, RingBuffer as _, RingBufferExt, RingBufferWrite,
-};
+use brachiograph::ServoPosition;
+use brachiograph_runner::logic::ServoDriver;
 use stm32f1xx_hal::{device::TIM3, timer::PwmChannel};
 
-const TICK_HZ: u32 = 100;
-
-type Duration = fugit::TimerDurationU64<TICK_HZ>;
-type Instant = fugit::TimerInstantU64<TICK_HZ>;
-
-#[derive(Default)]
-pub struct OpQueue {
-    // TODO: would be sort of nice if we can make this big, but it overflows the stack. We can
-    // probably shrink `Op` by a factor of 2 or more. It isn't a huge deal, though: we're unlikely
-    // to process more than a handful of ops per second, so there's no need to queue up too many.
-    queue: RingBuffer<Op, 32>,
+/// The emergency stop button, if there is one. It connects PB12 to ground when pressed.
+pub struct EStop {
+    #[cfg(feature = "estop")]
+    pin: stm32f1xx_hal::gpio::Pin<'B', 12, stm32f1xx_hal::gpio::Input<stm32f1xx_hal::gpio::PullUp>>,
 }
 
-impl OpQueue {
-    fn enqueue(&mut self, op: Op) -> Result<(), ()> {
-        if self.queue.is_full() {
-            Err(())
-        } else {
-            self.queue.push(op);
-            Ok(())
-        }
+impl EStop {
+    #[cfg(feature = "estop")]
+    fn is_pressed(&self) -> bool {
+        self.pin.is_low()
     }
 
-    fn clear(&mut self) {
-        self.queue.clear();
+    #[cfg(not(feature = "estop"))]
+    fn is_pressed(&self) -> bool {
+        false
     }
 }
 
-pub enum State {
-    // We do not maintain an op queue, and commands are given in raw pwm duties. This is the mode
-    // we use while calibrating.
-    Raw,
-    // We accept commands in terms of positions.
-    Cooked {
-        op_queue: OpQueue,
-        brachio: Brachiograph,
-    },
-    // We are transitioning from raw to cooked mode.
-    Cooking {
-        op_queue: OpQueue,
-        init: ServoPosition,
-        target: ServoPosition,
-        start: Instant,
-        end: Instant,
-    },
-}
-
 pub struct Pwms {
     shoulder: PwmChannel<TIM3, 0>,
     elbow: PwmChannel<TIM3, 1>,
@@ -67,44 +36,49 @@ impl Pwms {
         shoulder: PwmChannel<TIM3, 0>,
         elbow: PwmChannel<TIM3, 1>,
         pen: PwmChannel<TIM3, 2>,
-        pos: ServoPosition,
     ) -> Pwms {
         let mut pwms = Pwms {
             shoulder,
             elbow,
             pen,
         };
-        pwms.set(pos);
         pwms.shoulder.enable();
         pwms.elbow.enable();
         pwms.pen.enable();
         pwms
     }
+}
 
-    pub fn set(&mut self, pos: ServoPosition) {
+impl ServoDriver for Pwms {
+    fn set(&mut self, pos: ServoPosition) {
         self.shoulder.set_duty(pos.shoulder);
         self.elbow.set_duty(pos.elbow);
         self.pen.set_duty(pos.pen);
     }
 
-    pub fn get(&self) -> ServoPosition {
+    fn get(&self) -> ServoPosition {
         ServoPosition {
             shoulder: self.shoulder.get_duty(),
             elbow: self.elbow.get_duty(),
             pen: self.pen.get_duty(),
         }
     }
+
+    fn set_frame_period(&mut self, frame_us: u16) {
+        // The channels don't give us a way to change the period once they've been split off, but
+        // the timer counts in microseconds so we only need to change where it wraps around.
+        // Safety: we own TIM3 (through the channels), and this register only affects the period.
+        let tim = unsafe { &*TIM3::ptr() };
+        tim.arr.write(|w| w.arr().bits(frame_us - 1));
+    }
 }
 
 #[rtic::app(device = stm32f1xx_hal::pac, dispatchers = [SPI1])]
 mod app {
-    use super::{Duration, OpQueue, Pwms, State};
-    use brachiograph::{
-        geom, pwm::CalibratedPosition, Brachiograph, Fixed, Op, Resp, ServoPosition,
-    };
-    use brachiograph_runner::serial::UsbSerial;
+    use super::{EStop, Pwms};
+    use brachiograph::{pwm::Timing, Reply};
+    use brachiograph_runner::{logic::Machine, serial::UsbSerial};
     use cortex_m::asm;
-    use ringbuffer::{RingBufferExt, RingBufferRead};
     use stm32f1xx_hal::{
         prelude::*,
         usb::{Peripheral, UsbBus, UsbBusType},
@@ -114,21 +88,18 @@ mod app {
     use usbd_serial::{SerialPort, USB_CLASS_CDC};
 
     #[monotonic(binds = SysTick, default = true)]
-    type Mono = Systick<{ crate::TICK_HZ }>;
+    type Mono = Systick<{ brachiograph_runner::logic::TICK_HZ }>;
 
     #[shared]
     struct Shared {
         serial: UsbSerial,
-        calib: CalibratedPosition,
-        state: State,
-        pwms: Pwms,
+        machine: Machine<Pwms>,
+        estop: EStop,
         _led: stm32f1xx_hal::gpio::Pin<'A', 1, stm32f1xx_hal::gpio::Output>,
     }
 
     #[local]
-    struct Local {
-        geom_config: geom::Config,
-    }
+    struct Local {}
 
     #[init]
     fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
@@ -172,6 +143,20 @@ mod app {
             .build();
         let serial = UsbSerial::new(usb_dev, serial);
 
+        #[cfg(feature = "estop")]
+        let mut estop = {
+            use stm32f1xx_hal::gpio::{Edge, ExtiPin};
+
+            let mut exti = cx.device.EXTI;
+            let mut pin = gpiob.pb12.into_pull_up_input(&mut gpiob.crh);
+            pin.make_interrupt_source(&mut afio);
+            pin.trigger_on_edge(&mut exti, Edge::Falling);
+            pin.enable_interrupt(&mut exti);
+            EStop { pin }
+        };
+        #[cfg(not(feature = "estop"))]
+        let estop = EStop {};
+
         let led = gpioa.pa1.into_push_pull_output(&mut gpioa.crl);
         let mut timer = cx.device.TIM1.counter_ms(&clocks);
         timer.start(1.secs()).unwrap();
@@ -186,36 +171,36 @@ mod app {
             .pwm_us::<stm32f1xx_hal::timer::Tim3NoRemap, _, _>(
                 (shoulder_pin, elbow_pin, pen_pin),
                 &mut afio.mapr,
-                fugit::Duration::<u32, 1, 1_000_000>::millis(20),
+                fugit::Duration::<u32, 1, 1_000_000>::micros(Timing::default().frame_us.into()),
                 &clocks,
             )
             .split();
 
-        let mut brachio = Brachiograph::new(-8, 8);
-        let mut calib = CalibratedPosition::default();
-        let geom_config = brachio.config().clone();
-        let now = fugit::Instant::<u64, 1, 1_000_000>::from_ticks(0);
-        let pwms = Pwms::init(
-            shoulder,
-            elbow,
-            pen,
-            calib.update(brachio.update(now), brachio.pen(now)),
-        );
-        let state = State::Cooked {
-            brachio,
-            op_queue: OpQueue::default(),
-        };
-        tick::spawn_after(Duration::millis(20)).unwrap();
+        let mut machine = Machine::new(Pwms::init(shoulder, elbow, pen));
+        // Holding the emergency stop button down while powering up runs the demo, once the
+        // button is let go. That way the brachiograph can show off without a computer.
+        #[cfg(feature = "estop")]
+        if estop.is_pressed() {
+            use stm32f1xx_hal::gpio::ExtiPin;
+
+            defmt::println!("running the demo");
+            while estop.is_pressed() {}
+            // Wait for the button to stop bouncing, and forget about the bounces so that they
+            // don't look like an emergency stop.
+            asm::delay(clocks.sysclk().raw() / 10);
+            estop.pin.clear_interrupt_pending_bit();
+            machine.run_demo();
+        }
+        tick::spawn_after(machine.tick_period()).unwrap();
 
         (
             Shared {
                 serial,
                 _led: led,
-                calib,
-                state,
-                pwms,
+                machine,
+                estop,
             },
-            Local { geom_config },
+            Local {},
             init::Monotonics(mono),
         )
     }
@@ -227,155 +212,51 @@ mod app {
         // Doc says "USB High Priority or CAN TX"
     }
 
-    fn validate_slow_op(geom_config: &geom::Config, op: &Op) -> bool {
-        if let Op::MoveTo(p) = &op {
-            geom_config.coord_is_valid(p.x, p.y)
-        } else {
-            true
-        }
+    /// Lifts the pen and stops everything when the emergency stop button is pressed.
+    ///
+    /// This has a higher priority than anything else, so that it can't get stuck behind the
+    /// queue.
+    #[cfg(feature = "estop")]
+    #[task(priority = 3, binds = EXTI15_10, shared = [machine, estop])]
+    fn estop(cx: estop::Context) {
+        use stm32f1xx_hal::gpio::ExtiPin;
+
+        let mut machine = cx.shared.machine;
+        let mut estop = cx.shared.estop;
+        (&mut machine, &mut estop).lock(|machine, estop| {
+            estop.pin.clear_interrupt_pending_bit();
+            machine.emergency_stop(monotonics::now());
+        });
     }
 
-    #[task(priority = 2, binds = USB_LP_CAN_RX0, shared = [serial, state, calib, pwms], local = [geom_config])]
+    #[task(priority = 2, binds = USB_LP_CAN_RX0, shared = [serial, machine, estop])]
     fn usb_rx0(cx: usb_rx0::Context) {
         let mut serial = cx.shared.serial;
-        let mut state = cx.shared.state;
-        let mut calib = cx.shared.calib;
-        let mut pwms = cx.shared.pwms;
-        let geom_config = cx.local.geom_config;
-        (&mut serial, &mut state, &mut calib, &mut pwms).lock(|serial, state, calib, pwms| {
+        let mut machine = cx.shared.machine;
+        let mut estop = cx.shared.estop;
+        (&mut serial, &mut machine, &mut estop).lock(|serial, machine, estop| {
             if !serial.poll() {
                 return;
             }
             while let Some(op) = serial.read() {
-                match op {
-                    Op::Cancel => {
-                        match state {
-                            State::Raw => {}
-                            State::Cooked { op_queue, .. } => op_queue.clear(),
-                            State::Cooking { op_queue, .. } => op_queue.clear(),
-                        }
-                        let _ = serial.send(Resp::Ack);
-                    }
-                    Op::Calibrate(joint, dir, joint_calib) => {
-                        calib.change_calibration(joint, dir, joint_calib);
-                        let _ = serial.send(Resp::Ack);
-                    }
-                    Op::GetPosition => {
-                        let _ = serial.send(Resp::CurPosition(pwms.get()));
-                    }
-                    Op::ChangePosition(delta) => {
-                        pwms.set(pwms.get() + delta);
-                        *state = State::Raw;
-                        let _ = serial.send(Resp::Ack);
-                    }
-                    op => {
-                        match state {
-                            State::Raw => {
-                                // TODO: error
-                                let _ = serial.send(Resp::Nack);
-                            }
-                            State::Cooked { op_queue, .. } | State::Cooking { op_queue, .. } => {
-                                if validate_slow_op(geom_config, &op) {
-                                    if op_queue.enqueue(op).is_err() {
-                                        let _ = serial.send(Resp::QueueFull);
-                                    } else {
-                                        let _ = serial.send(Resp::Ack);
-                                    }
-                                } else {
-                                    // TODO: specify the error in the response
-                                    let _ = serial.send(Resp::Nack);
-                                    continue;
-                                }
-                            }
-                        }
-                    }
-                }
+                let resp = machine.handle_op(op, monotonics::now(), estop.is_pressed());
+                let queue = machine.queue_status();
+                let _ = serial.send(Reply { resp, queue });
             }
             serial.write();
         })
     }
 
-    #[task(priority = 1, shared = [state, calib, pwms])]
+    #[task(priority = 1, shared = [machine])]
     fn tick(cx: tick::Context) {
-        let mut state = cx.shared.state;
-        let mut calib = cx.shared.calib;
-        let mut pwms = cx.shared.pwms;
-        (&mut state, &mut calib, &mut pwms).lock(|state, calib, pwms| {
-            match state {
-                State::Raw => {}
-                State::Cooked { brachio, op_queue } => {
-                    let now = monotonics::now();
-                    // TODO: no better way to convert instants??
-                    let geom_now = fugit::Instant::<u64, 1, 1_000_000>::from_ticks(0)
-                        + now.duration_since_epoch().convert();
-                    let angles = brachio.update(geom_now);
-                    let servos = calib.update(angles, brachio.pen(geom_now));
-
-                    pwms.set(servos);
-
-                    if let Some(resting) = brachio.resting() {
-                        if let Some(op) = op_queue.queue.peek() {
-                            match op {
-                                Op::PenUp => {
-                                    resting.pen_up(geom_now);
-                                    op_queue.queue.dequeue();
-                                }
-                                Op::PenDown => {
-                                    resting.pen_down(geom_now);
-                                    op_queue.queue.dequeue();
-                                }
-                                Op::MoveTo(point) => {
-                                    // TODO: error handling
-                                    if resting.move_to(geom_now, point.x, point.y).is_err() {
-                                        defmt::println!("failed to move");
-                                    }
-                                    op_queue.queue.dequeue();
-                                }
-                                op => {
-                                    defmt::println!("unexpected queued op {:?}", op);
-                                }
-                            }
-                        }
-                    }
-                }
-                State::Cooking {
-                    op_queue,
-                    init,
-                    target,
-                    start,
-                    end,
-                } => {
-                    let now = monotonics::now();
-                    if now >= *end {
-                        *state = State::Cooked {
-                            brachio: Brachiograph::new(-8, 8),
-                            op_queue: core::mem::take(op_queue),
-                        };
-                    } else {
-                        // FIXME: unwrap
-                        let total_ticks = end.checked_duration_since(*start).unwrap().ticks();
-                        let ticks_so_far = now.checked_duration_since(*start).unwrap().ticks();
-                        let ratio = Fixed::from_num(total_ticks) / Fixed::from_num(ticks_so_far);
-                        let sh_target = Fixed::from_num(target.shoulder);
-                        let sh_init = Fixed::from_num(init.shoulder);
-                        let el_target = Fixed::from_num(target.elbow);
-                        let el_init = Fixed::from_num(init.elbow);
-                        let shoulder = Fixed::to_num(sh_init + ratio * (sh_target - sh_init));
-                        let elbow = Fixed::to_num(el_init + ratio * (el_target - el_init));
-                        let pen = target.pen;
-                        pwms.set(ServoPosition {
-                            shoulder,
-                            elbow,
-                            pen,
-                        })
-                    }
-                }
-            }
+        let mut machine = cx.shared.machine;
+        machine.lock(|machine| {
+            machine.tick(monotonics::now());
 
             // TODO: can we have it idle if there's nothing to do? I haven't figured out how to
             // re-wake it if necessary, since `tick::spawn` panics if `tick` is already running
             // and I don't know how to *check* if it's running.
-            tick::spawn_after(Duration::millis(20)).unwrap();
+            tick::spawn_after(machine.tick_period()).unwrap();
         })
     }
 }
@@ -1,5 +1,5 @@
 use arrayvec::ArrayVec;
-use brachiograph::{Op, Resp};
+use brachiograph::{Op, Reply};
 use postcard::accumulator::{CobsAccumulator, FeedResult};
 use stm32f1xx_hal::usb::UsbBusType;
 use usb_device::prelude::*;
@@ -113,7 +113,7 @@ impl UsbSerial {
     }
 
     /// Tries to send or queue a message. Returns the message if the queue was full.
-    pub fn send(&mut self, msg: Resp) -> Result<(), Resp> {
+    pub fn send(&mut self, msg: Reply) -> Result<(), Reply> {
         self.write();
         let len = self.write_buf.len();
         let ret = unsafe {
@@ -0,0 +1,108 @@
+//! Time, as the firmware counts it.
+//!
+//! The firmware's clock ticks at [`TICK_HZ`], but the [`brachiograph`] crate (and the protocol)
+//! count microseconds. Everything that converts between the two, or that asks how far apart two
+//! instants are, should go through here. None of it panics: a panic in the middle of a drawing
+//! leaves the arm stuck until someone resets it, so a clock that seems to have gone backwards
+//! just means that whatever was happening is finished.
+
+use brachiograph::Fixed;
+
+// This is fine enough for any tick in `brachiograph::pwm::Timing`.
+pub const TICK_HZ: u32 = 1000;
+
+pub type Duration = fugit::TimerDurationU64<TICK_HZ>;
+pub type Instant = fugit::TimerInstantU64<TICK_HZ>;
+
+// How many of the brachiograph crate's ticks there are in one of ours.
+const GEOM_TICKS_PER_TICK: u64 = {
+    assert!(1_000_000 % TICK_HZ == 0);
+    1_000_000 / TICK_HZ as u64
+};
+
+/// The instant in the brachiograph crate's timebase that is the same as `now`.
+///
+/// Instants too far in the future to be represented (about half a million years) are clamped
+/// to the last representable one.
+pub fn to_geom(now: Instant) -> brachiograph::Instant {
+    brachiograph::Instant::from_ticks(now.ticks().saturating_mul(GEOM_TICKS_PER_TICK))
+}
+
+/// How far `now` is through the time between `start` and `end`, from 0 to 1.
+///
+/// If `now` is out of order with the other two (or they're out of order with each other),
+/// this is 1.
+pub fn progress(start: Instant, end: Instant, now: Instant) -> Fixed {
+    let (Some(total), Some(so_far)) = (
+        end.checked_duration_since(start),
+        now.checked_duration_since(start),
+    ) else {
+        return Fixed::ONE;
+    };
+    if so_far >= total {
+        Fixed::ONE
+    } else {
+        // A `Fixed` only goes up to 2^19, so long stretches of time get counted more coarsely.
+        let (mut total, mut so_far) = (total.ticks(), so_far.ticks());
+        while total > 1 << 16 {
+            total >>= 1;
+            so_far >>= 1;
+        }
+        if total == 0 {
+            return Fixed::ONE;
+        }
+        Fixed::from_num(so_far) / Fixed::from_num(total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Instant {
+        Instant::from_ticks(0) + Duration::millis(ms)
+    }
+
+    #[test]
+    fn conversion() {
+        assert_eq!(to_geom(ms(0)), brachiograph::Instant::from_ticks(0));
+        assert_eq!(
+            to_geom(ms(1500)),
+            brachiograph::Instant::from_ticks(0) + brachiograph::Duration::millis(1500)
+        );
+        assert_eq!(
+            to_geom(Instant::from_ticks(u64::MAX)),
+            brachiograph::Instant::from_ticks(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn progress_through() {
+        assert_eq!(progress(ms(10), ms(20), ms(10)), 0);
+        assert_eq!(progress(ms(10), ms(20), ms(15)), Fixed::ONE / 2);
+        assert_eq!(progress(ms(10), ms(20), ms(20)), 1);
+        assert_eq!(progress(ms(10), ms(20), ms(30)), 1);
+        // Out of order.
+        assert_eq!(progress(ms(10), ms(20), ms(5)), 1);
+        assert_eq!(progress(ms(20), ms(10), ms(15)), 1);
+        assert_eq!(progress(ms(10), ms(10), ms(10)), 1);
+        // Longer than a `Fixed` can count.
+        let hour = 60 * 60 * 1000;
+        let quarter = progress(ms(0), ms(hour), ms(hour / 4));
+        assert!((quarter - Fixed::ONE / 4).abs() < 0.001, "{quarter}");
+    }
+
+    #[test]
+    fn wrapping_around() {
+        // The clock will never get anywhere near wrapping, but if it did then the instants just
+        // after the wrap should count as after the ones just before it.
+        let before = Instant::from_ticks(u64::MAX - 4);
+        let after = before + Duration::from_ticks(10);
+        assert_eq!(after.ticks(), 5);
+        assert!(after > before);
+        assert_eq!(
+            progress(before, after + Duration::from_ticks(10), after),
+            Fixed::ONE / 2
+        );
+    }
+}
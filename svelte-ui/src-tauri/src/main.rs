@@ -11,7 +11,7 @@ use std::sync::{
 use tauri::api::dialog::FileDialogBuilder;
 use tauri::{AppHandle, CustomMenuItem, Manager, Menu, MenuItem, Submenu};
 
-use brachiograph_host::{Op, Serial};
+use brachiograph_host::{profile::Profile, svg, Job, Serial};
 use brachiologo::Program;
 
 struct State {
@@ -21,10 +21,14 @@ struct State {
 #[derive(Clone, Debug, Serialize)]
 enum RunError {
     Connection,
+    // The brachiograph's emergency stop button was pressed.
+    EStop,
     Code {
         start_line: u32,
         start_col: u32,
         len: u32,
+        // What went wrong, ready to show to the user.
+        msg: String,
     },
 }
 
@@ -34,6 +38,7 @@ impl<'a> From<brachiologo::ParseError<'a>> for RunError {
             start_line: e.input.location_line(),
             start_col: e.input.get_column() as u32,
             len: e.input.len() as u32,
+            msg: e.to_string(),
         }
     }
 }
@@ -44,6 +49,7 @@ impl<'a> From<brachiologo::Error<'a>> for RunError {
             start_line: e.span().location_line(),
             start_col: e.span().get_column() as u32,
             len: e.span().len() as u32,
+            msg: e.to_string(),
         }
     }
 }
@@ -88,7 +94,21 @@ fn main() {
             _ => {}
         })
         .manage(state)
-        .invoke_handler(tauri::generate_handler![run, check_status, write_file])
+        .invoke_handler(tauri::generate_handler![
+            run,
+            check_status,
+            write_file,
+            lint,
+            workspace,
+            move_to,
+            jog,
+            set_pen,
+            jog_position,
+            preview_svg,
+            svg_cost,
+            plot_svg,
+            clear_estop
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -96,16 +116,56 @@ fn main() {
 enum Cmd {
     Ping,
     Run(String),
+    // Lift the pen and move it to a point.
+    MoveTo(f64, f64),
+    // Move the pen a little way from where it is, without lifting it.
+    Jog(f64, f64),
+    // Put the pen down (if true) or lift it, without moving.
+    Pen(bool),
+    // Find out where the pen is.
+    JogPosition,
+    Plot(Job),
+    ClearEStop,
 }
 
 #[derive(Clone, Debug, Serialize)]
 enum Response {
     Ready,
     Missing,
+    // The emergency stop button was pressed, and the brachiograph won't move until it's cleared.
+    EStop,
+}
+
+// Where the pen is, for the jog panel.
+#[derive(Clone, Debug, Serialize)]
+struct JogPosition {
+    x: f64,
+    y: f64,
+    pen_down: bool,
+}
+
+// What the brachio thread remembers between jogs.
+#[derive(Default)]
+struct Jogger {
+    // Where the last jog was aiming for. We don't ask the brachiograph, because it would tell
+    // us where it is in the middle of an unfinished move, and then jogging quickly would drift.
+    target: Option<kurbo::Point>,
+    pen_down: bool,
+}
+
+// Tells the front end about an error from sending ops, forgetting about the port if it's broken.
+fn report(app: &AppHandle, port: &mut Option<Serial>, e: RunError) {
+    if let RunError::EStop = e {
+        app.emit_all("brachio-msg", Response::EStop).unwrap();
+    } else {
+        *port = None;
+        app.emit_all("brachio-msg", Response::Missing).unwrap();
+    }
 }
 
 fn brachio_thread(app: AppHandle, rx: Receiver<Cmd>) {
     let mut port = Serial::detect();
+    let mut jogger = Jogger::default();
 
     while let Ok(msg) = rx.recv() {
         match msg {
@@ -114,29 +174,34 @@ fn brachio_thread(app: AppHandle, rx: Receiver<Cmd>) {
                 if port.is_none() {
                     port = Serial::detect();
                 }
-                if port.is_some() {
+                if let Some(p) = port.as_mut() {
+                    // Older firmware doesn't know how to describe itself, so don't insist.
+                    if let Ok(info) = p.info() {
+                        app.emit_all("brachio-info", info).unwrap();
+                    }
                     app.emit_all("brachio-msg", Response::Ready).unwrap();
                 } else {
                     app.emit_all("brachio-msg", Response::Missing).unwrap();
                 }
             }
             Cmd::Run(s) => {
+                jogger = Jogger::default();
                 if port.is_none() {
                     port = Serial::detect();
                 }
                 if let Some(p) = port.as_mut() {
                     if let Err(e) = try_run(&s, p) {
                         match e {
+                            RunError::EStop => {
+                                app.emit_all("brachio-msg", Response::EStop).unwrap();
+                            }
                             RunError::Connection => {
                                 port = None;
                                 app.emit_all("brachio-msg", Response::Missing).unwrap();
                             }
-                            RunError::Code {
-                                start_line,
-                                start_col,
-                                len,
-                            } => {
+                            RunError::Code { ref msg, .. } => {
                                 println!("code error {e:?}");
+                                app.emit_all("code-error", msg.clone()).unwrap();
                             }
                         }
                     }
@@ -144,32 +209,139 @@ fn brachio_thread(app: AppHandle, rx: Receiver<Cmd>) {
                     app.emit_all("brachio-msg", Response::Missing).unwrap();
                 }
             }
+            Cmd::MoveTo(x, y) => {
+                jogger = Jogger {
+                    target: Some(kurbo::Point::new(x, y)),
+                    pen_down: false,
+                };
+                if let Some(p) = port.as_mut() {
+                    if let Err(e) = try_move(x, y, p) {
+                        report(&app, &mut port, e);
+                    }
+                } else {
+                    app.emit_all("brachio-msg", Response::Missing).unwrap();
+                }
+            }
+            Cmd::Jog(..) | Cmd::Pen(_) | Cmd::JogPosition => {
+                if let Some(p) = port.as_mut() {
+                    match try_jog(&msg, &mut jogger, p) {
+                        Ok(pos) => app.emit_all("brachio-position", pos).unwrap(),
+                        Err(e) => report(&app, &mut port, e),
+                    }
+                } else {
+                    app.emit_all("brachio-msg", Response::Missing).unwrap();
+                }
+            }
+            Cmd::Plot(job) => {
+                jogger = Jogger::default();
+                if let Some(p) = port.as_mut() {
+                    if let Err(e) = try_plot(&job, p) {
+                        report(&app, &mut port, e);
+                    }
+                } else {
+                    app.emit_all("brachio-msg", Response::Missing).unwrap();
+                }
+            }
+            Cmd::ClearEStop => {
+                if let Some(p) = port.as_mut() {
+                    match send(p, brachiograph::Op::ClearEStop) {
+                        Ok(()) => app.emit_all("brachio-msg", Response::Ready).unwrap(),
+                        Err(e) => report(&app, &mut port, e),
+                    }
+                } else {
+                    app.emit_all("brachio-msg", Response::Missing).unwrap();
+                }
+            }
         }
     }
 }
 
+// Sends a single op, checking whether the emergency stop button has been pressed.
+fn send(serial: &mut Serial, op: brachiograph::Op) -> Result<(), RunError> {
+    match serial.send(op).map_err(|_| RunError::Connection)? {
+        brachiograph::Resp::EStop => Err(RunError::EStop),
+        _ => Ok(()),
+    }
+}
+
+fn try_plot(job: &Job, serial: &mut Serial) -> Result<(), RunError> {
+    for op in Profile::Normal.settings().ops() {
+        send(serial, op)?;
+    }
+    for (i, op) in job.iter().enumerate() {
+        if let Some(len) = job.stroke_len(i) {
+            serial
+                .warm_up(len.min(8))
+                .map_err(|_| RunError::Connection)?;
+        }
+        send(serial, op.clone())?;
+    }
+    send(serial, brachiograph::Op::PenUp)
+}
+
+fn try_move(x: f64, y: f64, serial: &mut Serial) -> Result<(), RunError> {
+    let to = brachiograph::Point {
+        x: brachiograph::Fixed::from_num(x),
+        y: brachiograph::Fixed::from_num(y),
+    };
+    send(serial, brachiograph::Op::PenUp)?;
+    send(serial, brachiograph::Op::MoveTo(to))
+}
+
+// Carries out a jog command, and returns where the pen ended up.
+fn try_jog(cmd: &Cmd, jogger: &mut Jogger, serial: &mut Serial) -> Result<JogPosition, RunError> {
+    let here = match jogger.target {
+        Some(p) => p,
+        None => serial.position().map_err(|_| RunError::Connection)?,
+    };
+    match *cmd {
+        Cmd::Jog(dx, dy) => {
+            // Keep to the page, because the brachiograph would refuse to go off it.
+            let ws = workspace();
+            let to = kurbo::Point::new(
+                (here.x + dx).clamp(ws.x_min, ws.x_max),
+                (here.y + dy).clamp(ws.y_min, ws.y_max),
+            );
+            send(
+                serial,
+                brachiograph::Op::MoveTo(brachiograph::Point {
+                    x: brachiograph::Fixed::from_num(to.x),
+                    y: brachiograph::Fixed::from_num(to.y),
+                }),
+            )?;
+            jogger.target = Some(to);
+        }
+        Cmd::Pen(down) => {
+            let op = if down {
+                brachiograph::Op::PenDown
+            } else {
+                brachiograph::Op::PenUp
+            };
+            send(serial, op)?;
+            jogger.pen_down = down;
+            jogger.target = Some(here);
+        }
+        _ => jogger.target = Some(here),
+    }
+    let pos = jogger.target.unwrap_or(here);
+    Ok(JogPosition {
+        x: pos.x,
+        y: pos.y,
+        pen_down: jogger.pen_down,
+    })
+}
+
 fn try_run(code: &str, serial: &mut Serial) -> Result<(), RunError> {
     let prog = Program::parse(code)?;
     println!("got prog");
     let primitives = prog.exec()?;
     println!("got prims");
-    let ops = brachiograph_host::interpret(&primitives);
-    let rect = kurbo::Rect::new(-80.0, 50.0, 80.0, 130.0);
-    let ops = ops.into_iter().map(|p| p.center_and_clamp(&rect));
-    // TODO: add "init" and "finish" ops
-    serial
-        .send(Op::MoveTo { x: 0.0, y: 90.0 })
-        .map_err(|_| RunError::Connection)?;
-    serial.send(Op::PenDown).map_err(|_| RunError::Connection)?;
+    let rect = kurbo::Rect::new(-8.0, 5.0, 8.0, 13.0);
+    let ops = brachiograph_plan::Planner::default().plan_turtle(&primitives, rect);
     for op in ops {
-        serial.send(op).map_err(|_| RunError::Connection)?;
+        send(serial, op)?;
     }
-    serial.send(Op::PenUp).map_err(|_| RunError::Connection)?;
-    serial
-        .send(Op::MoveTo { x: -80.0, y: 80.0 })
-        .map_err(|_| RunError::Connection)?;
-
-    Ok(())
+    send(serial, brachiograph::Op::Park)
 }
 
 #[tauri::command]
@@ -178,12 +350,159 @@ fn run(code: String, state: tauri::State<State>) {
     state.tx.lock().unwrap().send(Cmd::Run(code)).unwrap();
 }
 
+#[tauri::command]
+fn move_to(x: f64, y: f64, state: tauri::State<State>) {
+    state.tx.lock().unwrap().send(Cmd::MoveTo(x, y)).unwrap();
+}
+
+/// Moves the pen by a small amount, leaving it up or down. The new position comes back in a
+/// `brachio-position` event.
+#[tauri::command]
+fn jog(dx: f64, dy: f64, state: tauri::State<State>) {
+    state.tx.lock().unwrap().send(Cmd::Jog(dx, dy)).unwrap();
+}
+
+/// Puts the pen down or lifts it, without moving it.
+#[tauri::command]
+fn set_pen(down: bool, state: tauri::State<State>) {
+    state.tx.lock().unwrap().send(Cmd::Pen(down)).unwrap();
+}
+
+/// Asks for a `brachio-position` event saying where the pen is.
+#[tauri::command]
+fn jog_position(state: tauri::State<State>) {
+    state.tx.lock().unwrap().send(Cmd::JogPosition).unwrap();
+}
+
+// TODO: make the rect configurable
+fn svg_job(svg: &str) -> Result<Job, String> {
+    let settings = Profile::Normal.settings();
+    let hatching = svg::Hatching::default();
+    let fonts = svg::Fonts::system();
+    svg::to_job(
+        svg.as_bytes(),
+        svg::DEFAULT_RECT,
+        &hatching,
+        &fonts,
+        &settings,
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// Returns the strokes that would be drawn for some SVG markup, as lists of points in
+/// brachiograph coordinates.
+#[tauri::command]
+fn preview_svg(svg: String) -> Result<Vec<Vec<(f64, f64)>>, String> {
+    let job = svg_job(&svg)?;
+    let bez = brachiograph_host::path::from_ops(job.ops(), kurbo::Point::ORIGIN, false);
+    let mut strokes: Vec<Vec<(f64, f64)>> = Vec::new();
+    for el in bez.elements() {
+        match *el {
+            kurbo::PathEl::MoveTo(p) => strokes.push(vec![(p.x, p.y)]),
+            kurbo::PathEl::LineTo(p) => {
+                if let Some(stroke) = strokes.last_mut() {
+                    stroke.push((p.x, p.y));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(strokes)
+}
+
+// How much it costs to plot one layer of an SVG.
+#[derive(Clone, Debug, Serialize)]
+struct LayerCost {
+    name: String,
+    // In brachiograph units.
+    draw_distance: f64,
+    travel_distance: f64,
+    pen_toggles: usize,
+    seconds: f64,
+}
+
+/// Breaks down how long it would take to plot some SVG markup, layer by layer.
+#[tauri::command]
+fn svg_cost(svg: String) -> Result<Vec<LayerCost>, String> {
+    let job = svg_job(&svg)?;
+    let settings = Profile::Normal.settings();
+    Ok(job
+        .layer_stats(&settings)
+        .into_iter()
+        .map(|layer| LayerCost {
+            name: layer.name,
+            draw_distance: layer.stats.draw_distance,
+            travel_distance: layer.stats.travel_distance,
+            pen_toggles: layer.stats.pen_ups + layer.stats.pen_downs,
+            seconds: layer.duration.as_secs_f64(),
+        })
+        .collect())
+}
+
+#[tauri::command]
+fn plot_svg(svg: String, state: tauri::State<State>) -> Result<(), String> {
+    let job = svg_job(&svg)?;
+    state.tx.lock().unwrap().send(Cmd::Plot(job)).unwrap();
+    Ok(())
+}
+
+#[tauri::command]
+fn clear_estop(state: tauri::State<State>) {
+    state.tx.lock().unwrap().send(Cmd::ClearEStop).unwrap();
+}
+
 #[tauri::command]
 fn check_status(state: tauri::State<State>) {
     println!("check status");
     state.tx.lock().unwrap().send(Cmd::Ping).unwrap();
 }
 
+#[derive(Clone, Debug, Serialize)]
+struct LintWarning {
+    // Byte offsets into the code.
+    start: usize,
+    end: usize,
+    message: String,
+}
+
+// `locale` is the language that the user wants the warnings in, like "en" or "es".
+#[tauri::command]
+fn lint(code: String, locale: String) -> Vec<LintWarning> {
+    // Parse errors get reported when running, so here we just ignore them.
+    let Ok((_, prog)) = brachiologo::parse::program(code.as_str().into()) else {
+        return Vec::new();
+    };
+    let messages = brachiologo::i18n::Messages::new(&locale);
+    brachiologo::lint::lint(&prog)
+        .into_iter()
+        .map(|w| LintWarning {
+            start: w.span.start,
+            end: w.span.end,
+            message: messages.warning(&w.kind),
+        })
+        .collect()
+}
+
+// The part of the page that the brachiograph can draw on, in brachiograph units.
+#[derive(Clone, Debug, Serialize)]
+struct Workspace {
+    x_min: f64,
+    x_max: f64,
+    y_min: f64,
+    y_max: f64,
+}
+
+#[tauri::command]
+fn workspace() -> Workspace {
+    let config = brachiograph::geom::Config::default();
+    Workspace {
+        x_min: config.x_range.0.to_num(),
+        x_max: config.x_range.1.to_num(),
+        y_min: config.y_range.0.to_num(),
+        y_max: config.y_range.1.to_num(),
+    }
+}
+
 #[tauri::command]
 fn write_file(path: String, text: String) {
     if let Err(e) = std::fs::write(path, text) {
@@ -2,3 +2,34 @@ export enum MsgKind {
   Error,
   Info,
 }
+
+export interface LintWarning {
+  // Byte offsets into the code.
+  start: number
+  end: number
+  message: string
+}
+
+export interface Workspace {
+  // In brachiograph units.
+  x_min: number
+  x_max: number
+  y_min: number
+  y_max: number
+}
+
+export interface LayerCost {
+  name: string
+  // In brachiograph units.
+  draw_distance: number
+  travel_distance: number
+  pen_toggles: number
+  seconds: number
+}
+
+export interface JogPosition {
+  // In brachiograph units.
+  x: number
+  y: number
+  pen_down: boolean
+}
@@ -0,0 +1,85 @@
+import { derived, writable } from 'svelte/store'
+
+// Everything that the app says, in every language that it speaks. Errors and warnings in the
+// Logo code come from the back end, which has its own messages (in brachiologo's `locales`
+// directory), so they aren't here.
+const en = {
+  connect: 'Connect',
+  notConnected: 'No brachiograph connected...',
+  run: 'Run',
+  ready: 'Ready',
+  estop: 'Emergency stop! Release the button, then clear the stop to carry on.',
+  clearEStop: 'Clear emergency stop',
+  deviceInfo: 'Firmware {hash} (built {date}), calibration {crc}',
+  unknown: 'unknown',
+  line: 'line {line}:',
+  pasteSvg: 'Paste SVG',
+  plot: 'Plot',
+  clear: 'Clear',
+  noSvg: "The clipboard doesn't have any SVG in it",
+  badSvg: "Couldn't read the SVG: {error}",
+  layer: 'Layer',
+  drawing: 'Drawing',
+  moving: 'Moving',
+  pen: 'Pen',
+  time: 'Time',
+  lift: 'Lift',
+  lower: 'Lower',
+  penDown: 'pen down',
+  penUp: 'pen up',
+  language: 'Language',
+}
+
+export type Key = keyof typeof en
+
+const es: Record<Key, string> = {
+  connect: 'Conectar',
+  notConnected: 'No hay ningún brachiograph conectado...',
+  run: 'Ejecutar',
+  ready: 'Listo',
+  estop: '¡Parada de emergencia! Suelta el botón y luego quita la parada para seguir.',
+  clearEStop: 'Quitar la parada de emergencia',
+  deviceInfo: 'Firmware {hash} (compilado {date}), calibración {crc}',
+  unknown: 'desconocido',
+  line: 'línea {line}:',
+  pasteSvg: 'Pegar SVG',
+  plot: 'Dibujar',
+  clear: 'Borrar',
+  noSvg: 'No hay ningún SVG en el portapapeles',
+  badSvg: 'No pude leer el SVG: {error}',
+  layer: 'Capa',
+  drawing: 'Dibujando',
+  moving: 'Moviendo',
+  pen: 'Lápiz',
+  time: 'Tiempo',
+  lift: 'Subir',
+  lower: 'Bajar',
+  penDown: 'lápiz abajo',
+  penUp: 'lápiz arriba',
+  language: 'Idioma',
+}
+
+// The languages that we speak, with the names that their speakers use for them.
+export const locales: Record<string, { name: string, messages: Record<Key, string> }> = {
+  en: { name: 'English', messages: en },
+  es: { name: 'Español', messages: es },
+}
+
+// Whatever language was picked last time, or else the system's language if we speak it.
+function initialLocale(): string {
+  const saved = localStorage.getItem('locale')
+  if (saved && saved in locales) {
+    return saved
+  }
+  const lang = navigator.language.split('-')[0]
+  return lang in locales ? lang : 'en'
+}
+
+export const locale = writable(initialLocale())
+locale.subscribe((l) => localStorage.setItem('locale', l))
+
+// Looks up a message in the current language, filling in any `{name}`s from `vars`.
+export const t = derived(locale, (l) => (key: Key, vars: Record<string, unknown> = {}) => {
+  const msg = (locales[l] ?? locales.en).messages[key]
+  return msg.replace(/\{(\w+)\}/g, (_, name) => `${vars[name]}`)
+})